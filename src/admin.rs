@@ -0,0 +1,151 @@
+// Admin interface: one JSON request per line on a loopback TCP port, answered
+// with one JSON response line. The server side lives in backend::admin, the
+// `server admin` subcommand below is its client.
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+
+use clap::Subcommand;
+use miette::{IntoDiagnostic, WrapErr};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AdminRequest {
+    ListSessions,
+    ListStreams,
+    Storage,
+    Kick { session_id: u64, reason: String },
+    FlushRecordings,
+    SetLogFilter { filter: String },
+    Shutdown,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AdminResponse {
+    Sessions { sessions: Vec<SessionSummary> },
+    Streams { streams: Vec<StreamSummary> },
+    Storage { recordings_dir: String, files: u64, bytes: u64 },
+    Flushed { recordings: usize },
+    Ok,
+    Error { message: String },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SessionSummary {
+    pub id: u64,
+    pub peer: SocketAddr,
+    pub user: Option<String>,
+    /// RFC 3339 timestamp.
+    pub connected_at: String,
+    pub udp_endpoint: Option<SocketAddr>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StreamSummary {
+    pub addr: SocketAddr,
+    pub session_id: Option<u64>,
+    pub received: u64,
+    pub expected: u64,
+    pub lost: u64,
+    pub jitter_ms: f64,
+}
+
+/// Commands of the `server admin` subcommand.
+#[derive(Debug, Subcommand)]
+pub enum AdminCommand {
+    /// List connected sessions
+    Sessions,
+    /// Show packet and loss counters of live voice streams
+    Streams,
+    /// Show how much space stored recordings use
+    Storage,
+    /// Disconnect a session
+    Kick {
+        session_id: u64,
+        #[arg(long, default_value = "Disconnected by an operator")]
+        reason: String,
+    },
+    /// Save all buffered audio now
+    Flush,
+    /// Replace the log filter of the running server, e.g. `info,server::backend=debug`
+    #[command(alias = "log-level")]
+    LogFilter { filter: String },
+    /// Shut the server down gracefully
+    Shutdown,
+}
+
+impl AdminCommand {
+    fn request(self) -> AdminRequest {
+        match self {
+            AdminCommand::Sessions => AdminRequest::ListSessions,
+            AdminCommand::Streams => AdminRequest::ListStreams,
+            AdminCommand::Storage => AdminRequest::Storage,
+            AdminCommand::Kick { session_id, reason } => AdminRequest::Kick { session_id, reason },
+            AdminCommand::Flush => AdminRequest::FlushRecordings,
+            AdminCommand::LogFilter { filter } => AdminRequest::SetLogFilter { filter },
+            AdminCommand::Shutdown => AdminRequest::Shutdown,
+        }
+    }
+}
+
+/// Sends one command to a running server and prints the answer.
+pub fn run(addr: SocketAddr, command: AdminCommand) -> miette::Result<()> {
+    let mut stream = TcpStream::connect_timeout(&addr, Duration::from_secs(5))
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Could not reach the admin interface on {}", addr))?;
+    let mut line = serde_json::to_string(&command.request()).into_diagnostic()?;
+    line.push('\n');
+    stream.write_all(line.as_bytes()).into_diagnostic()?;
+
+    let mut response = String::new();
+    BufReader::new(stream).read_line(&mut response).into_diagnostic()?;
+    let response: AdminResponse = serde_json::from_str(&response).into_diagnostic()?;
+    print_response(response)
+}
+
+fn print_response(response: AdminResponse) -> miette::Result<()> {
+    match response {
+        AdminResponse::Sessions { sessions } => {
+            println!("{:<6} {:<22} {:<16} {:<21} VOICE", "ID", "PEER", "USER", "CONNECTED");
+            for session in sessions {
+                let voice = session.udp_endpoint.map_or("-".to_string(), |addr| addr.to_string());
+                println!(
+                    "{:<6} {:<22} {:<16} {:<21} {}",
+                    session.id,
+                    session.peer,
+                    session.user.as_deref().unwrap_or("-"),
+                    session.connected_at,
+                    voice
+                );
+            }
+        }
+        AdminResponse::Streams { streams } => {
+            println!(
+                "{:<22} {:<8} {:>10} {:>10} {:>8} {:>10}",
+                "ADDRESS", "SESSION", "RECEIVED", "EXPECTED", "LOST", "JITTER"
+            );
+            for stream in streams {
+                let session = stream.session_id.map_or("-".to_string(), |id| id.to_string());
+                println!(
+                    "{:<22} {:<8} {:>10} {:>10} {:>8} {:>8.1}ms",
+                    stream.addr, session, stream.received, stream.expected, stream.lost, stream.jitter_ms
+                );
+            }
+        }
+        AdminResponse::Storage { recordings_dir, files, bytes } => {
+            println!(
+                "{}: {} recordings, {:.1} MiB",
+                recordings_dir,
+                files,
+                bytes as f64 / (1024.0 * 1024.0)
+            );
+        }
+        AdminResponse::Flushed { recordings } => println!("Saved {} recordings", recordings),
+        AdminResponse::Ok => println!("OK"),
+        AdminResponse::Error { message } => return Err(miette::miette!("{}", message)),
+    }
+    Ok(())
+}
@@ -0,0 +1,197 @@
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::PoisonError;
+use chrono::SecondsFormat;
+use miette::IntoDiagnostic;
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
+use super::audio::AudioProcessor;
+use super::runtime::{self, TcpListener, TcpStream};
+use super::session::SessionRegistry;
+use super::voice_stream::StreamDirectory;
+use crate::admin::{AdminRequest, AdminResponse, SessionSummary, StreamSummary};
+use crate::logging::{self, FilterHandle};
+
+/// Requests are a single short line, anything longer is not a request.
+const MAX_REQUEST_LEN: usize = 64 * 1024;
+
+/// Everything the admin interface can inspect or act on.
+pub struct AdminState {
+    pub sessions: SessionRegistry,
+    pub streams: StreamDirectory,
+    pub audio_processor: AudioProcessor,
+    pub recordings_dir: PathBuf,
+    pub log_filter: FilterHandle,
+    pub shutdown: CancellationToken,
+}
+
+/// Serves the admin interface. It must only listen on loopback, requests are
+/// not authenticated.
+pub struct AdminServer {
+    listener: TcpListener,
+    state: Rc<AdminState>,
+}
+
+impl AdminServer {
+    pub fn bind(addr: SocketAddr, state: AdminState) -> miette::Result<Self> {
+        let listener = TcpListener::bind(addr).into_diagnostic()?;
+        tracing::info!("Admin interface listening on {}", addr);
+        Ok(Self {
+            listener,
+            state: Rc::new(state),
+        })
+    }
+
+    pub async fn run(self) {
+        loop {
+            tokio::select! {
+                _ = self.state.shutdown.cancelled() => break,
+                result = self.listener.accept() => match result {
+                    Ok((stream, peer)) => {
+                        let span = tracing::info_span!("admin", %peer);
+                        runtime::spawn(handle_connection(stream, peer, Rc::clone(&self.state)).instrument(span));
+                    }
+                    Err(err) => tracing::error!("Admin accept failed: {}", err),
+                },
+            }
+        }
+    }
+}
+
+async fn handle_connection(stream: TcpStream, peer: SocketAddr, state: Rc<AdminState>) {
+    let response = match read_request(&stream).await {
+        Ok(request) => {
+            tracing::info!("Admin request: {:?}", request);
+            handle_request(&state, request).await
+        }
+        Err(err) => AdminResponse::Error {
+            message: format!("Invalid request: {}", err),
+        },
+    };
+
+    let Ok(mut line) = serde_json::to_vec(&response) else {
+        return;
+    };
+    line.push(b'\n');
+    let (result, _) = stream.write_all(line).await;
+    if let Err(err) = result {
+        tracing::warn!("Could not answer admin request from {}: {}", peer, err);
+    }
+}
+
+async fn read_request(stream: &TcpStream) -> miette::Result<AdminRequest> {
+    let mut request = Vec::new();
+    let mut buffer = vec![0u8; 4096];
+    loop {
+        let (result, return_buf) = stream.read(buffer).await;
+        buffer = return_buf;
+        let num_bytes_read = result.into_diagnostic()?;
+        if num_bytes_read == 0 {
+            break;
+        }
+        request.extend_from_slice(&buffer[..num_bytes_read]);
+        if request.contains(&b'\n') {
+            break;
+        }
+        if request.len() > MAX_REQUEST_LEN {
+            miette::bail!("request is longer than {} bytes", MAX_REQUEST_LEN);
+        }
+    }
+
+    let line = request.split(|&byte| byte == b'\n').next().unwrap_or_default();
+    serde_json::from_slice(line).into_diagnostic()
+}
+
+async fn handle_request(state: &AdminState, request: AdminRequest) -> AdminResponse {
+    match request {
+        AdminRequest::ListSessions => AdminResponse::Sessions {
+            sessions: state
+                .sessions
+                .list()
+                .into_iter()
+                .map(|session| SessionSummary {
+                    id: session.id,
+                    peer: session.peer,
+                    user: session.user,
+                    connected_at: session.connected_at.to_rfc3339_opts(SecondsFormat::Secs, true),
+                    udp_endpoint: session.udp_endpoint,
+                })
+                .collect(),
+        },
+        AdminRequest::ListStreams => {
+            let mut streams: Vec<StreamSummary> = state
+                .streams
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .values()
+                .cloned()
+                .collect();
+            streams.sort_by_key(|stream| stream.addr);
+            AdminResponse::Streams { streams }
+        }
+        AdminRequest::Storage => storage_usage(&state.recordings_dir),
+        AdminRequest::Kick { session_id, reason } => match state.sessions.disconnect(session_id, &reason) {
+            Ok(()) => AdminResponse::Ok,
+            Err(err) => AdminResponse::Error { message: err.to_string() },
+        },
+        AdminRequest::FlushRecordings => match state.audio_processor.flush_all().await {
+            Ok(recordings) => AdminResponse::Flushed { recordings },
+            Err(err) => AdminResponse::Error { message: err.to_string() },
+        },
+        AdminRequest::SetLogFilter { filter } => {
+            let parsed = match logging::parse_filter(&filter) {
+                Ok(parsed) => parsed,
+                Err(err) => {
+                    return AdminResponse::Error {
+                        message: format!("Invalid log filter {:?}: {}", filter, err),
+                    }
+                }
+            };
+            match state.log_filter.reload(parsed) {
+                Ok(()) => {
+                    tracing::info!("Log filter changed to {}", filter);
+                    AdminResponse::Ok
+                }
+                Err(err) => AdminResponse::Error { message: err.to_string() },
+            }
+        }
+        AdminRequest::Shutdown => {
+            tracing::info!("Shutdown requested through the admin interface");
+            state.shutdown.cancel();
+            AdminResponse::Ok
+        }
+    }
+}
+
+fn storage_usage(recordings_dir: &Path) -> AdminResponse {
+    let (files, bytes) = directory_usage(recordings_dir);
+    AdminResponse::Storage {
+        recordings_dir: recordings_dir.display().to_string(),
+        files,
+        bytes,
+    }
+}
+
+/// Counts the files in `dir` and the directories of each user below it.
+fn directory_usage(dir: &Path) -> (u64, u64) {
+    let mut files = 0;
+    let mut bytes = 0;
+    // A directory that does not exist yet just holds nothing
+    if let Ok(entries) = std::fs::read_dir(dir) {
+        for entry in entries.flatten() {
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+            if metadata.is_file() {
+                files += 1;
+                bytes += metadata.len();
+            } else if metadata.is_dir() {
+                let (user_files, user_bytes) = directory_usage(&entry.path());
+                files += user_files;
+                bytes += user_bytes;
+            }
+        }
+    }
+    (files, bytes)
+}
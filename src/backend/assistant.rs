@@ -0,0 +1,376 @@
+use serde_json::json;
+use super::context::{estimate_tokens, Context, ContextBuilder};
+use super::conversation::{Conversation, Speaker, Summary, Turn};
+use super::prompts::PromptLibrary;
+use super::tools::{ToolCall, ToolDefinition, ToolOutcome, ToolRegistry};
+use crate::config::BackendKind;
+
+/// Name the assistant's messages are shown with.
+pub const ASSISTANT_NAME: &str = "Assistant";
+/// Rounds of tool calls in one answer, a backend that keeps asking gets cut off.
+const MAX_TOOL_ROUNDS: usize = 4;
+/// Longest excerpt of a turn in a summary.
+const MAX_EXCERPT_LEN: usize = 100;
+/// First line of a summary once its oldest lines were dropped.
+const OMITTED: &str = "- ...";
+
+/// What a backend wants to do next with a conversation.
+#[derive(Debug)]
+pub enum Step {
+    Reply(String),
+    /// Run these tools and ask again with their results in the conversation.
+    CallTools(Vec<ToolCall>),
+}
+
+/// The assistant's answer, with the tool calls made on the way.
+#[derive(Debug)]
+pub struct Answer {
+    pub text: String,
+    pub tool_calls: Vec<ToolOutcome>,
+    /// A new summary of older turns, for the caller to store with the conversation.
+    pub summary: Option<Summary>,
+}
+
+/// Produces the reply to a conversation, using the backend chosen in the config.
+#[derive(Clone)]
+pub struct Assistant {
+    kind: BackendKind,
+    /// Set when the assistant may call tools.
+    tools: Option<ToolRegistry>,
+    prompts: PromptLibrary,
+    context: ContextBuilder,
+}
+
+impl Assistant {
+    pub fn new(kind: BackendKind, tools: Option<ToolRegistry>, prompts: PromptLibrary, context: ContextBuilder) -> Self {
+        Self {
+            kind,
+            tools,
+            prompts,
+            context,
+        }
+    }
+
+    /// Model name the backend is offered under by the OpenAI-compatible API.
+    pub fn model(&self) -> &'static str {
+        match self.kind {
+            BackendKind::Sample => "talk-to-me-sample",
+            BackendKind::Echo => "talk-to-me-echo",
+        }
+    }
+
+    pub fn tools(&self) -> Option<&ToolRegistry> {
+        self.tools.as_ref()
+    }
+
+    pub fn prompts(&self) -> &PromptLibrary {
+        &self.prompts
+    }
+
+    /// The conversation's template filled in for whoever spoke last. A
+    /// template that went missing is skipped, the conversation goes on without it.
+    fn system_prompt(&self, conversation: &Conversation) -> Option<String> {
+        let template = conversation.template()?;
+        let user = conversation.last_from_participant().map(|(sender, _)| sender).unwrap_or_default();
+        match self.prompts.render(template, user) {
+            Ok(prompt) => Some(prompt),
+            Err(err) => {
+                tracing::warn!("Answering without a system prompt: {}", err);
+                None
+            }
+        }
+    }
+
+    /// Answers the latest turn with the given system prompt instead of the
+    /// conversation's template, without calling tools. Turns beyond the
+    /// context budget are summarised, the summary is not kept.
+    pub fn respond(&self, conversation: &Conversation, system_prompt: Option<&str>) -> String {
+        let context = self.context(conversation, system_prompt);
+        self.reply(&context.conversation, system_prompt)
+    }
+
+    /// Answers the latest turn, running the tools the backend asks for. Their
+    /// results are added to the conversation as the backend sees them, the
+    /// caller records them wherever the conversation is kept, together with
+    /// a new summary when one was made.
+    pub async fn answer(&self, conversation: Conversation) -> Answer {
+        // Rendered once, every step of the answer sees the same prompt
+        let system_prompt = self.system_prompt(&conversation);
+        let system_prompt = system_prompt.as_deref();
+        let Context {
+            mut conversation,
+            new_summary: summary,
+        } = self.context(&conversation, system_prompt);
+        let Some(tools) = &self.tools else {
+            return Answer {
+                text: self.reply(&conversation, system_prompt),
+                tool_calls: Vec::new(),
+                summary,
+            };
+        };
+        let definitions = tools.definitions();
+        let mut tool_calls = Vec::new();
+        for _ in 0..MAX_TOOL_ROUNDS {
+            let calls = match self.next_step(&conversation, system_prompt, &definitions) {
+                Step::Reply(text) => {
+                    return Answer {
+                        text,
+                        tool_calls,
+                        summary,
+                    }
+                }
+                Step::CallTools(calls) => calls,
+            };
+            for call in calls {
+                let outcome = tools.invoke(call).await;
+                conversation.push(Speaker::Tool(outcome.call.tool.clone()), outcome.output());
+                tool_calls.push(outcome);
+            }
+        }
+        tracing::warn!("The backend still wanted tools after {} rounds", MAX_TOOL_ROUNDS);
+        Answer {
+            text: self.reply(&conversation, system_prompt),
+            tool_calls,
+            summary,
+        }
+    }
+
+    fn reply(&self, conversation: &Conversation, system_prompt: Option<&str>) -> String {
+        match self.next_step(conversation, system_prompt, &[]) {
+            Step::Reply(text) => text,
+            Step::CallTools(_) => unreachable!("no tools were offered"),
+        }
+    }
+
+    /// The latest turns within the context budget, leaving room for the system prompt.
+    fn context(&self, conversation: &Conversation, system_prompt: Option<&str>) -> Context {
+        let reserved = system_prompt.map_or(0, estimate_tokens);
+        self.context
+            .build(conversation, reserved, |previous, turns| self.summarize(previous, turns))
+    }
+
+    /// Folds `turns` into the `previous` summary. Both backends keep an
+    /// excerpt of each turn and drop the oldest lines once over the budget.
+    fn summarize(&self, previous: Option<&str>, turns: &[&Turn]) -> String {
+        match self.kind {
+            BackendKind::Sample | BackendKind::Echo => {
+                excerpt_summary(previous, turns, self.context.summary_max_tokens())
+            }
+        }
+    }
+
+    /// Asks the backend for its next step. It may only call the tools in `tools`.
+    fn next_step(&self, conversation: &Conversation, system_prompt: Option<&str>, tools: &[ToolDefinition]) -> Step {
+        let (sender, text) = conversation.last_from_participant().unwrap_or_default();
+        let results = tool_results(conversation);
+        match self.kind {
+            BackendKind::Sample => {
+                if results.is_empty() {
+                    if let Some(call) = plan_tool_call(text, tools) {
+                        return Step::CallTools(vec![call]);
+                    }
+                }
+                let earlier = conversation.summary().map(|summary| summary.text.as_str());
+                Step::Reply(sample_response(sender, text, conversation, system_prompt, earlier, &results))
+            }
+            BackendKind::Echo => Step::Reply(text.to_string()),
+        }
+    }
+}
+
+/// Tool results that came in since the latest participant turn.
+fn tool_results(conversation: &Conversation) -> Vec<&Turn> {
+    let mut results: Vec<&Turn> = conversation
+        .turns()
+        .rev()
+        .take_while(|turn| !matches!(turn.speaker, Speaker::Participant(_)))
+        .filter(|turn| matches!(turn.speaker, Speaker::Tool(_)))
+        .collect();
+    results.reverse();
+    results
+}
+
+/// The sample backend understands a few fixed phrasings, enough to show
+/// tools at work: "what time is it", "calculate 2 + 2", "search notes for
+/// groceries" and "read file todo.txt".
+fn plan_tool_call(text: &str, tools: &[ToolDefinition]) -> Option<ToolCall> {
+    let text = text.trim();
+    // Lowercased in place, so byte offsets still match the original text
+    let lower = text.to_ascii_lowercase();
+    let after = |prefixes: &[&str]| {
+        prefixes
+            .iter()
+            .find(|prefix| lower.starts_with(*prefix))
+            .map(|prefix| text[prefix.len()..].trim().trim_end_matches('?').trim())
+            .filter(|rest| !rest.is_empty())
+    };
+
+    let (tool, arguments) = if ["what time", "time is it", "current time", "the time"]
+        .iter()
+        .any(|phrase| lower.contains(phrase))
+    {
+        ("current_time", json!({}))
+    } else if let Some(expression) = after(&["calculate ", "compute ", "what is ", "what's "])
+        .filter(|expression| expression.chars().any(|c| c.is_ascii_digit()))
+        .filter(|expression| expression.chars().all(|c| "0123456789.+-*/%^() ".contains(c)))
+    {
+        ("calculate", json!({ "expression": expression }))
+    } else if let Some(query) = after(&["search notes for ", "search my notes for "]) {
+        ("search_notes", json!({ "query": query }))
+    } else if let Some(path) = after(&["read file ", "show file "]) {
+        ("read_file", json!({ "path": path }))
+    } else {
+        return None;
+    };
+    tools.iter().any(|definition| definition.name == tool).then(|| ToolCall {
+        tool: tool.to_string(),
+        arguments,
+    })
+}
+
+/// One line per turn, after what `previous` already said, cut from the
+/// oldest line on to `max_tokens`.
+fn excerpt_summary(previous: Option<&str>, turns: &[&Turn], max_tokens: usize) -> String {
+    let mut lines: Vec<String> = previous
+        .into_iter()
+        .flat_map(str::lines)
+        .filter(|line| *line != OMITTED)
+        .map(str::to_string)
+        .collect();
+    for turn in turns {
+        let speaker = match &turn.speaker {
+            Speaker::Participant(name) => name.clone(),
+            Speaker::Assistant => ASSISTANT_NAME.to_string(),
+            Speaker::Tool(tool) => format!("Tool {}", tool),
+        };
+        lines.push(format!("- {}: {}", speaker, excerpt(&turn.text)));
+    }
+
+    let mut tokens: usize = lines.iter().map(|line| estimate_tokens(line) + 1).sum();
+    let mut omitted = false;
+    while tokens > max_tokens && lines.len() > 1 {
+        tokens -= estimate_tokens(&lines.remove(0)) + 1;
+        omitted = true;
+    }
+    if omitted {
+        lines.insert(0, OMITTED.to_string());
+    }
+    lines.join("\n")
+}
+
+/// The first line with text, without Markdown emphasis, shortened.
+fn excerpt(text: &str) -> String {
+    let line = text
+        .lines()
+        .map(|line| line.trim_matches(|c: char| c == '*' || c == '#' || c == '>' || c == '`' || c.is_whitespace()))
+        .find(|line| !line.is_empty())
+        .unwrap_or_default();
+    match line.char_indices().nth(MAX_EXCERPT_LEN) {
+        Some((end, _)) => format!("{}...", &line[..end]),
+        None => line.to_string(),
+    }
+}
+
+fn sample_response(
+    sender: &str,
+    text: &str,
+    conversation: &Conversation,
+    system_prompt: Option<&str>,
+    earlier: Option<&str>,
+    tool_results: &[&Turn],
+) -> String {
+    let mut response = String::new();
+    if let Some(prompt) = system_prompt {
+        response.push_str("**Following these instructions:**\n");
+        for line in prompt.trim().lines() {
+            response.push_str(&format!("> {}\n", line));
+        }
+        response.push('\n');
+    }
+    if let Some(earlier) = earlier {
+        response.push_str("**Earlier in this conversation:**\n");
+        response.push_str(earlier);
+        response.push_str("\n\n");
+    }
+    response.push_str(&format!(
+        "**Received a message from {}:**\n\
+        ```\n{}\n```\n\n",
+        sender, text
+    ));
+    if !tool_results.is_empty() {
+        response.push_str("**Tools used:**\n");
+        for turn in tool_results {
+            if let Speaker::Tool(tool) = &turn.speaker {
+                response.push_str(&format!("- `{}`:\n```\n{}\n```\n", tool, turn.text));
+            }
+        }
+        response.push('\n');
+    }
+    response.push_str(&format!(
+        "Here's a sample response:\n\n\
+        # Lorem Ipsum\n\
+        ## About this text\n\
+        Lorem ipsum dolor sit amet, *consectetur* adipiscing elit. \
+        Sed do **eiusmod** tempor incididunt ut labore et dolore magna aliqua.\n\n\
+        - Point 1\n\
+        - Point 2\n\
+        - Point 3\n\n\
+        > This is a blockquote with your message length: {} bytes, \
+        and the conversation so far: {} turns\n",
+        text.len(),
+        conversation.len()
+    ));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn turn(speaker: Speaker, text: &str) -> Turn {
+        Turn {
+            speaker,
+            text: text.to_string(),
+            tokens: 0,
+        }
+    }
+
+    #[test]
+    fn excerpts_take_the_first_line_with_text() {
+        assert_eq!(excerpt("\n## **Hello** there\nsecond line"), "Hello** there");
+        assert_eq!(excerpt("> `quoted`"), "quoted");
+        assert_eq!(excerpt("  \n "), "");
+        let long = "é".repeat(MAX_EXCERPT_LEN + 1);
+        assert_eq!(excerpt(&long), format!("{}...", "é".repeat(MAX_EXCERPT_LEN)));
+        assert_eq!(excerpt(&long[..2 * MAX_EXCERPT_LEN]), "é".repeat(MAX_EXCERPT_LEN));
+    }
+
+    #[test]
+    fn summaries_name_every_speaker() {
+        let turns = [
+            turn(Speaker::Participant("alice".to_string()), "What time is it?"),
+            turn(Speaker::Tool("clock".to_string()), "12:00"),
+            turn(Speaker::Assistant, "It is noon."),
+        ];
+        let turns: Vec<&Turn> = turns.iter().collect();
+        assert_eq!(
+            excerpt_summary(Some("- bob: hi"), &turns, 1000),
+            "- bob: hi\n- alice: What time is it?\n- Tool clock: 12:00\n- Assistant: It is noon."
+        );
+    }
+
+    #[test]
+    fn summaries_drop_the_oldest_lines() {
+        let turns = [
+            turn(Speaker::Participant("alice".to_string()), "first"),
+            turn(Speaker::Participant("alice".to_string()), "second"),
+            turn(Speaker::Participant("alice".to_string()), "third"),
+        ];
+        let turns: Vec<&Turn> = turns.iter().collect();
+        let summary = excerpt_summary(Some("- ...\n- bob: hi"), &turns, 10);
+        assert_eq!(summary, "- ...\n- alice: second\n- alice: third");
+
+        // A single line is kept even over the budget
+        assert_eq!(excerpt_summary(None, &turns[..1], 0), "- alice: first");
+    }
+}
@@ -1,126 +1,264 @@
 use std::collections::HashMap;
 use std::net::SocketAddr;
-use std::time::{Duration, Instant};
-use tokio_uring::net::UdpSocket;
-use std::sync::Arc;
-use miette::IntoDiagnostic;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use miette::{miette, IntoDiagnostic};
 use std::fs::File;
 use std::io::Write;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use tokio::sync::oneshot;
+use super::metrics::metrics;
 
-const MAX_UDP_PACKET_SIZE: usize = 1200;
-const SAMPLE_RATE: u32 = 44100;
-const MIN_AUDIO_DURATION: f32 = 2.0; // Minimum seconds of audio to save
+// Opus can always be decoded at 48kHz, whatever rate the sender encoded at.
+pub const SAMPLE_RATE: u32 = 48000;
 
-pub struct AudioChunk {
+#[derive(Default)]
+struct AudioChunk {
     samples: Vec<f32>,
-    last_update: Instant,
+    // User the source belongs to, whose recordings go to their own directory
+    owner: Option<String>,
 }
 
+/// Samples of one source ready to be stored.
+struct Recording {
+    source: AudioSource,
+    owner: Option<String>,
+    samples: Vec<f32>,
+}
+
+enum WriterCommand {
+    Write(Recording),
+    // Answered once everything queued before it is written
+    Sync(oneshot::Sender<()>),
+}
+
+/// Where a stream of samples comes from. Uploaded files go through the same
+/// buffering and storage as live microphone input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AudioSource {
+    Voice(SocketAddr),
+    Upload { peer: SocketAddr, upload_id: u32 },
+}
+
+impl AudioSource {
+    fn label(&self) -> String {
+        match self {
+            AudioSource::Voice(addr) => addr.port().to_string(),
+            AudioSource::Upload { peer, upload_id } => format!("{}_upload{}", peer.port(), upload_id),
+        }
+    }
+}
+
+/// Stores recordings. Each source buffers its own samples in an
+/// [`AudioTrack`], finished chunks are written by a dedicated thread so
+/// no stream ever waits on the disk.
+#[derive(Clone)]
 pub struct AudioProcessor {
-    chunks: HashMap<SocketAddr, AudioChunk>,
-    socket: Arc<UdpSocket>,
-    recording_counter: usize,
+    // Buffers of the live sources, for flushing them all at once
+    tracks: Arc<Mutex<HashMap<AudioSource, Arc<Mutex<AudioChunk>>>>>,
+    writer: Sender<WriterCommand>,
+    // Seconds of audio buffered per source before it is saved
+    chunk_secs: f32,
 }
 
 impl AudioProcessor {
-    pub fn new(socket: Arc<UdpSocket>) -> Self {
-        Self {
-            chunks: HashMap::new(),
-            socket,
-            recording_counter: 0,
+    pub fn new(recordings_dir: PathBuf, chunk_secs: f32) -> miette::Result<Self> {
+        let (writer, commands) = mpsc::channel();
+        thread::Builder::new()
+            .name("recordings".to_string())
+            .spawn(move || write_recordings(recordings_dir, commands))
+            .into_diagnostic()?;
+        Ok(Self {
+            tracks: Arc::default(),
+            writer,
+            chunk_secs,
+        })
+    }
+
+    /// Starts buffering a source. What is still buffered is saved when the
+    /// track is dropped.
+    pub fn open(&self, source: AudioSource) -> AudioTrack {
+        let chunk = Arc::new(Mutex::new(AudioChunk::default()));
+        self.lock_tracks().insert(source, Arc::clone(&chunk));
+        AudioTrack {
+            source,
+            chunk,
+            processor: self.clone(),
         }
     }
 
-    pub async fn process_packet(&mut self, addr: SocketAddr, data: Vec<u8>) -> miette::Result<()> {
-        // Convert bytes back to f32 samples
-        let new_samples: Vec<f32> = data.chunks(4)
-            .filter_map(|chunk| {
-                if chunk.len() == 4 {
-                    let arr: [u8; 4] = chunk.try_into().ok()?;
-                    Some(f32::from_le_bytes(arr))
-                } else {
-                    None
-                }
-            })
+    /// Queues everything still buffered, for every source, and waits until it
+    /// is written. Returns how many recordings were saved.
+    pub async fn flush_all(&self) -> miette::Result<usize> {
+        let chunks: Vec<(AudioSource, Arc<Mutex<AudioChunk>>)> = self
+            .lock_tracks()
+            .iter()
+            .map(|(source, chunk)| (*source, Arc::clone(chunk)))
             .collect();
+        let mut queued = 0;
+        for (source, chunk) in chunks {
+            // Sources may still be live, so they keep their owner
+            let mut chunk = chunk.lock().unwrap_or_else(PoisonError::into_inner);
+            if !chunk.samples.is_empty() {
+                let samples = std::mem::take(&mut chunk.samples);
+                self.save(source, chunk.owner.clone(), samples)?;
+                queued += 1;
+            }
+        }
+        let (done, written) = oneshot::channel();
+        self.writer
+            .send(WriterCommand::Sync(done))
+            .map_err(|_| miette!("The recording writer has stopped"))?;
+        written.await.into_diagnostic()?;
+        Ok(queued)
+    }
 
-        // Get or create chunk for this address
-        let chunk = self.chunks.entry(addr).or_insert_with(|| AudioChunk {
-            samples: Vec::new(),
-            last_update: Instant::now(),
-        });
-        
-        // Add new samples to the chunk
-        chunk.samples.extend(new_samples);
-        chunk.last_update = Instant::now();
+    fn save(&self, source: AudioSource, owner: Option<String>, samples: Vec<f32>) -> miette::Result<()> {
+        self.writer
+            .send(WriterCommand::Write(Recording { source, owner, samples }))
+            .map_err(|_| miette!("The recording writer has stopped"))
+    }
 
-        let duration = chunk.samples.len() as f32 / SAMPLE_RATE as f32;
-        
-        // Debug audio statistics and save WAV when we have enough data
-        if duration >= MIN_AUDIO_DURATION {
-            let max_amplitude = chunk.samples.iter().map(|s| s.abs()).fold(0f32, f32::max);
-            let avg_amplitude: f32 = chunk.samples.iter().map(|s| s.abs()).sum::<f32>() / chunk.samples.len() as f32;
-            
-            tracing::info!(
-                "Audio stats - Samples: {}, Max amplitude: {:.6}, Avg amplitude: {:.6}",
-                chunk.samples.len(),
-                max_amplitude,
-                avg_amplitude
-            );
-            // Save the audio chunk as WAV
-            let samples = std::mem::take(&mut chunk.samples);
-            self.save_wav_file(addr, samples)?;
+    fn lock_tracks(&self) -> std::sync::MutexGuard<'_, HashMap<AudioSource, Arc<Mutex<AudioChunk>>>> {
+        self.tracks.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// The samples buffered for one source, owned by whatever receives them.
+pub struct AudioTrack {
+    source: AudioSource,
+    chunk: Arc<Mutex<AudioChunk>>,
+    processor: AudioProcessor,
+}
+
+impl AudioTrack {
+    /// Adds decoded mono samples at the recording sample rate. Recordings of
+    /// a source with an owner are stored in that user's directory.
+    pub fn push_samples(&self, owner: Option<&str>, samples: &[f32]) -> miette::Result<()> {
+        let mut chunk = self.chunk.lock().unwrap_or_else(PoisonError::into_inner);
+        if chunk.owner.is_none() {
+            chunk.owner = owner.map(str::to_string);
         }
+        chunk.samples.extend_from_slice(samples);
 
-        Ok(())
+        let duration = chunk.samples.len() as f32 / SAMPLE_RATE as f32;
+        if duration < self.processor.chunk_secs {
+            return Ok(());
+        }
+        let max_amplitude = chunk.samples.iter().map(|s| s.abs()).fold(0f32, f32::max);
+        let avg_amplitude: f32 = chunk.samples.iter().map(|s| s.abs()).sum::<f32>() / chunk.samples.len() as f32;
+        tracing::info!(
+            "Audio stats - Samples: {}, Max amplitude: {:.6}, Avg amplitude: {:.6}",
+            chunk.samples.len(),
+            max_amplitude,
+            avg_amplitude
+        );
+        let samples = std::mem::take(&mut chunk.samples);
+        self.processor.save(self.source, chunk.owner.clone(), samples)
     }
+}
 
-    fn save_wav_file(&mut self, addr: SocketAddr, samples: Vec<f32>) -> miette::Result<()> {
-        // Create recordings directory if it doesn't exist
-        let recordings_dir = Path::new("recordings");
-        std::fs::create_dir_all(recordings_dir).into_diagnostic()?;
-
-        // Generate filename with timestamp
-        let filename = recordings_dir.join(format!(
-            "recording_{:03}_{}.wav",
-            self.recording_counter,
-            addr.port()
-        ));
-        self.recording_counter += 1;
-
-        let file = File::create(&filename).into_diagnostic()?;
-        let mut writer = std::io::BufWriter::new(file);
-
-        // Write WAV header
-        writer.write_all(b"RIFF").into_diagnostic()?;
-        let size = 36 + (samples.len() * 4) as u32;
-        writer.write_all(&size.to_le_bytes()).into_diagnostic()?;
-        writer.write_all(b"WAVE").into_diagnostic()?;
-        writer.write_all(b"fmt ").into_diagnostic()?;
-        writer.write_all(&16u32.to_le_bytes()).into_diagnostic()?; // Subchunk1Size
-        writer.write_all(&1u16.to_le_bytes()).into_diagnostic()?;  // AudioFormat (PCM)
-        writer.write_all(&1u16.to_le_bytes()).into_diagnostic()?;  // NumChannels (Mono)
-        writer.write_all(&SAMPLE_RATE.to_le_bytes()).into_diagnostic()?;
-        writer.write_all(&(SAMPLE_RATE * 4).to_le_bytes()).into_diagnostic()?; // ByteRate
-        writer.write_all(&4u16.to_le_bytes()).into_diagnostic()?;  // BlockAlign
-        writer.write_all(&32u16.to_le_bytes()).into_diagnostic()?; // BitsPerSample
-        writer.write_all(b"data").into_diagnostic()?;
-        writer.write_all(&(samples.len() * 4).to_le_bytes()).into_diagnostic()?;
-
-        // Write samples
-        for sample in samples {
-            writer.write_all(&sample.to_le_bytes()).into_diagnostic()?;
+impl Drop for AudioTrack {
+    fn drop(&mut self) {
+        {
+            let mut tracks = self.processor.lock_tracks();
+            // The source may have been opened again since, that track stays
+            if tracks.get(&self.source).is_some_and(|chunk| Arc::ptr_eq(chunk, &self.chunk)) {
+                tracks.remove(&self.source);
+            }
+        }
+        let mut chunk = self.chunk.lock().unwrap_or_else(PoisonError::into_inner);
+        if chunk.samples.is_empty() {
+            return;
+        }
+        let samples = std::mem::take(&mut chunk.samples);
+        if let Err(err) = self.processor.save(self.source, chunk.owner.take(), samples) {
+            tracing::error!("Failed to save the recording of {:?}: {:?}", self.source, err);
         }
+    }
+}
 
-        tracing::info!("Saved WAV file: {:?}", filename);
-        Ok(())
+/// Runs on the writer thread until every [`AudioProcessor`] is gone.
+fn write_recordings(recordings_dir: PathBuf, commands: Receiver<WriterCommand>) {
+    let mut recording_counter = 0;
+    for command in commands {
+        match command {
+            WriterCommand::Write(recording) => {
+                let source = recording.source;
+                if let Err(err) = save_wav_file(&recordings_dir, recording_counter, recording) {
+                    tracing::error!("Failed to save the recording of {:?}: {:?}", source, err);
+                }
+                recording_counter += 1;
+            }
+            WriterCommand::Sync(done) => {
+                let _ = done.send(());
+            }
+        }
     }
+}
+
+fn save_wav_file(
+    recordings_dir: &Path,
+    counter: usize,
+    recording: Recording,
+) -> miette::Result<()> {
+    let Recording {
+        source,
+        owner,
+        samples,
+    } = recording;
+    let dir = match owner {
+        Some(user) => recordings_dir.join(user),
+        None => recordings_dir.to_path_buf(),
+    };
+    // Create recordings directory if it doesn't exist
+    std::fs::create_dir_all(&dir).into_diagnostic()?;
+
+    // Generate filename with timestamp
+    let filename = dir.join(format!("recording_{:03}_{}.wav", counter, source.label()));
 
-    pub fn cleanup_old_chunks(&mut self) {
-        const MAX_AGE: Duration = Duration::from_secs(30);
-        self.chunks.retain(|_, chunk| {
-            chunk.last_update.elapsed() < MAX_AGE
-        });
+    // Write next to the final name and rename once complete, so an
+    // interrupted write never leaves a truncated recording behind
+    let partial = filename.with_extension("wav.part");
+    let file = File::create(&partial).into_diagnostic()?;
+    let mut writer = std::io::BufWriter::new(file);
+    let data_size = (samples.len() * 4) as u32;
+
+    // Write WAV header
+    writer.write_all(b"RIFF").into_diagnostic()?;
+    writer
+        .write_all(&(36 + data_size).to_le_bytes())
+        .into_diagnostic()?;
+    writer.write_all(b"WAVE").into_diagnostic()?;
+    writer.write_all(b"fmt ").into_diagnostic()?;
+    writer.write_all(&16u32.to_le_bytes()).into_diagnostic()?; // Subchunk1Size
+    writer.write_all(&3u16.to_le_bytes()).into_diagnostic()?; // AudioFormat (IEEE float)
+    writer.write_all(&1u16.to_le_bytes()).into_diagnostic()?; // NumChannels (Mono)
+    writer
+        .write_all(&SAMPLE_RATE.to_le_bytes())
+        .into_diagnostic()?;
+    writer
+        .write_all(&(SAMPLE_RATE * 4).to_le_bytes())
+        .into_diagnostic()?; // ByteRate
+    writer.write_all(&4u16.to_le_bytes()).into_diagnostic()?; // BlockAlign
+    writer.write_all(&32u16.to_le_bytes()).into_diagnostic()?; // BitsPerSample
+    writer.write_all(b"data").into_diagnostic()?;
+    writer
+        .write_all(&data_size.to_le_bytes())
+        .into_diagnostic()?;
+
+    // Write samples
+    for sample in samples {
+        writer.write_all(&sample.to_le_bytes()).into_diagnostic()?;
     }
-} 
\ No newline at end of file
+    writer.flush().into_diagnostic()?;
+    writer.get_ref().sync_all().into_diagnostic()?;
+    drop(writer);
+    std::fs::rename(&partial, &filename).into_diagnostic()?;
+    metrics().recordings_written.inc();
+    metrics().recording_bytes_written.add(44 + data_size as u64);
+
+    tracing::info!("Saved WAV file: {:?}", filename);
+    Ok(())
+}
@@ -0,0 +1,212 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::SystemTime;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::OsRng;
+use chrono::{DateTime, SecondsFormat, Utc};
+use clap::Subcommand;
+use miette::{IntoDiagnostic, WrapErr};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use super::tls::write_private;
+
+/// Prefix of every token, so a leaked one is easy to recognize.
+const TOKEN_PREFIX: &str = "ttm_";
+const TOKEN_BYTES: usize = 32;
+const MAX_NAME_LEN: usize = 32;
+
+/// An account allowed to connect. Its name also names its recordings directory.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserRecord {
+    pub name: String,
+    /// Hex SHA-256 of the token. Tokens are random, so unlike passwords they
+    /// need no slow hash, and checking one never stalls the event loop.
+    pub token_sha256: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct UsersFile {
+    #[serde(default, rename = "user")]
+    users: Vec<UserRecord>,
+}
+
+/// Modification time and length of the users file, `None` while there is none.
+type FileStamp = Option<(SystemTime, u64)>;
+
+/// User names by token hash, as of one version of the users file.
+#[derive(Debug)]
+struct ParsedUsers {
+    stamp: FileStamp,
+    names: HashMap<String, String>,
+}
+
+/// The users file, managed with `server user ...`.
+///
+/// It is parsed again whenever it changes, so users added or removed while
+/// the server runs take effect on their next connection. Logins in between
+/// only look at the file's modification time.
+#[derive(Debug, Clone)]
+pub struct UserStore {
+    path: PathBuf,
+    parsed: Arc<Mutex<Option<ParsedUsers>>>,
+}
+
+impl UserStore {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            parsed: Arc::default(),
+        }
+    }
+
+    /// Returns the name of the user `token` belongs to.
+    pub fn authenticate(&self, token: &str) -> miette::Result<Option<String>> {
+        let hash = hash_token(token);
+        let stamp = self.stamp()?;
+        let mut parsed = self.parsed.lock().unwrap_or_else(PoisonError::into_inner);
+        if parsed.as_ref().is_none_or(|parsed| parsed.stamp != stamp) {
+            let names = self
+                .load()?
+                .users
+                .into_iter()
+                .map(|user| (user.token_sha256, user.name))
+                .collect();
+            tracing::debug!("Loaded the users file {}", self.path.display());
+            *parsed = Some(ParsedUsers { stamp, names });
+        }
+        Ok(parsed.as_ref().and_then(|parsed| parsed.names.get(&hash).cloned()))
+    }
+
+    fn stamp(&self) -> miette::Result<FileStamp> {
+        match std::fs::metadata(&self.path) {
+            Ok(metadata) => Ok(Some((metadata.modified().into_diagnostic()?, metadata.len()))),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err)
+                .into_diagnostic()
+                .wrap_err_with(|| format!("Could not read {}", self.path.display())),
+        }
+    }
+
+    fn load(&self) -> miette::Result<UsersFile> {
+        // No file yet just means no users
+        let contents = match std::fs::read_to_string(&self.path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(UsersFile::default()),
+            Err(err) => {
+                return Err(err)
+                    .into_diagnostic()
+                    .wrap_err_with(|| format!("Could not read {}", self.path.display()))
+            }
+        };
+        toml::from_str(&contents)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Invalid users file {}", self.path.display()))
+    }
+
+    fn save(&self, file: &UsersFile) -> miette::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent).into_diagnostic()?;
+        }
+        let contents = toml::to_string(file).into_diagnostic()?;
+        write_private(&self.path, contents.as_bytes())
+    }
+}
+
+/// Commands of the `server user` subcommand. They edit the users file directly.
+#[derive(Debug, Subcommand)]
+pub enum UserCommand {
+    /// Create a user and print its token
+    Add { name: String },
+    /// Replace a user's token and print the new one
+    ResetToken { name: String },
+    /// Delete a user. Its recordings are kept.
+    Remove { name: String },
+    /// List users
+    List,
+}
+
+pub fn run_user_command(users_file: &Path, command: UserCommand) -> miette::Result<()> {
+    let store = UserStore::new(users_file.to_path_buf());
+    let mut file = store.load()?;
+    match command {
+        UserCommand::Add { name } => {
+            validate_name(&name)?;
+            if file.users.iter().any(|user| user.name == name) {
+                miette::bail!("User {} already exists, use reset-token to issue a new token", name);
+            }
+            let token = generate_token();
+            file.users.push(UserRecord {
+                name: name.clone(),
+                token_sha256: hash_token(&token),
+                created_at: Utc::now(),
+            });
+            store.save(&file)?;
+            print_token(&name, &token);
+        }
+        UserCommand::ResetToken { name } => {
+            let Some(user) = file.users.iter_mut().find(|user| user.name == name) else {
+                miette::bail!("No user named {}", name);
+            };
+            let token = generate_token();
+            user.token_sha256 = hash_token(&token);
+            store.save(&file)?;
+            print_token(&name, &token);
+        }
+        UserCommand::Remove { name } => {
+            let before = file.users.len();
+            file.users.retain(|user| user.name != name);
+            if file.users.len() == before {
+                miette::bail!("No user named {}", name);
+            }
+            store.save(&file)?;
+            println!("Removed {}", name);
+        }
+        UserCommand::List => {
+            println!("{:<32} CREATED", "NAME");
+            for user in file.users {
+                println!(
+                    "{:<32} {}",
+                    user.name,
+                    user.created_at.to_rfc3339_opts(SecondsFormat::Secs, true)
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+fn print_token(name: &str, token: &str) {
+    println!("Token for {}: {}", name, token);
+    println!("It is not stored and cannot be shown again.");
+}
+
+/// Names become directory names, so only a safe set of characters is allowed.
+fn validate_name(name: &str) -> miette::Result<()> {
+    let valid_chars = name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if name.is_empty() || name.len() > MAX_NAME_LEN || !valid_chars {
+        miette::bail!(
+            "Invalid user name {:?}: use 1 to {} letters, digits, '-' or '_'",
+            name,
+            MAX_NAME_LEN
+        );
+    }
+    Ok(())
+}
+
+fn generate_token() -> String {
+    let mut bytes = [0u8; TOKEN_BYTES];
+    OsRng.fill_bytes(&mut bytes);
+    format!("{}{}", TOKEN_PREFIX, hex(&bytes))
+}
+
+fn hash_token(token: &str) -> String {
+    hex(&Sha256::digest(token.as_bytes()))
+}
+
+pub(super) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
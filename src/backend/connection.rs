@@ -1,61 +1,609 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::time::{Duration, Instant};
 use miette::IntoDiagnostic;
-use tokio_uring::net::TcpStream;
+use tokio_util::sync::CancellationToken;
+use super::assistant::{Assistant, ASSISTANT_NAME};
+use super::audio::{AudioProcessor, AudioSource, AudioTrack};
+use super::auth::UserStore;
+use super::conversation::{Conversation, ConversationStore, Speaker};
+use super::limits::{SessionLimits, TokenBucket};
+use super::metrics::metrics;
+use super::prompts::PromptError;
+use super::rooms::{RoomError, RoomRegistry};
+use super::runtime::BufResult;
+use super::session::{SessionHandle, SessionId, SessionRegistry};
+use super::tls::Transport;
+use crate::protocol::frame::{encode_message, ClientFrame, FrameDecoder, FrameError};
+use crate::protocol::message::{ClientMessage, ServerMessage, UPLOAD_SAMPLE_RATE};
+
+const READ_BUFFER_SIZE: usize = 16 * 1024;
+/// How long a client has to send its token when the server requires one.
+const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+/// Why a remote client of a server without TLS gets no voice key.
+const VOICE_NEEDS_TLS: &str =
+    "Voice is off: the server does not use TLS, so the voice key would cross the network in cleartext";
+
+type ReadFuture = Pin<Box<dyn Future<Output = BufResult<usize, Vec<u8>>>>>;
+
+struct Upload {
+    file_name: String,
+    samples_received: u64,
+    // Saves what is still buffered when the upload is dropped
+    recording: AudioTrack,
+}
+
+/// What every chat connection shares with the rest of the server.
+#[derive(Clone)]
+pub struct ConnectionContext {
+    pub audio_processor: AudioProcessor,
+    pub assistant: Assistant,
+    pub sessions: SessionRegistry,
+    pub rooms: RoomRegistry,
+    pub conversations: ConversationStore,
+    /// Set when clients must authenticate.
+    pub users: Option<UserStore>,
+    pub limits: SessionLimits,
+    /// Hand voice keys to remote clients over connections without TLS.
+    pub allow_plaintext_voice: bool,
+    pub shutdown: CancellationToken,
+}
 
 pub struct ConnectionHandler {
-    stream: TcpStream,
+    transport: Transport,
+    peer: SocketAddr,
+    audio_processor: AudioProcessor,
+    assistant: Assistant,
+    sessions: SessionRegistry,
+    rooms: RoomRegistry,
+    users: Option<UserStore>,
+    user: Option<String>,
+    // Shown to other room members, the user name or a guest name
+    display_name: String,
+    // The room the client is in, its chat goes there instead of `conversation`
+    room: Option<String>,
+    conversations: ConversationStore,
+    // Id of the private conversation in the store, from the first message on
+    conversation: Option<String>,
+    shutdown: CancellationToken,
+    limits: SessionLimits,
+    allow_plaintext_voice: bool,
+    message_rate: TokenBucket,
+    byte_rate: TokenBucket,
+    // Set once a throttled client was told, until a message gets through again
+    throttle_notified: bool,
+    // How long to wait before the next read so the byte rate is kept
+    read_delay: Duration,
     total_bytes_read: usize,
     buffer: Vec<u8>,
+    plaintext: Vec<u8>,
+    decoder: FrameDecoder,
+    uploads: HashMap<u32, Upload>,
 }
 
 impl ConnectionHandler {
-    pub fn new(stream: TcpStream) -> Self {
+    pub fn new(transport: Transport, peer: SocketAddr, context: ConnectionContext) -> Self {
+        let limits = context.limits;
         Self {
-            stream,
+            transport,
+            peer,
+            audio_processor: context.audio_processor,
+            assistant: context.assistant,
+            sessions: context.sessions,
+            rooms: context.rooms,
+            users: context.users,
+            user: None,
+            display_name: String::new(),
+            room: None,
+            conversations: context.conversations,
+            conversation: None,
+            shutdown: context.shutdown,
+            limits,
+            allow_plaintext_voice: context.allow_plaintext_voice,
+            message_rate: TokenBucket::per_minute(limits.messages_per_minute),
+            byte_rate: TokenBucket::per_second(limits.bytes_per_second),
+            throttle_notified: false,
+            read_delay: Duration::ZERO,
             total_bytes_read: 0,
-            buffer: vec![0u8; 1024],
+            buffer: vec![0u8; READ_BUFFER_SIZE],
+            plaintext: Vec::new(),
+            decoder: FrameDecoder::with_max_len(limits.max_message_bytes),
+            uploads: HashMap::new(),
         }
     }
 
     pub async fn process(&mut self) -> miette::Result<()> {
-        tracing::info!("Processing socket connection");
+        tracing::info!("Processing socket connection from {}", self.peer);
 
-        loop {
-            let (result_num_bytes_read, return_buf) = self.stream.read(self.buffer.clone()).await;
-            self.buffer = return_buf;
-            let num_bytes_read = result_num_bytes_read.into_diagnostic()?;
-            
-            if num_bytes_read == 0 {
-                break;
-            }
-
-            let response = self.create_response(&self.buffer[..num_bytes_read], num_bytes_read);
-            let response_bytes = response.into_bytes();
-            let (result_num_byte_written, _) = self.stream.write_all(response_bytes).await;
-            result_num_byte_written.into_diagnostic()?;
-            
-            self.total_bytes_read += num_bytes_read;
-            tracing::info!("total_byte_read: {}", self.total_bytes_read);
+        metrics().tcp_connections_total.inc();
+        metrics().tcp_connections_active.inc();
+        let result = match self.authenticate().await {
+            Ok(true) => {
+                let mut session = self.sessions.register(self.peer, self.user.clone(), &self.shutdown);
+                tracing::Span::current().record("id", session.id);
+                let result = self.serve(&mut session).await;
+                if let Some(room) = self.room.take() {
+                    self.rooms.leave(&room, session.id);
+                }
+                self.sessions.unregister(session.id);
+                result
+            }
+            Ok(false) => Ok(()),
+            Err(err) => Err(err),
+        };
+        // The client may already be gone, so failing to say goodbye is fine
+        if let Err(err) = self.transport.close().await {
+            tracing::debug!("Could not close the connection cleanly: {:?}", err);
+        }
+        metrics().tcp_connections_active.dec();
+
+        // Anything the client did not finish uploading is still stored
+        for upload_id in self.uploads.keys().copied().collect::<Vec<_>>() {
+            self.finish_upload(upload_id).await?;
         }
         tracing::info!("connection is done");
 
+        result
+    }
+
+    /// Waits for the client's token when the server requires one. Returns
+    /// whether the connection goes on, a client that is turned away is told why.
+    async fn authenticate(&mut self) -> miette::Result<bool> {
+        let Some(users) = self.users.clone() else {
+            return Ok(true);
+        };
+        let deadline = tokio::time::sleep(AUTH_TIMEOUT);
+        tokio::pin!(deadline);
+
+        loop {
+            let mut read = self.start_read();
+            tokio::select! {
+                _ = self.shutdown.cancelled() => return Ok(false),
+                _ = &mut deadline => {
+                    metrics().auth_failures.inc();
+                    self.reject("No token received in time").await?;
+                    return Ok(false);
+                }
+                (result_num_bytes_read, return_buf) = &mut read => {
+                    self.buffer = return_buf;
+                    let num_bytes_read = result_num_bytes_read.into_diagnostic()?;
+                    if num_bytes_read == 0 {
+                        return Ok(false);
+                    }
+                    self.receive(num_bytes_read).await?;
+                }
+            }
+
+            // Frames after the token stay in the decoder until the session is served
+            let frame = match self.decoder.next_client_frame() {
+                Ok(Some(frame)) => frame,
+                Ok(None) => continue,
+                Err(err) => {
+                    self.reject_frame(err).await?;
+                    return Ok(false);
+                }
+            };
+            metrics().tcp_messages_received.inc();
+            let ClientFrame::Message(ClientMessage::Authenticate { token }) = frame else {
+                metrics().auth_failures.inc();
+                self.reject("Authenticate before sending anything else").await?;
+                return Ok(false);
+            };
+            match users.authenticate(&token) {
+                Ok(Some(user)) => {
+                    tracing::info!("{} authenticated as {}", self.peer, user);
+                    tracing::Span::current().record("user", user.as_str());
+                    self.user = Some(user);
+                    return Ok(true);
+                }
+                Ok(None) => {
+                    metrics().auth_failures.inc();
+                    tracing::warn!("Rejected an invalid token from {}", self.peer);
+                    self.reject("Invalid token").await?;
+                    return Ok(false);
+                }
+                Err(err) => {
+                    tracing::error!("Could not check the token from {}: {:?}", self.peer, err);
+                    self.reject("The server could not check your token").await?;
+                    return Ok(false);
+                }
+            }
+        }
+    }
+
+    async fn reject(&self, reason: &str) -> miette::Result<()> {
+        self.send(&ServerMessage::Disconnected {
+            reason: reason.to_string(),
+        })
+        .await
+    }
+
+    /// The stream cannot be read past a bad frame, so the client is told why
+    /// and the connection ends.
+    async fn reject_frame(&self, err: FrameError) -> miette::Result<()> {
+        tracing::warn!("Closing connection from {}: {}", self.peer, err);
+        let reason = match err {
+            FrameError::TooLarge(len) => format!(
+                "Message of {} bytes is larger than the limit of {} bytes",
+                len, self.limits.max_message_bytes
+            ),
+            err => format!("Invalid frame: {}", err),
+        };
+        self.reject(&reason).await
+    }
+
+    /// Takes in bytes read from the socket. Reading pauses afterwards while
+    /// the session is over its byte rate.
+    async fn receive(&mut self, num_bytes_read: usize) -> miette::Result<()> {
+        metrics().tcp_bytes_received.add(num_bytes_read as u64);
+        self.total_bytes_read += num_bytes_read;
+        self.read_delay = self.byte_rate.take_with_delay(num_bytes_read as u64);
+        if !self.read_delay.is_zero() {
+            tracing::debug!("{} is over its byte rate, pausing reads for {:?}", self.peer, self.read_delay);
+        }
+        self.plaintext.clear();
+        self.transport.decode(&self.buffer[..num_bytes_read], &mut self.plaintext).await?;
+        self.decoder.push(&self.plaintext);
+        Ok(())
+    }
+
+    /// Handles every complete frame. Returns `false` when a bad frame ended the connection.
+    async fn handle_frames(&mut self, session: SessionId) -> miette::Result<bool> {
+        loop {
+            match self.decoder.next_client_frame() {
+                Ok(Some(frame)) => {
+                    metrics().tcp_messages_received.inc();
+                    self.handle_frame(session, frame).await?;
+                }
+                Ok(None) => return Ok(true),
+                Err(err) => {
+                    self.reject_frame(err).await?;
+                    return Ok(false);
+                }
+            }
+        }
+    }
+
+    async fn serve(&mut self, session: &mut SessionHandle) -> miette::Result<()> {
+        self.display_name = match &self.user {
+            Some(user) => user.clone(),
+            None => format!("guest-{}", session.id),
+        };
+        // Anyone who reads the key can listen in, so it only crosses the
+        // network in cleartext when that is allowed
+        let voice_private = self.transport.is_encrypted() || self.peer.ip().to_canonical().is_loopback();
+        let (voice_key, voice_unavailable) = if voice_private || self.allow_plaintext_voice {
+            (Some(session.voice_key), None)
+        } else {
+            tracing::info!("Not giving {} a voice key, the connection is not encrypted", self.peer);
+            (None, Some(VOICE_NEEDS_TLS.to_string()))
+        };
+        self.send(&ServerMessage::Welcome {
+            session_id: session.id,
+            voice_key,
+            voice_unavailable,
+            user: self.user.clone(),
+            display_name: self.display_name.clone(),
+        })
+        .await?;
+        // Anything the client sent right after its token
+        if !self.handle_frames(session.id).await? {
+            return Ok(());
+        }
+
+        // The read stays pending across loop iterations, dropping it could
+        // lose bytes the kernel has already handed over
+        let mut read = self.start_read();
+        loop {
+            tokio::select! {
+                biased;
+                Some(message) = session.outbound.recv() => {
+                    self.send(&message).await?;
+                }
+                _ = session.closed.cancelled() => {
+                    // Deliver what was queued first, a disconnect queues its reason
+                    while let Ok(message) = session.outbound.try_recv() {
+                        self.send(&message).await?;
+                    }
+                    if self.shutdown.is_cancelled() {
+                        tracing::info!("Closing connection from {} for shutdown", self.peer);
+                        self.send(&ServerMessage::ShuttingDown).await?;
+                    }
+                    return Ok(());
+                }
+                (result_num_bytes_read, return_buf) = &mut read => {
+                    self.buffer = return_buf;
+                    let num_bytes_read = result_num_bytes_read.into_diagnostic()?;
+
+                    if num_bytes_read == 0 {
+                        return Ok(());
+                    }
+
+                    // A frame that has been read is always handled and answered
+                    self.receive(num_bytes_read).await?;
+                    if !self.handle_frames(session.id).await? {
+                        return Ok(());
+                    }
+
+                    tracing::trace!("{} bytes read in total", self.total_bytes_read);
+                    read = self.start_read();
+                }
+            }
+        }
+    }
+
+    fn start_read(&mut self) -> ReadFuture {
+        let stream = self.transport.socket();
+        let buffer = std::mem::take(&mut self.buffer);
+        let delay = std::mem::take(&mut self.read_delay);
+        Box::pin(async move {
+            if !delay.is_zero() {
+                tokio::time::sleep(delay).await;
+            }
+            stream.read(buffer).await
+        })
+    }
+
+    async fn handle_frame(&mut self, session: SessionId, frame: ClientFrame) -> miette::Result<()> {
+        // Uploads are held to the byte rate, only what makes the server do work counts here
+        let counted = matches!(
+            frame,
+            ClientFrame::Message(
+                ClientMessage::Chat { .. }
+                    | ClientMessage::UploadStart { .. }
+                    | ClientMessage::CreateRoom { .. }
+                    | ClientMessage::JoinRoom { .. }
+                    | ClientMessage::ListRooms
+                    | ClientMessage::ListPrompts
+                    | ClientMessage::NewConversation { .. }
+                    | ClientMessage::SetPrompt { .. }
+                    | ClientMessage::SetSpeakerGain { .. }
+            )
+        );
+        if counted {
+            if !self.message_rate.try_take(1) {
+                return self.throttle().await;
+            }
+            self.throttle_notified = false;
+        }
+
+        match frame {
+            // Clients send their token even when the server does not ask for one
+            ClientFrame::Message(ClientMessage::Authenticate { .. }) => {
+                tracing::debug!("Ignoring a token sent after the session started");
+            }
+            ClientFrame::Message(ClientMessage::Chat { text }) => {
+                self.chat(session, text).await?;
+            }
+            ClientFrame::Message(ClientMessage::CreateRoom { room }) => {
+                let template = self.assistant.prompts().default_template();
+                let result = self.rooms.create(&room, session, &self.display_name, template);
+                self.enter_room(session, room, result).await?;
+            }
+            ClientFrame::Message(ClientMessage::JoinRoom { room }) => {
+                if self.room.as_deref() == Some(room.as_str()) {
+                    return Ok(());
+                }
+                let result = self.rooms.join(&room, session, &self.display_name);
+                self.enter_room(session, room, result).await?;
+            }
+            ClientFrame::Message(ClientMessage::LeaveRoom) => {
+                if let Some(room) = self.room.take() {
+                    self.rooms.leave(&room, session);
+                    self.send(&ServerMessage::RoomLeft { room }).await?;
+                }
+            }
+            ClientFrame::Message(ClientMessage::ListRooms) => {
+                self.send(&ServerMessage::RoomList { rooms: self.rooms.list() }).await?;
+            }
+            ClientFrame::Message(ClientMessage::ListPrompts) => {
+                let prompts = self.assistant.prompts();
+                self.send(&ServerMessage::PromptList {
+                    prompts: prompts.list(),
+                    default: prompts.default_template(),
+                })
+                .await?;
+            }
+            ClientFrame::Message(ClientMessage::NewConversation { template }) => {
+                if let Err(err) = self.check_template(template.as_deref()) {
+                    return self.send(&ServerMessage::Error { message: err.to_string() }).await;
+                }
+                let id = self.conversations.create(self.user.clone(), template.clone()).id;
+                tracing::info!("Conversation {} started with template {:?}", id, template);
+                self.conversation = Some(id);
+                self.send(&ServerMessage::ConversationStarted { template }).await?;
+            }
+            ClientFrame::Message(ClientMessage::SetPrompt { template }) => {
+                self.set_prompt(template).await?;
+            }
+            ClientFrame::Message(ClientMessage::SetSpeakerGain { speaker, gain }) => {
+                if let Err(err) = self.rooms.set_gain(session, &speaker, gain) {
+                    self.send(&ServerMessage::Error { message: err.to_string() }).await?;
+                }
+            }
+            ClientFrame::Message(ClientMessage::UploadStart { upload_id, file_name }) => {
+                tracing::info!("Upload {} started: {}", upload_id, file_name);
+                let source = AudioSource::Upload { peer: self.peer, upload_id };
+                let upload = Upload {
+                    file_name,
+                    samples_received: 0,
+                    recording: self.audio_processor.open(source),
+                };
+                self.uploads.insert(upload_id, upload);
+            }
+            ClientFrame::UploadChunk { upload_id, samples } => {
+                let Some(upload) = self.uploads.get_mut(&upload_id) else {
+                    return self.send(&ServerMessage::Error {
+                        message: format!("Unknown upload {}", upload_id),
+                    })
+                    .await;
+                };
+                upload.samples_received += samples.len() as u64;
+                upload.recording.push_samples(self.user.as_deref(), &samples)?;
+            }
+            ClientFrame::Message(ClientMessage::UploadEnd { upload_id }) => {
+                self.finish_upload(upload_id).await?;
+            }
+        }
         Ok(())
     }
 
-    fn create_response(&self, message: &[u8], length: usize) -> String {
-        format!(
-            "**Received your message:**\n\
-            ```\n{}\n```\n\n\
-            Here's a sample response:\n\n\
-            # Lorem Ipsum\n\
-            ## About this text\n\
-            Lorem ipsum dolor sit amet, *consectetur* adipiscing elit. \
-            Sed do **eiusmod** tempor incididunt ut labore et dolore magna aliqua.\n\n\
-            - Point 1\n\
-            - Point 2\n\
-            - Point 3\n\n\
-            > This is a blockquote with your message length: {} bytes\n",
-            String::from_utf8_lossy(message),
-            length
-        )
-    }
-} 
\ No newline at end of file
+    /// Answers in the client's room when it is in one, privately otherwise.
+    async fn chat(&mut self, session: SessionId, text: String) -> miette::Result<()> {
+        let received_at = Instant::now();
+        let Some(room) = self.room.clone() else {
+            let (id, conversation) = self.add_private_turn(text);
+            let answer = self.assistant.answer(conversation).await;
+            if let Some(summary) = answer.summary {
+                self.conversations.set_summary(&id, self.user.as_deref(), summary);
+            }
+            for outcome in &answer.tool_calls {
+                let speaker = Speaker::Tool(outcome.call.tool.clone());
+                self.conversations.push(&id, self.user.as_deref(), speaker, outcome.output());
+                self.send(&outcome.to_message(None)).await?;
+            }
+            self.conversations
+                .push(&id, self.user.as_deref(), Speaker::Assistant, answer.text.clone());
+            self.send(&ServerMessage::Chat {
+                sender: ASSISTANT_NAME.to_string(),
+                text: answer.text,
+                room: None,
+            })
+            .await?;
+            metrics().reply_latency.observe(received_at.elapsed());
+            return Ok(());
+        };
+
+        match self.rooms.post(&room, session, &text) {
+            Ok(conversation) => {
+                // The sender gets the reply through its session like every other member
+                let answer = self.assistant.answer(conversation).await;
+                if let Some(summary) = answer.summary {
+                    self.rooms.set_summary(&room, summary);
+                }
+                for outcome in &answer.tool_calls {
+                    self.rooms.add_tool_result(&room, outcome);
+                }
+                self.rooms.reply(&room, answer.text);
+                metrics().reply_latency.observe(received_at.elapsed());
+                Ok(())
+            }
+            Err(err) => {
+                self.room = None;
+                self.send(&ServerMessage::Error { message: err.to_string() }).await
+            }
+        }
+    }
+
+    /// Adds the client's message to its private conversation, which is
+    /// started on the first message. A conversation deleted through the HTTP
+    /// API is started over.
+    fn add_private_turn(&mut self, text: String) -> (String, Conversation) {
+        let speaker = Speaker::Participant(self.display_name.clone());
+        if let Some(id) = self.conversation.clone() {
+            if let Some(conversation) = self.conversations.push(&id, self.user.as_deref(), speaker.clone(), text.clone()) {
+                return (id, conversation);
+            }
+        }
+        let template = self.assistant.prompts().default_template();
+        let id = self.conversations.create(self.user.clone(), template).id;
+        self.conversation = Some(id.clone());
+        let conversation = self
+            .conversations
+            .push(&id, self.user.as_deref(), speaker, text)
+            .unwrap_or_default();
+        (id, conversation)
+    }
+
+    /// Switches the template of the room the client is in, or of its private
+    /// conversation, which is started when there is none yet.
+    async fn set_prompt(&mut self, template: Option<String>) -> miette::Result<()> {
+        if let Err(err) = self.check_template(template.as_deref()) {
+            return self.send(&ServerMessage::Error { message: err.to_string() }).await;
+        }
+        if let Some(room) = self.room.clone() {
+            if let Err(err) = self.rooms.set_template(&room, template, &self.display_name) {
+                self.room = None;
+                return self.send(&ServerMessage::Error { message: err.to_string() }).await;
+            }
+            return Ok(());
+        }
+
+        let owner = self.user.as_deref();
+        let switched = self
+            .conversation
+            .as_deref()
+            .is_some_and(|id| self.conversations.set_template(id, owner, template.clone()));
+        if !switched {
+            let id = self.conversations.create(self.user.clone(), template.clone()).id;
+            self.conversation = Some(id);
+        }
+        self.send(&ServerMessage::PromptChanged {
+            template,
+            room: None,
+            changed_by: self.display_name.clone(),
+        })
+        .await
+    }
+
+    fn check_template(&self, template: Option<&str>) -> Result<(), PromptError> {
+        match template {
+            Some(template) => self.assistant.prompts().check(template),
+            None => Ok(()),
+        }
+    }
+
+    /// Moves the client into a room it created or joined, out of the one it was in.
+    async fn enter_room(
+        &mut self,
+        session: SessionId,
+        room: String,
+        result: Result<Vec<String>, RoomError>,
+    ) -> miette::Result<()> {
+        let members = match result {
+            Ok(members) => members,
+            Err(err) => return self.send(&ServerMessage::Error { message: err.to_string() }).await,
+        };
+        if let Some(previous) = self.room.replace(room.clone()) {
+            self.rooms.leave(&previous, session);
+            self.send(&ServerMessage::RoomLeft { room: previous }).await?;
+        }
+        self.send(&ServerMessage::RoomJoined { room, members }).await
+    }
+
+    /// Drops a message over the session's rate. The client hears about it
+    /// once per burst, not once per dropped message.
+    async fn throttle(&mut self) -> miette::Result<()> {
+        metrics().tcp_messages_throttled.inc();
+        if self.throttle_notified {
+            return Ok(());
+        }
+        self.throttle_notified = true;
+        tracing::warn!("{} is over its message rate, dropping messages", self.peer);
+        self.send(&ServerMessage::Error {
+            message: format!(
+                "Slow down: at most {} messages per minute are accepted, your message was dropped",
+                self.limits.messages_per_minute
+            ),
+        })
+        .await
+    }
+
+    async fn finish_upload(&mut self, upload_id: u32) -> miette::Result<()> {
+        let Some(upload) = self.uploads.remove(&upload_id) else {
+            return Ok(());
+        };
+        let duration_ms = upload.samples_received * 1000 / UPLOAD_SAMPLE_RATE as u64;
+        tracing::info!("Upload {} finished: {} ({}ms)", upload_id, upload.file_name, duration_ms);
+        self.send(&ServerMessage::UploadComplete { upload_id, duration_ms }).await
+    }
+
+    async fn send(&self, message: &ServerMessage) -> miette::Result<()> {
+        let num_bytes_written = self.transport.send(encode_message(message)).await?;
+        metrics().tcp_messages_sent.inc();
+        metrics().tcp_bytes_sent.add(num_bytes_written as u64);
+        Ok(())
+    }
+}
@@ -0,0 +1,241 @@
+use super::conversation::{Conversation, Summary, Turn, MAX_TURNS};
+use super::metrics::metrics;
+use crate::config::ContextConfig;
+
+/// Tokens a turn costs beyond its text, for the speaker and separators.
+const TURN_OVERHEAD: usize = 4;
+
+/// Rough token count, about four characters per token. No tokenizer is
+/// involved, it only has to be close enough to budget with.
+pub fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
+
+pub fn turn_tokens(text: &str) -> usize {
+    estimate_tokens(text) + TURN_OVERHEAD
+}
+
+/// The part of a conversation the backend is given.
+pub struct Context {
+    /// The latest turns, with the summary of everything before them.
+    pub conversation: Conversation,
+    /// A summary made for this context, for the caller to store with the
+    /// conversation. `None` when the stored one still did.
+    pub new_summary: Option<Summary>,
+}
+
+/// Keeps what the backend sees within a token budget: the latest turns
+/// as they were said, older ones folded into a rolling summary.
+#[derive(Clone)]
+pub struct ContextBuilder {
+    max_tokens: usize,
+    summary_max_tokens: usize,
+}
+
+impl ContextBuilder {
+    pub fn new(config: &ContextConfig) -> Self {
+        Self {
+            max_tokens: config.max_tokens,
+            summary_max_tokens: config.summary_max_tokens,
+        }
+    }
+
+    pub fn summary_max_tokens(&self) -> usize {
+        self.summary_max_tokens
+    }
+
+    /// The context for answering `conversation`, with `reserved` tokens taken
+    /// by the system prompt. `summarize` folds turns into the previous summary.
+    ///
+    /// A new summary leaves the latest turns half the budget, so the turns
+    /// added after it fit for a while and the summary is reused until they
+    /// outgrow the budget again. The turns after the summary are also kept
+    /// below `MAX_TURNS`, so none is forgotten before it is summarised.
+    pub fn build(
+        &self,
+        conversation: &Conversation,
+        reserved: usize,
+        summarize: impl FnOnce(Option<&str>, &[&Turn]) -> String,
+    ) -> Context {
+        let budget = self.max_tokens.saturating_sub(reserved);
+        let summary = conversation.summary();
+        let summary_tokens = summary.map_or(0, |summary| summary.tokens);
+        let covered = summary.map_or(0, |summary| summary.through).max(conversation.first_position());
+        let end = conversation.first_position() + conversation.len() as u64;
+        let unchanged = || Context {
+            conversation: conversation.window(covered, summary.cloned()),
+            new_summary: None,
+        };
+
+        let fits = fit_from(conversation, budget.saturating_sub(summary_tokens)) <= covered;
+        if fits && end - covered < MAX_TURNS as u64 {
+            return unchanged();
+        }
+
+        let recent_budget = budget.saturating_sub(self.summary_max_tokens) / 2;
+        let through = fit_from(conversation, recent_budget)
+            .max(end.saturating_sub(MAX_TURNS as u64 / 2))
+            .max(covered);
+        // The latest turn alone is over the budget, there is nothing to fold
+        if through <= covered {
+            return unchanged();
+        }
+        let skip = (covered - conversation.first_position()) as usize;
+        let folded: Vec<&Turn> = conversation
+            .turns()
+            .skip(skip)
+            .take((through - covered) as usize)
+            .collect();
+        let text = summarize(summary.map(|summary| summary.text.as_str()), &folded);
+        let new_summary = Summary {
+            tokens: estimate_tokens(&text),
+            text,
+            through,
+        };
+        metrics().context_summaries.inc();
+        tracing::debug!(
+            "Summarised {} turns into {} tokens, {} turns left as they were",
+            folded.len(),
+            new_summary.tokens,
+            end - through
+        );
+        Context {
+            conversation: conversation.window(through, Some(new_summary.clone())),
+            new_summary: Some(new_summary),
+        }
+    }
+}
+
+/// Position of the oldest turn from which the rest fit in `budget`. The
+/// latest turn is always kept, however long it is.
+fn fit_from(conversation: &Conversation, budget: usize) -> u64 {
+    let mut position = conversation.first_position() + conversation.len() as u64;
+    let mut used = 0;
+    for turn in conversation.turns().rev() {
+        used += turn.tokens;
+        if used > budget && used > turn.tokens {
+            break;
+        }
+        position -= 1;
+    }
+    position
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::conversation::Speaker;
+
+    fn builder(max_tokens: usize, summary_max_tokens: usize) -> ContextBuilder {
+        ContextBuilder::new(&ContextConfig {
+            max_tokens,
+            summary_max_tokens,
+        })
+    }
+
+    /// Turns of 40 characters, 14 tokens each.
+    fn conversation(turns: usize) -> Conversation {
+        let mut conversation = Conversation::default();
+        for i in 0..turns {
+            conversation.push(Speaker::Participant("alice".to_string()), format!("{:040}", i));
+        }
+        conversation
+    }
+
+    fn no_summary(_: Option<&str>, _: &[&Turn]) -> String {
+        panic!("nothing should be summarised")
+    }
+
+    #[test]
+    fn estimates_round_up() {
+        assert_eq!(estimate_tokens(""), 0);
+        assert_eq!(estimate_tokens("abcde"), 2);
+        assert_eq!(estimate_tokens("ééééé"), 2);
+        assert_eq!(turn_tokens("abcd"), 1 + TURN_OVERHEAD);
+    }
+
+    #[test]
+    fn everything_fits() {
+        let conversation = conversation(5);
+        let context = builder(1000, 100).build(&conversation, 0, no_summary);
+        assert!(context.new_summary.is_none());
+        assert_eq!(context.conversation.len(), 5);
+    }
+
+    #[test]
+    fn older_turns_are_folded_and_the_summary_reused() {
+        let mut conversation = conversation(20);
+        let builder = builder(200, 40);
+        let mut folded = 0;
+        let context = builder.build(&conversation, 0, |previous, turns| {
+            assert!(previous.is_none());
+            folded = turns.len();
+            "summary".to_string()
+        });
+        let summary = context.new_summary.expect("over the budget");
+        // The latest turns keep half of what the summary leaves
+        assert_eq!(folded, 15);
+        assert_eq!(summary.through, 15);
+        assert_eq!(context.conversation.len(), 5);
+        assert_eq!(context.conversation.first_position(), 15);
+
+        conversation.set_summary(summary);
+        conversation.push(Speaker::Assistant, "ok".to_string());
+        let context = builder.build(&conversation, 0, no_summary);
+        assert_eq!(context.conversation.summary().map(|summary| summary.through), Some(15));
+        assert_eq!(context.conversation.len(), 6);
+    }
+
+    #[test]
+    fn a_new_summary_folds_in_the_previous_one() {
+        let mut conversation = conversation(20);
+        conversation.set_summary(Summary {
+            text: "earlier".to_string(),
+            through: 4,
+            tokens: 2,
+        });
+        let context = builder(200, 40).build(&conversation, 0, |previous, turns| {
+            assert_eq!(previous, Some("earlier"));
+            assert_eq!(turns.len(), 11);
+            "later".to_string()
+        });
+        assert_eq!(context.new_summary.map(|summary| summary.through), Some(15));
+    }
+
+    #[test]
+    fn the_system_prompt_takes_from_the_budget() {
+        let conversation = conversation(5);
+        assert!(builder(100, 20).build(&conversation, 0, no_summary).new_summary.is_none());
+        let context = builder(100, 20).build(&conversation, 60, |_, _| "summary".to_string());
+        assert!(context.new_summary.is_some());
+    }
+
+    #[test]
+    fn a_latest_turn_over_the_budget_is_kept_alone() {
+        let mut conversation = Conversation::default();
+        conversation.push(Speaker::Participant("alice".to_string()), "x".repeat(4000));
+        let context = builder(100, 20).build(&conversation, 0, no_summary);
+        assert!(context.new_summary.is_none());
+        assert_eq!(context.conversation.len(), 1);
+
+        // Also once everything before it is summarised, through equals covered
+        conversation.set_summary(Summary {
+            text: "earlier".to_string(),
+            through: 0,
+            tokens: 2,
+        });
+        let context = builder(100, 20).build(&conversation, 0, no_summary);
+        assert!(context.new_summary.is_none());
+        assert_eq!(context.conversation.len(), 1);
+    }
+
+    #[test]
+    fn turns_are_summarised_before_they_could_be_forgotten() {
+        let conversation = conversation(MAX_TURNS);
+        let context = builder(usize::MAX, 100).build(&conversation, 0, |_, turns| {
+            assert_eq!(turns.len(), MAX_TURNS / 2);
+            "summary".to_string()
+        });
+        assert_eq!(context.conversation.len(), MAX_TURNS / 2);
+    }
+}
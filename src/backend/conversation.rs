@@ -0,0 +1,250 @@
+use std::cmp::Reverse;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::OsRng;
+use chrono::{DateTime, Utc};
+use super::auth::hex;
+use super::context::turn_tokens;
+
+/// Turns kept per conversation, older ones are forgotten once a summary
+/// covers them.
+pub const MAX_TURNS: usize = 200;
+/// Turns kept even when they were never summarised, so a conversation nobody
+/// asks the assistant about cannot grow without bounds.
+const MAX_KEPT_TURNS: usize = 2 * MAX_TURNS;
+/// Conversations kept by the store, the least recently used go first.
+const MAX_CONVERSATIONS: usize = 10_000;
+const ID_PREFIX: &str = "conv_";
+const ID_BYTES: usize = 12;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Speaker {
+    /// A person, by display name.
+    Participant(String),
+    Assistant,
+    /// The result of a tool the assistant called, by tool name.
+    Tool(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct Turn {
+    pub speaker: Speaker,
+    pub text: String,
+    /// Estimated tokens, counted once when the turn is added.
+    pub tokens: usize,
+}
+
+/// Older turns of a conversation, told in short by the backend.
+#[derive(Debug, Clone)]
+pub struct Summary {
+    pub text: String,
+    /// Turns it stands for, counted from the start of the conversation.
+    pub through: u64,
+    pub tokens: usize,
+}
+
+/// What the assistant has seen, either one client's private chat or
+/// everything said in a room.
+#[derive(Debug, Clone, Default)]
+pub struct Conversation {
+    turns: VecDeque<Turn>,
+    /// The system prompt template the assistant answers with.
+    template: Option<String>,
+    summary: Option<Summary>,
+    /// Turns forgotten to stay within `MAX_TURNS`.
+    forgotten: u64,
+}
+
+impl Conversation {
+    pub fn with_template(template: Option<String>) -> Self {
+        Self {
+            template,
+            ..Self::default()
+        }
+    }
+
+    pub fn template(&self) -> Option<&str> {
+        self.template.as_deref()
+    }
+
+    pub fn set_template(&mut self, template: Option<String>) {
+        self.template = template;
+    }
+
+    pub fn push(&mut self, speaker: Speaker, text: String) {
+        let summarised = self.summary.as_ref().map_or(0, |summary| summary.through);
+        while self.turns.len() >= MAX_TURNS && (self.forgotten < summarised || self.turns.len() >= MAX_KEPT_TURNS) {
+            if self.forgotten >= summarised {
+                tracing::warn!("Forgetting a turn that was never summarised");
+            }
+            self.turns.pop_front();
+            self.forgotten += 1;
+        }
+        let tokens = turn_tokens(&text);
+        self.turns.push_back(Turn { speaker, text, tokens });
+    }
+
+    pub fn summary(&self) -> Option<&Summary> {
+        self.summary.as_ref()
+    }
+
+    /// Keeps `summary` unless the conversation already has one that covers more.
+    pub fn set_summary(&mut self, summary: Summary) {
+        if self.summary.as_ref().is_none_or(|current| current.through < summary.through) {
+            self.summary = Some(summary);
+        }
+    }
+
+    /// Position of the oldest turn still kept, counted from the start of the conversation.
+    pub fn first_position(&self) -> u64 {
+        self.forgotten
+    }
+
+    /// The conversation from the turn at `position` on, with `summary` in
+    /// place of everything before it.
+    pub fn window(&self, position: u64, summary: Option<Summary>) -> Conversation {
+        let skip = position.saturating_sub(self.forgotten) as usize;
+        Conversation {
+            turns: self.turns.iter().skip(skip).cloned().collect(),
+            template: self.template.clone(),
+            summary,
+            forgotten: self.forgotten + skip.min(self.turns.len()) as u64,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.turns.len()
+    }
+
+    /// Oldest first.
+    pub fn turns(&self) -> impl DoubleEndedIterator<Item = &Turn> {
+        self.turns.iter()
+    }
+
+    /// The most recent turn a participant took.
+    pub fn last_from_participant(&self) -> Option<(&str, &str)> {
+        self.turns.iter().rev().find_map(|turn| match &turn.speaker {
+            Speaker::Participant(name) => Some((name.as_str(), turn.text.as_str())),
+            Speaker::Assistant | Speaker::Tool(_) => None,
+        })
+    }
+}
+
+/// A private conversation as the store keeps it.
+#[derive(Debug, Clone)]
+pub struct StoredConversation {
+    pub id: String,
+    /// The user it belongs to, `None` when the server does not authenticate.
+    pub owner: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub conversation: Conversation,
+}
+
+/// Private conversations with the assistant, shared by chat connections and
+/// the HTTP API. Each one is only visible to its owner. Kept in memory, so
+/// they are gone after a restart.
+#[derive(Clone, Default)]
+pub struct ConversationStore {
+    conversations: Arc<Mutex<HashMap<String, StoredConversation>>>,
+}
+
+impl ConversationStore {
+    /// Starts an empty conversation for `owner` with a system prompt template.
+    pub fn create(&self, owner: Option<String>, template: Option<String>) -> StoredConversation {
+        let mut bytes = [0u8; ID_BYTES];
+        OsRng.fill_bytes(&mut bytes);
+        let now = Utc::now();
+        let stored = StoredConversation {
+            id: format!("{}{}", ID_PREFIX, hex(&bytes)),
+            owner,
+            created_at: now,
+            updated_at: now,
+            conversation: Conversation::with_template(template),
+        };
+
+        let mut conversations = self.lock();
+        if conversations.len() >= MAX_CONVERSATIONS {
+            let oldest = conversations
+                .values()
+                .min_by_key(|stored| stored.updated_at)
+                .map(|stored| stored.id.clone());
+            if let Some(oldest) = oldest {
+                conversations.remove(&oldest);
+            }
+        }
+        conversations.insert(stored.id.clone(), stored.clone());
+        stored
+    }
+
+    /// The conversations of `owner`, most recently used first.
+    pub fn list(&self, owner: Option<&str>) -> Vec<StoredConversation> {
+        let mut owned: Vec<StoredConversation> = self
+            .lock()
+            .values()
+            .filter(|stored| stored.owner.as_deref() == owner)
+            .cloned()
+            .collect();
+        owned.sort_by_key(|stored| Reverse(stored.updated_at));
+        owned
+    }
+
+    pub fn get(&self, id: &str, owner: Option<&str>) -> Option<StoredConversation> {
+        self.lock()
+            .get(id)
+            .filter(|stored| stored.owner.as_deref() == owner)
+            .cloned()
+    }
+
+    /// Returns whether `owner` had a conversation with that id.
+    pub fn delete(&self, id: &str, owner: Option<&str>) -> bool {
+        let mut conversations = self.lock();
+        let owned = conversations.get(id).is_some_and(|stored| stored.owner.as_deref() == owner);
+        if owned {
+            conversations.remove(id);
+        }
+        owned
+    }
+
+    /// Adds a turn and returns the conversation as it is now, `None` when
+    /// `owner` has no conversation with that id.
+    pub fn push(&self, id: &str, owner: Option<&str>, speaker: Speaker, text: String) -> Option<Conversation> {
+        let mut conversations = self.lock();
+        let stored = conversations
+            .get_mut(id)
+            .filter(|stored| stored.owner.as_deref() == owner)?;
+        stored.conversation.push(speaker, text);
+        stored.updated_at = Utc::now();
+        Some(stored.conversation.clone())
+    }
+
+    /// Stores the summary the assistant made for a conversation, so it is not made again.
+    pub fn set_summary(&self, id: &str, owner: Option<&str>, summary: Summary) {
+        let mut conversations = self.lock();
+        if let Some(stored) = conversations
+            .get_mut(id)
+            .filter(|stored| stored.owner.as_deref() == owner)
+        {
+            stored.conversation.set_summary(summary);
+        }
+    }
+
+    /// Switches the template, `false` when `owner` has no conversation with that id.
+    pub fn set_template(&self, id: &str, owner: Option<&str>, template: Option<String>) -> bool {
+        let mut conversations = self.lock();
+        let Some(stored) = conversations
+            .get_mut(id)
+            .filter(|stored| stored.owner.as_deref() == owner)
+        else {
+            return false;
+        };
+        stored.conversation.set_template(template);
+        stored.updated_at = Utc::now();
+        true
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, StoredConversation>> {
+        self.conversations.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
@@ -0,0 +1,246 @@
+use std::fmt::Write;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use miette::{Diagnostic, IntoDiagnostic};
+use rustls::ServerConfig;
+use serde::Serialize;
+use thiserror::Error;
+use tokio_util::sync::CancellationToken;
+use super::limits::{ConnectionLimiter, ConnectionPermit};
+use super::metrics::metrics;
+use super::runtime::{Tasks, TcpListener};
+use super::tls::Transport;
+
+/// Longest HTTP request head we wait for.
+const MAX_HEAD_LEN: usize = 8 * 1024;
+
+/// Requests the HTTP listeners refuse to read, answered with an error status.
+#[derive(Debug, Error, Diagnostic)]
+pub enum HttpError {
+    #[error("Request head is longer than {MAX_HEAD_LEN} bytes")]
+    #[diagnostic(code(http::head_too_large))]
+    HeadTooLarge,
+
+    #[error("Request body of {0} bytes is larger than the limit of {1} bytes")]
+    #[diagnostic(code(http::body_too_large))]
+    BodyTooLarge(usize, usize),
+
+    #[error("Malformed request: {0}")]
+    #[diagnostic(code(http::malformed))]
+    Malformed(&'static str),
+}
+
+impl HttpError {
+    pub fn status(&self) -> &'static str {
+        match self {
+            HttpError::HeadTooLarge => "431 Request Header Fields Too Large",
+            HttpError::BodyTooLarge(..) => "413 Content Too Large",
+            HttpError::Malformed(_) => "400 Bad Request",
+        }
+    }
+}
+
+/// Accepts connections on one of the HTTP based ports, with TLS when the
+/// chat port has it. Connections count against the same per-address limit
+/// as chat connections.
+pub struct HttpListener {
+    listener: TcpListener,
+    tls: Option<Arc<ServerConfig>>,
+    name: &'static str,
+}
+
+impl HttpListener {
+    pub fn bind(addr: SocketAddr, tls: Option<Arc<ServerConfig>>, name: &'static str) -> miette::Result<Self> {
+        let listener = TcpListener::bind(addr).into_diagnostic()?;
+        Ok(Self { listener, tls, name })
+    }
+
+    pub fn is_tls(&self) -> bool {
+        self.tls.is_some()
+    }
+
+    /// Hands every connection to `handle` until shutdown. The connections
+    /// are tracked by `tasks`, so shutdown waits for them like for chat ones.
+    pub async fn run<F, Fut>(self, limiter: ConnectionLimiter, tasks: Tasks, shutdown: CancellationToken, handle: F)
+    where
+        F: Fn(Transport, SocketAddr, ConnectionPermit) -> Fut,
+        Fut: Future<Output = ()> + 'static,
+    {
+        // Stays pending across iterations, a completed accept must not be dropped
+        let mut accept = Box::pin(self.listener.accept());
+        loop {
+            tokio::select! {
+                _ = shutdown.cancelled() => break,
+                result = &mut accept => {
+                    accept = Box::pin(self.listener.accept());
+                    let (stream, peer) = match result {
+                        Ok(accepted) => accepted,
+                        Err(err) => {
+                            tracing::error!("{} accept failed: {}", self.name, err);
+                            continue;
+                        }
+                    };
+                    let Some(permit) = limiter.try_acquire(peer.ip()) else {
+                        metrics().tcp_connections_rejected.inc();
+                        tracing::warn!(
+                            "Refused {} connection from {}, its address already has {} open",
+                            self.name,
+                            peer,
+                            limiter.max_per_ip()
+                        );
+                        continue;
+                    };
+                    let transport = match Transport::new(stream, self.tls.as_ref()) {
+                        Ok(transport) => transport,
+                        Err(err) => {
+                            tracing::error!("Could not set up TLS for {}: {:?}", peer, err);
+                            continue;
+                        }
+                    };
+                    tasks.spawn(handle(transport, peer, permit));
+                }
+            }
+        }
+    }
+}
+
+/// One HTTP/1.1 request. Every connection carries a single request, the
+/// response closes it.
+pub struct Request {
+    pub method: String,
+    /// Without the query string.
+    pub path: String,
+    // Names are lowercase
+    headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    fn parse_head(head: &[u8]) -> Result<Self, HttpError> {
+        let head = std::str::from_utf8(head).map_err(|_| HttpError::Malformed("head is not UTF-8"))?;
+        let mut lines = head.split("\r\n");
+        let mut request_line = lines.next().unwrap_or_default().split(' ');
+        let (Some(method), Some(target)) = (request_line.next(), request_line.next()) else {
+            return Err(HttpError::Malformed("invalid request line"));
+        };
+        let path = target.split('?').next().unwrap_or(target);
+        let headers = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_string()))
+            .collect();
+        Ok(Self {
+            method: method.to_string(),
+            path: path.to_string(),
+            headers,
+            body: Vec::new(),
+        })
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Whether a comma separated header lists `token`, ignoring case.
+    pub fn header_has(&self, name: &str, token: &str) -> bool {
+        self.header(name)
+            .is_some_and(|value| value.split(',').any(|item| item.trim().eq_ignore_ascii_case(token)))
+    }
+
+    /// The token of an `Authorization: Bearer` header.
+    pub fn bearer_token(&self) -> Option<&str> {
+        let (scheme, token) = self.header("authorization")?.split_once(' ')?;
+        scheme.eq_ignore_ascii_case("bearer").then_some(token.trim())
+    }
+}
+
+/// Reads one request with a body of at most `max_body` bytes, `None` when
+/// the client leaves before sending one. Requests that cannot be read fail
+/// with an [`HttpError`] the caller can answer.
+pub async fn read_request(transport: &Transport, max_body: usize) -> miette::Result<Option<Request>> {
+    let stream = transport.socket();
+    let mut data = Vec::new();
+    let mut buffer = vec![0u8; 4096];
+    let mut request: Option<(Request, usize)> = None;
+    loop {
+        if request.is_none() {
+            if let Some(head_len) = data.windows(4).position(|window| window == b"\r\n\r\n") {
+                let parsed = Request::parse_head(&data[..head_len])?;
+                let body_len = match parsed.header("content-length") {
+                    Some(len) => len.parse().map_err(|_| HttpError::Malformed("invalid Content-Length"))?,
+                    None => 0,
+                };
+                if body_len > max_body {
+                    return Err(HttpError::BodyTooLarge(body_len, max_body).into());
+                }
+                data.drain(..head_len + 4);
+                request = Some((parsed, body_len));
+            } else if data.len() > MAX_HEAD_LEN {
+                return Err(HttpError::HeadTooLarge.into());
+            }
+        }
+        if let Some((parsed, body_len)) = &mut request {
+            if data.len() >= *body_len {
+                data.truncate(*body_len);
+                parsed.body = data;
+                return Ok(request.map(|(parsed, _)| parsed));
+            }
+        }
+
+        let (result, return_buf) = stream.read(buffer).await;
+        buffer = return_buf;
+        let num_bytes_read = result.into_diagnostic()?;
+        if num_bytes_read == 0 {
+            return Ok(None);
+        }
+        transport.decode(&buffer[..num_bytes_read], &mut data).await?;
+    }
+}
+
+/// A complete response that closes the connection.
+pub fn response(status: &str, content_type: &str, body: &[u8]) -> Vec<u8> {
+    response_with_headers(status, &[("Content-Type", content_type)], body)
+}
+
+pub fn response_with_headers(status: &str, headers: &[(&str, &str)], body: &[u8]) -> Vec<u8> {
+    let mut response = format!("HTTP/1.1 {}\r\n", status);
+    for (name, value) in headers {
+        let _ = write!(response, "{}: {}\r\n", name, value);
+    }
+    let _ = write!(response, "Content-Length: {}\r\nConnection: close\r\n\r\n", body.len());
+    let mut response = response.into_bytes();
+    response.extend_from_slice(body);
+    response
+}
+
+pub fn plain_response(status: &str, body: &str) -> Vec<u8> {
+    response(status, "text/plain; charset=utf-8", body.as_bytes())
+}
+
+pub fn json<T: Serialize>(status: &str, body: &T) -> Vec<u8> {
+    let body = serde_json::to_vec(body).expect("responses always serialize");
+    response(status, "application/json", &body)
+}
+
+/// One server-sent event with JSON data, of the default type unless `name` is given.
+pub fn event<T: Serialize>(name: Option<&str>, data: &T) -> String {
+    let data = serde_json::to_string(data).expect("events always serialize");
+    match name {
+        Some(name) => format!("event: {}\ndata: {}\n\n", name, data),
+        None => format!("data: {}\n\n", data),
+    }
+}
+
+/// Sends a complete response and closes the connection. The client may
+/// already be gone, so failing to is only logged.
+pub async fn respond(transport: &Transport, response: Vec<u8>) {
+    if let Err(err) = transport.send(response).await {
+        tracing::debug!("Could not send the HTTP response: {:?}", err);
+    }
+    if let Err(err) = transport.close().await {
+        tracing::debug!("Could not close the connection cleanly: {:?}", err);
+    }
+}
@@ -0,0 +1,533 @@
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use chrono::{SecondsFormat, Utc};
+use rustls::ServerConfig;
+use serde::{Deserialize, Serialize};
+use tracing::Instrument;
+use super::assistant::{Assistant, ASSISTANT_NAME};
+use super::auth::UserStore;
+use super::connection::ConnectionContext;
+use super::conversation::{ConversationStore, Speaker, StoredConversation, Turn};
+use super::http::{event, json, plain_response, read_request, respond, response_with_headers, HttpError, HttpListener, Request};
+use super::limits::{ConnectionLimiter, ConnectionPermit, MessageRates};
+use super::metrics::metrics;
+use super::openai;
+use super::prompts::PromptError;
+use super::runtime::Tasks;
+use super::tls::Transport;
+use super::tools::ToolOutcome;
+
+/// How long a client has to send its whole request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// What a participant is called when the server does not authenticate.
+const GUEST_NAME: &str = "guest";
+
+#[derive(Serialize)]
+struct ConversationSummary {
+    id: String,
+    created_at: String,
+    updated_at: String,
+    turns: usize,
+    template: Option<String>,
+}
+
+impl From<&StoredConversation> for ConversationSummary {
+    fn from(stored: &StoredConversation) -> Self {
+        Self {
+            id: stored.id.clone(),
+            created_at: stored.created_at.to_rfc3339_opts(SecondsFormat::Secs, true),
+            updated_at: stored.updated_at.to_rfc3339_opts(SecondsFormat::Secs, true),
+            turns: stored.conversation.len(),
+            template: stored.conversation.template().map(str::to_string),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ConversationList {
+    conversations: Vec<ConversationSummary>,
+}
+
+#[derive(Serialize)]
+struct ConversationHistory {
+    #[serde(flatten)]
+    summary: ConversationSummary,
+    /// What the assistant remembers of turns beyond its context budget.
+    earlier: Option<EarlierView>,
+    messages: Vec<MessageView>,
+}
+
+#[derive(Serialize)]
+struct EarlierView {
+    summary: String,
+    /// How many of the first turns it stands for.
+    turns: u64,
+}
+
+#[derive(Serialize)]
+struct MessageView {
+    /// `user`, `assistant` or `tool`, with the tool's name as the sender.
+    role: &'static str,
+    sender: String,
+    text: String,
+}
+
+impl From<&Turn> for MessageView {
+    fn from(turn: &Turn) -> Self {
+        match &turn.speaker {
+            Speaker::Participant(name) => Self {
+                role: "user",
+                sender: name.clone(),
+                text: turn.text.clone(),
+            },
+            Speaker::Assistant => Self {
+                role: "assistant",
+                sender: ASSISTANT_NAME.to_string(),
+                text: turn.text.clone(),
+            },
+            Speaker::Tool(name) => Self {
+                role: "tool",
+                sender: name.clone(),
+                text: turn.text.clone(),
+            },
+        }
+    }
+}
+
+/// Body of `POST /v1/conversations`, which may also be empty.
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct CreateConversation {
+    /// The server's default template when unset.
+    #[serde(default)]
+    template: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UpdateConversation {
+    /// `null` removes the template.
+    template: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PostMessage {
+    text: String,
+    /// Answer with server-sent events instead of one JSON document.
+    #[serde(default)]
+    stream: bool,
+}
+
+#[derive(Serialize)]
+struct Reply {
+    conversation_id: String,
+    message: MessageView,
+    tool_calls: Vec<ToolCallView>,
+}
+
+/// A tool the assistant called on the way to its reply.
+#[derive(Serialize)]
+struct ToolCallView {
+    tool: String,
+    arguments: serde_json::Value,
+    output: String,
+    ok: bool,
+    duration_ms: u64,
+}
+
+impl From<&ToolOutcome> for ToolCallView {
+    fn from(outcome: &ToolOutcome) -> Self {
+        Self {
+            tool: outcome.call.tool.clone(),
+            arguments: outcome.call.arguments.clone(),
+            output: outcome.output(),
+            ok: outcome.result.is_ok(),
+            duration_ms: outcome.duration.as_millis() as u64,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct TextDelta<'a> {
+    text: &'a str,
+}
+
+#[derive(Serialize)]
+struct ApiError {
+    error: String,
+}
+
+pub enum ApiResponse {
+    Complete(Vec<u8>),
+    /// Server-sent events, each already formatted.
+    Events(Vec<String>),
+}
+
+/// What the API works with, the same assistant and conversations as chat connections.
+struct Api {
+    assistant: Assistant,
+    conversations: ConversationStore,
+    users: Option<UserStore>,
+    max_body: usize,
+    /// Unix time the server started, reported as the creation time of models.
+    started_at: i64,
+    // Messages to the assistant per user, or per address without authentication
+    message_rates: MessageRates,
+}
+
+/// Scripted access to the assistant over HTTP, authenticated with the same
+/// user tokens as chat connections:
+///
+/// - `POST /v1/conversations` starts a conversation, with `{"template": ...}`
+///   naming its system prompt template when not the default
+/// - `GET /v1/conversations` lists the caller's conversations
+/// - `GET /v1/conversations/{id}` returns one with its messages
+/// - `PATCH /v1/conversations/{id}` with `{"template": ...}` switches its template
+/// - `DELETE /v1/conversations/{id}` deletes one
+/// - `POST /v1/conversations/{id}/messages` with `{"text": ...}` answers it,
+///   as server-sent events when `"stream": true` or `Accept: text/event-stream`
+/// - `GET /v1/prompts` lists the system prompt templates
+/// - `GET /v1/tools` lists the tools the assistant may call
+///
+/// Private chat from the native and browser clients shows up here as well.
+/// The conversation endpoints need `[auth]`, without it nobody owns a
+/// conversation and they are refused.
+///
+/// Tools written for OpenAI can use the server through `GET /v1/models` and
+/// `POST /v1/chat/completions`, streaming included, with their system
+/// messages as the system prompt. Messages to the assistant count against the
+/// same rate as a chat session's. `POST /v1/audio/transcriptions` answers 501,
+/// the server records voice but has no speech recognition to transcribe it.
+pub struct ApiServer {
+    listener: HttpListener,
+}
+
+impl ApiServer {
+    pub fn bind(addr: SocketAddr, tls: Option<Arc<ServerConfig>>) -> miette::Result<Self> {
+        let listener = HttpListener::bind(addr, tls, "HTTP API")?;
+        let scheme = if listener.is_tls() { "https" } else { "http" };
+        tracing::info!("HTTP API listening on {}://{}/v1/", scheme, addr);
+        Ok(Self { listener })
+    }
+
+    pub async fn run(self, context: ConnectionContext, limiter: ConnectionLimiter, tasks: Tasks) {
+        let api = Rc::new(Api {
+            assistant: context.assistant,
+            conversations: context.conversations,
+            users: context.users,
+            max_body: context.limits.max_message_bytes,
+            started_at: Utc::now().timestamp(),
+            message_rates: MessageRates::new(context.limits.messages_per_minute),
+        });
+        self.listener
+            .run(limiter, tasks, context.shutdown, |transport, peer, permit| {
+                let span = tracing::info_span!("api", %peer, user = tracing::field::Empty);
+                handle_connection(transport, peer, Rc::clone(&api), permit).instrument(span)
+            })
+            .await;
+    }
+}
+
+async fn handle_connection(
+    transport: Transport,
+    peer: SocketAddr,
+    api: Rc<Api>,
+    // Counts the connection against its address until it ends
+    _permit: ConnectionPermit,
+) {
+    let request = match tokio::time::timeout(REQUEST_TIMEOUT, read_request(&transport, api.max_body)).await {
+        Ok(Ok(Some(request))) => request,
+        Ok(Ok(None)) => return,
+        Ok(Err(err)) => {
+            tracing::debug!("Invalid HTTP request from {}: {:?}", peer, err);
+            if let Some(err) = err.downcast_ref::<HttpError>() {
+                respond(&transport, error(err.status(), err.to_string())).await;
+            }
+            return;
+        }
+        Err(_) => {
+            respond(&transport, plain_response("408 Request Timeout", "No request received in time\n")).await;
+            return;
+        }
+    };
+    metrics().http_api_requests.inc();
+    tracing::debug!("{} {}", request.method, request.path);
+
+    let response = match api.authenticate(&request) {
+        Ok(user) => api.handle(&request, user, peer).await,
+        Err(response) => ApiResponse::Complete(response),
+    };
+    match response {
+        ApiResponse::Complete(response) => respond(&transport, response).await,
+        ApiResponse::Events(events) => {
+            // No length is known up front, closing the connection ends the stream
+            let head = "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n";
+            for event in std::iter::once(head.to_string()).chain(events) {
+                if let Err(err) = transport.send(event.into_bytes()).await {
+                    tracing::debug!("Event stream to {} ended early: {:?}", peer, err);
+                    return;
+                }
+            }
+            if let Err(err) = transport.close().await {
+                tracing::debug!("Could not close the connection cleanly: {:?}", err);
+            }
+        }
+    }
+}
+
+impl Api {
+    /// The user the request's bearer token belongs to, `None` when the server
+    /// does not authenticate. A request that fails gets the response to send.
+    fn authenticate(&self, request: &Request) -> Result<Option<String>, Vec<u8>> {
+        let Some(users) = &self.users else {
+            return Ok(None);
+        };
+        let Some(token) = request.bearer_token() else {
+            metrics().auth_failures.inc();
+            return Err(unauthorized("Send your token as Authorization: Bearer <token>"));
+        };
+        match users.authenticate(token) {
+            Ok(Some(user)) => {
+                tracing::Span::current().record("user", user.as_str());
+                Ok(Some(user))
+            }
+            Ok(None) => {
+                metrics().auth_failures.inc();
+                tracing::warn!("Rejected an invalid API token");
+                Err(unauthorized("Invalid token"))
+            }
+            Err(err) => {
+                tracing::error!("Could not check an API token: {:?}", err);
+                Err(error("500 Internal Server Error", "The server could not check your token"))
+            }
+        }
+    }
+
+    async fn handle(&self, request: &Request, user: Option<String>, peer: SocketAddr) -> ApiResponse {
+        let owner = user.as_deref();
+        let caller = user.clone().unwrap_or_else(|| peer.ip().to_string());
+        let segments: Vec<&str> = request.path.trim_matches('/').split('/').collect();
+        // Anonymous callers cannot be told apart, every one of them would see
+        // the others' conversations and those of anonymous chat clients
+        if self.users.is_none() && matches!(segments.as_slice(), ["v1", "conversations", ..]) {
+            return ApiResponse::Complete(error(
+                "403 Forbidden",
+                "Conversations are only available when the server authenticates its users",
+            ));
+        }
+        let response = match (request.method.as_str(), segments.as_slice()) {
+            ("POST", ["v1", "conversations"]) => {
+                let body = if request.body.is_empty() {
+                    Ok(CreateConversation::default())
+                } else {
+                    serde_json::from_slice::<CreateConversation>(&request.body)
+                };
+                let template = match body {
+                    Ok(body) => body.template.or_else(|| self.assistant.prompts().default_template()),
+                    Err(err) => return ApiResponse::Complete(error("400 Bad Request", format!("Invalid conversation: {}", err))),
+                };
+                if let Err(err) = self.check_template(template.as_deref()) {
+                    return ApiResponse::Complete(template_error(err));
+                }
+                let stored = self.conversations.create(user.clone(), template);
+                tracing::info!("Conversation {} started over the API", stored.id);
+                json("201 Created", &ConversationSummary::from(&stored))
+            }
+            ("GET", ["v1", "conversations"]) => {
+                let conversations = self.conversations.list(owner).iter().map(ConversationSummary::from).collect();
+                json("200 OK", &ConversationList { conversations })
+            }
+            ("GET", ["v1", "conversations", id]) => match self.conversations.get(id, owner) {
+                Some(stored) => json(
+                    "200 OK",
+                    &ConversationHistory {
+                        summary: ConversationSummary::from(&stored),
+                        earlier: stored.conversation.summary().map(|summary| EarlierView {
+                            summary: summary.text.clone(),
+                            turns: summary.through,
+                        }),
+                        messages: stored.conversation.turns().map(MessageView::from).collect(),
+                    },
+                ),
+                None => not_found(id),
+            },
+            ("PATCH", ["v1", "conversations", id]) => {
+                let update: UpdateConversation = match serde_json::from_slice(&request.body) {
+                    Ok(update) => update,
+                    Err(err) => return ApiResponse::Complete(error("400 Bad Request", format!("Invalid update: {}", err))),
+                };
+                if let Err(err) = self.check_template(update.template.as_deref()) {
+                    return ApiResponse::Complete(template_error(err));
+                }
+                if !self.conversations.set_template(id, owner, update.template) {
+                    return ApiResponse::Complete(not_found(id));
+                }
+                match self.conversations.get(id, owner) {
+                    Some(stored) => json("200 OK", &ConversationSummary::from(&stored)),
+                    None => not_found(id),
+                }
+            }
+            ("DELETE", ["v1", "conversations", id]) => {
+                if !self.conversations.delete(id, owner) {
+                    return ApiResponse::Complete(not_found(id));
+                }
+                tracing::info!("Conversation {} deleted over the API", id);
+                json("200 OK", &serde_json::json!({ "deleted": id }))
+            }
+            ("POST", ["v1", "conversations", id, "messages"]) => {
+                if !self.take_message(&caller) {
+                    return ApiResponse::Complete(error("429 Too Many Requests", self.rate_message()));
+                }
+                return self.post_message(request, id, user).await;
+            }
+            ("GET", ["v1", "prompts"]) => {
+                let prompts = self.assistant.prompts();
+                json(
+                    "200 OK",
+                    &serde_json::json!({ "prompts": prompts.list(), "default": prompts.default_template() }),
+                )
+            }
+            ("GET", ["v1", "tools"]) => {
+                let tools = self.assistant.tools().map(|tools| tools.summaries()).unwrap_or_default();
+                json("200 OK", &serde_json::json!({ "tools": tools }))
+            }
+            ("GET", ["v1", "models"]) => openai::models(&self.assistant, self.started_at),
+            ("GET", ["v1", "models", id]) => openai::get_model(&self.assistant, self.started_at, id),
+            ("POST", ["v1", "chat", "completions"]) => {
+                if !self.take_message(&caller) {
+                    let message = self.rate_message();
+                    return ApiResponse::Complete(openai::error(
+                        "429 Too Many Requests",
+                        &message,
+                        "rate_limit_error",
+                        Some("rate_limit_exceeded"),
+                    ));
+                }
+                return openai::chat_completion(&self.assistant, request, owner);
+            }
+            ("POST", ["v1", "audio", "transcriptions"]) => openai::transcription(),
+            (
+                _,
+                ["v1", "conversations"]
+                | ["v1", "conversations", _]
+                | ["v1", "conversations", _, "messages"]
+                | ["v1", "models"]
+                | ["v1", "models", _]
+                | ["v1", "prompts"]
+                | ["v1", "tools"]
+                | ["v1", "chat", "completions"]
+                | ["v1", "audio", "transcriptions"],
+            ) => error("405 Method Not Allowed", format!("{} is not supported here", request.method)),
+            _ => error("404 Not Found", format!("No endpoint at {}", request.path)),
+        };
+        ApiResponse::Complete(response)
+    }
+
+    fn check_template(&self, template: Option<&str>) -> Result<(), PromptError> {
+        match template {
+            Some(template) => self.assistant.prompts().check(template),
+            None => Ok(()),
+        }
+    }
+
+    /// Counts a message to the assistant against the caller's rate, `false`
+    /// when the caller is over it.
+    fn take_message(&self, caller: &str) -> bool {
+        let allowed = self.message_rates.try_take(caller);
+        if !allowed {
+            metrics().api_messages_throttled.inc();
+            tracing::warn!("{} is over the message rate, refusing API messages", caller);
+        }
+        allowed
+    }
+
+    fn rate_message(&self) -> String {
+        format!("Too many messages, the limit is {} per minute", self.message_rates.messages_per_minute())
+    }
+
+    /// Adds the caller's message and answers it with the assistant, like a
+    /// chat message in a private conversation.
+    async fn post_message(&self, request: &Request, id: &str, user: Option<String>) -> ApiResponse {
+        let message: PostMessage = match serde_json::from_slice(&request.body) {
+            Ok(message) => message,
+            Err(err) => return ApiResponse::Complete(error("400 Bad Request", format!("Invalid message: {}", err))),
+        };
+        if message.text.trim().is_empty() {
+            return ApiResponse::Complete(error("400 Bad Request", "The message text is empty"));
+        }
+        let received_at = Instant::now();
+        let owner = user.as_deref();
+        let sender = user.clone().unwrap_or_else(|| GUEST_NAME.to_string());
+        let Some(conversation) = self
+            .conversations
+            .push(id, owner, Speaker::Participant(sender), message.text)
+        else {
+            return ApiResponse::Complete(not_found(id));
+        };
+        let answer = self.assistant.answer(conversation).await;
+        if let Some(summary) = answer.summary {
+            self.conversations.set_summary(id, owner, summary);
+        }
+        for outcome in &answer.tool_calls {
+            self.conversations
+                .push(id, owner, Speaker::Tool(outcome.call.tool.clone()), outcome.output());
+        }
+        let text = answer.text;
+        self.conversations.push(id, owner, Speaker::Assistant, text.clone());
+        metrics().reply_latency.observe(received_at.elapsed());
+
+        let stream = message.stream || request.header_has("accept", "text/event-stream");
+        let reply = Reply {
+            conversation_id: id.to_string(),
+            message: MessageView {
+                role: "assistant",
+                sender: ASSISTANT_NAME.to_string(),
+                text: text.clone(),
+            },
+            tool_calls: answer.tool_calls.iter().map(ToolCallView::from).collect(),
+        };
+        if !stream {
+            return ApiResponse::Complete(json("200 OK", &reply));
+        }
+        // The backends answer all at once, the reply is streamed a line at a time
+        // after the tool calls that led to it
+        let mut events: Vec<String> = reply.tool_calls.iter().map(|call| event(Some("tool"), call)).collect();
+        events.extend(
+            text.split_inclusive('\n')
+                .map(|line| event(Some("delta"), &TextDelta { text: line })),
+        );
+        events.push(event(Some("done"), &reply));
+        ApiResponse::Events(events)
+    }
+}
+
+fn error(status: &str, message: impl Into<String>) -> Vec<u8> {
+    json(status, &ApiError { error: message.into() })
+}
+
+fn template_error(err: PromptError) -> Vec<u8> {
+    let status = match err {
+        PromptError::NotFound(_) | PromptError::InvalidName(_) => "422 Unprocessable Entity",
+        PromptError::Unreadable(..) => "500 Internal Server Error",
+    };
+    error(status, err.to_string())
+}
+
+fn not_found(id: &str) -> Vec<u8> {
+    error("404 Not Found", format!("No conversation {}", id))
+}
+
+fn unauthorized(message: &str) -> Vec<u8> {
+    let body = serde_json::to_vec(&ApiError {
+        error: message.to_string(),
+    })
+    .expect("responses always serialize");
+    response_with_headers(
+        "401 Unauthorized",
+        &[("Content-Type", "application/json"), ("WWW-Authenticate", "Bearer")],
+        &body,
+    )
+}
@@ -0,0 +1,41 @@
+use std::net::SocketAddr;
+
+use crate::protocol::audio::LatencyProbe;
+use crate::protocol::latency::LatencyWindow;
+
+/// Log a summary after this many probes from one client.
+const LOG_EVERY: u64 = 20;
+
+/// Latency measurements for one client running the latency diagnostic mode.
+#[derive(Default)]
+pub struct LatencyStats {
+    probes: u64,
+    server_processing: LatencyWindow,
+    round_trip: LatencyWindow,
+    mouth_to_server: LatencyWindow,
+}
+
+impl LatencyStats {
+    pub fn record(&mut self, addr: SocketAddr, probe: &LatencyProbe, server_processing_us: u32) {
+        self.probes += 1;
+        self.server_processing.push(server_processing_us);
+        // The client reports what it measured from the previous echo
+        if probe.last_round_trip_us > 0 {
+            self.round_trip.push(probe.last_round_trip_us);
+        }
+        if probe.last_mouth_to_server_us > 0 {
+            self.mouth_to_server.push(probe.last_mouth_to_server_us);
+        }
+
+        if self.probes.is_multiple_of(LOG_EVERY) {
+            tracing::info!(
+                "Latency for {} over {} probes - mouth to server: {}; server processing: {}; round trip: {}",
+                addr,
+                self.server_processing.len(),
+                self.mouth_to_server.percentiles(),
+                self.server_processing.percentiles(),
+                self.round_trip.percentiles()
+            );
+        }
+    }
+}
@@ -0,0 +1,241 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// Refills at a steady rate up to its capacity, so short bursts pass and
+/// sustained traffic is held to the rate.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    per_second: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// Starts full, a burst of `capacity` is allowed right away.
+    pub fn new(capacity: u64, per_second: f64) -> Self {
+        Self {
+            capacity: capacity as f64,
+            tokens: capacity as f64,
+            per_second,
+            updated: Instant::now(),
+        }
+    }
+
+    pub fn per_second(rate: u64) -> Self {
+        Self::new(rate, rate as f64)
+    }
+
+    pub fn per_minute(rate: u64) -> Self {
+        Self::new(rate, rate as f64 / 60.0)
+    }
+
+    /// Takes `amount` tokens if the bucket holds that many. An amount above
+    /// the capacity never fits, callers keep it within the capacity.
+    pub fn try_take(&mut self, amount: u64) -> bool {
+        self.refill();
+        if self.tokens < amount as f64 {
+            return false;
+        }
+        self.tokens -= amount as f64;
+        true
+    }
+
+    /// Takes `amount` tokens even when that leaves the bucket in debt, and
+    /// returns how long it takes to pay the debt back. Used where the data
+    /// already arrived and the sender should be slowed down instead.
+    pub fn take_with_delay(&mut self, amount: u64) -> Duration {
+        self.refill();
+        self.tokens -= amount as f64;
+        if self.tokens >= 0.0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64(-self.tokens / self.per_second)
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_second).min(self.capacity);
+        self.updated = now;
+    }
+}
+
+/// Limits of one chat session.
+#[derive(Debug, Clone, Copy)]
+pub struct SessionLimits {
+    pub messages_per_minute: u64,
+    pub bytes_per_second: u64,
+    pub max_message_bytes: usize,
+}
+
+/// Limits of the voice datagrams from one address.
+#[derive(Debug, Clone, Copy)]
+pub struct AddressLimits {
+    pub streams: usize,
+    pub packets_per_second: u64,
+    pub bytes_per_second: u64,
+}
+
+/// Callers whose message rate is tracked before idle ones are forgotten.
+const MAX_TRACKED_CALLERS: usize = 4096;
+
+/// Holds every caller of a connectionless interface to `messages_per_minute`,
+/// the same rate a chat session gets. Callers are users, or addresses when
+/// the server does not authenticate.
+#[derive(Debug)]
+pub struct MessageRates {
+    messages_per_minute: u64,
+    // Each caller's bucket and when it was last used
+    callers: RefCell<HashMap<String, (TokenBucket, Instant)>>,
+}
+
+impl MessageRates {
+    pub fn new(messages_per_minute: u64) -> Self {
+        Self {
+            messages_per_minute,
+            callers: RefCell::default(),
+        }
+    }
+
+    pub fn messages_per_minute(&self) -> u64 {
+        self.messages_per_minute
+    }
+
+    /// Counts a message against the caller's rate, `false` when the caller
+    /// is over it.
+    pub fn try_take(&self, caller: &str) -> bool {
+        let mut callers = self.callers.borrow_mut();
+        let now = Instant::now();
+        if callers.len() >= MAX_TRACKED_CALLERS && !callers.contains_key(caller) {
+            // A bucket untouched for a minute has refilled, forgetting it changes nothing
+            callers.retain(|_, (_, used)| now.duration_since(*used) < Duration::from_secs(60));
+        }
+        let (bucket, used) = callers
+            .entry(caller.to_string())
+            .or_insert_with(|| (TokenBucket::per_minute(self.messages_per_minute), now));
+        *used = now;
+        bucket.try_take(1)
+    }
+}
+
+/// Counts open chat connections per address.
+#[derive(Debug, Clone)]
+pub struct ConnectionLimiter {
+    open: Rc<RefCell<HashMap<IpAddr, usize>>>,
+    max_per_ip: usize,
+}
+
+impl ConnectionLimiter {
+    pub fn new(max_per_ip: usize) -> Self {
+        Self {
+            open: Rc::default(),
+            max_per_ip,
+        }
+    }
+
+    pub fn max_per_ip(&self) -> usize {
+        self.max_per_ip
+    }
+
+    /// Returns `None` when `ip` already has as many connections as allowed.
+    /// The connection counts until the permit is dropped.
+    pub fn try_acquire(&self, ip: IpAddr) -> Option<ConnectionPermit> {
+        let mut open = self.open.borrow_mut();
+        let count = open.entry(ip).or_default();
+        if *count >= self.max_per_ip {
+            return None;
+        }
+        *count += 1;
+        Some(ConnectionPermit {
+            open: Rc::clone(&self.open),
+            ip,
+        })
+    }
+}
+
+pub struct ConnectionPermit {
+    open: Rc<RefCell<HashMap<IpAddr, usize>>>,
+    ip: IpAddr,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let mut open = self.open.borrow_mut();
+        if let Some(count) = open.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    /// Pretends `elapsed` passed since the bucket last refilled.
+    fn wait(bucket: &mut TokenBucket, elapsed: Duration) {
+        bucket.updated -= elapsed;
+    }
+
+    #[test]
+    fn bursts_pass_then_the_rate_holds() {
+        let mut bucket = TokenBucket::per_second(10);
+        assert!(bucket.try_take(10));
+        assert!(!bucket.try_take(1));
+
+        wait(&mut bucket, Duration::from_millis(500));
+        assert!(bucket.try_take(5));
+        assert!(!bucket.try_take(1));
+
+        // Refills stop at the capacity
+        wait(&mut bucket, Duration::from_secs(60));
+        assert!(bucket.try_take(10));
+        assert!(!bucket.try_take(1));
+    }
+
+    #[test]
+    fn amounts_above_the_capacity_never_fit() {
+        let mut bucket = TokenBucket::new(4, 100.0);
+        wait(&mut bucket, Duration::from_secs(3600));
+        assert!(!bucket.try_take(5));
+        // Refusing it took nothing
+        assert!(bucket.try_take(4));
+    }
+
+    #[test]
+    fn debt_is_paid_back_at_the_rate() {
+        let mut bucket = TokenBucket::per_second(100);
+        assert_eq!(bucket.take_with_delay(100), Duration::ZERO);
+        let delay = bucket.take_with_delay(50);
+        assert!((delay.as_secs_f64() - 0.5).abs() < 0.01, "{:?}", delay);
+        assert!(!bucket.try_take(1));
+    }
+
+    #[test]
+    fn message_rates_are_per_caller() {
+        let rates = MessageRates::new(2);
+        assert!(rates.try_take("alice"));
+        assert!(rates.try_take("alice"));
+        assert!(!rates.try_take("alice"));
+        assert!(rates.try_take("bob"));
+    }
+
+    #[test]
+    fn permits_are_returned_when_dropped() {
+        let limiter = ConnectionLimiter::new(1);
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let permit = limiter.try_acquire(ip);
+        assert!(permit.is_some());
+        assert!(limiter.try_acquire(ip).is_none());
+        assert!(limiter.try_acquire(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))).is_some());
+        drop(permit);
+        assert!(limiter.try_acquire(ip).is_some());
+    }
+}
@@ -0,0 +1,218 @@
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::time::Duration;
+use miette::IntoDiagnostic;
+use once_cell::sync::Lazy;
+use tokio_util::sync::CancellationToken;
+use super::http::{plain_response, read_request, respond, response, HttpError};
+use super::runtime::{self, TcpListener, TcpStream};
+use super::tls::Transport;
+
+/// Upper bounds of the reply latency buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 10] = [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5];
+/// How long a scraper has to send its request.
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(10);
+
+static METRICS: Lazy<Metrics> = Lazy::new(Metrics::default);
+
+/// Process-wide metrics, updated from wherever the event happens.
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, value: u64) {
+        self.0.fetch_add(value, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Default)]
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dec(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Default)]
+pub struct Histogram {
+    // One per bucket in LATENCY_BUCKETS, not cumulative
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_us: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|&bound| seconds <= bound) {
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_us.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    pub tcp_connections_active: Gauge,
+    pub tcp_connections_total: Counter,
+    pub tcp_connections_rejected: Counter,
+    pub websocket_connections_total: Counter,
+    pub http_api_requests: Counter,
+    pub api_messages_throttled: Counter,
+    pub tcp_messages_received: Counter,
+    pub tcp_messages_sent: Counter,
+    pub tcp_messages_throttled: Counter,
+    pub tcp_bytes_received: Counter,
+    pub tcp_bytes_sent: Counter,
+    pub reply_latency: Histogram,
+    pub auth_failures: Counter,
+    pub tool_calls: Counter,
+    pub tool_failures: Counter,
+    pub context_summaries: Counter,
+    pub udp_packets_received: Counter,
+    pub udp_packets_dropped: Counter,
+    pub udp_packets_throttled: Counter,
+    pub udp_packets_lost: Counter,
+    pub udp_packets_decoded: Counter,
+    pub udp_decode_errors: Counter,
+    pub udp_packets_unauthenticated: Counter,
+    pub udp_packets_replayed: Counter,
+    pub room_audio_packets_sent: Counter,
+    pub recordings_written: Counter,
+    pub recording_bytes_written: Counter,
+}
+
+impl Metrics {
+    /// Renders every metric in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        gauge(&mut out, "talk_to_me_tcp_connections_active", "Open TCP connections", &self.tcp_connections_active);
+        counter(&mut out, "talk_to_me_tcp_connections_total", "Accepted TCP connections", &self.tcp_connections_total);
+        counter(&mut out, "talk_to_me_tcp_connections_rejected_total", "TCP connections refused because their address had too many open", &self.tcp_connections_rejected);
+        counter(&mut out, "talk_to_me_websocket_connections_total", "Chat connections from browsers upgraded to WebSocket", &self.websocket_connections_total);
+        counter(&mut out, "talk_to_me_http_api_requests_total", "Requests to the HTTP API", &self.http_api_requests);
+        counter(&mut out, "talk_to_me_api_messages_throttled_total", "HTTP API messages to the assistant refused because their caller exceeded its message rate", &self.api_messages_throttled);
+        counter(&mut out, "talk_to_me_tcp_messages_received_total", "Frames received from clients", &self.tcp_messages_received);
+        counter(&mut out, "talk_to_me_tcp_messages_sent_total", "Messages sent to clients", &self.tcp_messages_sent);
+        counter(&mut out, "talk_to_me_tcp_messages_throttled_total", "Messages dropped because their session exceeded its message rate", &self.tcp_messages_throttled);
+        counter(&mut out, "talk_to_me_tcp_bytes_received_total", "Bytes read from TCP clients", &self.tcp_bytes_received);
+        counter(&mut out, "talk_to_me_tcp_bytes_sent_total", "Bytes written to TCP clients", &self.tcp_bytes_sent);
+        histogram(&mut out, "talk_to_me_reply_latency_seconds", "Time from receiving a chat message to sending the reply", &self.reply_latency);
+        counter(&mut out, "talk_to_me_auth_failures_total", "Connections turned away for a missing or invalid token", &self.auth_failures);
+        counter(&mut out, "talk_to_me_tool_calls_total", "Tool calls the assistant made", &self.tool_calls);
+        counter(&mut out, "talk_to_me_tool_failures_total", "Tool calls that failed or timed out", &self.tool_failures);
+        counter(&mut out, "talk_to_me_context_summaries_total", "Summaries made of turns beyond the context budget", &self.context_summaries);
+        counter(&mut out, "talk_to_me_udp_packets_received_total", "Datagrams received", &self.udp_packets_received);
+        counter(&mut out, "talk_to_me_udp_packets_dropped_total", "Datagrams dropped because their stream was behind", &self.udp_packets_dropped);
+        counter(&mut out, "talk_to_me_udp_packets_throttled_total", "Datagrams dropped by the rate and stream limits of their address", &self.udp_packets_throttled);
+        counter(&mut out, "talk_to_me_udp_packets_lost_total", "Audio packets that never arrived, from sequence gaps", &self.udp_packets_lost);
+        counter(&mut out, "talk_to_me_udp_packets_decoded_total", "Opus frames decoded", &self.udp_packets_decoded);
+        counter(&mut out, "talk_to_me_udp_decode_errors_total", "Opus frames that failed to decode", &self.udp_decode_errors);
+        counter(&mut out, "talk_to_me_udp_packets_unauthenticated_total", "Datagrams dropped because they were not sealed with their session's key", &self.udp_packets_unauthenticated);
+        counter(&mut out, "talk_to_me_udp_packets_replayed_total", "Sealed datagrams dropped as replays", &self.udp_packets_replayed);
+        counter(&mut out, "talk_to_me_room_audio_packets_sent_total", "Forwarded or mixed audio packets sent to room members", &self.room_audio_packets_sent);
+        counter(&mut out, "talk_to_me_recordings_written_total", "WAV files written", &self.recordings_written);
+        counter(&mut out, "talk_to_me_recording_bytes_written_total", "Bytes of WAV files written", &self.recording_bytes_written);
+        out
+    }
+}
+
+fn counter(out: &mut String, name: &str, help: &str, counter: &Counter) {
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} counter\n{} {}", name, help, name, name, counter.get());
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, gauge: &Gauge) {
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} gauge\n{} {}", name, help, name, name, gauge.get());
+}
+
+fn histogram(out: &mut String, name: &str, help: &str, histogram: &Histogram) {
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} histogram", name, help, name);
+    let mut cumulative = 0;
+    for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&histogram.buckets) {
+        cumulative += bucket.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+    }
+    let count = histogram.count.load(Ordering::Relaxed);
+    let sum = histogram.sum_us.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+    let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+    let _ = writeln!(out, "{}_sum {}\n{}_count {}", name, sum, name, count);
+}
+
+/// Serves `GET /metrics` over plain HTTP. Meant for a loopback address, a
+/// scraper on another host should go through a proxy.
+pub struct MetricsServer {
+    listener: TcpListener,
+}
+
+impl MetricsServer {
+    pub fn bind(addr: SocketAddr) -> miette::Result<Self> {
+        let listener = TcpListener::bind(addr).into_diagnostic()?;
+        tracing::info!("Metrics available on http://{}/metrics", addr);
+        Ok(Self { listener })
+    }
+
+    pub async fn run(self, shutdown: CancellationToken) {
+        loop {
+            tokio::select! {
+                _ = shutdown.cancelled() => break,
+                result = self.listener.accept() => match result {
+                    Ok((stream, _)) => {
+                        runtime::spawn(serve_scrape(stream));
+                    }
+                    Err(err) => tracing::error!("Metrics accept failed: {}", err),
+                },
+            }
+        }
+    }
+}
+
+async fn serve_scrape(stream: TcpStream) {
+    let transport = match Transport::new(stream, None) {
+        Ok(transport) => transport,
+        Err(err) => {
+            tracing::debug!("Could not answer metrics scrape: {:?}", err);
+            return;
+        }
+    };
+    // A scrape has no body
+    let request = match tokio::time::timeout(SCRAPE_TIMEOUT, read_request(&transport, 0)).await {
+        Ok(Ok(Some(request))) => request,
+        Ok(Ok(None)) | Err(_) => return,
+        Ok(Err(err)) => {
+            tracing::debug!("Invalid metrics scrape: {:?}", err);
+            if let Some(err) = err.downcast_ref::<HttpError>() {
+                respond(&transport, plain_response(err.status(), &format!("{}\n", err))).await;
+            }
+            return;
+        }
+    };
+    let answer = if request.method == "GET" && request.path == "/metrics" {
+        response("200 OK", "text/plain; version=0.0.4", metrics().render().as_bytes())
+    } else {
+        plain_response("404 Not Found", "")
+    };
+    respond(&transport, answer).await;
+}
@@ -1,6 +1,43 @@
+mod admin;
+mod assistant;
+mod auth;
 mod connection;
+mod context;
+mod conversation;
 mod audio;
+mod http;
+mod http_api;
+mod latency;
+mod limits;
+mod metrics;
+mod openai;
+mod prompts;
+mod rooms;
+pub mod runtime;
+mod session;
+mod stream_stats;
+mod tls;
+mod tools;
 mod udp_handler;
+mod voice_rooms;
+mod voice_stream;
+mod websocket;
 
-pub use connection::ConnectionHandler;
-pub use udp_handler::UdpHandler; 
\ No newline at end of file
+pub use admin::{AdminServer, AdminState};
+pub use assistant::Assistant;
+pub use auth::{run_user_command, UserCommand, UserStore};
+pub use audio::AudioProcessor;
+pub use connection::{ConnectionContext, ConnectionHandler};
+pub use context::ContextBuilder;
+pub use conversation::ConversationStore;
+pub use http_api::ApiServer;
+pub use limits::{AddressLimits, ConnectionLimiter, ConnectionPermit, SessionLimits};
+pub use metrics::{metrics, MetricsServer};
+pub use prompts::PromptLibrary;
+pub use rooms::RoomRegistry;
+pub use session::SessionRegistry;
+pub use tls::{generate_self_signed, load_server_config, Transport};
+pub use tools::ToolRegistry;
+pub use udp_handler::UdpHandler;
+pub use voice_stream::{StreamDirectory, StreamSettings};
+pub use websocket::WebSocketServer;
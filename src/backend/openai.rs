@@ -0,0 +1,314 @@
+use std::time::Instant;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::OsRng;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use super::assistant::Assistant;
+use super::auth::hex;
+use super::context::estimate_tokens;
+use super::conversation::{Conversation, Speaker};
+use super::http::{event, json, Request};
+use super::http_api::ApiResponse;
+use super::metrics::metrics;
+
+const ID_BYTES: usize = 12;
+const OWNER: &str = "talk-to-me";
+
+/// Whatever `model` asks for, the configured backend answers.
+#[derive(Deserialize)]
+struct ChatCompletionRequest {
+    messages: Vec<ChatMessage>,
+    #[serde(default)]
+    stream: bool,
+}
+
+#[derive(Deserialize)]
+struct ChatMessage {
+    role: String,
+    content: Option<Content>,
+    name: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Content {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+#[derive(Deserialize)]
+struct ContentPart {
+    #[serde(rename = "type")]
+    kind: String,
+    text: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ChatCompletion<'a> {
+    id: &'a str,
+    object: &'static str,
+    created: i64,
+    model: &'static str,
+    choices: [Choice<'a>; 1],
+    usage: Usage,
+}
+
+#[derive(Serialize)]
+struct Choice<'a> {
+    index: u32,
+    message: AssistantMessage<'a>,
+    finish_reason: &'static str,
+}
+
+#[derive(Serialize)]
+struct AssistantMessage<'a> {
+    role: &'static str,
+    content: &'a str,
+}
+
+#[derive(Serialize)]
+struct Usage {
+    prompt_tokens: usize,
+    completion_tokens: usize,
+    total_tokens: usize,
+}
+
+#[derive(Serialize)]
+struct ChatCompletionChunk<'a> {
+    id: &'a str,
+    object: &'static str,
+    created: i64,
+    model: &'static str,
+    choices: [ChunkChoice<'a>; 1],
+}
+
+#[derive(Serialize)]
+struct ChunkChoice<'a> {
+    index: u32,
+    delta: Delta<'a>,
+    finish_reason: Option<&'static str>,
+}
+
+#[derive(Serialize, Default)]
+struct Delta<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    role: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct ModelList {
+    object: &'static str,
+    data: Vec<Model>,
+}
+
+#[derive(Serialize)]
+struct Model {
+    id: &'static str,
+    object: &'static str,
+    created: i64,
+    owned_by: &'static str,
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    error: ErrorDetail<'a>,
+}
+
+#[derive(Serialize)]
+struct ErrorDetail<'a> {
+    message: &'a str,
+    #[serde(rename = "type")]
+    kind: &'a str,
+    param: Option<&'a str>,
+    code: Option<&'a str>,
+}
+
+/// `GET /v1/models`, the configured backend is the only model.
+pub fn models(assistant: &Assistant, started_at: i64) -> Vec<u8> {
+    json(
+        "200 OK",
+        &ModelList {
+            object: "list",
+            data: vec![model(assistant, started_at)],
+        },
+    )
+}
+
+/// `GET /v1/models/{id}`
+pub fn get_model(assistant: &Assistant, started_at: i64, id: &str) -> Vec<u8> {
+    if id != assistant.model() {
+        let message = format!("The model `{}` does not exist", id);
+        return error("404 Not Found", &message, "invalid_request_error", Some("model_not_found"));
+    }
+    json("200 OK", &model(assistant, started_at))
+}
+
+/// `POST /v1/chat/completions`. The request carries the whole conversation,
+/// nothing is stored on the server.
+pub fn chat_completion(assistant: &Assistant, request: &Request, user: Option<&str>) -> ApiResponse {
+    let completion: ChatCompletionRequest = match serde_json::from_slice(&request.body) {
+        Ok(completion) => completion,
+        Err(err) => return invalid_request(&format!("Invalid request body: {}", err)),
+    };
+    let received_at = Instant::now();
+    let (conversation, system_prompt) = match to_conversation(completion.messages, user) {
+        Ok(converted) => converted,
+        Err(message) => return invalid_request(message),
+    };
+    let text = assistant.respond(&conversation, system_prompt.as_deref());
+    metrics().reply_latency.observe(received_at.elapsed());
+
+    let mut bytes = [0u8; ID_BYTES];
+    OsRng.fill_bytes(&mut bytes);
+    let id = format!("chatcmpl-{}", hex(&bytes));
+    let created = Utc::now().timestamp();
+    let model = assistant.model();
+    if !completion.stream {
+        let prompt_tokens = system_prompt.as_deref().map_or(0, estimate_tokens)
+            + conversation.turns().map(|turn| turn.tokens).sum::<usize>();
+        let completion_tokens = estimate_tokens(&text);
+        return ApiResponse::Complete(json(
+            "200 OK",
+            &ChatCompletion {
+                id: &id,
+                object: "chat.completion",
+                created,
+                model,
+                choices: [Choice {
+                    index: 0,
+                    message: AssistantMessage {
+                        role: "assistant",
+                        content: &text,
+                    },
+                    finish_reason: "stop",
+                }],
+                usage: Usage {
+                    prompt_tokens,
+                    completion_tokens,
+                    total_tokens: prompt_tokens + completion_tokens,
+                },
+            },
+        ));
+    }
+
+    let chunk = |delta: Delta, finish_reason: Option<&'static str>| {
+        event(None, &ChatCompletionChunk {
+            id: &id,
+            object: "chat.completion.chunk",
+            created,
+            model,
+            choices: [ChunkChoice {
+                index: 0,
+                delta,
+                finish_reason,
+            }],
+        })
+    };
+    // Same as the conversation API, the reply is streamed a line at a time
+    let mut events = vec![chunk(
+        Delta {
+            role: Some("assistant"),
+            content: Some(""),
+        },
+        None,
+    )];
+    events.extend(text.split_inclusive('\n').map(|line| {
+        chunk(
+            Delta {
+                content: Some(line),
+                ..Delta::default()
+            },
+            None,
+        )
+    }));
+    events.push(chunk(Delta::default(), Some("stop")));
+    events.push("data: [DONE]\n\n".to_string());
+    ApiResponse::Events(events)
+}
+
+/// `POST /v1/audio/transcriptions`. Voice is recorded, but the server has no
+/// speech recognition to transcribe it with.
+pub fn transcription() -> Vec<u8> {
+    error(
+        "501 Not Implemented",
+        "Transcription is not available: this server records audio but has no speech recognition to turn it into text",
+        "invalid_request_error",
+        Some("unsupported"),
+    )
+}
+
+/// A rejected request in the error format OpenAI clients expect.
+pub fn error(status: &str, message: &str, kind: &str, code: Option<&str>) -> Vec<u8> {
+    json(
+        status,
+        &ErrorBody {
+            error: ErrorDetail {
+                message,
+                kind,
+                param: None,
+                code,
+            },
+        },
+    )
+}
+
+fn invalid_request(message: &str) -> ApiResponse {
+    ApiResponse::Complete(error("400 Bad Request", message, "invalid_request_error", None))
+}
+
+/// Turns the messages of a request into a conversation the backends can
+/// answer, and the system prompt its system messages make up. Tool messages
+/// have no place in it and are left out.
+fn to_conversation(
+    messages: Vec<ChatMessage>,
+    user: Option<&str>,
+) -> Result<(Conversation, Option<String>), &'static str> {
+    let mut conversation = Conversation::default();
+    let mut system_prompt: Vec<String> = Vec::new();
+    for message in messages {
+        let speaker = match message.role.as_str() {
+            "user" => {
+                let name = message.name.or_else(|| user.map(str::to_string));
+                Some(Speaker::Participant(name.unwrap_or_else(|| "user".to_string())))
+            }
+            "assistant" => Some(Speaker::Assistant),
+            "system" | "developer" => None,
+            "tool" | "function" => continue,
+            _ => return Err("Unknown message role"),
+        };
+        let text = match message.content {
+            None => String::new(),
+            Some(Content::Text(text)) => text,
+            Some(Content::Parts(parts)) => {
+                let mut texts = Vec::new();
+                for part in parts {
+                    match (part.kind.as_str(), part.text) {
+                        ("text", Some(text)) => texts.push(text),
+                        _ => return Err("Only text content is supported"),
+                    }
+                }
+                texts.join("\n")
+            }
+        };
+        match speaker {
+            Some(speaker) => conversation.push(speaker, text),
+            None => system_prompt.push(text),
+        }
+    }
+    if conversation.last_from_participant().is_none() {
+        return Err("The messages need at least one from the user");
+    }
+    let system_prompt = (!system_prompt.is_empty()).then(|| system_prompt.join("\n\n"));
+    Ok((conversation, system_prompt))
+}
+
+fn model(assistant: &Assistant, started_at: i64) -> Model {
+    Model {
+        id: assistant.model(),
+        object: "model",
+        created: started_at,
+        owned_by: OWNER,
+    }
+}
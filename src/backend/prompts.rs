@@ -0,0 +1,259 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::SystemTime;
+use chrono::Local;
+use miette::Diagnostic;
+use thiserror::Error;
+use super::assistant::ASSISTANT_NAME;
+use crate::config::PromptsConfig;
+use crate::protocol::message::PromptSummary;
+
+const MAX_NAME_LEN: usize = 64;
+const EXTENSIONS: [&str; 2] = ["md", "txt"];
+/// Larger files are not used as templates.
+const MAX_TEMPLATE_BYTES: u64 = 64 * 1024;
+/// Longest description shown for a template.
+const MAX_DESCRIPTION_LEN: usize = 120;
+
+#[derive(Debug, Error, Diagnostic)]
+pub enum PromptError {
+    #[error("Invalid template name {0:?}: use 1 to {MAX_NAME_LEN} letters, digits, '-' or '_'")]
+    #[diagnostic(code(prompts::invalid_name))]
+    InvalidName(String),
+
+    #[error("No prompt template named {0}")]
+    #[diagnostic(code(prompts::not_found))]
+    NotFound(String),
+
+    #[error("Could not read the prompt template {0}: {1}")]
+    #[diagnostic(code(prompts::unreadable))]
+    Unreadable(String, String),
+}
+
+/// A template file as last read, with the modification time and length it had.
+struct CachedTemplate {
+    modified: SystemTime,
+    len: u64,
+    text: String,
+}
+
+/// System prompt templates, one file each in the configured directory.
+/// Files are read again when they change, so they can be edited while the
+/// server runs.
+#[derive(Clone)]
+pub struct PromptLibrary {
+    dir: Option<PathBuf>,
+    default: Option<String>,
+    cache: Arc<Mutex<HashMap<PathBuf, CachedTemplate>>>,
+}
+
+impl PromptLibrary {
+    pub fn new(config: &PromptsConfig) -> Self {
+        let library = Self {
+            dir: config.dir.clone(),
+            default: config.default.clone(),
+            cache: Arc::default(),
+        };
+        if let Some(dir) = &library.dir {
+            tracing::info!("{} prompt templates in {}", library.list().len(), dir.display());
+        }
+        if let Some(default) = &library.default {
+            if let Err(err) = library.load(default) {
+                tracing::warn!("The default prompt template is not usable yet: {}", err);
+            }
+        }
+        library
+    }
+
+    /// The template new conversations start with.
+    pub fn default_template(&self) -> Option<String> {
+        self.default.clone()
+    }
+
+    /// Every template, by name.
+    pub fn list(&self) -> Vec<PromptSummary> {
+        let Some(dir) = &self.dir else {
+            return Vec::new();
+        };
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(err) => {
+                tracing::warn!("Could not list prompt templates in {}: {}", dir.display(), err);
+                return Vec::new();
+            }
+        };
+        let mut prompts: Vec<PromptSummary> = entries
+            .flatten()
+            .filter_map(|entry| {
+                let path = entry.path();
+                let name = template_name(&path)?;
+                let text = self.load(&name).ok()?;
+                Some(PromptSummary {
+                    description: describe(&text),
+                    name,
+                })
+            })
+            .collect();
+        prompts.sort_by(|a, b| a.name.cmp(&b.name));
+        prompts
+    }
+
+    /// Checks that a conversation can use the template.
+    pub fn check(&self, name: &str) -> Result<(), PromptError> {
+        self.load(name).map(|_| ())
+    }
+
+    /// The template filled in for `user`, who the assistant is answering.
+    /// Unknown variables are left as written.
+    pub fn render(&self, name: &str, user: &str) -> Result<String, PromptError> {
+        let template = self.load(name)?;
+        let now = Local::now();
+        Ok(substitute(&template, |variable| match variable {
+            "user" => Some(user.to_string()),
+            "assistant" => Some(ASSISTANT_NAME.to_string()),
+            "date" => Some(now.format("%Y-%m-%d").to_string()),
+            "time" => Some(now.format("%H:%M").to_string()),
+            "weekday" => Some(now.format("%A").to_string()),
+            _ => None,
+        }))
+    }
+
+    fn load(&self, name: &str) -> Result<String, PromptError> {
+        let valid_chars = name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if name.is_empty() || name.len() > MAX_NAME_LEN || !valid_chars {
+            return Err(PromptError::InvalidName(name.to_string()));
+        }
+        let dir = self.dir.as_ref().ok_or_else(|| PromptError::NotFound(name.to_string()))?;
+        let (path, metadata) = EXTENSIONS
+            .iter()
+            .map(|extension| dir.join(format!("{}.{}", name, extension)))
+            .find_map(|path| {
+                let metadata = fs::metadata(&path).ok().filter(|metadata| metadata.is_file())?;
+                Some((path, metadata))
+            })
+            .ok_or_else(|| PromptError::NotFound(name.to_string()))?;
+        let unreadable = |reason: String| PromptError::Unreadable(name.to_string(), reason);
+        if metadata.len() > MAX_TEMPLATE_BYTES {
+            return Err(unreadable(format!("larger than {} bytes", MAX_TEMPLATE_BYTES)));
+        }
+        let modified = metadata.modified().map_err(|err| unreadable(err.to_string()))?;
+
+        let mut cache = self.cache.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(cached) = cache.get(&path) {
+            if cached.modified == modified && cached.len == metadata.len() {
+                return Ok(cached.text.clone());
+            }
+        }
+        let text = fs::read_to_string(&path).map_err(|err| unreadable(err.to_string()))?;
+        cache.insert(
+            path,
+            CachedTemplate {
+                modified,
+                len: metadata.len(),
+                text: text.clone(),
+            },
+        );
+        Ok(text)
+    }
+}
+
+/// The name of the template in `path`, `None` for files that are not templates.
+fn template_name(path: &Path) -> Option<String> {
+    let extension = path.extension()?.to_str()?;
+    if !EXTENSIONS.contains(&extension) {
+        return None;
+    }
+    path.file_stem()?.to_str().map(str::to_string)
+}
+
+/// The first line with text, without Markdown heading marks.
+fn describe(template: &str) -> String {
+    let line = template
+        .lines()
+        .map(|line| line.trim_start_matches('#').trim())
+        .find(|line| !line.is_empty())
+        .unwrap_or_default();
+    match line.char_indices().nth(MAX_DESCRIPTION_LEN) {
+        Some((end, _)) => format!("{}...", &line[..end]),
+        None => line.to_string(),
+    }
+}
+
+/// Replaces each `{{variable}}` `value` knows, spaces inside the braces allowed.
+fn substitute(template: &str, value: impl Fn(&str) -> Option<String>) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let Some(end) = after.find("}}") else {
+            out.push_str(&rest[start..]);
+            return out;
+        };
+        match value(after[..end].trim()) {
+            Some(replacement) => out.push_str(&replacement),
+            None => out.push_str(&rest[start..start + 2 + end + 2]),
+        }
+        rest = &after[end + 2..];
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fill(template: &str) -> String {
+        substitute(template, |variable| match variable {
+            "user" => Some("alice".to_string()),
+            "empty" => Some(String::new()),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn known_variables_are_replaced() {
+        assert_eq!(fill("Hi {{user}}, {{ user }}!"), "Hi alice, alice!");
+        assert_eq!(fill("[{{empty}}]"), "[]");
+        assert_eq!(fill("{{user}}{{user}}"), "alicealice");
+        assert_eq!(fill("no variables"), "no variables");
+    }
+
+    #[test]
+    fn unknown_and_unterminated_variables_stay() {
+        assert_eq!(fill("{{ unknown }} {{user}}"), "{{ unknown }} alice");
+        assert_eq!(fill("{{user}} and {{user"), "alice and {{user");
+        assert_eq!(fill("{{"), "{{");
+        assert_eq!(fill("}} {{user}}"), "}} alice");
+        // A replacement is not searched for variables again
+        let nested = substitute("{{a}}", |_| Some("{{a}}".to_string()));
+        assert_eq!(nested, "{{a}}");
+    }
+
+    #[test]
+    fn multibyte_text_around_variables() {
+        assert_eq!(fill("héllo {{user}} 👋 {{"), "héllo alice 👋 {{");
+    }
+
+    #[test]
+    fn templates_are_md_and_txt_files() {
+        assert_eq!(template_name(Path::new("prompts/helpful.md")).as_deref(), Some("helpful"));
+        assert_eq!(template_name(Path::new("prompts/terse.txt")).as_deref(), Some("terse"));
+        assert_eq!(template_name(Path::new("prompts/notes.json")), None);
+        assert_eq!(template_name(Path::new("prompts/README")), None);
+    }
+
+    #[test]
+    fn descriptions_are_the_first_heading_or_line() {
+        assert_eq!(describe("\n\n# Helpful\nBe kind."), "Helpful");
+        assert_eq!(describe("Plain first line\nmore"), "Plain first line");
+        assert_eq!(describe(""), "");
+        let long = "x".repeat(MAX_DESCRIPTION_LEN + 5);
+        assert_eq!(describe(&long), format!("{}...", "x".repeat(MAX_DESCRIPTION_LEN)));
+    }
+}
@@ -0,0 +1,346 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use miette::Diagnostic;
+use thiserror::Error;
+use super::assistant::ASSISTANT_NAME;
+use super::conversation::{Conversation, Speaker, Summary};
+use super::session::{SessionId, SessionRegistry};
+use super::tools::ToolOutcome;
+use crate::protocol::message::{RoomSummary, ServerMessage};
+
+const MAX_ROOM_NAME_LEN: usize = 32;
+/// Loudest a listener may turn up a speaker in the mix.
+pub const MAX_GAIN: f32 = 4.0;
+
+#[derive(Debug, Error, Diagnostic)]
+pub enum RoomError {
+    #[error("Invalid room name {0:?}: use 1 to {MAX_ROOM_NAME_LEN} letters, digits, '-' or '_'")]
+    #[diagnostic(code(room::invalid_name))]
+    InvalidName(String),
+
+    #[error("Room {0} already exists")]
+    #[diagnostic(code(room::exists))]
+    Exists(String),
+
+    #[error("No room named {0}")]
+    #[diagnostic(code(room::not_found))]
+    NotFound(String),
+
+    #[error("You are not in a room")]
+    #[diagnostic(code(room::not_joined))]
+    NotJoined,
+
+    #[error("Nobody named {0} is in your room")]
+    #[diagnostic(code(room::no_member))]
+    NoMember(String),
+
+    #[error("Gain {0} is out of range, use 0 to {MAX_GAIN}")]
+    #[diagnostic(code(room::invalid_gain))]
+    InvalidGain(f32),
+}
+
+#[derive(Debug, Clone)]
+pub struct Member {
+    pub session: SessionId,
+    pub name: String,
+    /// How loud this member hears each speaker, by session. Missing ones are at 1.0.
+    pub gains: HashMap<SessionId, f32>,
+}
+
+/// Who is in a room, as the voice path sees it. The members are shared with
+/// the registry until they change, so taking them copies nothing.
+#[derive(Debug, Clone)]
+pub struct RoomMembers {
+    pub room: String,
+    pub members: Arc<Vec<Member>>,
+}
+
+struct Room {
+    members: Arc<Vec<Member>>,
+    conversation: Conversation,
+}
+
+impl Room {
+    fn member_names(&self) -> Vec<String> {
+        self.members.iter().map(|member| member.name.clone()).collect()
+    }
+}
+
+#[derive(Default)]
+struct Rooms {
+    by_name: HashMap<String, Room>,
+    // The room each member is in, the voice path looks it up for every packet
+    by_session: HashMap<SessionId, String>,
+}
+
+/// Rooms where several clients talk to one shared assistant conversation.
+/// A room exists while it has members.
+#[derive(Clone)]
+pub struct RoomRegistry {
+    rooms: Arc<Mutex<Rooms>>,
+    sessions: SessionRegistry,
+}
+
+impl RoomRegistry {
+    pub fn new(sessions: SessionRegistry) -> Self {
+        Self {
+            rooms: Arc::default(),
+            sessions,
+        }
+    }
+
+    /// Creates a room with `session` as its first member, the conversation
+    /// starting with the system prompt `template`. Returns the members.
+    pub fn create(&self, room: &str, session: SessionId, name: &str, template: Option<String>) -> Result<Vec<String>, RoomError> {
+        validate_room_name(room)?;
+        let mut rooms = self.lock();
+        if rooms.by_name.contains_key(room) {
+            return Err(RoomError::Exists(room.to_string()));
+        }
+        rooms.by_name.insert(
+            room.to_string(),
+            Room {
+                members: Arc::new(vec![Member {
+                    session,
+                    name: name.to_string(),
+                    gains: HashMap::new(),
+                }]),
+                conversation: Conversation::with_template(template),
+            },
+        );
+        rooms.by_session.insert(session, room.to_string());
+        tracing::info!("Room {} created by {}", room, name);
+        Ok(vec![name.to_string()])
+    }
+
+    /// Adds `session` to a room and tells the other members. Returns the members.
+    pub fn join(&self, room: &str, session: SessionId, name: &str) -> Result<Vec<String>, RoomError> {
+        let mut rooms = self.lock();
+        let Rooms { by_name, by_session } = &mut *rooms;
+        let entry = by_name.get_mut(room).ok_or_else(|| RoomError::NotFound(room.to_string()))?;
+        if !entry.members.iter().any(|member| member.session == session) {
+            self.fan_out(
+                entry,
+                None,
+                ServerMessage::MemberJoined {
+                    room: room.to_string(),
+                    name: name.to_string(),
+                },
+            );
+            Arc::make_mut(&mut entry.members).push(Member {
+                session,
+                name: name.to_string(),
+                gains: HashMap::new(),
+            });
+            by_session.insert(session, room.to_string());
+            tracing::info!("{} joined room {}", name, room);
+        }
+        Ok(entry.member_names())
+    }
+
+    /// Removes `session` from a room and tells the members left. The room
+    /// goes away with its last member.
+    pub fn leave(&self, room: &str, session: SessionId) {
+        let mut rooms = self.lock();
+        let Rooms { by_name, by_session } = &mut *rooms;
+        let Some(entry) = by_name.get_mut(room) else {
+            return;
+        };
+        let Some(position) = entry.members.iter().position(|member| member.session == session) else {
+            return;
+        };
+        let member = Arc::make_mut(&mut entry.members).remove(position);
+        by_session.remove(&session);
+        tracing::info!("{} left room {}", member.name, room);
+        if entry.members.is_empty() {
+            by_name.remove(room);
+            tracing::info!("Room {} closed", room);
+            return;
+        }
+        self.fan_out(
+            entry,
+            None,
+            ServerMessage::MemberLeft {
+                room: room.to_string(),
+                name: member.name,
+            },
+        );
+    }
+
+    pub fn list(&self) -> Vec<RoomSummary> {
+        let mut rooms: Vec<RoomSummary> = self
+            .lock()
+            .by_name
+            .iter()
+            .map(|(name, room)| RoomSummary {
+                name: name.clone(),
+                members: room.members.len(),
+            })
+            .collect();
+        rooms.sort_by(|a, b| a.name.cmp(&b.name));
+        rooms
+    }
+
+    /// Adds a member's message to the room's conversation and sends it to
+    /// everyone else there. Returns the conversation for the assistant to answer.
+    pub fn post(&self, room: &str, session: SessionId, text: &str) -> Result<Conversation, RoomError> {
+        let mut rooms = self.lock();
+        let entry = rooms.by_name.get_mut(room).ok_or_else(|| RoomError::NotFound(room.to_string()))?;
+        let name = entry
+            .members
+            .iter()
+            .find(|member| member.session == session)
+            .map(|member| member.name.clone())
+            .ok_or_else(|| RoomError::NotFound(room.to_string()))?;
+        entry.conversation.push(Speaker::Participant(name.clone()), text.to_string());
+        self.fan_out(
+            entry,
+            Some(session),
+            ServerMessage::Chat {
+                sender: name,
+                text: text.to_string(),
+                room: Some(room.to_string()),
+            },
+        );
+        Ok(entry.conversation.clone())
+    }
+
+    /// Adds the assistant's answer to the room's conversation and sends it to every member.
+    pub fn reply(&self, room: &str, text: String) {
+        let mut rooms = self.lock();
+        let Some(entry) = rooms.by_name.get_mut(room) else {
+            return;
+        };
+        entry.conversation.push(Speaker::Assistant, text.clone());
+        self.fan_out(
+            entry,
+            None,
+            ServerMessage::Chat {
+                sender: ASSISTANT_NAME.to_string(),
+                text,
+                room: Some(room.to_string()),
+            },
+        );
+    }
+
+    /// Stores the summary the assistant made for the room's conversation.
+    pub fn set_summary(&self, room: &str, summary: Summary) {
+        if let Some(entry) = self.lock().by_name.get_mut(room) {
+            entry.conversation.set_summary(summary);
+        }
+    }
+
+    /// Switches the template of the room's conversation and tells every member.
+    pub fn set_template(&self, room: &str, template: Option<String>, changed_by: &str) -> Result<(), RoomError> {
+        let mut rooms = self.lock();
+        let entry = rooms.by_name.get_mut(room).ok_or_else(|| RoomError::NotFound(room.to_string()))?;
+        entry.conversation.set_template(template.clone());
+        self.fan_out(
+            entry,
+            None,
+            ServerMessage::PromptChanged {
+                template,
+                room: Some(room.to_string()),
+                changed_by: changed_by.to_string(),
+            },
+        );
+        Ok(())
+    }
+
+    /// Adds the result of a tool the assistant called to the room's
+    /// conversation and shows the call to every member.
+    pub fn add_tool_result(&self, room: &str, outcome: &ToolOutcome) {
+        let mut rooms = self.lock();
+        let Some(entry) = rooms.by_name.get_mut(room) else {
+            return;
+        };
+        entry
+            .conversation
+            .push(Speaker::Tool(outcome.call.tool.clone()), outcome.output());
+        self.fan_out(entry, None, outcome.to_message(Some(room.to_string())));
+    }
+
+    /// The room `session` is in and everyone there.
+    pub fn members_of(&self, session: SessionId) -> Option<RoomMembers> {
+        let rooms = self.lock();
+        let name = rooms.by_session.get(&session)?;
+        rooms.by_name.get(name).map(|room| RoomMembers {
+            room: name.clone(),
+            members: Arc::clone(&room.members),
+        })
+    }
+
+    /// Every room and its members.
+    pub fn all_members(&self) -> Vec<RoomMembers> {
+        self.lock()
+            .by_name
+            .iter()
+            .map(|(name, room)| RoomMembers {
+                room: name.clone(),
+                members: Arc::clone(&room.members),
+            })
+            .collect()
+    }
+
+    /// Sets how loud `listener` hears the members called `speaker` in its room.
+    pub fn set_gain(&self, listener: SessionId, speaker: &str, gain: f32) -> Result<(), RoomError> {
+        if !(0.0..=MAX_GAIN).contains(&gain) {
+            return Err(RoomError::InvalidGain(gain));
+        }
+        let mut rooms = self.lock();
+        let Rooms { by_name, by_session } = &mut *rooms;
+        let room = by_session
+            .get(&listener)
+            .and_then(|name| by_name.get_mut(name))
+            .ok_or(RoomError::NotJoined)?;
+        let speakers: Vec<SessionId> = room
+            .members
+            .iter()
+            .filter(|member| member.name == speaker)
+            .map(|member| member.session)
+            .collect();
+        if speakers.is_empty() {
+            return Err(RoomError::NoMember(speaker.to_string()));
+        }
+        if let Some(member) = Arc::make_mut(&mut room.members)
+            .iter_mut()
+            .find(|member| member.session == listener)
+        {
+            for session in speakers {
+                member.gains.insert(session, gain);
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends a message to every member of a room.
+    pub fn announce(&self, room: &str, message: ServerMessage) {
+        if let Some(entry) = self.lock().by_name.get(room) {
+            self.fan_out(entry, None, message);
+        }
+    }
+
+    /// Sends a message to every member but `except`. A member that cannot
+    /// take it misses it, the others still get it.
+    fn fan_out(&self, room: &Room, except: Option<SessionId>, message: ServerMessage) {
+        for member in room.members.iter().filter(|member| Some(member.session) != except) {
+            if let Err(err) = self.sessions.send_to(member.session, message.clone()) {
+                tracing::debug!("Room message not delivered: {}", err);
+            }
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Rooms> {
+        self.rooms.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+fn validate_room_name(room: &str) -> Result<(), RoomError> {
+    let valid_chars = room
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if room.is_empty() || room.len() > MAX_ROOM_NAME_LEN || !valid_chars {
+        return Err(RoomError::InvalidName(room.to_string()));
+    }
+    Ok(())
+}
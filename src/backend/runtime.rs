@@ -0,0 +1,269 @@
+use std::cell::Cell;
+use std::future::Future;
+use std::io;
+use std::net::{Shutdown, SocketAddr};
+use miette::IntoDiagnostic;
+use socket2::SockRef;
+use tokio::task::{JoinHandle, LocalSet};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use crate::config::RuntimeKind;
+
+/// The outcome of an operation that owns its buffer while it runs, with the
+/// buffer handed back. io_uring needs that, epoll goes along with it.
+pub type BufResult<T, B> = (io::Result<T>, B);
+
+/// The driver sockets are created for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Driver {
+    #[cfg(feature = "io-uring")]
+    IoUring,
+    Epoll,
+}
+
+thread_local! {
+    // The server runs on one thread, set once the runtime is up
+    static DRIVER: Cell<Driver> = const { Cell::new(Driver::Epoll) };
+}
+
+/// Runs the server on the current thread with the driver `kind` asks for.
+/// `Auto` probes io_uring by setting it up, and falls back to epoll when
+/// the kernel or a seccomp policy refuses it.
+pub fn start<F: Future>(kind: RuntimeKind, future: F) -> miette::Result<F::Output> {
+    match kind {
+        RuntimeKind::Epoll => start_epoll(future),
+        #[cfg(feature = "io-uring")]
+        RuntimeKind::IoUring => {
+            let runtime = tokio_uring::Runtime::new(&tokio_uring::builder()).map_err(|err| {
+                miette::miette!("Could not set up io_uring ({}), use runtime = \"epoll\" or \"auto\" on this host", err)
+            })?;
+            Ok(start_io_uring(runtime, future))
+        }
+        #[cfg(feature = "io-uring")]
+        RuntimeKind::Auto => match tokio_uring::Runtime::new(&tokio_uring::builder()) {
+            Ok(runtime) => Ok(start_io_uring(runtime, future)),
+            Err(err) => {
+                tracing::warn!("io_uring is unavailable ({}), falling back to epoll", err);
+                start_epoll(future)
+            }
+        },
+        #[cfg(not(feature = "io-uring"))]
+        RuntimeKind::IoUring => {
+            miette::bail!("This server was built without the io-uring feature, use runtime = \"epoll\" or \"auto\"")
+        }
+        #[cfg(not(feature = "io-uring"))]
+        RuntimeKind::Auto => start_epoll(future),
+    }
+}
+
+#[cfg(feature = "io-uring")]
+fn start_io_uring<F: Future>(runtime: tokio_uring::Runtime, future: F) -> F::Output {
+    tracing::info!("Running on io_uring");
+    DRIVER.set(Driver::IoUring);
+    runtime.block_on(future)
+}
+
+fn start_epoll<F: Future>(future: F) -> miette::Result<F::Output> {
+    tracing::info!("Running on epoll");
+    DRIVER.set(Driver::Epoll);
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .into_diagnostic()?;
+    Ok(LocalSet::new().block_on(&runtime, future))
+}
+
+/// Runs `task` on the server's thread, it does not need to be `Send`.
+pub fn spawn<T: Future + 'static>(task: T) -> JoinHandle<T::Output> {
+    tokio::task::spawn_local(task)
+}
+
+/// Connections and streams shutdown waits for. Whatever outlives the wait is
+/// stopped, which drops it like any finished task.
+#[derive(Clone, Default)]
+pub struct Tasks {
+    tracker: TaskTracker,
+    stop: CancellationToken,
+}
+
+impl Tasks {
+    pub fn spawn<T: Future + 'static>(&self, task: T) {
+        let stop = self.stop.clone();
+        spawn(self.tracker.track_future(async move {
+            tokio::select! {
+                _ = stop.cancelled() => {}
+                _ = task => {}
+            }
+        }));
+    }
+
+    /// Accepts no more tasks, so `wait` can finish.
+    pub fn close(&self) {
+        self.tracker.close();
+    }
+
+    pub fn len(&self) -> usize {
+        self.tracker.len()
+    }
+
+    /// Resolves once the tracker is closed and every task has ended.
+    pub async fn wait(&self) {
+        self.tracker.wait().await;
+    }
+
+    /// Ends every task at its next wakeup and waits until they are dropped.
+    pub async fn stop(&self) {
+        self.stop.cancel();
+        self.tracker.wait().await;
+    }
+}
+
+pub struct TcpListener(ListenerInner);
+
+enum ListenerInner {
+    #[cfg(feature = "io-uring")]
+    IoUring(tokio_uring::net::TcpListener),
+    Epoll(tokio::net::TcpListener),
+}
+
+impl TcpListener {
+    pub fn bind(addr: SocketAddr) -> io::Result<Self> {
+        let inner = match DRIVER.get() {
+            #[cfg(feature = "io-uring")]
+            Driver::IoUring => ListenerInner::IoUring(tokio_uring::net::TcpListener::bind(addr)?),
+            Driver::Epoll => {
+                // A restart should not wait for old connections in TIME_WAIT
+                let socket = socket2::Socket::new(socket2::Domain::for_address(addr), socket2::Type::STREAM, None)?;
+                socket.set_reuse_address(true)?;
+                socket.set_nonblocking(true)?;
+                socket.bind(&addr.into())?;
+                socket.listen(1024)?;
+                ListenerInner::Epoll(tokio::net::TcpListener::from_std(socket.into())?)
+            }
+        };
+        Ok(Self(inner))
+    }
+
+    #[cfg(test)]
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match &self.0 {
+            #[cfg(feature = "io-uring")]
+            ListenerInner::IoUring(listener) => listener.local_addr(),
+            ListenerInner::Epoll(listener) => listener.local_addr(),
+        }
+    }
+
+    pub async fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+        match &self.0 {
+            #[cfg(feature = "io-uring")]
+            ListenerInner::IoUring(listener) => {
+                let (stream, peer) = listener.accept().await?;
+                Ok((TcpStream(StreamInner::IoUring(stream)), peer))
+            }
+            ListenerInner::Epoll(listener) => {
+                let (stream, peer) = listener.accept().await?;
+                Ok((TcpStream(StreamInner::Epoll(stream)), peer))
+            }
+        }
+    }
+}
+
+/// A TCP connection. Reads and writes take `&self`, so one task can keep a
+/// read pending while another writes.
+pub struct TcpStream(StreamInner);
+
+enum StreamInner {
+    #[cfg(feature = "io-uring")]
+    IoUring(tokio_uring::net::TcpStream),
+    Epoll(tokio::net::TcpStream),
+}
+
+impl TcpStream {
+    /// Reads into the initialized part of `buf`.
+    pub async fn read(&self, mut buf: Vec<u8>) -> BufResult<usize, Vec<u8>> {
+        match &self.0 {
+            #[cfg(feature = "io-uring")]
+            StreamInner::IoUring(stream) => stream.read(buf).await,
+            StreamInner::Epoll(stream) => loop {
+                if let Err(err) = stream.readable().await {
+                    return (Err(err), buf);
+                }
+                match stream.try_read(&mut buf) {
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                    result => return (result, buf),
+                }
+            },
+        }
+    }
+
+    pub async fn write_all(&self, buf: Vec<u8>) -> BufResult<(), Vec<u8>> {
+        match &self.0 {
+            #[cfg(feature = "io-uring")]
+            StreamInner::IoUring(stream) => stream.write_all(buf).await,
+            StreamInner::Epoll(stream) => {
+                let mut written = 0;
+                while written < buf.len() {
+                    if let Err(err) = stream.writable().await {
+                        return (Err(err), buf);
+                    }
+                    match stream.try_write(&buf[written..]) {
+                        Ok(0) => return (Err(io::ErrorKind::WriteZero.into()), buf),
+                        Ok(num_bytes_written) => written += num_bytes_written,
+                        Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                        Err(err) => return (Err(err), buf),
+                    }
+                }
+                (Ok(()), buf)
+            }
+        }
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match &self.0 {
+            #[cfg(feature = "io-uring")]
+            StreamInner::IoUring(stream) => stream.shutdown(how),
+            StreamInner::Epoll(stream) => SockRef::from(stream).shutdown(how),
+        }
+    }
+}
+
+pub struct UdpSocket(UdpInner);
+
+enum UdpInner {
+    #[cfg(feature = "io-uring")]
+    IoUring(tokio_uring::net::UdpSocket),
+    Epoll(tokio::net::UdpSocket),
+}
+
+impl UdpSocket {
+    pub async fn bind(addr: SocketAddr) -> io::Result<Self> {
+        let inner = match DRIVER.get() {
+            #[cfg(feature = "io-uring")]
+            Driver::IoUring => UdpInner::IoUring(tokio_uring::net::UdpSocket::bind(addr).await?),
+            Driver::Epoll => UdpInner::Epoll(tokio::net::UdpSocket::bind(addr).await?),
+        };
+        Ok(Self(inner))
+    }
+
+    pub async fn recv_from(&self, mut buf: Vec<u8>) -> BufResult<(usize, SocketAddr), Vec<u8>> {
+        match &self.0 {
+            #[cfg(feature = "io-uring")]
+            UdpInner::IoUring(socket) => socket.recv_from(buf).await,
+            UdpInner::Epoll(socket) => {
+                let result = socket.recv_from(&mut buf).await;
+                (result, buf)
+            }
+        }
+    }
+
+    pub async fn send_to(&self, buf: Vec<u8>, addr: SocketAddr) -> BufResult<usize, Vec<u8>> {
+        match &self.0 {
+            #[cfg(feature = "io-uring")]
+            UdpInner::IoUring(socket) => socket.send_to(buf, addr).await,
+            UdpInner::Epoll(socket) => {
+                let result = socket.send_to(&buf, addr).await;
+                (result, buf)
+            }
+        }
+    }
+}
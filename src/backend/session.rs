@@ -0,0 +1,219 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use chrono::{DateTime, Utc};
+use miette::Diagnostic;
+use thiserror::Error;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio_util::sync::CancellationToken;
+use crate::protocol::message::ServerMessage;
+use crate::protocol::sealed::{self, Direction, Opener, Sealer, VoiceKey};
+
+pub type SessionId = u64;
+
+/// Messages queued for one client before further sends to it fail.
+const OUTBOUND_QUEUE_LEN: usize = 256;
+
+#[derive(Debug, Clone)]
+pub struct SessionInfo {
+    pub id: SessionId,
+    pub peer: SocketAddr,
+    /// Who logged in, `None` when the server does not require authentication.
+    pub user: Option<String>,
+    pub connected_at: DateTime<Utc>,
+    /// Where the client's voice stream comes from, once it has bound one.
+    pub udp_endpoint: Option<SocketAddr>,
+}
+
+#[derive(Debug, Error, Diagnostic)]
+pub enum SessionError {
+    #[error("No session with id {0}")]
+    #[diagnostic(code(session::not_found))]
+    NotFound(SessionId),
+
+    #[error("Session {0} is not keeping up with its messages")]
+    #[diagnostic(code(session::full))]
+    Full(SessionId),
+
+    #[error("Session {0} is closing")]
+    #[diagnostic(code(session::closed))]
+    Closed(SessionId),
+
+    #[error("Session {id} is connected from {peer}, not from {endpoint}")]
+    #[diagnostic(code(session::endpoint_mismatch))]
+    EndpointMismatch {
+        id: SessionId,
+        peer: IpAddr,
+        endpoint: SocketAddr,
+    },
+}
+
+/// Encryption state of a session's voice datagrams. Shared by every stream
+/// the session sends from, so a new source address cannot replay old
+/// datagrams and the server never reuses a nonce.
+pub struct VoiceChannel {
+    pub opener: Opener,
+    pub sealer: Sealer,
+}
+
+struct Session {
+    info: SessionInfo,
+    outbound: mpsc::Sender<ServerMessage>,
+    closed: CancellationToken,
+    voice: Arc<Mutex<VoiceChannel>>,
+}
+
+/// What a connection needs to serve its session: messages other parts of the
+/// server send to it, and a token cancelled when it should close.
+pub struct SessionHandle {
+    pub id: SessionId,
+    /// Handed to the client in the welcome message.
+    pub voice_key: VoiceKey,
+    pub outbound: mpsc::Receiver<ServerMessage>,
+    pub closed: CancellationToken,
+}
+
+/// Every connected client, shared by all connections and the voice streams.
+#[derive(Clone, Default)]
+pub struct SessionRegistry {
+    sessions: Arc<Mutex<HashMap<SessionId, Session>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl SessionRegistry {
+    /// Registers a new connection. Its session closes when `shutdown` is cancelled.
+    pub fn register(&self, peer: SocketAddr, user: Option<String>, shutdown: &CancellationToken) -> SessionHandle {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let (sender, receiver) = mpsc::channel(OUTBOUND_QUEUE_LEN);
+        let closed = shutdown.child_token();
+        let voice_key = sealed::generate_key();
+        let voice = VoiceChannel {
+            opener: Opener::new(&voice_key, id, Direction::ClientToServer),
+            sealer: Sealer::new(&voice_key, id, Direction::ServerToClient),
+        };
+
+        tracing::info!(
+            "Session {} registered for {} as {}",
+            id,
+            peer,
+            user.as_deref().unwrap_or("anonymous")
+        );
+        self.lock().insert(
+            id,
+            Session {
+                info: SessionInfo {
+                    id,
+                    peer,
+                    user,
+                    connected_at: Utc::now(),
+                    udp_endpoint: None,
+                },
+                outbound: sender,
+                closed: closed.clone(),
+                voice: Arc::new(Mutex::new(voice)),
+            },
+        );
+
+        SessionHandle {
+            id,
+            voice_key,
+            outbound: receiver,
+            closed,
+        }
+    }
+
+    pub fn unregister(&self, id: SessionId) {
+        if self.lock().remove(&id).is_some() {
+            tracing::info!("Session {} unregistered", id);
+        }
+    }
+
+    /// Ties a voice stream to a session. Only accepted from the host the
+    /// session's TCP connection comes from. Returns the session's user.
+    pub fn bind_udp_endpoint(&self, id: SessionId, endpoint: SocketAddr) -> Result<Option<String>, SessionError> {
+        let mut sessions = self.lock();
+        let session = sessions.get_mut(&id).ok_or(SessionError::NotFound(id))?;
+        if session.info.peer.ip() != endpoint.ip() {
+            return Err(SessionError::EndpointMismatch {
+                id,
+                peer: session.info.peer.ip(),
+                endpoint,
+            });
+        }
+        session.info.udp_endpoint = Some(endpoint);
+        Ok(session.info.user.clone())
+    }
+
+    pub fn voice_channel(&self, id: SessionId) -> Option<Arc<Mutex<VoiceChannel>>> {
+        self.lock().get(&id).map(|session| Arc::clone(&session.voice))
+    }
+
+    /// Where to send a session's voice and the channel to seal it with, once
+    /// the session has bound a voice stream.
+    pub fn voice_target(&self, id: SessionId) -> Option<(SocketAddr, Arc<Mutex<VoiceChannel>>)> {
+        let sessions = self.lock();
+        let session = sessions.get(&id)?;
+        Some((session.info.udp_endpoint?, Arc::clone(&session.voice)))
+    }
+
+    /// The session whose voice stream comes from `endpoint`.
+    pub fn session_for_endpoint(&self, endpoint: SocketAddr) -> Option<SessionId> {
+        self.lock()
+            .values()
+            .find(|session| session.info.udp_endpoint == Some(endpoint))
+            .map(|session| session.info.id)
+    }
+
+    pub fn forget_udp_endpoint(&self, endpoint: SocketAddr) {
+        for session in self.lock().values_mut() {
+            if session.info.udp_endpoint == Some(endpoint) {
+                session.info.udp_endpoint = None;
+            }
+        }
+    }
+
+    pub fn list(&self) -> Vec<SessionInfo> {
+        let mut sessions: Vec<SessionInfo> = self.lock().values().map(|session| session.info.clone()).collect();
+        sessions.sort_by_key(|session| session.id);
+        sessions
+    }
+
+    pub fn send_to(&self, id: SessionId, message: ServerMessage) -> Result<(), SessionError> {
+        let sessions = self.lock();
+        let session = sessions.get(&id).ok_or(SessionError::NotFound(id))?;
+        deliver(session, message)
+    }
+
+    /// Tells a client why it is being disconnected, then closes its connection.
+    pub fn disconnect(&self, id: SessionId, reason: &str) -> Result<(), SessionError> {
+        let sessions = self.lock();
+        let session = sessions.get(&id).ok_or(SessionError::NotFound(id))?;
+        // The notice is still delivered, the connection drains its queue before closing
+        let _ = deliver(
+            session,
+            ServerMessage::Disconnected {
+                reason: reason.to_string(),
+            },
+        );
+        session.closed.cancel();
+        tracing::info!("Session {} disconnected: {}", id, reason);
+        Ok(())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<SessionId, Session>> {
+        // The map stays consistent even if a holder panicked
+        self.sessions.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+fn deliver(session: &Session, message: ServerMessage) -> Result<(), SessionError> {
+    let id = session.info.id;
+    if session.closed.is_cancelled() {
+        return Err(SessionError::Closed(id));
+    }
+    session.outbound.try_send(message).map_err(|err| match err {
+        TrySendError::Full(_) => SessionError::Full(id),
+        TrySendError::Closed(_) => SessionError::Closed(id),
+    })
+}
@@ -0,0 +1,102 @@
+use std::time::{Duration, Instant};
+
+use crate::protocol::audio::ReceiverReport;
+
+/// Loss and jitter bookkeeping for one incoming audio stream, following RFC 3550 section 6.4.
+pub struct StreamStats {
+    started: Instant,
+    base_sequence: Option<u32>,
+    highest_sequence: u32,
+    received: u64,
+    expected_prior: u64,
+    received_prior: u64,
+    last_transit: Option<f64>,
+    jitter: f64,
+    last_report: Instant,
+    report_interval: Duration,
+}
+
+impl StreamStats {
+    pub fn new(report_interval: Duration) -> Self {
+        let now = Instant::now();
+        Self {
+            started: now,
+            base_sequence: None,
+            highest_sequence: 0,
+            received: 0,
+            expected_prior: 0,
+            received_prior: 0,
+            last_transit: None,
+            jitter: 0.0,
+            last_report: now,
+            report_interval,
+        }
+    }
+
+    pub fn record(&mut self, sequence: u32, timestamp_ms: u32) {
+        match self.base_sequence {
+            None => {
+                self.base_sequence = Some(sequence);
+                self.highest_sequence = sequence;
+            }
+            Some(_) if sequence > self.highest_sequence => self.highest_sequence = sequence,
+            Some(_) => {}
+        }
+        self.received += 1;
+
+        // Transit time is only meaningful relative to the previous packet, so the
+        // unsynchronised clocks of both sides cancel out.
+        let arrival_ms = self.started.elapsed().as_secs_f64() * 1000.0;
+        let transit = arrival_ms - timestamp_ms as f64;
+        if let Some(last_transit) = self.last_transit {
+            let d = (transit - last_transit).abs();
+            self.jitter += (d - self.jitter) / 16.0;
+        }
+        self.last_transit = Some(transit);
+    }
+
+    pub fn expected(&self) -> u64 {
+        match self.base_sequence {
+            Some(base) => (self.highest_sequence - base) as u64 + 1,
+            None => 0,
+        }
+    }
+
+    pub fn received(&self) -> u64 {
+        self.received
+    }
+
+    pub fn jitter_ms(&self) -> f64 {
+        self.jitter
+    }
+
+    pub fn lost(&self) -> u64 {
+        self.expected().saturating_sub(self.received)
+    }
+
+    pub fn report_due(&self) -> bool {
+        self.base_sequence.is_some() && self.last_report.elapsed() >= self.report_interval
+    }
+
+    /// Builds a report covering the interval since the previous one.
+    pub fn report(&mut self) -> ReceiverReport {
+        let expected = self.expected();
+        let expected_interval = expected - self.expected_prior;
+        let received_interval = self.received - self.received_prior;
+        self.expected_prior = expected;
+        self.received_prior = self.received;
+        self.last_report = Instant::now();
+
+        let lost_interval = expected_interval.saturating_sub(received_interval);
+        let fraction_lost = (lost_interval << 8)
+            .checked_div(expected_interval)
+            .map_or(0, |fraction| fraction.min(255) as u8);
+
+        ReceiverReport {
+            fraction_lost,
+            cumulative_lost: self.lost().min(u32::MAX as u64) as u32,
+            highest_sequence: self.highest_sequence,
+            jitter_ms: self.jitter.round() as u32,
+        }
+    }
+}
@@ -0,0 +1,349 @@
+use std::cell::RefCell;
+use std::io::{ErrorKind, Read, Write};
+use std::net::Shutdown;
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+use miette::{IntoDiagnostic, WrapErr};
+use rustls::pki_types::CertificateDer;
+use rustls::{ServerConfig, ServerConnection};
+use super::runtime::TcpStream;
+use super::websocket::{WebSocketCodec, CLOSE_NORMAL};
+use crate::tls::fingerprint;
+
+/// How long a closing connection waits for the client to close its side.
+const CLOSE_LINGER: Duration = Duration::from_secs(1);
+
+/// Builds the TLS configuration from PEM files and logs the fingerprint
+/// clients pin for a self-signed certificate.
+pub fn load_server_config(cert_path: &Path, key_path: &Path) -> miette::Result<Arc<ServerConfig>> {
+    let certs = read_pem(cert_path, |mut pem| {
+        rustls_pemfile::certs(&mut pem).collect::<Result<Vec<CertificateDer<'static>>, _>>()
+    })?;
+    let Some(leaf) = certs.first() else {
+        miette::bail!("{} contains no certificate", cert_path.display());
+    };
+    tracing::info!("TLS certificate fingerprint (SHA-256): {}", fingerprint(leaf));
+
+    let key = read_pem(key_path, |mut pem| rustls_pemfile::private_key(&mut pem))?
+        .ok_or_else(|| miette::miette!("{} contains no private key", key_path.display()))?;
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .into_diagnostic()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .into_diagnostic()
+        .wrap_err("Certificate and key do not form a usable pair")?;
+    Ok(Arc::new(config))
+}
+
+fn read_pem<T>(
+    path: &Path,
+    parse: impl FnOnce(&[u8]) -> std::io::Result<T>,
+) -> miette::Result<T> {
+    let contents = std::fs::read(path)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Could not read {}", path.display()))?;
+    parse(&contents)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Could not parse {}", path.display()))
+}
+
+/// Writes a self-signed certificate for `names` and its key, for development
+/// setups where clients pin the printed fingerprint.
+pub fn generate_self_signed(names: Vec<String>, cert_path: &Path, key_path: &Path, force: bool) -> miette::Result<()> {
+    for path in [cert_path, key_path] {
+        if path.exists() && !force {
+            miette::bail!("{} already exists, pass --force to replace it", path.display());
+        }
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).into_diagnostic()?;
+        }
+    }
+
+    let certified = rcgen::generate_simple_self_signed(names).into_diagnostic()?;
+    std::fs::write(cert_path, certified.cert.pem()).into_diagnostic()?;
+    write_private(key_path, certified.key_pair.serialize_pem().as_bytes())?;
+
+    println!("Wrote {} and {}", cert_path.display(), key_path.display());
+    println!("SHA-256 fingerprint: {}", fingerprint(certified.cert.der()));
+    Ok(())
+}
+
+#[cfg(unix)]
+pub(super) fn write_private(path: &Path, contents: &[u8]) -> miette::Result<()> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .into_diagnostic()?;
+    file.write_all(contents).into_diagnostic()
+}
+
+#[cfg(not(unix))]
+pub(super) fn write_private(path: &Path, contents: &[u8]) -> miette::Result<()> {
+    std::fs::write(path, contents).into_diagnostic()
+}
+
+/// The byte stream of a chat connection, optionally wrapped in TLS and,
+/// for browsers, in WebSocket frames.
+///
+/// Socket reads stay with the caller so they can remain pending across
+/// `select!` iterations. TLS and WebSocket only transform the bytes: what was
+/// read goes through [`Transport::decode`], what is sent through [`Transport::send`].
+pub struct Transport {
+    stream: Rc<TcpStream>,
+    tls: Option<RefCell<ServerConnection>>,
+    websocket: Option<RefCell<WebSocketCodec>>,
+}
+
+impl Transport {
+    pub fn new(stream: TcpStream, tls: Option<&Arc<ServerConfig>>) -> miette::Result<Self> {
+        let tls = match tls {
+            Some(config) => Some(RefCell::new(ServerConnection::new(Arc::clone(config)).into_diagnostic()?)),
+            None => None,
+        };
+        Ok(Self {
+            stream: Rc::new(stream),
+            tls,
+            websocket: None,
+        })
+    }
+
+    /// Carries native frames in WebSocket messages from now on, once the
+    /// handshake is done.
+    pub fn upgrade_to_websocket(&mut self) {
+        self.websocket = Some(RefCell::default());
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.tls.is_some()
+    }
+
+    pub fn socket(&self) -> Rc<TcpStream> {
+        Rc::clone(&self.stream)
+    }
+
+    /// Appends the plaintext carried by `data`, as read from the socket, to
+    /// `plaintext`. Handshake and control replies are sent on the way.
+    pub async fn decode(&self, data: &[u8], plaintext: &mut Vec<u8>) -> miette::Result<()> {
+        let Some(websocket) = &self.websocket else {
+            return self.decode_tls(data, plaintext).await;
+        };
+        let mut unwrapped = Vec::new();
+        self.decode_tls(data, &mut unwrapped).await?;
+        let mut replies = Vec::new();
+        let result = websocket.borrow_mut().decode(&unwrapped, plaintext, &mut replies);
+        // Sent even after an error, the close frame explains it
+        self.send_bytes(replies).await?;
+        result.into_diagnostic()
+    }
+
+    async fn decode_tls(&self, data: &[u8], plaintext: &mut Vec<u8>) -> miette::Result<()> {
+        let Some(tls) = &self.tls else {
+            plaintext.extend_from_slice(data);
+            return Ok(());
+        };
+
+        let (result, outgoing) = {
+            let mut connection = tls.borrow_mut();
+            let result = read_records(&mut connection, data, plaintext);
+            // Sent even after an error, rustls queues an alert explaining it
+            (result, take_outgoing(&mut connection))
+        };
+        self.write_raw(outgoing).await?;
+        result
+    }
+
+    /// Sends `data`, encrypted when TLS is on, and returns the number of
+    /// bytes that went on the wire. Data sent before the handshake finished
+    /// is buffered and goes out with the handshake's last reply.
+    pub async fn send(&self, data: Vec<u8>) -> miette::Result<usize> {
+        let data = match &self.websocket {
+            Some(websocket) => websocket.borrow().encode(&data),
+            None => data,
+        };
+        self.send_bytes(data).await
+    }
+
+    async fn send_bytes(&self, data: Vec<u8>) -> miette::Result<usize> {
+        let Some(tls) = &self.tls else {
+            let len = data.len();
+            self.write_raw(data).await?;
+            return Ok(len);
+        };
+
+        // rustls buffers at most 64 KiB of records, longer messages are
+        // encrypted a part at a time with the socket written in between
+        let mut rest = &data[..];
+        let mut len = 0;
+        loop {
+            let (written, outgoing) = {
+                let mut connection = tls.borrow_mut();
+                let written = connection.writer().write(rest).into_diagnostic()?;
+                (written, take_outgoing(&mut connection))
+            };
+            rest = &rest[written..];
+            if written == 0 && outgoing.is_empty() && !rest.is_empty() {
+                // Only before the handshake, nothing goes out until it finishes
+                miette::bail!("TLS send buffer is full before the handshake finished");
+            }
+            len += outgoing.len();
+            self.write_raw(outgoing).await?;
+            if rest.is_empty() {
+                return Ok(len);
+            }
+        }
+    }
+
+    /// Ends the connection, telling a TLS or WebSocket client it ends on purpose.
+    pub async fn close(&self) -> miette::Result<()> {
+        if let Some(websocket) = &self.websocket {
+            let close = websocket.borrow_mut().close(CLOSE_NORMAL);
+            self.send_bytes(close).await?;
+        }
+        if let Some(tls) = &self.tls {
+            let outgoing = {
+                let mut connection = tls.borrow_mut();
+                connection.send_close_notify();
+                take_outgoing(&mut connection)
+            };
+            self.write_raw(outgoing).await?;
+        }
+
+        // Closing with unread data resets the connection, and the reset can
+        // discard the last messages before the client reads them. Whatever
+        // the client still sends is read and dropped for a moment instead.
+        self.stream.shutdown(Shutdown::Write).into_diagnostic()?;
+        let drain = async {
+            let mut buffer = vec![0u8; 4096];
+            loop {
+                let (result, return_buf) = self.stream.read(buffer).await;
+                buffer = return_buf;
+                if !matches!(result, Ok(num_bytes_read) if num_bytes_read > 0) {
+                    break;
+                }
+            }
+        };
+        let _ = tokio::time::timeout(CLOSE_LINGER, drain).await;
+        Ok(())
+    }
+
+    async fn write_raw(&self, data: Vec<u8>) -> miette::Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        let (result, _) = self.stream.write_all(data).await;
+        result.into_diagnostic()
+    }
+}
+
+fn read_records(connection: &mut ServerConnection, mut data: &[u8], plaintext: &mut Vec<u8>) -> miette::Result<()> {
+    while !data.is_empty() {
+        connection.read_tls(&mut data).into_diagnostic()?;
+        connection.process_new_packets().into_diagnostic()?;
+        // Drained after every record batch, rustls stops accepting input
+        // while its plaintext buffer is full
+        match connection.reader().read_to_end(plaintext) {
+            Ok(_) => {}
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+            Err(err) => return Err(err).into_diagnostic(),
+        }
+    }
+    Ok(())
+}
+
+fn take_outgoing(connection: &mut ServerConnection) -> Vec<u8> {
+    let mut outgoing = Vec::new();
+    while connection.wants_write() {
+        // Writing into a Vec cannot fail
+        if connection.write_tls(&mut outgoing).is_err() {
+            break;
+        }
+    }
+    outgoing
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+    use rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer, ServerName};
+    use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+    use super::super::runtime::{self, TcpListener};
+    use crate::config::RuntimeKind;
+
+    /// A server config with a fresh self-signed certificate for `localhost`,
+    /// and a client config that trusts it.
+    fn configs() -> (Arc<ServerConfig>, Arc<ClientConfig>) {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert = certified.cert.der().clone();
+        let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der()));
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+
+        let server = ServerConfig::builder_with_provider(Arc::clone(&provider))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert.clone()], key)
+            .unwrap();
+        let mut roots = RootCertStore::empty();
+        roots.add(cert).unwrap();
+        let client = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        (Arc::new(server), Arc::new(client))
+    }
+
+    /// Says hello, then reads `len` bytes back.
+    fn client(addr: SocketAddr, config: Arc<ClientConfig>, len: usize) -> Vec<u8> {
+        let name = ServerName::try_from("localhost").unwrap();
+        let connection = ClientConnection::new(config, name).unwrap();
+        let mut stream = StreamOwned::new(connection, std::net::TcpStream::connect(addr).unwrap());
+        stream.write_all(b"hello").unwrap();
+        stream.flush().unwrap();
+        let mut received = vec![0u8; len];
+        stream.read_exact(&mut received).unwrap();
+        received
+    }
+
+    #[test]
+    fn messages_over_the_tls_buffer_limit_are_sent_whole() {
+        let (server_config, client_config) = configs();
+        let message: Vec<u8> = (0..200 * 1024).map(|i| (i % 251) as u8).collect();
+
+        let received = runtime::start(RuntimeKind::Epoll, async {
+            let listener = TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+            let addr = listener.local_addr().unwrap();
+            let len = message.len();
+            let client = std::thread::spawn(move || client(addr, client_config, len));
+
+            let (stream, _) = listener.accept().await.unwrap();
+            let transport = Transport::new(stream, Some(&server_config)).unwrap();
+            let mut plaintext = Vec::new();
+            let mut buffer = vec![0u8; 4096];
+            while plaintext != b"hello" {
+                let (result, return_buf) = transport.socket().read(buffer).await;
+                buffer = return_buf;
+                let num_bytes_read = result.unwrap();
+                assert!(num_bytes_read > 0, "the client left during the handshake");
+                transport.decode(&buffer[..num_bytes_read], &mut plaintext).await.unwrap();
+            }
+
+            let sent = transport.send(message.clone()).await.unwrap();
+            assert!(sent > message.len());
+            tokio::task::spawn_blocking(move || client.join().unwrap()).await.unwrap()
+        })
+        .unwrap();
+        assert!(received == message);
+    }
+}
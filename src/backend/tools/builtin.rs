@@ -0,0 +1,267 @@
+use std::fs;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use chrono::Local;
+use miette::{IntoDiagnostic, WrapErr};
+use serde::Deserialize;
+use serde_json::Value;
+use super::calculator;
+use super::{Param, ParamType, Tool, ToolDefinition, ToolError};
+
+/// Matches returned by one search, the rest are counted.
+const MAX_NOTE_MATCHES: usize = 20;
+/// Notes larger than this are skipped.
+const MAX_NOTE_BYTES: u64 = 1024 * 1024;
+/// How deep the search goes below the notes directory.
+const MAX_NOTE_DEPTH: usize = 8;
+const NOTE_EXTENSIONS: [&str; 3] = ["md", "txt", "org"];
+/// Longest line of a note shown in a match.
+const MAX_MATCH_LEN: usize = 200;
+/// Longest part of a file `read_file` returns.
+const MAX_FILE_BYTES: u64 = 64 * 1024;
+
+pub struct CurrentTime;
+
+impl Tool for CurrentTime {
+    fn definition(&self) -> ToolDefinition {
+        ToolDefinition {
+            name: "current_time",
+            description: "The current date and time where the server runs.",
+            params: Vec::new(),
+        }
+    }
+
+    fn call(&self, _arguments: Value) -> Result<String, ToolError> {
+        Ok(Local::now().format("%A, %Y-%m-%d %H:%M:%S (UTC%:z)").to_string())
+    }
+}
+
+pub struct Calculate;
+
+#[derive(Deserialize)]
+struct CalculateArgs {
+    expression: String,
+}
+
+impl Tool for Calculate {
+    fn definition(&self) -> ToolDefinition {
+        ToolDefinition {
+            name: "calculate",
+            description: "Evaluates an arithmetic expression with + - * / % ^ and parentheses.",
+            params: vec![Param {
+                name: "expression",
+                kind: ParamType::String,
+                description: "For example `(2 + 3) * 4.5`",
+                required: true,
+            }],
+        }
+    }
+
+    fn call(&self, arguments: Value) -> Result<String, ToolError> {
+        let args: CalculateArgs = parse(arguments)?;
+        let value = calculator::evaluate(&args.expression).map_err(|err| ToolError::Failed(err.to_string()))?;
+        Ok(calculator::format(value))
+    }
+}
+
+/// Searches the text files of the notes directory line by line.
+pub struct SearchNotes {
+    root: PathBuf,
+}
+
+#[derive(Deserialize)]
+struct SearchNotesArgs {
+    query: String,
+}
+
+impl SearchNotes {
+    pub fn new(dir: &Path) -> miette::Result<Self> {
+        Ok(Self { root: open_dir(dir)? })
+    }
+}
+
+impl Tool for SearchNotes {
+    fn definition(&self) -> ToolDefinition {
+        ToolDefinition {
+            name: "search_notes",
+            description: "Finds lines in the local notes that contain the query, ignoring case.",
+            params: vec![Param {
+                name: "query",
+                kind: ParamType::String,
+                description: "Text to look for",
+                required: true,
+            }],
+        }
+    }
+
+    fn call(&self, arguments: Value) -> Result<String, ToolError> {
+        let args: SearchNotesArgs = parse(arguments)?;
+        let query = args.query.trim().to_lowercase();
+        if query.is_empty() {
+            return Err(ToolError::InvalidArguments("`query` is empty".to_string()));
+        }
+
+        let mut files = Vec::new();
+        collect_notes(&self.root, 0, &mut files);
+        files.sort();
+        let mut matches = Vec::new();
+        let mut total = 0;
+        for path in files {
+            let Ok(text) = fs::read_to_string(&path) else {
+                continue;
+            };
+            let name = path.strip_prefix(&self.root).unwrap_or(&path).display().to_string();
+            for (number, line) in text.lines().enumerate() {
+                if !line.to_lowercase().contains(&query) {
+                    continue;
+                }
+                total += 1;
+                if matches.len() < MAX_NOTE_MATCHES {
+                    matches.push(format!("{}:{}: {}", name, number + 1, truncate(line.trim(), MAX_MATCH_LEN)));
+                }
+            }
+        }
+        if matches.is_empty() {
+            return Ok(format!("No notes mention \"{}\"", args.query.trim()));
+        }
+        if total > matches.len() {
+            matches.push(format!("... and {} more", total - matches.len()));
+        }
+        Ok(matches.join("\n"))
+    }
+}
+
+fn collect_notes(dir: &Path, depth: usize, files: &mut Vec<PathBuf>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let hidden = entry.file_name().to_string_lossy().starts_with('.');
+        // Links are not followed, they could lead out of the directory
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        if hidden || file_type.is_symlink() {
+            continue;
+        }
+        let path = entry.path();
+        if file_type.is_dir() {
+            if depth < MAX_NOTE_DEPTH {
+                collect_notes(&path, depth + 1, files);
+            }
+            continue;
+        }
+        let is_note = path
+            .extension()
+            .is_some_and(|extension| NOTE_EXTENSIONS.iter().any(|known| extension.eq_ignore_ascii_case(known)));
+        let small = entry.metadata().is_ok_and(|metadata| metadata.len() <= MAX_NOTE_BYTES);
+        if is_note && small {
+            files.push(path);
+        }
+    }
+}
+
+/// Reads text files from one directory, nothing outside it.
+pub struct ReadFile {
+    root: PathBuf,
+}
+
+#[derive(Deserialize)]
+struct ReadFileArgs {
+    path: String,
+}
+
+impl ReadFile {
+    pub fn new(dir: &Path) -> miette::Result<Self> {
+        Ok(Self { root: open_dir(dir)? })
+    }
+
+    /// Resolves `path` inside the root. Links are resolved first, so neither
+    /// `..` nor a link can reach a file outside it.
+    fn resolve(&self, path: &str) -> Result<PathBuf, ToolError> {
+        let relative = Path::new(path);
+        let plain = relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+        if !plain {
+            return Err(ToolError::InvalidArguments(
+                "`path` must be relative and stay inside the shared directory".to_string(),
+            ));
+        }
+        let resolved = self
+            .root
+            .join(relative)
+            .canonicalize()
+            .map_err(|_| ToolError::Failed(format!("There is no file {}", path)))?;
+        if !resolved.starts_with(&self.root) {
+            return Err(ToolError::Failed(format!("{} is outside the shared directory", path)));
+        }
+        Ok(resolved)
+    }
+}
+
+impl Tool for ReadFile {
+    fn definition(&self) -> ToolDefinition {
+        ToolDefinition {
+            name: "read_file",
+            description: "Reads a text file from the shared directory.",
+            params: vec![Param {
+                name: "path",
+                kind: ParamType::String,
+                description: "Path relative to the shared directory",
+                required: true,
+            }],
+        }
+    }
+
+    fn call(&self, arguments: Value) -> Result<String, ToolError> {
+        let args: ReadFileArgs = parse(arguments)?;
+        let path = self.resolve(&args.path)?;
+        if !path.is_file() {
+            return Err(ToolError::Failed(format!("{} is not a file", args.path)));
+        }
+        let file = fs::File::open(&path).map_err(|err| ToolError::Failed(format!("Could not open {}: {}", args.path, err)))?;
+        let size = file.metadata().map(|metadata| metadata.len()).unwrap_or_default();
+        let mut bytes = Vec::new();
+        file.take(MAX_FILE_BYTES)
+            .read_to_end(&mut bytes)
+            .map_err(|err| ToolError::Failed(format!("Could not read {}: {}", args.path, err)))?;
+        // A cut may split a character, only what comes before it is kept
+        let text = match String::from_utf8(bytes) {
+            Ok(text) => text,
+            Err(err) if size > MAX_FILE_BYTES && err.utf8_error().error_len().is_none() => {
+                let valid = err.utf8_error().valid_up_to();
+                let mut bytes = err.into_bytes();
+                bytes.truncate(valid);
+                String::from_utf8(bytes).unwrap_or_default()
+            }
+            Err(_) => return Err(ToolError::Failed(format!("{} is not a text file", args.path))),
+        };
+        if size > MAX_FILE_BYTES {
+            return Ok(format!("{}\n[cut off after {} of {} bytes]", text, MAX_FILE_BYTES, size));
+        }
+        Ok(text)
+    }
+}
+
+fn open_dir(dir: &Path) -> miette::Result<PathBuf> {
+    let root = dir
+        .canonicalize()
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Could not open {}", dir.display()))?;
+    if !root.is_dir() {
+        miette::bail!("{} is not a directory", dir.display());
+    }
+    Ok(root)
+}
+
+fn parse<T: for<'de> Deserialize<'de>>(arguments: Value) -> Result<T, ToolError> {
+    serde_json::from_value(arguments).map_err(|err| ToolError::InvalidArguments(err.to_string()))
+}
+
+fn truncate(text: &str, max_chars: usize) -> String {
+    match text.char_indices().nth(max_chars) {
+        Some((end, _)) => format!("{}...", &text[..end]),
+        None => text.to_string(),
+    }
+}
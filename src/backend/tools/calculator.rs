@@ -0,0 +1,198 @@
+use thiserror::Error;
+
+/// Longest expression evaluated, which also bounds the nesting depth.
+const MAX_EXPRESSION_LEN: usize = 256;
+
+#[derive(Debug, Error)]
+pub enum CalculatorError {
+    #[error("The expression is longer than {MAX_EXPRESSION_LEN} characters")]
+    TooLong,
+    #[error("Unexpected `{0}` at position {1}")]
+    Unexpected(char, usize),
+    #[error("The expression ends too early")]
+    UnexpectedEnd,
+    #[error("Division by zero")]
+    DivisionByZero,
+    #[error("The result is not a finite number")]
+    NotFinite,
+}
+
+/// Evaluates an arithmetic expression over `f64`: numbers, `+ - * / % ^`,
+/// unary minus and parentheses. `^` binds tighter than unary minus, so
+/// `-2^2` is -4, and groups to the right.
+pub fn evaluate(expression: &str) -> Result<f64, CalculatorError> {
+    if expression.len() > MAX_EXPRESSION_LEN {
+        return Err(CalculatorError::TooLong);
+    }
+    let mut parser = Parser {
+        chars: expression.chars().collect(),
+        position: 0,
+    };
+    let value = parser.expression()?;
+    parser.skip_whitespace();
+    if let Some(&c) = parser.chars.get(parser.position) {
+        return Err(CalculatorError::Unexpected(c, parser.position));
+    }
+    if !value.is_finite() {
+        return Err(CalculatorError::NotFinite);
+    }
+    Ok(value)
+}
+
+/// Whole numbers without a fraction, others as `f64` prints them.
+pub fn format(value: f64) -> String {
+    if value.fract() == 0.0 && value.abs() < 1e15 {
+        format!("{}", value as i64)
+    } else {
+        format!("{}", value)
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    position: usize,
+}
+
+impl Parser {
+    fn expression(&mut self) -> Result<f64, CalculatorError> {
+        let mut value = self.term()?;
+        while let Some(operator) = self.next_if(&['+', '-']) {
+            let rhs = self.term()?;
+            value = if operator == '+' { value + rhs } else { value - rhs };
+        }
+        Ok(value)
+    }
+
+    fn term(&mut self) -> Result<f64, CalculatorError> {
+        let mut value = self.unary()?;
+        while let Some(operator) = self.next_if(&['*', '/', '%']) {
+            let rhs = self.unary()?;
+            value = match operator {
+                '*' => value * rhs,
+                _ if rhs == 0.0 => return Err(CalculatorError::DivisionByZero),
+                '/' => value / rhs,
+                _ => value % rhs,
+            };
+        }
+        Ok(value)
+    }
+
+    fn unary(&mut self) -> Result<f64, CalculatorError> {
+        if self.next_if(&['-']).is_some() {
+            return Ok(-self.unary()?);
+        }
+        if self.next_if(&['+']).is_some() {
+            return self.unary();
+        }
+        self.power()
+    }
+
+    fn power(&mut self) -> Result<f64, CalculatorError> {
+        let base = self.primary()?;
+        if self.next_if(&['^']).is_some() {
+            let exponent = self.unary()?;
+            return Ok(base.powf(exponent));
+        }
+        Ok(base)
+    }
+
+    fn primary(&mut self) -> Result<f64, CalculatorError> {
+        self.skip_whitespace();
+        let Some(&c) = self.chars.get(self.position) else {
+            return Err(CalculatorError::UnexpectedEnd);
+        };
+        if c == '(' {
+            self.position += 1;
+            let value = self.expression()?;
+            if self.next_if(&[')']).is_none() {
+                return match self.chars.get(self.position) {
+                    Some(&c) => Err(CalculatorError::Unexpected(c, self.position)),
+                    None => Err(CalculatorError::UnexpectedEnd),
+                };
+            }
+            return Ok(value);
+        }
+        let start = self.position;
+        while self
+            .chars
+            .get(self.position)
+            .is_some_and(|c| c.is_ascii_digit() || *c == '.')
+        {
+            self.position += 1;
+        }
+        let number: String = self.chars[start..self.position].iter().collect();
+        number.parse().map_err(|_| CalculatorError::Unexpected(c, start))
+    }
+
+    /// Takes the next non-space character if it is one of `expected`.
+    fn next_if(&mut self, expected: &[char]) -> Option<char> {
+        self.skip_whitespace();
+        let c = *self.chars.get(self.position)?;
+        if !expected.contains(&c) {
+            return None;
+        }
+        self.position += 1;
+        Some(c)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.chars.get(self.position).is_some_and(|c| c.is_whitespace()) {
+            self.position += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(expression: &str) -> f64 {
+        evaluate(expression).unwrap_or_else(|err| panic!("{}: {}", expression, err))
+    }
+
+    #[test]
+    fn precedence_and_associativity() {
+        assert_eq!(eval("2 + 3 * 4"), 14.0);
+        assert_eq!(eval("(2 + 3) * 4"), 20.0);
+        assert_eq!(eval("10 - 4 - 3"), 3.0);
+        assert_eq!(eval("2 ^ 3 ^ 2"), 512.0);
+        assert_eq!(eval("-2^2"), -4.0);
+        assert_eq!(eval("2^-1"), 0.5);
+        assert_eq!(eval("17 % 5 * 2"), 4.0);
+        assert_eq!(eval(" --1.5 + +2 "), 3.5);
+    }
+
+    #[test]
+    fn errors_say_where() {
+        assert!(matches!(evaluate("1 +"), Err(CalculatorError::UnexpectedEnd)));
+        assert!(matches!(evaluate("(1 + 2"), Err(CalculatorError::UnexpectedEnd)));
+        assert!(matches!(evaluate("1 + 2)"), Err(CalculatorError::Unexpected(')', 5))));
+        assert!(matches!(evaluate("2 x 3"), Err(CalculatorError::Unexpected('x', 2))));
+        assert!(evaluate("1..2").is_err());
+        assert!(matches!(evaluate(""), Err(CalculatorError::UnexpectedEnd)));
+    }
+
+    #[test]
+    fn results_must_be_finite() {
+        assert!(matches!(evaluate("1 / 0"), Err(CalculatorError::DivisionByZero)));
+        assert!(matches!(evaluate("5 % (2 - 2)"), Err(CalculatorError::DivisionByZero)));
+        assert!(matches!(evaluate("10 ^ 400"), Err(CalculatorError::NotFinite)));
+        assert!(matches!(evaluate("(-8) ^ 0.5"), Err(CalculatorError::NotFinite)));
+    }
+
+    #[test]
+    fn long_expressions_are_refused_before_parsing() {
+        let nested = format!("{}1{}", "(".repeat(200), ")".repeat(200));
+        assert!(matches!(evaluate(&nested), Err(CalculatorError::TooLong)));
+        let nested = format!("{}1{}", "(".repeat(100), ")".repeat(100));
+        assert_eq!(eval(&nested), 1.0);
+    }
+
+    #[test]
+    fn whole_numbers_print_without_a_fraction() {
+        assert_eq!(format(4.0), "4");
+        assert_eq!(format(-0.0), "0");
+        assert_eq!(format(0.25), "0.25");
+        assert_eq!(format(1e20), "100000000000000000000");
+    }
+}
@@ -0,0 +1,263 @@
+mod builtin;
+mod calculator;
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use miette::Diagnostic;
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use thiserror::Error;
+use super::metrics::metrics;
+use crate::config::ToolsConfig;
+use crate::protocol::message::ServerMessage;
+
+/// Why a tool call produced no result. The message is what the assistant
+/// and the client see in place of one.
+#[derive(Debug, Error, Diagnostic)]
+pub enum ToolError {
+    #[error("There is no tool named {0}")]
+    #[diagnostic(code(tools::unknown))]
+    Unknown(String),
+
+    #[error("Invalid arguments: {0}")]
+    #[diagnostic(code(tools::invalid_arguments))]
+    InvalidArguments(String),
+
+    #[error("Gave up after {0:?}")]
+    #[diagnostic(code(tools::timed_out))]
+    TimedOut(Duration),
+
+    #[error("{0}")]
+    #[diagnostic(code(tools::failed))]
+    Failed(String),
+}
+
+/// JSON schema type of a tool parameter. The built-in tools only take strings
+/// so far, other types are added with the first tool that needs them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParamType {
+    String,
+}
+
+impl ParamType {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ParamType::String => "string",
+        }
+    }
+
+    fn matches(&self, value: &Value) -> bool {
+        match self {
+            ParamType::String => value.is_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Param {
+    pub name: &'static str,
+    pub kind: ParamType,
+    pub description: &'static str,
+    pub required: bool,
+}
+
+/// What a backend is told about a tool: its name, what it does and the
+/// arguments it takes.
+#[derive(Debug, Clone)]
+pub struct ToolDefinition {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub params: Vec<Param>,
+}
+
+impl ToolDefinition {
+    /// The arguments as a JSON schema object.
+    pub fn schema(&self) -> Value {
+        let properties: Map<String, Value> = self
+            .params
+            .iter()
+            .map(|param| {
+                let property = json!({ "type": param.kind.as_str(), "description": param.description });
+                (param.name.to_string(), property)
+            })
+            .collect();
+        let required: Vec<&str> = self
+            .params
+            .iter()
+            .filter(|param| param.required)
+            .map(|param| param.name)
+            .collect();
+        json!({
+            "type": "object",
+            "properties": properties,
+            "required": required,
+            "additionalProperties": false,
+        })
+    }
+
+    /// Checks `arguments` against the schema, so tools only see arguments of
+    /// the declared types. A missing object counts as an empty one.
+    fn validate(&self, arguments: Value) -> Result<Value, ToolError> {
+        let arguments = match arguments {
+            Value::Null => Map::new(),
+            Value::Object(arguments) => arguments,
+            _ => return Err(ToolError::InvalidArguments("expected an object".to_string())),
+        };
+        for name in arguments.keys() {
+            if !self.params.iter().any(|param| param.name == name) {
+                return Err(ToolError::InvalidArguments(format!("unknown argument `{}`", name)));
+            }
+        }
+        for param in &self.params {
+            match arguments.get(param.name) {
+                None | Some(Value::Null) if param.required => {
+                    return Err(ToolError::InvalidArguments(format!("`{}` is required", param.name)));
+                }
+                Some(value) if !value.is_null() && !param.kind.matches(value) => {
+                    return Err(ToolError::InvalidArguments(format!(
+                        "`{}` must be a {}",
+                        param.name,
+                        param.kind.as_str()
+                    )));
+                }
+                _ => {}
+            }
+        }
+        Ok(Value::Object(arguments))
+    }
+}
+
+/// Something the assistant can do. Calls run on a blocking thread, so a
+/// tool may read files or compute without holding up the server.
+pub trait Tool: Send + Sync {
+    fn definition(&self) -> ToolDefinition;
+
+    /// Runs with arguments that passed the definition's schema.
+    fn call(&self, arguments: Value) -> Result<String, ToolError>;
+}
+
+/// A tool invocation a backend asked for.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ToolCall {
+    pub tool: String,
+    pub arguments: Value,
+}
+
+/// A finished tool call.
+#[derive(Debug)]
+pub struct ToolOutcome {
+    pub call: ToolCall,
+    pub result: Result<String, ToolError>,
+    pub duration: Duration,
+}
+
+impl ToolOutcome {
+    /// The result as the conversation records it.
+    pub fn output(&self) -> String {
+        match &self.result {
+            Ok(output) => output.clone(),
+            Err(err) => format!("Error: {}", err),
+        }
+    }
+
+    /// Tells clients about the call, in `room` or their private conversation.
+    pub fn to_message(&self, room: Option<String>) -> ServerMessage {
+        ServerMessage::ToolActivity {
+            tool: self.call.tool.clone(),
+            arguments: self.call.arguments.to_string(),
+            output: self.output(),
+            ok: self.result.is_ok(),
+            duration_ms: self.duration.as_millis() as u64,
+            room,
+        }
+    }
+}
+
+/// A tool as the HTTP API lists it.
+#[derive(Serialize)]
+pub struct ToolSummary {
+    name: &'static str,
+    description: &'static str,
+    parameters: Value,
+}
+
+/// The tools the assistant may call, set up from the `[tools]` config.
+#[derive(Clone)]
+pub struct ToolRegistry {
+    tools: Arc<BTreeMap<&'static str, Arc<dyn Tool>>>,
+    timeout: Duration,
+}
+
+impl ToolRegistry {
+    pub fn new(config: &ToolsConfig) -> Self {
+        let mut tools: Vec<Arc<dyn Tool>> = vec![Arc::new(builtin::CurrentTime), Arc::new(builtin::Calculate)];
+        if let Some(dir) = &config.notes_dir {
+            match builtin::SearchNotes::new(dir) {
+                Ok(tool) => tools.push(Arc::new(tool)),
+                Err(err) => tracing::warn!("Leaving out search_notes: {:?}", err),
+            }
+        }
+        if let Some(dir) = &config.files_dir {
+            match builtin::ReadFile::new(dir) {
+                Ok(tool) => tools.push(Arc::new(tool)),
+                Err(err) => tracing::warn!("Leaving out read_file: {:?}", err),
+            }
+        }
+        let tools: BTreeMap<_, _> = tools.into_iter().map(|tool| (tool.definition().name, tool)).collect();
+        tracing::info!(
+            "The assistant can call {}",
+            tools.keys().copied().collect::<Vec<_>>().join(", ")
+        );
+        Self {
+            tools: Arc::new(tools),
+            timeout: Duration::from_millis(config.timeout_ms),
+        }
+    }
+
+    pub fn definitions(&self) -> Vec<ToolDefinition> {
+        self.tools.values().map(|tool| tool.definition()).collect()
+    }
+
+    pub fn summaries(&self) -> Vec<ToolSummary> {
+        self.definitions()
+            .into_iter()
+            .map(|definition| ToolSummary {
+                name: definition.name,
+                description: definition.description,
+                parameters: definition.schema(),
+            })
+            .collect()
+    }
+
+    /// Runs one call to completion or until the timeout. A call that times
+    /// out keeps its thread until it returns, its result is dropped.
+    pub async fn invoke(&self, call: ToolCall) -> ToolOutcome {
+        let started_at = Instant::now();
+        let result = self.run(&call).await;
+        let duration = started_at.elapsed();
+        metrics().tool_calls.inc();
+        match &result {
+            Ok(_) => tracing::info!("Tool {} answered in {:?}", call.tool, duration),
+            Err(err) => {
+                metrics().tool_failures.inc();
+                tracing::warn!("Tool {} failed after {:?}: {}", call.tool, duration, err);
+            }
+        }
+        ToolOutcome { call, result, duration }
+    }
+
+    async fn run(&self, call: &ToolCall) -> Result<String, ToolError> {
+        let Some(tool) = self.tools.get(call.tool.as_str()) else {
+            return Err(ToolError::Unknown(call.tool.clone()));
+        };
+        let arguments = tool.definition().validate(call.arguments.clone())?;
+        let tool = Arc::clone(tool);
+        let task = tokio::task::spawn_blocking(move || tool.call(arguments));
+        match tokio::time::timeout(self.timeout, task).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(err)) => Err(ToolError::Failed(format!("The tool stopped unexpectedly: {}", err))),
+            Err(_) => Err(ToolError::TimedOut(self.timeout)),
+        }
+    }
+}
@@ -1,34 +1,205 @@
-use std::net::SocketAddr;
-use miette::IntoDiagnostic;
-use tokio_uring::net::UdpSocket;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::rc::Rc;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use miette::IntoDiagnostic;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::Instrument;
 use super::audio::AudioProcessor;
+use super::limits::{AddressLimits, TokenBucket};
+use super::metrics::metrics;
+use super::rooms::RoomRegistry;
+use super::runtime::{Tasks, UdpSocket};
+use super::session::SessionRegistry;
+use super::voice_rooms::VoiceRooms;
+use super::voice_stream::{Datagram, StreamDirectory, StreamSettings, VoiceStream};
+use crate::protocol::message::ServerMessage;
+
+/// Datagrams queued per stream before new ones are dropped. Audio that has
+/// waited this long is too late to be useful anyway.
+const STREAM_QUEUE_LEN: usize = 64;
+/// A client whose voice traffic is throttled hears about it at most this often.
+const THROTTLE_NOTICE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Rate limits of the datagrams from one address.
+struct AddressState {
+    packets: TokenBucket,
+    bytes: TokenBucket,
+    notified_at: Option<Instant>,
+}
 
+impl AddressState {
+    fn new(limits: AddressLimits) -> Self {
+        Self {
+            packets: TokenBucket::per_second(limits.packets_per_second),
+            bytes: TokenBucket::per_second(limits.bytes_per_second),
+            notified_at: None,
+        }
+    }
+}
+
+/// Routes incoming datagrams to one [`VoiceStream`] task per client address.
+/// It never waits on a stream, so a slow client cannot hold up the others.
 pub struct UdpHandler {
-    socket: Arc<UdpSocket>,
+    socket: Rc<UdpSocket>,
     audio_processor: AudioProcessor,
+    sessions: SessionRegistry,
+    voice_rooms: VoiceRooms,
+    directory: StreamDirectory,
+    settings: StreamSettings,
+    tasks: Tasks,
+    streams: HashMap<SocketAddr, mpsc::Sender<Datagram>>,
+    addresses: HashMap<IpAddr, AddressState>,
+    dropped: u64,
 }
 
 impl UdpHandler {
-    pub async fn new(addr: &str) -> miette::Result<Self> {
-        let udp_addr: SocketAddr = addr.parse().into_diagnostic()?;
+    pub async fn new(
+        udp_addr: SocketAddr,
+        audio_processor: AudioProcessor,
+        sessions: SessionRegistry,
+        rooms: RoomRegistry,
+        directory: StreamDirectory,
+        settings: StreamSettings,
+        tasks: Tasks,
+    ) -> miette::Result<Self> {
         tracing::info!("Attempting to bind UDP socket to {}", udp_addr);
-        let socket = Arc::new(UdpSocket::bind(udp_addr).await.into_diagnostic()?);
+        let socket = Rc::new(UdpSocket::bind(udp_addr).await.into_diagnostic()?);
         tracing::info!("Successfully bound UDP socket to {}", udp_addr);
-        
+
+        let voice_rooms = VoiceRooms::new(Rc::clone(&socket), sessions.clone(), rooms, settings.room_mode);
         Ok(Self {
-            socket: Arc::clone(&socket),
-            audio_processor: AudioProcessor::new(socket),
+            socket,
+            audio_processor,
+            sessions,
+            voice_rooms,
+            directory,
+            settings,
+            tasks,
+            streams: HashMap::new(),
+            addresses: HashMap::new(),
+            dropped: 0,
         })
     }
 
-    pub async fn process_packet(&mut self, data: Vec<u8>, addr: SocketAddr) -> miette::Result<()> {
-        tracing::info!("Received audio chunk from {}", addr.clone());
+    /// Hands a datagram to its stream. Nothing is copied out of the receive
+    /// buffer for datagrams the limits drop.
+    pub fn route(&mut self, addr: SocketAddr, data: &[u8], received_at: Instant) {
+        metrics().udp_packets_received.inc();
+        if !self.admit(addr, data.len()) {
+            metrics().udp_packets_throttled.inc();
+            return;
+        }
+        if self.streams.get(&addr).is_none_or(|sender| sender.is_closed()) {
+            let open = self
+                .streams
+                .iter()
+                .filter(|(stream_addr, sender)| stream_addr.ip() == addr.ip() && !sender.is_closed())
+                .count();
+            if open >= self.settings.limits.streams {
+                metrics().udp_packets_throttled.inc();
+                self.notify_throttled(addr, "too many voice streams from your address, new ones are ignored");
+                return;
+            }
+            if let Err(err) = self.start_stream(addr) {
+                tracing::error!("Could not start voice stream for {}: {:?}", addr, err);
+                return;
+            }
+        }
 
-        self.audio_processor.process_packet(addr, data).await
+        let Some(sender) = self.streams.get(&addr) else {
+            return;
+        };
+        let datagram = Datagram {
+            data: data.to_vec(),
+            received_at,
+        };
+        match sender.try_send(datagram) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) | Err(TrySendError::Closed(_)) => {
+                self.dropped += 1;
+                metrics().udp_packets_dropped.inc();
+                if self.dropped.is_power_of_two() {
+                    tracing::warn!("Dropped {} datagrams so far, latest from {}", self.dropped, addr);
+                }
+            }
+        }
     }
 
-    pub fn get_socket(&self) -> Arc<UdpSocket> {
-        Arc::clone(&self.socket)
+    /// Charges a datagram to its address. Returns `false` when the address
+    /// is over its packet or byte rate.
+    fn admit(&mut self, addr: SocketAddr, len: usize) -> bool {
+        let limits = self.settings.limits;
+        let state = self
+            .addresses
+            .entry(addr.ip())
+            .or_insert_with(|| AddressState::new(limits));
+        if state.packets.try_take(1) && state.bytes.try_take(len as u64) {
+            return true;
+        }
+        self.notify_throttled(addr, "your voice traffic exceeds the server's rate limits, audio is being dropped");
+        false
     }
-} 
\ No newline at end of file
+
+    /// Tells the session a throttled stream belongs to, over its chat
+    /// connection. Answering over UDP would let spoofed traffic aim replies elsewhere.
+    fn notify_throttled(&mut self, addr: SocketAddr, problem: &str) {
+        let limits = self.settings.limits;
+        let state = self
+            .addresses
+            .entry(addr.ip())
+            .or_insert_with(|| AddressState::new(limits));
+        let now = Instant::now();
+        if state
+            .notified_at
+            .is_some_and(|at| now.duration_since(at) < THROTTLE_NOTICE_INTERVAL)
+        {
+            return;
+        }
+        state.notified_at = Some(now);
+        tracing::warn!("Throttling voice traffic from {}: {}", addr, problem);
+        if let Some(session_id) = self.sessions.session_for_endpoint(addr) {
+            let message = ServerMessage::Error {
+                message: format!("Slow down: {}", problem),
+            };
+            if let Err(err) = self.sessions.send_to(session_id, message) {
+                tracing::debug!("Could not tell session {} it is throttled: {}", session_id, err);
+            }
+        }
+    }
+
+    fn start_stream(&mut self, addr: SocketAddr) -> miette::Result<()> {
+        // Streams that ended are only cleaned up here, so the maps never
+        // outgrow the number of clients seen since the last new one
+        self.streams.retain(|_, sender| !sender.is_closed());
+        let streams = &self.streams;
+        self.addresses
+            .retain(|ip, _| *ip == addr.ip() || streams.keys().any(|stream_addr| stream_addr.ip() == *ip));
+
+        let stream = VoiceStream::new(
+            addr,
+            Rc::clone(&self.socket),
+            &self.audio_processor,
+            self.sessions.clone(),
+            self.voice_rooms.clone(),
+            Arc::clone(&self.directory),
+            self.settings,
+        )?;
+        let (sender, receiver) = mpsc::channel(STREAM_QUEUE_LEN);
+        // The session is recorded once the client binds the stream
+        let span = tracing::info_span!("voice_stream", %addr, session = tracing::field::Empty);
+        self.tasks.spawn(stream.run(receiver).instrument(span));
+        self.streams.insert(addr, sender);
+        Ok(())
+    }
+
+    /// Shares voice within rooms, its `run` has to be spawned alongside the handler.
+    pub fn voice_rooms(&self) -> VoiceRooms {
+        self.voice_rooms.clone()
+    }
+
+    pub fn get_socket(&self) -> Rc<UdpSocket> {
+        Rc::clone(&self.socket)
+    }
+}
@@ -0,0 +1,342 @@
+use std::cell::RefCell;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::sync::PoisonError;
+use std::time::{Duration, Instant};
+use opus::{Application, Bitrate, Channels, Encoder};
+use tokio::time::MissedTickBehavior;
+use tokio_util::sync::CancellationToken;
+use super::audio::SAMPLE_RATE;
+use super::metrics::metrics;
+use super::rooms::{RoomMembers, RoomRegistry};
+use super::runtime::UdpSocket;
+use super::session::{SessionId, SessionRegistry};
+use crate::config::RoomAudioMode;
+use crate::protocol::audio::{AudioPacket, Packet, MIXED_SPEAKER, ROOM_AUDIO_HEADER_LEN};
+use crate::protocol::message::ServerMessage;
+use crate::protocol::sealed::SEALED_OVERHEAD;
+
+const MIX_FRAME: Duration = Duration::from_millis(20);
+const MIX_FRAME_SAMPLES: usize = SAMPLE_RATE as usize / 50;
+/// A speaker's audio waits for this much before it is mixed, so packets
+/// arriving a little late do not leave gaps.
+const MIX_PRIME_SAMPLES: usize = MIX_FRAME_SAMPLES * 2;
+/// Audio queued beyond this is too far behind and the oldest is dropped.
+const MAX_QUEUED_SAMPLES: usize = SAMPLE_RATE as usize / 5;
+const MIX_BITRATE: i32 = 32_000;
+/// Mixed frames must fit one sealed datagram the client can receive.
+const MAX_MIX_PAYLOAD: usize = 1200 - SEALED_OVERHEAD - ROOM_AUDIO_HEADER_LEN;
+
+/// Mix frames between two active speaker decisions.
+const SPEAKER_CHECK_TICKS: u32 = 10;
+/// Level, as smoothed RMS, above which a member counts as speaking.
+const SPEAKING_LEVEL: f32 = 0.02;
+/// A member who sent nothing for this long is quiet, whatever its last level.
+const QUIET_AFTER: Duration = Duration::from_millis(400);
+/// The active speaker only changes to someone else after holding it this long,
+/// so two people talking over each other do not flip it every check.
+const SPEAKER_HOLD: Duration = Duration::from_secs(1);
+const LEVEL_SMOOTHING: f32 = 0.3;
+
+struct Speaker {
+    level: f32,
+    heard_at: Instant,
+    // Decoded samples waiting to be mixed, only filled in mixing mode
+    queue: VecDeque<f32>,
+    primed: bool,
+}
+
+impl Speaker {
+    fn new() -> Self {
+        Self {
+            level: 0.0,
+            heard_at: Instant::now(),
+            queue: VecDeque::new(),
+            primed: false,
+        }
+    }
+
+    fn is_speaking(&self, now: Instant) -> bool {
+        self.level >= SPEAKING_LEVEL && now.duration_since(self.heard_at) < QUIET_AFTER
+    }
+
+    /// The next frame to mix, or `None` while the queue is refilling.
+    fn next_frame(&mut self) -> Option<Vec<f32>> {
+        if !self.primed {
+            if self.queue.len() < MIX_PRIME_SAMPLES {
+                return None;
+            }
+            self.primed = true;
+        }
+        let take = self.queue.len().min(MIX_FRAME_SAMPLES);
+        let mut frame: Vec<f32> = self.queue.drain(..take).collect();
+        frame.resize(MIX_FRAME_SAMPLES, 0.0);
+        if self.queue.is_empty() {
+            self.primed = false;
+        }
+        Some(frame)
+    }
+}
+
+/// The mix one listener receives.
+struct Listener {
+    encoder: Encoder,
+    sequence: u32,
+}
+
+impl Listener {
+    fn new() -> Result<Self, opus::Error> {
+        let mut encoder = Encoder::new(SAMPLE_RATE, Channels::Mono, Application::Voip)?;
+        encoder.set_bitrate(Bitrate::Bits(MIX_BITRATE))?;
+        Ok(Self { encoder, sequence: 0 })
+    }
+}
+
+struct ActiveSpeaker {
+    session: Option<SessionId>,
+    since: Instant,
+}
+
+#[derive(Default)]
+struct State {
+    speakers: HashMap<SessionId, Speaker>,
+    listeners: HashMap<SessionId, Listener>,
+    active: HashMap<String, ActiveSpeaker>,
+}
+
+/// Shares the voice of room members with the rest of their room, either by
+/// forwarding each speaker's packets or by mixing one stream per listener.
+/// Also tells rooms who is speaking.
+#[derive(Clone)]
+pub struct VoiceRooms {
+    socket: Rc<UdpSocket>,
+    sessions: SessionRegistry,
+    rooms: RoomRegistry,
+    mode: RoomAudioMode,
+    state: Rc<RefCell<State>>,
+    started: Instant,
+}
+
+impl VoiceRooms {
+    pub fn new(socket: Rc<UdpSocket>, sessions: SessionRegistry, rooms: RoomRegistry, mode: RoomAudioMode) -> Self {
+        Self {
+            socket,
+            sessions,
+            rooms,
+            mode,
+            state: Rc::default(),
+            started: Instant::now(),
+        }
+    }
+
+    /// Takes a frame a session's voice stream received, with its decoded samples.
+    pub async fn on_audio(&self, speaker: SessionId, audio: &AudioPacket, samples: &[f32]) {
+        let Some(room) = self.rooms.members_of(speaker) else {
+            return;
+        };
+        {
+            let mut state = self.state.borrow_mut();
+            let entry = state.speakers.entry(speaker).or_insert_with(Speaker::new);
+            let rms = (samples.iter().map(|s| s * s).sum::<f32>() / samples.len().max(1) as f32).sqrt();
+            entry.level += (rms - entry.level) * LEVEL_SMOOTHING;
+            entry.heard_at = Instant::now();
+            if self.mode == RoomAudioMode::Mix {
+                entry.queue.extend(samples);
+                let excess = entry.queue.len().saturating_sub(MAX_QUEUED_SAMPLES);
+                entry.queue.drain(..excess);
+            }
+        }
+        if self.mode == RoomAudioMode::Forward {
+            self.forward(speaker, audio, &room).await;
+        }
+    }
+
+    /// Passes a speaker's packet on to everyone else in the room, sealed for each of them.
+    async fn forward(&self, speaker: SessionId, audio: &AudioPacket, room: &RoomMembers) {
+        let packet = Packet::RoomAudio {
+            speaker,
+            audio: audio.clone(),
+        };
+        for member in room.members.iter().filter(|member| member.session != speaker) {
+            if let Some((endpoint, data)) = self.seal_for(member.session, &packet) {
+                self.send(endpoint, data).await;
+            }
+        }
+    }
+
+    /// Mixes rooms every frame in mixing mode and picks active speakers, until
+    /// shutdown. Forwarding needs no frames, it only wakes up to pick speakers.
+    pub async fn run(self, shutdown: CancellationToken) {
+        let (period, check_every) = match self.mode {
+            RoomAudioMode::Mix => (MIX_FRAME, SPEAKER_CHECK_TICKS),
+            RoomAudioMode::Forward => (MIX_FRAME * SPEAKER_CHECK_TICKS, 1),
+        };
+        let mut ticker = tokio::time::interval(period);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        let mut ticks = 0u32;
+        loop {
+            tokio::select! {
+                _ = shutdown.cancelled() => break,
+                _ = ticker.tick() => {}
+            }
+            let rooms = self.rooms.all_members();
+            self.forget_departed(&rooms);
+            if self.mode == RoomAudioMode::Mix {
+                for (endpoint, data) in self.mix(&rooms) {
+                    self.send(endpoint, data).await;
+                }
+            }
+            ticks = ticks.wrapping_add(1);
+            if ticks.is_multiple_of(check_every) {
+                self.update_active_speakers(&rooms);
+            }
+        }
+    }
+
+    /// Builds this frame's mix for every listener. Each one hears everyone
+    /// else in the room, at the gains the listener chose.
+    fn mix(&self, rooms: &[RoomMembers]) -> Vec<(SocketAddr, Vec<u8>)> {
+        let mut state = self.state.borrow_mut();
+        let State {
+            speakers, listeners, ..
+        } = &mut *state;
+        let timestamp_ms = self.started.elapsed().as_millis() as u32;
+        let mut datagrams = Vec::new();
+        let mut output = vec![0u8; MAX_MIX_PAYLOAD];
+        for room in rooms {
+            let frames: HashMap<SessionId, Vec<f32>> = room
+                .members
+                .iter()
+                .filter_map(|member| {
+                    let frame = speakers.get_mut(&member.session)?.next_frame()?;
+                    Some((member.session, frame))
+                })
+                .collect();
+            if frames.is_empty() {
+                continue;
+            }
+
+            for member in room.members.iter() {
+                let mut mixed = vec![0f32; MIX_FRAME_SAMPLES];
+                let mut heard = false;
+                for (speaker, frame) in frames.iter().filter(|(speaker, _)| **speaker != member.session) {
+                    let gain = member.gains.get(speaker).copied().unwrap_or(1.0);
+                    for (out, sample) in mixed.iter_mut().zip(frame) {
+                        *out += sample * gain;
+                    }
+                    heard = true;
+                }
+                if !heard {
+                    continue;
+                }
+                for sample in &mut mixed {
+                    *sample = sample.clamp(-1.0, 1.0);
+                }
+
+                let listener = match listeners.entry(member.session) {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    Entry::Vacant(entry) => match Listener::new() {
+                        Ok(listener) => entry.insert(listener),
+                        Err(err) => {
+                            tracing::error!("Could not create a mix encoder: {}", err);
+                            continue;
+                        }
+                    },
+                };
+                let len = match listener.encoder.encode_float(&mixed, &mut output) {
+                    Ok(len) => len,
+                    Err(err) => {
+                        tracing::error!("Failed to encode the mix for session {}: {}", member.session, err);
+                        continue;
+                    }
+                };
+                let packet = Packet::RoomAudio {
+                    speaker: MIXED_SPEAKER,
+                    audio: AudioPacket {
+                        sequence: listener.sequence,
+                        timestamp_ms,
+                        payload: output[..len].to_vec(),
+                    },
+                };
+                listener.sequence = listener.sequence.wrapping_add(1);
+                if let Some(datagram) = self.seal_for(member.session, &packet) {
+                    datagrams.push(datagram);
+                }
+            }
+        }
+        datagrams
+    }
+
+    /// Tells each room when its loudest member changes.
+    fn update_active_speakers(&self, rooms: &[RoomMembers]) {
+        let now = Instant::now();
+        let mut state = self.state.borrow_mut();
+        let State { speakers, active, .. } = &mut *state;
+
+        for room in rooms {
+            let loudest = room
+                .members
+                .iter()
+                .filter_map(|member| {
+                    let speaker = speakers.get(&member.session)?;
+                    speaker.is_speaking(now).then_some((member, speaker.level))
+                })
+                .max_by(|(_, a), (_, b)| a.total_cmp(b))
+                .map(|(member, _)| member);
+
+            let current = active.entry(room.room.clone()).or_insert(ActiveSpeaker {
+                session: None,
+                since: now,
+            });
+            let candidate = loudest.map(|member| member.session);
+            if candidate == current.session {
+                continue;
+            }
+            let current_speaking = current
+                .session
+                .and_then(|session| speakers.get(&session))
+                .is_some_and(|speaker| speaker.is_speaking(now));
+            if current_speaking && now.duration_since(current.since) < SPEAKER_HOLD {
+                continue;
+            }
+            current.session = candidate;
+            current.since = now;
+            self.rooms.announce(
+                &room.room,
+                ServerMessage::ActiveSpeaker {
+                    room: room.room.clone(),
+                    speaker: loudest.map(|member| member.name.clone()),
+                },
+            );
+        }
+    }
+
+    /// Drops the state of members who left their room and of rooms that closed.
+    fn forget_departed(&self, rooms: &[RoomMembers]) {
+        let in_rooms: HashSet<SessionId> = rooms
+            .iter()
+            .flat_map(|room| room.members.iter().map(|member| member.session))
+            .collect();
+        let mut state = self.state.borrow_mut();
+        state.speakers.retain(|session, _| in_rooms.contains(session));
+        state.listeners.retain(|session, _| in_rooms.contains(session));
+        state.active.retain(|room, _| rooms.iter().any(|members| &members.room == room));
+    }
+
+    /// Seals a packet for a session, once that session has a voice stream to send it to.
+    fn seal_for(&self, session: SessionId, packet: &Packet) -> Option<(SocketAddr, Vec<u8>)> {
+        let (endpoint, voice) = self.sessions.voice_target(session)?;
+        let data = voice.lock().unwrap_or_else(PoisonError::into_inner).sealer.seal(packet);
+        Some((endpoint, data))
+    }
+
+    async fn send(&self, endpoint: SocketAddr, data: Vec<u8>) {
+        let (result, _) = self.socket.send_to(data, endpoint).await;
+        match result {
+            Ok(_) => metrics().room_audio_packets_sent.inc(),
+            Err(err) => tracing::debug!("Could not send room audio to {}: {}", endpoint, err),
+        }
+    }
+}
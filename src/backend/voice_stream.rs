@@ -0,0 +1,293 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::sync::{Arc, PoisonError};
+use std::time::{Duration, Instant};
+use miette::IntoDiagnostic;
+use opus::{Channels, Decoder};
+use tokio::sync::mpsc;
+use super::audio::{AudioProcessor, AudioSource, AudioTrack, SAMPLE_RATE};
+use super::latency::LatencyStats;
+use super::limits::AddressLimits;
+use super::metrics::metrics;
+use super::runtime::UdpSocket;
+use super::session::{SessionRegistry, VoiceChannel};
+use super::stream_stats::StreamStats;
+use super::voice_rooms::VoiceRooms;
+use crate::admin::StreamSummary;
+use crate::config::RoomAudioMode;
+use crate::protocol::audio::{LatencyEcho, LatencyProbe, Packet, ReceiverReport};
+use crate::protocol::sealed::{sealed_session_id, OpenError};
+
+// Longest Opus packet is 120ms.
+const MAX_FRAME_SAMPLES: usize = SAMPLE_RATE as usize * 120 / 1000;
+/// A stream that sends nothing for this long is considered finished.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Latest counters of every live voice stream, published for the admin interface.
+pub type StreamDirectory = Arc<std::sync::Mutex<HashMap<SocketAddr, StreamSummary>>>;
+
+/// Settings every voice stream starts with.
+#[derive(Debug, Clone, Copy)]
+pub struct StreamSettings {
+    /// How often receiver reports are sent back.
+    pub report_interval: Duration,
+    /// Accept datagrams that are not sealed with a session key.
+    pub allow_plaintext: bool,
+    /// How room members hear each other.
+    pub room_mode: RoomAudioMode,
+    /// Only store audio from streams bound to a logged in user.
+    pub auth_required: bool,
+    pub limits: AddressLimits,
+}
+
+/// One datagram handed from the receive loop to the stream it belongs to.
+pub struct Datagram {
+    pub data: Vec<u8>,
+    /// When the receive loop got it, so latency probes account for queueing.
+    pub received_at: Instant,
+}
+
+/// Owns everything about one client's voice stream and processes its
+/// datagrams in arrival order, independently of every other stream.
+pub struct VoiceStream {
+    addr: SocketAddr,
+    socket: Rc<UdpSocket>,
+    // Saves what is still buffered when the stream is dropped
+    recording: AudioTrack,
+    sessions: SessionRegistry,
+    voice_rooms: VoiceRooms,
+    session_id: Option<u64>,
+    // User of the bound session, who owns the recordings
+    owner: Option<String>,
+    // Set once a sealed datagram authenticated, everything else is then dropped
+    voice: Option<Arc<std::sync::Mutex<VoiceChannel>>>,
+    settings: StreamSettings,
+    directory: StreamDirectory,
+    decoder: Decoder,
+    decoded: Vec<f32>,
+    stream_stats: StreamStats,
+    // Loss already added to the global counter
+    reported_lost: u64,
+    latency_stats: LatencyStats,
+}
+
+impl VoiceStream {
+    pub fn new(
+        addr: SocketAddr,
+        socket: Rc<UdpSocket>,
+        audio_processor: &AudioProcessor,
+        sessions: SessionRegistry,
+        voice_rooms: VoiceRooms,
+        directory: StreamDirectory,
+        settings: StreamSettings,
+    ) -> miette::Result<Self> {
+        Ok(Self {
+            addr,
+            socket,
+            recording: audio_processor.open(AudioSource::Voice(addr)),
+            sessions,
+            voice_rooms,
+            session_id: None,
+            owner: None,
+            voice: None,
+            settings,
+            directory,
+            decoder: Decoder::new(SAMPLE_RATE, Channels::Mono).into_diagnostic()?,
+            decoded: vec![0f32; MAX_FRAME_SAMPLES],
+            stream_stats: StreamStats::new(settings.report_interval),
+            reported_lost: 0,
+            latency_stats: LatencyStats::default(),
+        })
+    }
+
+    /// Runs until the router drops the sender or the client goes quiet, then
+    /// stores whatever audio is still buffered.
+    pub async fn run(mut self, mut receiver: mpsc::Receiver<Datagram>) {
+        tracing::info!("Voice stream from {} started", self.addr);
+
+        while let Ok(Some(datagram)) = tokio::time::timeout(IDLE_TIMEOUT, receiver.recv()).await {
+            if let Err(err) = self.process_packet(datagram).await {
+                tracing::error!("Failed to process packet from {}: {:?}", self.addr, err);
+            }
+        }
+
+        self.sessions.forget_udp_endpoint(self.addr);
+        self.directory
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&self.addr);
+        tracing::info!("Voice stream from {} ended", self.addr);
+    }
+
+    async fn process_packet(&mut self, datagram: Datagram) -> miette::Result<()> {
+        let packet = match self.open(&datagram.data) {
+            Some(Packet::Audio(packet)) => packet,
+            Some(Packet::LatencyProbe(probe)) => return self.echo_probe(probe, datagram.received_at).await,
+            // Sealed streams are bound by the key they use, a bind inside one is redundant
+            Some(Packet::Bind { session_id }) if self.voice.is_none() => {
+                self.bind(session_id);
+                return Ok(());
+            }
+            Some(Packet::Bind { .. }) => return Ok(()),
+            Some(other) => {
+                tracing::warn!("Unexpected packet from {}: {:?}", self.addr, other);
+                return Ok(());
+            }
+            None => return Ok(()),
+        };
+        if self.settings.auth_required && self.owner.is_none() {
+            metrics().udp_packets_unauthenticated.inc();
+            tracing::debug!("Dropped audio from {}, which is not bound to a logged in user", self.addr);
+            return Ok(());
+        }
+
+        self.stream_stats.record(packet.sequence, packet.timestamp_ms);
+        if self.stream_stats.report_due() {
+            let report = self.stream_stats.report();
+            self.publish();
+            self.send_report(report).await?;
+        }
+
+        let len = match self.decoder.decode_float(&packet.payload, &mut self.decoded, false) {
+            Ok(len) => len,
+            Err(err) => {
+                metrics().udp_decode_errors.inc();
+                return Err(err).into_diagnostic();
+            }
+        };
+        metrics().udp_packets_decoded.inc();
+        if let Some(session_id) = self.session_id {
+            self.voice_rooms.on_audio(session_id, &packet, &self.decoded[..len]).await;
+        }
+        self.recording.push_samples(self.owner.as_deref(), &self.decoded[..len])
+    }
+
+    /// Authenticates and decodes a datagram. Returns `None` for anything that
+    /// has to be dropped.
+    fn open(&mut self, data: &[u8]) -> Option<Packet> {
+        if let Some(session_id) = sealed_session_id(data) {
+            return self.open_sealed(session_id, data);
+        }
+        // Once a stream is sealed, plaintext claiming to come from it is forged
+        if !self.settings.allow_plaintext || self.voice.is_some() {
+            metrics().udp_packets_unauthenticated.inc();
+            tracing::debug!("Dropped unsealed packet of {} bytes from {}", data.len(), self.addr);
+            return None;
+        }
+        let packet = Packet::decode(data);
+        if packet.is_none() {
+            tracing::warn!("Malformed packet of {} bytes from {}", data.len(), self.addr);
+        }
+        packet
+    }
+
+    fn open_sealed(&mut self, session_id: u64, data: &[u8]) -> Option<Packet> {
+        if self.session_id.is_some_and(|bound| bound != session_id) {
+            metrics().udp_packets_unauthenticated.inc();
+            tracing::debug!("Dropped packet for session {} on a stream of another session", session_id);
+            return None;
+        }
+        let Some(voice) = self.voice.clone().or_else(|| self.sessions.voice_channel(session_id)) else {
+            metrics().udp_packets_unauthenticated.inc();
+            tracing::debug!("Dropped packet for unknown session {} from {}", session_id, self.addr);
+            return None;
+        };
+
+        let result = voice.lock().unwrap_or_else(PoisonError::into_inner).opener.open(data);
+        match result {
+            Ok(packet) => {
+                if self.voice.is_none() {
+                    self.voice = Some(voice);
+                    self.bind(session_id);
+                }
+                Some(packet)
+            }
+            Err(OpenError::Replayed(counter)) => {
+                metrics().udp_packets_replayed.inc();
+                tracing::debug!("Dropped replayed packet {} from {}", counter, self.addr);
+                None
+            }
+            Err(OpenError::Malformed) => {
+                tracing::warn!("Malformed sealed packet from {}", self.addr);
+                None
+            }
+            Err(OpenError::Unauthenticated) => {
+                metrics().udp_packets_unauthenticated.inc();
+                tracing::debug!("Dropped packet from {} that failed authentication", self.addr);
+                None
+            }
+        }
+    }
+
+    fn bind(&mut self, session_id: u64) {
+        match self.sessions.bind_udp_endpoint(session_id, self.addr) {
+            Ok(user) => {
+                tracing::info!("Voice stream from {} bound to session {}", self.addr, session_id);
+                tracing::Span::current().record("session", session_id);
+                self.session_id = Some(session_id);
+                self.owner = user;
+                self.publish();
+            }
+            Err(err) => tracing::warn!("Rejected bind from {}: {}", self.addr, err),
+        }
+    }
+
+    /// Seals a reply when the client seals its stream.
+    fn encode(&self, packet: Packet) -> Vec<u8> {
+        match &self.voice {
+            Some(voice) => voice.lock().unwrap_or_else(PoisonError::into_inner).sealer.seal(&packet),
+            None => packet.encode(),
+        }
+    }
+
+    fn publish(&mut self) {
+        // Late packets can lower the loss count, the global counter only grows
+        let lost = self.stream_stats.lost();
+        metrics().udp_packets_lost.add(lost.saturating_sub(self.reported_lost));
+        self.reported_lost = self.reported_lost.max(lost);
+
+        let summary = StreamSummary {
+            addr: self.addr,
+            session_id: self.session_id,
+            received: self.stream_stats.received(),
+            expected: self.stream_stats.expected(),
+            lost: self.stream_stats.lost(),
+            jitter_ms: self.stream_stats.jitter_ms(),
+        };
+        self.directory
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(self.addr, summary);
+    }
+
+    async fn echo_probe(&mut self, probe: LatencyProbe, received_at: Instant) -> miette::Result<()> {
+        let server_processing_us = received_at.elapsed().as_micros().min(u32::MAX as u128) as u32;
+        let echo = LatencyEcho {
+            probe_id: probe.probe_id,
+            sent_us: probe.sent_us,
+            server_processing_us,
+        };
+        let (result, _) = self.socket.send_to(self.encode(Packet::LatencyEcho(echo)), self.addr).await;
+        result.into_diagnostic()?;
+
+        self.latency_stats.record(self.addr, &probe, server_processing_us);
+        Ok(())
+    }
+
+    async fn send_report(&self, report: ReceiverReport) -> miette::Result<()> {
+        tracing::debug!(
+            "Receiver report for {}: loss {:.1}%, jitter {}ms, highest sequence {}",
+            self.addr,
+            report.loss_ratio() * 100.0,
+            report.jitter_ms,
+            report.highest_sequence
+        );
+        let (result, _) = self
+            .socket
+            .send_to(self.encode(Packet::ReceiverReport(report)), self.addr)
+            .await;
+        result.into_diagnostic()?;
+        Ok(())
+    }
+}
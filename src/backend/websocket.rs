@@ -0,0 +1,499 @@
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use miette::Diagnostic;
+use rustls::ServerConfig;
+use sha1::{Digest, Sha1};
+use thiserror::Error;
+use tracing::Instrument;
+use super::connection::{ConnectionContext, ConnectionHandler};
+use super::http::{
+    plain_response, read_request, respond, response, response_with_headers, HttpError, HttpListener, Request,
+};
+use super::limits::{ConnectionLimiter, ConnectionPermit};
+use super::metrics::metrics;
+use super::runtime::Tasks;
+use super::tls::Transport;
+use crate::config::WebSocketConfig;
+use crate::protocol::frame::{encode_message_json, split_frame, HEADER_LEN, KIND_MESSAGE, MAX_FRAME_LEN};
+
+/// Browsers open the chat connection here, every other path is plain HTTP.
+const WEBSOCKET_PATH: &str = "/ws";
+/// Appended to the client's key to prove the server speaks WebSocket, RFC 6455 section 4.2.2.
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// How long a client has to send its upgrade request.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// A binary message holds one native frame, header included.
+const MAX_MESSAGE_LEN: usize = MAX_FRAME_LEN + HEADER_LEN;
+const MAX_CONTROL_LEN: usize = 125;
+const CHAT_PAGE: &str = include_str!("resources/chat.html");
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+pub const CLOSE_NORMAL: u16 = 1000;
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+const CLOSE_TOO_LARGE: u16 = 1009;
+
+#[derive(Debug, Error, Diagnostic)]
+pub enum WebSocketError {
+    #[error("WebSocket protocol violation: {0}")]
+    #[diagnostic(code(websocket::protocol))]
+    Protocol(&'static str),
+
+    #[error("WebSocket message of {0} bytes is larger than the limit of {MAX_MESSAGE_LEN} bytes")]
+    #[diagnostic(code(websocket::too_large))]
+    TooLarge(u64),
+}
+
+impl WebSocketError {
+    fn close_code(&self) -> u16 {
+        match self {
+            WebSocketError::Protocol(_) => CLOSE_PROTOCOL_ERROR,
+            WebSocketError::TooLarge(_) => CLOSE_TOO_LARGE,
+        }
+    }
+}
+
+struct FrameHeader {
+    fin: bool,
+    opcode: u8,
+    mask: [u8; 4],
+    header_len: usize,
+    payload_len: usize,
+}
+
+/// Translates between WebSocket messages and the native chat framing, so the
+/// connection handler sees browsers the same way as the native client. A
+/// text message carries one JSON message, a binary message carries native
+/// frames unchanged, which is how uploads go.
+#[derive(Default)]
+pub struct WebSocketCodec {
+    buffer: Vec<u8>,
+    // Opcode and data of a message whose fragments are still arriving
+    fragments: Option<(u8, Vec<u8>)>,
+    close_sent: bool,
+}
+
+impl WebSocketCodec {
+    /// Appends the native frames carried by `data` to `frames`, and the
+    /// control frames to answer with to `replies`. A client breaking the
+    /// protocol is sent a close frame explaining why.
+    pub fn decode(&mut self, data: &[u8], frames: &mut Vec<u8>, replies: &mut Vec<u8>) -> Result<(), WebSocketError> {
+        self.buffer.extend_from_slice(data);
+        let result = self.decode_buffered(frames, replies);
+        if let Err(err) = &result {
+            replies.extend(self.close(err.close_code()));
+        }
+        result
+    }
+
+    fn decode_buffered(&mut self, frames: &mut Vec<u8>, replies: &mut Vec<u8>) -> Result<(), WebSocketError> {
+        while let Some(header) = parse_header(&self.buffer)? {
+            let end = header.header_len + header.payload_len;
+            if self.buffer.len() < end {
+                return Ok(());
+            }
+            let payload: Vec<u8> = self.buffer[header.header_len..end]
+                .iter()
+                .enumerate()
+                .map(|(i, byte)| byte ^ header.mask[i % 4])
+                .collect();
+            self.buffer.drain(..end);
+
+            if header.opcode & 0x08 != 0 && (!header.fin || payload.len() > MAX_CONTROL_LEN) {
+                return Err(WebSocketError::Protocol("control frames must be short and unfragmented"));
+            }
+            match header.opcode {
+                OPCODE_PING => replies.extend(encode_frame(OPCODE_PONG, &payload)),
+                OPCODE_PONG => {}
+                // The client closes the connection once it has our answer
+                OPCODE_CLOSE => replies.extend(self.close(CLOSE_NORMAL)),
+                OPCODE_TEXT | OPCODE_BINARY => {
+                    if self.fragments.is_some() {
+                        return Err(WebSocketError::Protocol("new message before the previous one ended"));
+                    }
+                    if header.fin {
+                        finish_message(header.opcode, &payload, frames);
+                    } else {
+                        self.fragments = Some((header.opcode, payload));
+                    }
+                }
+                OPCODE_CONTINUATION => {
+                    let Some((opcode, mut data)) = self.fragments.take() else {
+                        return Err(WebSocketError::Protocol("continuation without a message"));
+                    };
+                    data.extend_from_slice(&payload);
+                    if data.len() > MAX_MESSAGE_LEN {
+                        return Err(WebSocketError::TooLarge(data.len() as u64));
+                    }
+                    if header.fin {
+                        finish_message(opcode, &data, frames);
+                    } else {
+                        self.fragments = Some((opcode, data));
+                    }
+                }
+                _ => return Err(WebSocketError::Protocol("unknown opcode")),
+            }
+        }
+        Ok(())
+    }
+
+    /// Wraps native frames for the browser, messages as text and anything
+    /// else as binary.
+    pub fn encode(&self, mut data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len() + 16);
+        while let Some((kind, payload, rest)) = split_frame(data) {
+            if kind == KIND_MESSAGE {
+                out.extend(encode_frame(OPCODE_TEXT, payload));
+            } else {
+                out.extend(encode_frame(OPCODE_BINARY, &data[..data.len() - rest.len()]));
+            }
+            data = rest;
+        }
+        out
+    }
+
+    /// The close frame to send, empty when one was already sent.
+    pub fn close(&mut self, code: u16) -> Vec<u8> {
+        if std::mem::replace(&mut self.close_sent, true) {
+            return Vec::new();
+        }
+        encode_frame(OPCODE_CLOSE, &code.to_be_bytes())
+    }
+}
+
+fn finish_message(opcode: u8, data: &[u8], frames: &mut Vec<u8>) {
+    if opcode == OPCODE_TEXT {
+        frames.extend(encode_message_json(data));
+    } else {
+        frames.extend_from_slice(data);
+    }
+}
+
+/// Reads a client frame header, `None` until all of it has arrived.
+fn parse_header(data: &[u8]) -> Result<Option<FrameHeader>, WebSocketError> {
+    let (first, second) = match data {
+        [first, second, ..] => (*first, *second),
+        _ => return Ok(None),
+    };
+    if first & 0x70 != 0 {
+        return Err(WebSocketError::Protocol("reserved bits are set"));
+    }
+    if second & 0x80 == 0 {
+        return Err(WebSocketError::Protocol("client frames must be masked"));
+    }
+    let (payload_len, mask_at) = match second & 0x7f {
+        126 => match data.get(2..4) {
+            Some(len) => (u16::from_be_bytes([len[0], len[1]]) as u64, 4),
+            None => return Ok(None),
+        },
+        127 => match data.get(2..10) {
+            Some(len) => (u64::from_be_bytes(len.try_into().expect("slice of 8 bytes")), 10),
+            None => return Ok(None),
+        },
+        len => (len as u64, 2),
+    };
+    if payload_len > MAX_MESSAGE_LEN as u64 {
+        return Err(WebSocketError::TooLarge(payload_len));
+    }
+    let Some(mask) = data.get(mask_at..mask_at + 4) else {
+        return Ok(None);
+    };
+    Ok(Some(FrameHeader {
+        fin: first & 0x80 != 0,
+        opcode: first & 0x0f,
+        mask: [mask[0], mask[1], mask[2], mask[3]],
+        header_len: mask_at + 4,
+        payload_len: payload_len as usize,
+    }))
+}
+
+/// Frames `payload` as the server sends it, whole and unmasked.
+fn encode_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | opcode);
+    match payload.len() {
+        len if len < 126 => frame.push(len as u8),
+        len if len <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// What an HTTP request on the WebSocket port gets.
+enum Route {
+    Upgrade { accept: String },
+    Respond(Vec<u8>),
+}
+
+fn route(request: &Request, serve_page: bool, allowed_origins: &[String]) -> Route {
+    if request.method != "GET" {
+        return Route::Respond(plain_response("405 Method Not Allowed", "Only GET is supported\n"));
+    }
+    match request.path.as_str() {
+        WEBSOCKET_PATH => {
+            let Some(key) = request.header("sec-websocket-key") else {
+                return Route::Respond(plain_response("400 Bad Request", "Expected a WebSocket upgrade\n"));
+            };
+            if !request.header_has("upgrade", "websocket") || !request.header_has("connection", "upgrade") {
+                return Route::Respond(plain_response("400 Bad Request", "Expected a WebSocket upgrade\n"));
+            }
+            if !origin_allowed(request, allowed_origins) {
+                tracing::warn!("Refused a WebSocket upgrade from origin {:?}", request.header("origin"));
+                return Route::Respond(plain_response("403 Forbidden", "Chat connections from this page are not allowed\n"));
+            }
+            if request.header("sec-websocket-version") != Some("13") {
+                return Route::Respond(response_with_headers(
+                    "426 Upgrade Required",
+                    &[("Sec-WebSocket-Version", "13")],
+                    b"",
+                ));
+            }
+            let mut hasher = Sha1::new();
+            hasher.update(key.as_bytes());
+            hasher.update(HANDSHAKE_GUID.as_bytes());
+            Route::Upgrade {
+                accept: BASE64.encode(hasher.finalize()),
+            }
+        }
+        "/" | "/index.html" if serve_page => {
+            Route::Respond(response("200 OK", "text/html; charset=utf-8", CHAT_PAGE.as_bytes()))
+        }
+        _ => Route::Respond(plain_response("404 Not Found", "Not found\n")),
+    }
+}
+
+/// Whether the page that opened the connection may chat. Browsers always
+/// send the origin of the page, so another site cannot ride on the
+/// credentials of a user who visits it. Clients without an origin are not
+/// browsers and are let through.
+fn origin_allowed(request: &Request, allowed_origins: &[String]) -> bool {
+    let Some(origin) = request.header("origin") else {
+        return true;
+    };
+    let same_origin = origin
+        .split_once("://")
+        .zip(request.header("host"))
+        .is_some_and(|((_, host), request_host)| host.eq_ignore_ascii_case(request_host));
+    same_origin
+        || allowed_origins
+            .iter()
+            .any(|allowed| allowed.trim_end_matches('/').eq_ignore_ascii_case(origin))
+}
+
+/// Accepts browser chat connections. After the WebSocket handshake each one
+/// is served by a [`ConnectionHandler`] like a native connection, with the
+/// same sessions, rooms, authentication and limits. Optionally serves a
+/// minimal chat page on `/`. Browsers may only connect from the server's own
+/// pages and the configured origins.
+pub struct WebSocketServer {
+    listener: HttpListener,
+    serve_page: bool,
+    allowed_origins: Rc<[String]>,
+}
+
+impl WebSocketServer {
+    pub fn bind(addr: SocketAddr, tls: Option<Arc<ServerConfig>>, config: &WebSocketConfig) -> miette::Result<Self> {
+        let listener = HttpListener::bind(addr, tls, "WebSocket")?;
+        let scheme = if listener.is_tls() { "s" } else { "" };
+        tracing::info!("WebSocket chat listening on ws{}://{}{}", scheme, addr, WEBSOCKET_PATH);
+        if config.serve_page {
+            tracing::info!("Chat page available on http{}://{}/", scheme, addr);
+        }
+        Ok(Self {
+            listener,
+            serve_page: config.serve_page,
+            allowed_origins: config.allowed_origins.clone().into(),
+        })
+    }
+
+    pub async fn run(self, context: ConnectionContext, limiter: ConnectionLimiter, tasks: Tasks) {
+        let shutdown = context.shutdown.clone();
+        let serve_page = self.serve_page;
+        let allowed_origins = self.allowed_origins;
+        self.listener
+            .run(limiter, tasks, shutdown, |transport, peer, permit| {
+                // The id and user are recorded once the client is authenticated
+                let span = tracing::info_span!(
+                    "session",
+                    id = tracing::field::Empty,
+                    %peer,
+                    user = tracing::field::Empty
+                );
+                let allowed_origins = Rc::clone(&allowed_origins);
+                handle_connection(transport, peer, serve_page, allowed_origins, context.clone(), permit).instrument(span)
+            })
+            .await;
+    }
+}
+
+async fn handle_connection(
+    mut transport: Transport,
+    peer: SocketAddr,
+    serve_page: bool,
+    allowed_origins: Rc<[String]>,
+    context: ConnectionContext,
+    // Counts the connection against its address until it ends
+    _permit: ConnectionPermit,
+) {
+    // Clients wait for the handshake before sending frames, so nothing follows the request
+    let request = match tokio::time::timeout(HANDSHAKE_TIMEOUT, read_request(&transport, 0)).await {
+        Ok(Ok(Some(request))) => request,
+        Ok(Ok(None)) => return,
+        Ok(Err(err)) => {
+            tracing::debug!("Invalid HTTP request from {}: {:?}", peer, err);
+            if let Some(err) = err.downcast_ref::<HttpError>() {
+                respond(&transport, plain_response(err.status(), &format!("{}\n", err))).await;
+            }
+            return;
+        }
+        Err(_) => {
+            tracing::debug!("No HTTP request from {} in time", peer);
+            return;
+        }
+    };
+
+    match route(&request, serve_page, &allowed_origins) {
+        Route::Upgrade { accept } => {
+            let response = format!(
+                "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+                accept
+            );
+            if let Err(err) = transport.send(response.into_bytes()).await {
+                tracing::debug!("Could not complete the WebSocket handshake with {}: {:?}", peer, err);
+                return;
+            }
+            transport.upgrade_to_websocket();
+            metrics().websocket_connections_total.inc();
+            let mut handler = ConnectionHandler::new(transport, peer, context);
+            if let Err(err) = handler.process().await {
+                tracing::error!("WebSocket connection from {} failed: {:?}", peer, err);
+            }
+        }
+        Route::Respond(response) => {
+            tracing::debug!("{} {} from {}", request.method, request.path, peer);
+            respond(&transport, response).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::frame::{encode_client_frame, encode_message, ClientFrame, FrameDecoder};
+    use crate::protocol::message::ClientMessage;
+
+    const MASK: [u8; 4] = [0x37, 0xfa, 0x21, 0x3d];
+
+    /// A frame as a browser sends it, masked.
+    fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = encode_frame(opcode, payload);
+        if !fin {
+            frame[0] &= 0x7f;
+        }
+        frame[1] |= 0x80;
+        let header_len = frame.len() - payload.len();
+        frame.splice(header_len..header_len, MASK);
+        for (i, byte) in frame[header_len + 4..].iter_mut().enumerate() {
+            *byte ^= MASK[i % 4];
+        }
+        frame
+    }
+
+    fn decode(codec: &mut WebSocketCodec, data: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let (mut frames, mut replies) = (Vec::new(), Vec::new());
+        codec.decode(data, &mut frames, &mut replies).unwrap();
+        (frames, replies)
+    }
+
+    #[test]
+    fn text_messages_become_native_frames() {
+        let json = br#"{"type":"chat","text":"hi"}"#;
+        let wire = client_frame(true, OPCODE_TEXT, json);
+        let mut codec = WebSocketCodec::default();
+        let mut frames = Vec::new();
+        for byte in &wire {
+            frames.extend(decode(&mut codec, &[*byte]).0);
+        }
+        assert_eq!(frames, encode_message_json(json));
+
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&frames);
+        let message: ClientMessage = decoder.next_message().unwrap().unwrap();
+        assert_eq!(message, ClientMessage::Chat { text: "hi".to_string() });
+    }
+
+    #[test]
+    fn fragments_are_joined_around_control_frames() {
+        let native = encode_message(&ClientMessage::ListRooms);
+        let (head, tail) = native.split_at(3);
+        let mut wire = client_frame(false, OPCODE_BINARY, head);
+        wire.extend(client_frame(true, OPCODE_PING, b"are you there"));
+        wire.extend(client_frame(true, OPCODE_CONTINUATION, tail));
+
+        let (frames, replies) = decode(&mut WebSocketCodec::default(), &wire);
+        assert_eq!(frames, native);
+        assert_eq!(replies, encode_frame(OPCODE_PONG, b"are you there"));
+    }
+
+    #[test]
+    fn close_is_answered_once() {
+        let mut codec = WebSocketCodec::default();
+        let close = client_frame(true, OPCODE_CLOSE, &CLOSE_NORMAL.to_be_bytes());
+        let (_, replies) = decode(&mut codec, &close);
+        assert_eq!(replies, encode_frame(OPCODE_CLOSE, &CLOSE_NORMAL.to_be_bytes()));
+        assert!(decode(&mut codec, &close).1.is_empty());
+        assert!(codec.close(CLOSE_NORMAL).is_empty());
+    }
+
+    #[test]
+    fn violations_close_with_a_reason() {
+        let cases = [
+            (encode_frame(OPCODE_TEXT, b"unmasked"), CLOSE_PROTOCOL_ERROR),
+            (client_frame(true, OPCODE_CONTINUATION, b"orphan"), CLOSE_PROTOCOL_ERROR),
+            (client_frame(false, OPCODE_PING, b""), CLOSE_PROTOCOL_ERROR),
+            (client_frame(true, 0x3, b""), CLOSE_PROTOCOL_ERROR),
+            // Refused from the header, before the payload arrives
+            ([0x82, 0xff].into_iter().chain((MAX_MESSAGE_LEN as u64 + 1).to_be_bytes()).collect(), CLOSE_TOO_LARGE),
+        ];
+        for (wire, code) in cases {
+            let mut codec = WebSocketCodec::default();
+            let (mut frames, mut replies) = (Vec::new(), Vec::new());
+            assert!(codec.decode(&wire, &mut frames, &mut replies).is_err());
+            assert!(frames.is_empty());
+            assert_eq!(replies, encode_frame(OPCODE_CLOSE, &code.to_be_bytes()));
+        }
+    }
+
+    #[test]
+    fn native_frames_go_out_as_text_or_binary() {
+        let message = encode_message(&ClientMessage::ListRooms);
+        let chunk = encode_client_frame(&ClientFrame::UploadChunk {
+            upload_id: 1,
+            samples: vec![0.0; 50],
+        });
+        let mut native = message.clone();
+        native.extend_from_slice(&chunk);
+
+        let mut expected = encode_frame(OPCODE_TEXT, &message[HEADER_LEN..]);
+        expected.extend(encode_frame(OPCODE_BINARY, &chunk));
+        assert_eq!(WebSocketCodec::default().encode(&native), expected);
+        // Payloads past 125 bytes use the 16 bit length
+        assert_eq!(&expected[expected.len() - chunk.len() - 4..][..4], [0x82, 126, 0, 209]);
+    }
+}
@@ -2,14 +2,19 @@ mod ui;
 
 use gtk::{gdk, Application, CssProvider};
 use gtk::{gio, prelude::*, style_context_add_provider_for_display};
-use tracing_subscriber;
+use talk_to_me::{logging, protocol, tls};
 
 const APP_ID: &'static str = "com.geeksesi.talk-to-me";
 
 fn main() -> glib::ExitCode {
-    tracing_subscriber::fmt()
-        .with_max_level(tracing::Level::DEBUG)
-        .init();
+    // Held until exit, dropping it stops the log file writer
+    let _logging = match logging::init(&logging::LogSettings::from_env("info", "client")) {
+        Ok(logging) => Some(logging),
+        Err(err) => {
+            eprintln!("Logging disabled: {:?}", err);
+            None
+        }
+    };
 
     // Initialize GTK first
     gtk::init().expect("Failed to initialize GTK.");
@@ -0,0 +1,982 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use clap::{Parser, Subcommand, ValueEnum};
+use miette::{Diagnostic, NamedSource, SourceSpan};
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::admin::AdminCommand;
+use crate::backend::{AddressLimits, SessionLimits, UserCommand};
+use crate::logging::{self, LogFormat, LogRotation, LogSettings};
+use crate::protocol::frame::MAX_FRAME_LEN;
+use crate::MAX_DATAGRAM_SIZE;
+
+/// Used when `--config` is not given. Unlike an explicit path, it may be missing.
+const DEFAULT_CONFIG_PATH: &str = "talk-to-me.toml";
+
+/// Server settings, layered as defaults, then the TOML file, then
+/// `TALK_TO_ME_*` environment variables, then command-line flags.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub storage: StorageConfig,
+    pub tls: TlsConfig,
+    pub auth: AuthConfig,
+    pub backend: BackendConfig,
+    pub audio: AudioConfig,
+    pub limits: LimitsConfig,
+    pub log: LogConfig,
+    pub admin: AdminConfig,
+    pub metrics: MetricsConfig,
+    pub websocket: WebSocketConfig,
+    pub api: ApiConfig,
+    pub tools: ToolsConfig,
+    pub prompts: PromptsConfig,
+    pub context: ContextConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind_address: IpAddr,
+    pub tcp_port: u16,
+    pub udp_port: u16,
+    /// How long shutdown waits for connections to finish before exiting anyway.
+    pub shutdown_grace_secs: u64,
+    /// Which I/O driver the sockets run on.
+    pub runtime: RuntimeKind,
+}
+
+/// The I/O driver of the server's sockets.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum RuntimeKind {
+    /// io_uring when the kernel allows it, epoll otherwise.
+    #[default]
+    Auto,
+    /// io_uring only, startup fails without it.
+    #[value(name = "io_uring")]
+    IoUring,
+    /// tokio's epoll driver, for kernels and containers without io_uring.
+    Epoll,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub recordings_dir: PathBuf,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// Serve chat over TLS. Clients must then connect with TLS as well.
+    pub enabled: bool,
+    /// PEM certificate chain, leaf first.
+    pub cert_path: PathBuf,
+    /// PEM private key of the leaf certificate.
+    pub key_path: PathBuf,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Require every chat connection to log in with a user's token.
+    pub enabled: bool,
+    /// Users and their hashed tokens, written by `server user`.
+    pub users_file: PathBuf,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BackendConfig {
+    pub kind: BackendKind,
+}
+
+/// Which assistant answers chat messages.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum BackendKind {
+    /// Replies with a fixed markdown sample quoting the message.
+    #[default]
+    Sample,
+    /// Replies with the message itself.
+    Echo,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AudioConfig {
+    /// Seconds of audio buffered per stream before it is written to disk.
+    pub chunk_secs: f32,
+    /// How often receiver reports are sent back to voice clients.
+    pub report_interval_ms: u64,
+    /// Accept voice datagrams that are not sealed with the session key, from
+    /// clients that predate encryption. Anyone on the path can then inject audio.
+    /// Also gives remote clients voice without TLS, whose keys then cross the network in cleartext.
+    pub allow_plaintext: bool,
+    /// How voice reaches the other members of a room.
+    pub room_mode: RoomAudioMode,
+}
+
+/// How the server shares a room member's voice with the rest of the room.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum RoomAudioMode {
+    /// Passes every speaker's packets on unchanged, listeners mix them.
+    #[default]
+    Forward,
+    /// Decodes every speaker and sends each listener one stream mixed from
+    /// everyone else, at the gain the listener chose for each speaker.
+    Mix,
+}
+
+/// Quotas that keep one client from starving the others.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// Chat and control messages one session may send per minute. Upload
+    /// chunks only count towards the byte rate.
+    pub messages_per_minute: u64,
+    /// Bytes one session may send per second. The server stops reading from
+    /// a session that goes faster, so it slows down instead of losing data.
+    pub tcp_bytes_per_second: u64,
+    /// Largest single message or upload chunk.
+    pub max_message_bytes: usize,
+    /// Chat connections open at once from one address.
+    pub connections_per_ip: usize,
+    /// Voice streams, one per source port, open at once from one address.
+    pub streams_per_ip: usize,
+    pub udp_packets_per_second: u64,
+    /// At least the largest datagram, which has to fit in the bucket at once.
+    pub udp_bytes_per_second: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// `RUST_LOG` style directives. A plain level such as `debug` still works.
+    #[serde(alias = "level")]
+    pub filter: String,
+    pub format: LogFormat,
+    /// Write rotating log files here instead of to stdout.
+    pub directory: Option<PathBuf>,
+    pub rotation: LogRotation,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    pub enabled: bool,
+    /// Must be a loopback address, admin requests are not authenticated.
+    pub address: SocketAddr,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    pub enabled: bool,
+    /// Prometheus scrapes `/metrics` here. Must be a loopback address.
+    pub address: SocketAddr,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebSocketConfig {
+    /// Accept chat from browsers over WebSocket, on `server.bind_address`.
+    /// Uses TLS when `tls.enabled` is set, like the native chat port.
+    pub enabled: bool,
+    pub port: u16,
+    /// Serve a minimal chat page on `/` of the WebSocket port.
+    pub serve_page: bool,
+    /// Pages other than the server's own that may open chat connections,
+    /// as `scheme://host[:port]`.
+    pub allowed_origins: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ApiConfig {
+    /// Serve the HTTP API on `server.bind_address`, with TLS when `tls.enabled`
+    /// is set. Requests need a user token when `auth.enabled` is set.
+    pub enabled: bool,
+    pub port: u16,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ToolsConfig {
+    /// Let the assistant call tools while it answers.
+    pub enabled: bool,
+    /// How long one tool call may run before it is given up on.
+    pub timeout_ms: u64,
+    /// Notes the `search_notes` tool searches, it is left out when unset.
+    pub notes_dir: Option<PathBuf>,
+    /// The only directory the `read_file` tool reads from, it is left out when unset.
+    pub files_dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PromptsConfig {
+    /// Directory of system prompt templates, one `.md` or `.txt` file each,
+    /// named after the file.
+    pub dir: Option<PathBuf>,
+    /// Template new conversations start with, none when unset.
+    pub default: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ContextConfig {
+    /// Estimated tokens of conversation the backend is given per answer,
+    /// the system prompt and the summary included.
+    pub max_tokens: usize,
+    /// Estimated tokens the summary of older turns may take.
+    pub summary_max_tokens: usize,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind_address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            tcp_port: 3000,
+            udp_port: 3001,
+            shutdown_grace_secs: 5,
+            runtime: RuntimeKind::Auto,
+        }
+    }
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            recordings_dir: PathBuf::from("recordings"),
+        }
+    }
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            cert_path: PathBuf::from("certs/cert.pem"),
+            key_path: PathBuf::from("certs/key.pem"),
+        }
+    }
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            users_file: PathBuf::from("users.toml"),
+        }
+    }
+}
+
+impl Default for AudioConfig {
+    fn default() -> Self {
+        Self {
+            chunk_secs: 2.0,
+            report_interval_ms: 1000,
+            allow_plaintext: false,
+            room_mode: RoomAudioMode::default(),
+        }
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            messages_per_minute: 120,
+            tcp_bytes_per_second: 4 * 1024 * 1024,
+            max_message_bytes: MAX_FRAME_LEN,
+            connections_per_ip: 8,
+            streams_per_ip: 4,
+            // 10ms Opus frames are 100 per second, probes and binds come on top
+            udp_packets_per_second: 250,
+            udp_bytes_per_second: 128 * 1024,
+        }
+    }
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            address: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 3002)),
+        }
+    }
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            address: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 9464)),
+        }
+    }
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            port: 3003,
+            serve_page: true,
+            allowed_origins: Vec::new(),
+        }
+    }
+}
+
+impl Default for ApiConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            port: 3004,
+        }
+    }
+}
+
+impl Default for ToolsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            timeout_ms: 5000,
+            notes_dir: None,
+            files_dir: None,
+        }
+    }
+}
+
+impl Default for ContextConfig {
+    fn default() -> Self {
+        Self {
+            max_tokens: 4096,
+            summary_max_tokens: 512,
+        }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            filter: "info".to_string(),
+            format: LogFormat::default(),
+            directory: None,
+            rotation: LogRotation::default(),
+        }
+    }
+}
+
+/// Command-line flags. Every flag can also be set through the environment
+/// variable next to it, which the flag overrides.
+#[derive(Debug, Parser)]
+#[command(name = "server", version, about = "Talk to me chat and voice server")]
+pub struct Cli {
+    /// TOML configuration file
+    #[arg(short, long, env = "TALK_TO_ME_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Address to listen on for both TCP and UDP
+    #[arg(long, env = "TALK_TO_ME_BIND_ADDRESS")]
+    pub bind_address: Option<IpAddr>,
+    /// Port for chat and uploads
+    #[arg(long, env = "TALK_TO_ME_TCP_PORT")]
+    pub tcp_port: Option<u16>,
+    /// Port for live voice
+    #[arg(long, env = "TALK_TO_ME_UDP_PORT")]
+    pub udp_port: Option<u16>,
+    /// Seconds to wait for connections to finish on shutdown
+    #[arg(long, env = "TALK_TO_ME_SHUTDOWN_GRACE_SECS")]
+    pub shutdown_grace_secs: Option<u64>,
+    /// I/O driver of the sockets
+    #[arg(long, env = "TALK_TO_ME_RUNTIME")]
+    pub runtime: Option<RuntimeKind>,
+    /// Directory where received audio is stored
+    #[arg(long, env = "TALK_TO_ME_RECORDINGS_DIR")]
+    pub recordings_dir: Option<PathBuf>,
+    /// Whether chat connections use TLS
+    #[arg(long, env = "TALK_TO_ME_TLS_ENABLED")]
+    pub tls_enabled: Option<bool>,
+    /// PEM certificate chain for TLS
+    #[arg(long, env = "TALK_TO_ME_TLS_CERT")]
+    pub tls_cert: Option<PathBuf>,
+    /// PEM private key for TLS
+    #[arg(long, env = "TALK_TO_ME_TLS_KEY")]
+    pub tls_key: Option<PathBuf>,
+    /// Whether chat connections must authenticate with a user token
+    #[arg(long, env = "TALK_TO_ME_AUTH_ENABLED")]
+    pub auth_enabled: Option<bool>,
+    /// File holding users and their hashed tokens
+    #[arg(long, env = "TALK_TO_ME_USERS_FILE")]
+    pub users_file: Option<PathBuf>,
+    /// Assistant that answers chat messages
+    #[arg(long, env = "TALK_TO_ME_BACKEND")]
+    pub backend: Option<BackendKind>,
+    /// Seconds of audio buffered before it is written to disk
+    #[arg(long, env = "TALK_TO_ME_AUDIO_CHUNK_SECS")]
+    pub audio_chunk_secs: Option<f32>,
+    /// Milliseconds between receiver reports to voice clients
+    #[arg(long, env = "TALK_TO_ME_REPORT_INTERVAL_MS")]
+    pub report_interval_ms: Option<u64>,
+    /// Accept unencrypted voice datagrams
+    #[arg(long, env = "TALK_TO_ME_AUDIO_ALLOW_PLAINTEXT")]
+    pub audio_allow_plaintext: Option<bool>,
+    /// How voice is shared within a room
+    #[arg(long, env = "TALK_TO_ME_ROOM_AUDIO_MODE")]
+    pub room_audio_mode: Option<RoomAudioMode>,
+    /// Chat messages one session may send per minute
+    #[arg(long, env = "TALK_TO_ME_LIMIT_MESSAGES_PER_MINUTE")]
+    pub limit_messages_per_minute: Option<u64>,
+    /// Bytes one session may send per second over TCP
+    #[arg(long, env = "TALK_TO_ME_LIMIT_TCP_BYTES_PER_SECOND")]
+    pub limit_tcp_bytes_per_second: Option<u64>,
+    /// Largest message or upload chunk in bytes
+    #[arg(long, env = "TALK_TO_ME_LIMIT_MAX_MESSAGE_BYTES")]
+    pub limit_max_message_bytes: Option<usize>,
+    /// Chat connections open at once from one address
+    #[arg(long, env = "TALK_TO_ME_LIMIT_CONNECTIONS_PER_IP")]
+    pub limit_connections_per_ip: Option<usize>,
+    /// Voice streams open at once from one address
+    #[arg(long, env = "TALK_TO_ME_LIMIT_STREAMS_PER_IP")]
+    pub limit_streams_per_ip: Option<usize>,
+    /// Voice datagrams per second from one address
+    #[arg(long, env = "TALK_TO_ME_LIMIT_UDP_PACKETS_PER_SECOND")]
+    pub limit_udp_packets_per_second: Option<u64>,
+    /// Voice bytes per second from one address
+    #[arg(long, env = "TALK_TO_ME_LIMIT_UDP_BYTES_PER_SECOND")]
+    pub limit_udp_bytes_per_second: Option<u64>,
+    /// Log filter directives, e.g. `info,server::backend=debug`
+    #[arg(long, alias = "log-level", env = "RUST_LOG")]
+    pub log_filter: Option<String>,
+    /// How log lines are written
+    #[arg(long, env = "TALK_TO_ME_LOG_FORMAT")]
+    pub log_format: Option<LogFormat>,
+    /// Directory for rotating log files instead of stdout
+    #[arg(long, env = "TALK_TO_ME_LOG_DIR")]
+    pub log_dir: Option<PathBuf>,
+    /// How often a new log file is started
+    #[arg(long, env = "TALK_TO_ME_LOG_ROTATION")]
+    pub log_rotation: Option<LogRotation>,
+    /// Whether to serve the admin interface
+    #[arg(long, env = "TALK_TO_ME_ADMIN_ENABLED")]
+    pub admin_enabled: Option<bool>,
+    /// Loopback address of the admin interface
+    #[arg(long, env = "TALK_TO_ME_ADMIN_ADDRESS")]
+    pub admin_address: Option<SocketAddr>,
+    /// Whether to serve Prometheus metrics
+    #[arg(long, env = "TALK_TO_ME_METRICS_ENABLED")]
+    pub metrics_enabled: Option<bool>,
+    /// Loopback address of the metrics endpoint
+    #[arg(long, env = "TALK_TO_ME_METRICS_ADDRESS")]
+    pub metrics_address: Option<SocketAddr>,
+    /// Whether to accept chat from browsers over WebSocket
+    #[arg(long, env = "TALK_TO_ME_WEBSOCKET_ENABLED")]
+    pub websocket_enabled: Option<bool>,
+    /// Port for WebSocket chat and the chat page
+    #[arg(long, env = "TALK_TO_ME_WEBSOCKET_PORT")]
+    pub websocket_port: Option<u16>,
+    /// Whether to serve the chat page on the WebSocket port
+    #[arg(long, env = "TALK_TO_ME_WEBSOCKET_SERVE_PAGE")]
+    pub websocket_serve_page: Option<bool>,
+    /// Comma separated origins of other pages that may open chat connections
+    #[arg(long, env = "TALK_TO_ME_WEBSOCKET_ALLOWED_ORIGINS", value_delimiter = ',')]
+    pub websocket_allowed_origins: Option<Vec<String>>,
+    /// Whether to serve the HTTP API
+    #[arg(long, env = "TALK_TO_ME_API_ENABLED")]
+    pub api_enabled: Option<bool>,
+    /// Port for the HTTP API
+    #[arg(long, env = "TALK_TO_ME_API_PORT")]
+    pub api_port: Option<u16>,
+    /// Whether the assistant may call tools
+    #[arg(long, env = "TALK_TO_ME_TOOLS_ENABLED")]
+    pub tools_enabled: Option<bool>,
+    /// Milliseconds one tool call may run
+    #[arg(long, env = "TALK_TO_ME_TOOLS_TIMEOUT_MS")]
+    pub tools_timeout_ms: Option<u64>,
+    /// Directory of notes the assistant can search
+    #[arg(long, env = "TALK_TO_ME_TOOLS_NOTES_DIR")]
+    pub tools_notes_dir: Option<PathBuf>,
+    /// Directory the assistant can read files from
+    #[arg(long, env = "TALK_TO_ME_TOOLS_FILES_DIR")]
+    pub tools_files_dir: Option<PathBuf>,
+    /// Directory of system prompt templates
+    #[arg(long, env = "TALK_TO_ME_PROMPTS_DIR")]
+    pub prompts_dir: Option<PathBuf>,
+    /// Prompt template new conversations start with
+    #[arg(long, env = "TALK_TO_ME_PROMPTS_DEFAULT")]
+    pub prompts_default: Option<String>,
+    /// Estimated tokens of conversation the backend is given
+    #[arg(long, env = "TALK_TO_ME_CONTEXT_MAX_TOKENS")]
+    pub context_max_tokens: Option<usize>,
+    /// Estimated tokens the summary of older turns may take
+    #[arg(long, env = "TALK_TO_ME_CONTEXT_SUMMARY_MAX_TOKENS")]
+    pub context_summary_max_tokens: Option<usize>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Inspect or manage a running server through its admin interface
+    #[command(subcommand)]
+    Admin(AdminCommand),
+    /// Manage the users allowed to connect when authentication is enabled
+    #[command(subcommand)]
+    User(UserCommand),
+    /// Write a self-signed certificate and key to the configured TLS paths, for development
+    GenerateCert {
+        /// Host names and IP addresses the certificate is valid for
+        #[arg(long = "name", default_values = ["localhost", "127.0.0.1", "::1"])]
+        names: Vec<String>,
+        /// Replace existing files
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+#[derive(Debug, Error, Diagnostic)]
+pub enum ConfigError {
+    #[error("Could not read config file {path}")]
+    #[diagnostic(code(config::read))]
+    Read {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("Invalid config file: {message}")]
+    #[diagnostic(code(config::parse))]
+    Parse {
+        message: String,
+        #[source_code]
+        src: NamedSource<String>,
+        #[label("here")]
+        span: Option<SourceSpan>,
+    },
+
+    #[error("Invalid value for {field}: {message}")]
+    #[diagnostic(code(config::invalid))]
+    Invalid {
+        field: &'static str,
+        message: String,
+        #[help]
+        help: Option<String>,
+    },
+}
+
+impl Config {
+    pub fn load(cli: Cli) -> Result<Self, ConfigError> {
+        let mut config = match &cli.config {
+            Some(path) => Self::from_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => Self::from_file(Path::new(DEFAULT_CONFIG_PATH))?,
+            None => Self::default(),
+        };
+        config.apply_cli(cli);
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let contents = std::fs::read_to_string(path).map_err(|source| ConfigError::Read {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        toml::from_str(&contents).map_err(|err| ConfigError::Parse {
+            message: err.message().to_string(),
+            span: err.span().map(SourceSpan::from),
+            src: NamedSource::new(path.display().to_string(), contents),
+        })
+    }
+
+    fn apply_cli(&mut self, cli: Cli) {
+        if let Some(bind_address) = cli.bind_address {
+            self.server.bind_address = bind_address;
+        }
+        if let Some(tcp_port) = cli.tcp_port {
+            self.server.tcp_port = tcp_port;
+        }
+        if let Some(udp_port) = cli.udp_port {
+            self.server.udp_port = udp_port;
+        }
+        if let Some(shutdown_grace_secs) = cli.shutdown_grace_secs {
+            self.server.shutdown_grace_secs = shutdown_grace_secs;
+        }
+        if let Some(runtime) = cli.runtime {
+            self.server.runtime = runtime;
+        }
+        if let Some(recordings_dir) = cli.recordings_dir {
+            self.storage.recordings_dir = recordings_dir;
+        }
+        if let Some(enabled) = cli.tls_enabled {
+            self.tls.enabled = enabled;
+        }
+        if let Some(cert_path) = cli.tls_cert {
+            self.tls.cert_path = cert_path;
+        }
+        if let Some(key_path) = cli.tls_key {
+            self.tls.key_path = key_path;
+        }
+        if let Some(enabled) = cli.auth_enabled {
+            self.auth.enabled = enabled;
+        }
+        if let Some(users_file) = cli.users_file {
+            self.auth.users_file = users_file;
+        }
+        if let Some(backend) = cli.backend {
+            self.backend.kind = backend;
+        }
+        if let Some(chunk_secs) = cli.audio_chunk_secs {
+            self.audio.chunk_secs = chunk_secs;
+        }
+        if let Some(report_interval_ms) = cli.report_interval_ms {
+            self.audio.report_interval_ms = report_interval_ms;
+        }
+        if let Some(allow_plaintext) = cli.audio_allow_plaintext {
+            self.audio.allow_plaintext = allow_plaintext;
+        }
+        if let Some(room_mode) = cli.room_audio_mode {
+            self.audio.room_mode = room_mode;
+        }
+        if let Some(messages_per_minute) = cli.limit_messages_per_minute {
+            self.limits.messages_per_minute = messages_per_minute;
+        }
+        if let Some(bytes_per_second) = cli.limit_tcp_bytes_per_second {
+            self.limits.tcp_bytes_per_second = bytes_per_second;
+        }
+        if let Some(max_message_bytes) = cli.limit_max_message_bytes {
+            self.limits.max_message_bytes = max_message_bytes;
+        }
+        if let Some(connections_per_ip) = cli.limit_connections_per_ip {
+            self.limits.connections_per_ip = connections_per_ip;
+        }
+        if let Some(streams_per_ip) = cli.limit_streams_per_ip {
+            self.limits.streams_per_ip = streams_per_ip;
+        }
+        if let Some(packets_per_second) = cli.limit_udp_packets_per_second {
+            self.limits.udp_packets_per_second = packets_per_second;
+        }
+        if let Some(bytes_per_second) = cli.limit_udp_bytes_per_second {
+            self.limits.udp_bytes_per_second = bytes_per_second;
+        }
+        if let Some(filter) = cli.log_filter {
+            self.log.filter = filter;
+        }
+        if let Some(format) = cli.log_format {
+            self.log.format = format;
+        }
+        if let Some(directory) = cli.log_dir {
+            self.log.directory = Some(directory);
+        }
+        if let Some(rotation) = cli.log_rotation {
+            self.log.rotation = rotation;
+        }
+        if let Some(enabled) = cli.admin_enabled {
+            self.admin.enabled = enabled;
+        }
+        if let Some(address) = cli.admin_address {
+            self.admin.address = address;
+        }
+        if let Some(enabled) = cli.metrics_enabled {
+            self.metrics.enabled = enabled;
+        }
+        if let Some(address) = cli.metrics_address {
+            self.metrics.address = address;
+        }
+        if let Some(enabled) = cli.websocket_enabled {
+            self.websocket.enabled = enabled;
+        }
+        if let Some(port) = cli.websocket_port {
+            self.websocket.port = port;
+        }
+        if let Some(serve_page) = cli.websocket_serve_page {
+            self.websocket.serve_page = serve_page;
+        }
+        if let Some(allowed_origins) = cli.websocket_allowed_origins {
+            self.websocket.allowed_origins = allowed_origins;
+        }
+        if let Some(enabled) = cli.api_enabled {
+            self.api.enabled = enabled;
+        }
+        if let Some(port) = cli.api_port {
+            self.api.port = port;
+        }
+        if let Some(enabled) = cli.tools_enabled {
+            self.tools.enabled = enabled;
+        }
+        if let Some(timeout_ms) = cli.tools_timeout_ms {
+            self.tools.timeout_ms = timeout_ms;
+        }
+        if let Some(notes_dir) = cli.tools_notes_dir {
+            self.tools.notes_dir = Some(notes_dir);
+        }
+        if let Some(files_dir) = cli.tools_files_dir {
+            self.tools.files_dir = Some(files_dir);
+        }
+        if let Some(dir) = cli.prompts_dir {
+            self.prompts.dir = Some(dir);
+        }
+        if let Some(default) = cli.prompts_default {
+            self.prompts.default = Some(default);
+        }
+        if let Some(max_tokens) = cli.context_max_tokens {
+            self.context.max_tokens = max_tokens;
+        }
+        if let Some(summary_max_tokens) = cli.context_summary_max_tokens {
+            self.context.summary_max_tokens = summary_max_tokens;
+        }
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.server.tcp_port == 0 {
+            return Err(invalid("server.tcp_port", "must not be 0", None));
+        }
+        if self.server.udp_port == 0 {
+            return Err(invalid("server.udp_port", "must not be 0", None));
+        }
+        let mut tcp_ports = vec![("server.tcp_port", self.server.tcp_port)];
+        if self.websocket.enabled {
+            tcp_ports.push(("websocket.port", self.websocket.port));
+        }
+        if self.api.enabled {
+            tcp_ports.push(("api.port", self.api.port));
+        }
+        for (i, (field, port)) in tcp_ports.iter().enumerate() {
+            if *port == 0 {
+                return Err(invalid(field, "must not be 0", None));
+            }
+            if let Some((other, _)) = tcp_ports[..i].iter().find(|(_, other_port)| other_port == port) {
+                return Err(invalid(
+                    field,
+                    format!("{} is already used by {}", port, other),
+                    Some("Every listener needs its own port"),
+                ));
+            }
+        }
+        for origin in &self.websocket.allowed_origins {
+            let host = origin.split_once("://").map(|(_, host)| host);
+            if !host.is_some_and(|host| !host.is_empty() && !host.contains('/')) {
+                return Err(invalid(
+                    "websocket.allowed_origins",
+                    format!("{:?} is not an origin", origin),
+                    Some("Write origins as scheme://host or scheme://host:port, without a path"),
+                ));
+            }
+        }
+        if self.storage.recordings_dir.as_os_str().is_empty() {
+            return Err(invalid("storage.recordings_dir", "must not be empty", None));
+        }
+        if self.tls.enabled && self.tls.cert_path.as_os_str().is_empty() {
+            return Err(invalid("tls.cert_path", "must not be empty when TLS is enabled", None));
+        }
+        if self.tls.enabled && self.tls.key_path.as_os_str().is_empty() {
+            return Err(invalid("tls.key_path", "must not be empty when TLS is enabled", None));
+        }
+        if self.auth.enabled && self.auth.users_file.as_os_str().is_empty() {
+            return Err(invalid(
+                "auth.users_file",
+                "must not be empty when authentication is enabled",
+                None,
+            ));
+        }
+        if !(self.audio.chunk_secs.is_finite() && self.audio.chunk_secs > 0.0) {
+            return Err(invalid(
+                "audio.chunk_secs",
+                format!("{} is not a positive number of seconds", self.audio.chunk_secs),
+                None,
+            ));
+        }
+        if self.audio.report_interval_ms < 100 {
+            return Err(invalid(
+                "audio.report_interval_ms",
+                format!("{}ms is too short", self.audio.report_interval_ms),
+                Some("Clients adapt their bitrate on each report, use at least 100ms"),
+            ));
+        }
+        let limits = [
+            ("limits.messages_per_minute", self.limits.messages_per_minute as usize),
+            ("limits.tcp_bytes_per_second", self.limits.tcp_bytes_per_second as usize),
+            ("limits.max_message_bytes", self.limits.max_message_bytes),
+            ("limits.connections_per_ip", self.limits.connections_per_ip),
+            ("limits.streams_per_ip", self.limits.streams_per_ip),
+            ("limits.udp_packets_per_second", self.limits.udp_packets_per_second as usize),
+            ("limits.udp_bytes_per_second", self.limits.udp_bytes_per_second as usize),
+        ];
+        for (field, value) in limits {
+            if value == 0 {
+                return Err(invalid(field, "must not be 0", Some("A limit of 0 would refuse every client")));
+            }
+        }
+        // A datagram larger than the whole bucket could never be let through
+        if self.limits.udp_bytes_per_second < MAX_DATAGRAM_SIZE as u64 {
+            return Err(invalid(
+                "limits.udp_bytes_per_second",
+                format!("{} is below the largest datagram of {} bytes", self.limits.udp_bytes_per_second, MAX_DATAGRAM_SIZE),
+                Some("Voice needs a few kilobytes per second, the default is 131072"),
+            ));
+        }
+        if self.limits.max_message_bytes > MAX_FRAME_LEN {
+            return Err(invalid(
+                "limits.max_message_bytes",
+                format!("{} is above the protocol limit of {}", self.limits.max_message_bytes, MAX_FRAME_LEN),
+                None,
+            ));
+        }
+        if self.tools.timeout_ms == 0 {
+            return Err(invalid("tools.timeout_ms", "must not be 0", Some("A timeout of 0 would fail every tool call")));
+        }
+        if self.prompts.default.is_some() && self.prompts.dir.is_none() {
+            return Err(invalid(
+                "prompts.default",
+                "is set without prompts.dir",
+                Some("Templates are read from prompts.dir, set it as well"),
+            ));
+        }
+        if self.context.summary_max_tokens == 0 || self.context.summary_max_tokens * 2 > self.context.max_tokens {
+            return Err(invalid(
+                "context.summary_max_tokens",
+                format!("must be between 1 and half of context.max_tokens ({})", self.context.max_tokens),
+                Some("The rest of the budget is needed for the latest turns"),
+            ));
+        }
+        require_loopback("admin.address", self.admin.address)?;
+        require_loopback("metrics.address", self.metrics.address)?;
+        if let Err(err) = logging::parse_filter(&self.log.filter) {
+            return Err(invalid(
+                "log.filter",
+                err,
+                Some("Use directives like `info` or `info,server::backend=debug`"),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn tcp_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.server.bind_address, self.server.tcp_port)
+    }
+
+    pub fn udp_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.server.bind_address, self.server.udp_port)
+    }
+
+    pub fn websocket_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.server.bind_address, self.websocket.port)
+    }
+
+    pub fn api_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.server.bind_address, self.api.port)
+    }
+
+    pub fn shutdown_grace(&self) -> Duration {
+        Duration::from_secs(self.server.shutdown_grace_secs)
+    }
+
+    pub fn report_interval(&self) -> Duration {
+        Duration::from_millis(self.audio.report_interval_ms)
+    }
+
+    pub fn session_limits(&self) -> SessionLimits {
+        SessionLimits {
+            messages_per_minute: self.limits.messages_per_minute,
+            bytes_per_second: self.limits.tcp_bytes_per_second,
+            max_message_bytes: self.limits.max_message_bytes,
+        }
+    }
+
+    pub fn address_limits(&self) -> AddressLimits {
+        AddressLimits {
+            streams: self.limits.streams_per_ip,
+            packets_per_second: self.limits.udp_packets_per_second,
+            bytes_per_second: self.limits.udp_bytes_per_second,
+        }
+    }
+
+    pub fn log_settings(&self) -> LogSettings {
+        LogSettings {
+            filter: self.log.filter.clone(),
+            format: self.log.format,
+            directory: self.log.directory.clone(),
+            rotation: self.log.rotation,
+            file_prefix: "server",
+        }
+    }
+}
+
+fn require_loopback(field: &'static str, address: SocketAddr) -> Result<(), ConfigError> {
+    if address.ip().is_loopback() {
+        return Ok(());
+    }
+    Err(invalid(
+        field,
+        format!("{} is not a loopback address", address),
+        Some("This endpoint has no authentication, bind it to 127.0.0.1 or ::1"),
+    ))
+}
+
+fn invalid(field: &'static str, message: impl Into<String>, help: Option<&str>) -> ConfigError {
+    ConfigError::Invalid {
+        field,
+        message: message.into(),
+        help: help.map(str::to_string),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes `contents` to a config file of its own and returns the flags that load it.
+    fn with_file(name: &str, contents: &str) -> Vec<String> {
+        let path = std::env::temp_dir().join(format!("talk-to-me-{}-{}.toml", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        vec!["server".to_string(), "--config".to_string(), path.display().to_string()]
+    }
+
+    fn load(args: Vec<String>) -> Result<Config, ConfigError> {
+        Config::load(Cli::try_parse_from(args).unwrap())
+    }
+
+    #[test]
+    fn flags_override_the_file_and_the_file_overrides_defaults() {
+        let mut args = with_file("layers", "[server]\ntcp_port = 4100\nudp_port = 4101\n");
+        args.extend(["--tcp-port".to_string(), "4200".to_string()]);
+        let config = load(args).unwrap();
+        assert_eq!(config.server.tcp_port, 4200);
+        assert_eq!(config.server.udp_port, 4101);
+        assert_eq!(config.server.shutdown_grace_secs, ServerConfig::default().shutdown_grace_secs);
+    }
+
+    #[test]
+    fn environment_sits_between_the_file_and_flags() {
+        // No other test reads this variable
+        std::env::set_var("TALK_TO_ME_REPORT_INTERVAL_MS", "250");
+        let args = with_file("env", "[audio]\nreport_interval_ms = 500\n");
+        assert_eq!(load(args.clone()).unwrap().audio.report_interval_ms, 250);
+
+        let mut args = args;
+        args.extend(["--report-interval-ms".to_string(), "300".to_string()]);
+        assert_eq!(load(args).unwrap().audio.report_interval_ms, 300);
+        std::env::remove_var("TALK_TO_ME_REPORT_INTERVAL_MS");
+    }
+
+    #[test]
+    fn unknown_keys_point_at_the_file() {
+        let err = load(with_file("unknown", "[server]\ntcp_prot = 1\n")).unwrap_err();
+        assert!(matches!(err, ConfigError::Parse { span: Some(_), .. }), "{:?}", err);
+    }
+
+    #[test]
+    fn invalid_values_name_their_field() {
+        let mut args = with_file("ports", "[api]\nenabled = true\nport = 3000\n");
+        args.extend(["--tcp-port".to_string(), "3000".to_string()]);
+        let err = load(args).unwrap_err();
+        assert!(matches!(err, ConfigError::Invalid { field: "api.port", .. }), "{:?}", err);
+
+        let err = load(with_file("zero", "[server]\nudp_port = 0\n")).unwrap_err();
+        assert!(matches!(err, ConfigError::Invalid { field: "server.udp_port", .. }), "{:?}", err);
+    }
+}
@@ -0,0 +1,3 @@
+pub mod logging;
+pub mod protocol;
+pub mod tls;
@@ -0,0 +1,125 @@
+// Tracing setup shared by the server and the client binaries.
+
+use std::path::PathBuf;
+
+use clap::ValueEnum;
+use miette::IntoDiagnostic;
+use serde::Deserialize;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{reload, EnvFilter, Registry};
+
+/// Replaces the filter of the global subscriber while it is running.
+pub type FilterHandle = reload::Handle<EnvFilter, Registry>;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    /// Human readable lines
+    #[default]
+    Text,
+    /// One JSON object per line, for log shipping
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum LogRotation {
+    Hourly,
+    #[default]
+    Daily,
+    Never,
+}
+
+#[derive(Debug, Clone)]
+pub struct LogSettings {
+    /// `RUST_LOG` style directives, e.g. `info,server::backend=debug`.
+    pub filter: String,
+    pub format: LogFormat,
+    /// Write rotating files here instead of to stdout.
+    pub directory: Option<PathBuf>,
+    pub rotation: LogRotation,
+    /// File names start with this, followed by the date for rotated files.
+    pub file_prefix: &'static str,
+}
+
+impl LogSettings {
+    /// Settings from `RUST_LOG`, `TALK_TO_ME_LOG_FORMAT` and `TALK_TO_ME_LOG_DIR`,
+    /// for the client which has no configuration file.
+    pub fn from_env(default_filter: &str, file_prefix: &'static str) -> Self {
+        let format = std::env::var("TALK_TO_ME_LOG_FORMAT")
+            .ok()
+            .and_then(|format| LogFormat::from_str(&format, true).ok())
+            .unwrap_or_default();
+        Self {
+            filter: std::env::var("RUST_LOG").unwrap_or_else(|_| default_filter.to_string()),
+            format,
+            directory: std::env::var_os("TALK_TO_ME_LOG_DIR").map(PathBuf::from),
+            rotation: LogRotation::Daily,
+            file_prefix,
+        }
+    }
+}
+
+/// Keeps logging running. Dropping it flushes and stops the file writer, so
+/// it has to live until the program exits.
+pub struct Logging {
+    pub filter: FilterHandle,
+    _file_guard: Option<WorkerGuard>,
+}
+
+pub fn parse_filter(directives: &str) -> Result<EnvFilter, String> {
+    EnvFilter::try_new(directives).map_err(|err| err.to_string())
+}
+
+pub fn init(settings: &LogSettings) -> miette::Result<Logging> {
+    let filter = parse_filter(&settings.filter).map_err(|err| miette::miette!("Invalid log filter: {}", err))?;
+    let (filter, handle) = reload::Layer::new(filter);
+
+    let (writer, file_guard) = match &settings.directory {
+        Some(directory) => {
+            let rotation = match settings.rotation {
+                LogRotation::Hourly => Rotation::HOURLY,
+                LogRotation::Daily => Rotation::DAILY,
+                LogRotation::Never => Rotation::NEVER,
+            };
+            let appender = RollingFileAppender::builder()
+                .rotation(rotation)
+                .filename_prefix(settings.file_prefix)
+                .filename_suffix("log")
+                .build(directory)
+                .into_diagnostic()?;
+            let (writer, guard) = tracing_appender::non_blocking(appender);
+            (BoxMakeWriter::new(writer), Some(guard))
+        }
+        None => (BoxMakeWriter::new(std::io::stdout), None),
+    };
+
+    let output = match settings.format {
+        LogFormat::Text => tracing_subscriber::fmt::layer()
+            .with_writer(writer)
+            .with_ansi(settings.directory.is_none())
+            .with_target(true)
+            .boxed(),
+        // Span fields such as the session id end up on every line
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .with_writer(writer)
+            .boxed(),
+    };
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(output)
+        .try_init()
+        .into_diagnostic()?;
+
+    Ok(Logging {
+        filter: handle,
+        _file_guard: file_guard,
+    })
+}
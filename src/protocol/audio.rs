@@ -0,0 +1,279 @@
+// Wire format shared by the client and the server for the UDP voice channel.
+//
+// Every datagram starts with a one byte kind followed by a fixed little-endian
+// header for that kind:
+//
+//   Audio:          kind | sequence u32 | timestamp_ms u32 | opus payload
+//   ReceiverReport: kind | fraction_lost u8 | cumulative_lost u32 | highest_sequence u32 | jitter_ms u32
+//   LatencyProbe:   kind | probe_id u32 | sent_us u64 | capture_delay_us u32
+//                        | last_round_trip_us u32 | last_mouth_to_server_us u32
+//   LatencyEcho:    kind | probe_id u32 | sent_us u64 | server_processing_us u32
+//   Bind:           kind | session_id u64
+//   RoomAudio:      kind | speaker u64 | sequence u32 | timestamp_ms u32 | opus payload
+//
+// Kind 0x06 is a sealed datagram carrying one of these encrypted, see `sealed`.
+
+const KIND_AUDIO: u8 = 0x01;
+const KIND_RECEIVER_REPORT: u8 = 0x02;
+const KIND_LATENCY_PROBE: u8 = 0x03;
+const KIND_LATENCY_ECHO: u8 = 0x04;
+const KIND_BIND: u8 = 0x05;
+const KIND_ROOM_AUDIO: u8 = 0x07;
+
+pub const AUDIO_HEADER_LEN: usize = 1 + 4 + 4;
+const RECEIVER_REPORT_LEN: usize = 1 + 1 + 4 + 4 + 4;
+const LATENCY_PROBE_LEN: usize = 1 + 4 + 8 + 4 + 4 + 4;
+const LATENCY_ECHO_LEN: usize = 1 + 4 + 8 + 4;
+const BIND_LEN: usize = 1 + 8;
+pub const ROOM_AUDIO_HEADER_LEN: usize = 1 + 8 + 4 + 4;
+
+/// Speaker of room audio the server mixed from everyone else in the room.
+pub const MIXED_SPEAKER: u64 = 0;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AudioPacket {
+    pub sequence: u32,
+    /// Sender clock in milliseconds when the frame was captured.
+    pub timestamp_ms: u32,
+    pub payload: Vec<u8>,
+}
+
+/// Feedback sent by the receiver of a stream, modelled after the RTCP receiver report block.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReceiverReport {
+    /// Fraction of packets lost since the previous report, as a fixed point number (lost * 256 / expected).
+    pub fraction_lost: u8,
+    pub cumulative_lost: u32,
+    pub highest_sequence: u32,
+    /// Interarrival jitter in milliseconds.
+    pub jitter_ms: u32,
+}
+
+impl ReceiverReport {
+    pub fn loss_ratio(&self) -> f32 {
+        self.fraction_lost as f32 / 256.0
+    }
+}
+
+/// Timestamped marker injected into the voice stream by the latency diagnostic mode.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LatencyProbe {
+    pub probe_id: u32,
+    /// Sender clock in microseconds when the probe left the client.
+    pub sent_us: u64,
+    /// Time between the microphone capturing the audio and the probe being sent.
+    pub capture_delay_us: u32,
+    /// The client's latest measurements, so the server can report them too. Zero when unknown.
+    pub last_round_trip_us: u32,
+    pub last_mouth_to_server_us: u32,
+}
+
+/// The server's answer to a [`LatencyProbe`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LatencyEcho {
+    pub probe_id: u32,
+    /// Copied from the probe.
+    pub sent_us: u64,
+    /// Time the server spent between receiving the probe and sending the echo.
+    pub server_processing_us: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Packet {
+    Audio(AudioPacket),
+    ReceiverReport(ReceiverReport),
+    LatencyProbe(LatencyProbe),
+    LatencyEcho(LatencyEcho),
+    /// Sent by the client so the server knows which TCP session a voice stream belongs to.
+    Bind { session_id: u64 },
+    /// Audio of another room member, sent by the server. `speaker` is the
+    /// member's session, or [`MIXED_SPEAKER`] for the server's mix.
+    RoomAudio { speaker: u64, audio: AudioPacket },
+}
+
+impl Packet {
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            Packet::Audio(packet) => {
+                let mut buf = Vec::with_capacity(AUDIO_HEADER_LEN + packet.payload.len());
+                buf.push(KIND_AUDIO);
+                buf.extend_from_slice(&packet.sequence.to_le_bytes());
+                buf.extend_from_slice(&packet.timestamp_ms.to_le_bytes());
+                buf.extend_from_slice(&packet.payload);
+                buf
+            }
+            Packet::ReceiverReport(report) => {
+                let mut buf = Vec::with_capacity(RECEIVER_REPORT_LEN);
+                buf.push(KIND_RECEIVER_REPORT);
+                buf.push(report.fraction_lost);
+                buf.extend_from_slice(&report.cumulative_lost.to_le_bytes());
+                buf.extend_from_slice(&report.highest_sequence.to_le_bytes());
+                buf.extend_from_slice(&report.jitter_ms.to_le_bytes());
+                buf
+            }
+            Packet::LatencyProbe(probe) => {
+                let mut buf = Vec::with_capacity(LATENCY_PROBE_LEN);
+                buf.push(KIND_LATENCY_PROBE);
+                buf.extend_from_slice(&probe.probe_id.to_le_bytes());
+                buf.extend_from_slice(&probe.sent_us.to_le_bytes());
+                buf.extend_from_slice(&probe.capture_delay_us.to_le_bytes());
+                buf.extend_from_slice(&probe.last_round_trip_us.to_le_bytes());
+                buf.extend_from_slice(&probe.last_mouth_to_server_us.to_le_bytes());
+                buf
+            }
+            Packet::LatencyEcho(echo) => {
+                let mut buf = Vec::with_capacity(LATENCY_ECHO_LEN);
+                buf.push(KIND_LATENCY_ECHO);
+                buf.extend_from_slice(&echo.probe_id.to_le_bytes());
+                buf.extend_from_slice(&echo.sent_us.to_le_bytes());
+                buf.extend_from_slice(&echo.server_processing_us.to_le_bytes());
+                buf
+            }
+            Packet::Bind { session_id } => {
+                let mut buf = Vec::with_capacity(BIND_LEN);
+                buf.push(KIND_BIND);
+                buf.extend_from_slice(&session_id.to_le_bytes());
+                buf
+            }
+            Packet::RoomAudio { speaker, audio } => {
+                let mut buf = Vec::with_capacity(ROOM_AUDIO_HEADER_LEN + audio.payload.len());
+                buf.push(KIND_ROOM_AUDIO);
+                buf.extend_from_slice(&speaker.to_le_bytes());
+                buf.extend_from_slice(&audio.sequence.to_le_bytes());
+                buf.extend_from_slice(&audio.timestamp_ms.to_le_bytes());
+                buf.extend_from_slice(&audio.payload);
+                buf
+            }
+        }
+    }
+
+    pub fn decode(data: &[u8]) -> Option<Packet> {
+        match *data.first()? {
+            KIND_AUDIO if data.len() >= AUDIO_HEADER_LEN => Some(Packet::Audio(AudioPacket {
+                sequence: read_u32(data, 1),
+                timestamp_ms: read_u32(data, 5),
+                payload: data[AUDIO_HEADER_LEN..].to_vec(),
+            })),
+            KIND_RECEIVER_REPORT if data.len() >= RECEIVER_REPORT_LEN => {
+                Some(Packet::ReceiverReport(ReceiverReport {
+                    fraction_lost: data[1],
+                    cumulative_lost: read_u32(data, 2),
+                    highest_sequence: read_u32(data, 6),
+                    jitter_ms: read_u32(data, 10),
+                }))
+            }
+            KIND_LATENCY_PROBE if data.len() >= LATENCY_PROBE_LEN => {
+                Some(Packet::LatencyProbe(LatencyProbe {
+                    probe_id: read_u32(data, 1),
+                    sent_us: read_u64(data, 5),
+                    capture_delay_us: read_u32(data, 13),
+                    last_round_trip_us: read_u32(data, 17),
+                    last_mouth_to_server_us: read_u32(data, 21),
+                }))
+            }
+            KIND_LATENCY_ECHO if data.len() >= LATENCY_ECHO_LEN => {
+                Some(Packet::LatencyEcho(LatencyEcho {
+                    probe_id: read_u32(data, 1),
+                    sent_us: read_u64(data, 5),
+                    server_processing_us: read_u32(data, 13),
+                }))
+            }
+            KIND_BIND if data.len() >= BIND_LEN => Some(Packet::Bind {
+                session_id: read_u64(data, 1),
+            }),
+            KIND_ROOM_AUDIO if data.len() >= ROOM_AUDIO_HEADER_LEN => Some(Packet::RoomAudio {
+                speaker: read_u64(data, 1),
+                audio: AudioPacket {
+                    sequence: read_u32(data, 9),
+                    timestamp_ms: read_u32(data, 13),
+                    payload: data[ROOM_AUDIO_HEADER_LEN..].to_vec(),
+                },
+            }),
+            _ => None,
+        }
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&data[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&data[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn audio(payload: &[u8]) -> AudioPacket {
+        AudioPacket {
+            sequence: 0x0102_0304,
+            timestamp_ms: 0xA0B0_C0D0,
+            payload: payload.to_vec(),
+        }
+    }
+
+    #[test]
+    fn every_kind_round_trips() {
+        let packets = [
+            Packet::Audio(audio(&[1, 2, 3])),
+            Packet::Audio(audio(&[])),
+            Packet::ReceiverReport(ReceiverReport {
+                fraction_lost: 64,
+                cumulative_lost: 17,
+                highest_sequence: u32::MAX,
+                jitter_ms: 42,
+            }),
+            Packet::LatencyProbe(LatencyProbe {
+                probe_id: 7,
+                sent_us: u64::MAX - 1,
+                capture_delay_us: 1_500,
+                last_round_trip_us: 30_000,
+                last_mouth_to_server_us: 45_000,
+            }),
+            Packet::LatencyEcho(LatencyEcho {
+                probe_id: 7,
+                sent_us: 123_456_789,
+                server_processing_us: 80,
+            }),
+            Packet::Bind { session_id: 99 },
+            Packet::RoomAudio {
+                speaker: MIXED_SPEAKER,
+                audio: audio(&[9; 40]),
+            },
+        ];
+        for packet in packets {
+            assert_eq!(Packet::decode(&packet.encode()), Some(packet));
+        }
+    }
+
+    #[test]
+    fn headers_are_little_endian_at_fixed_offsets() {
+        let encoded = Packet::Audio(audio(&[0xFF])).encode();
+        assert_eq!(encoded, [KIND_AUDIO, 4, 3, 2, 1, 0xD0, 0xC0, 0xB0, 0xA0, 0xFF]);
+        assert_eq!(Packet::Bind { session_id: 1 }.encode().len(), BIND_LEN);
+    }
+
+    #[test]
+    fn truncated_and_unknown_datagrams_are_rejected() {
+        for packet in [
+            Packet::Audio(audio(&[])),
+            Packet::Bind { session_id: 5 },
+            Packet::RoomAudio {
+                speaker: 3,
+                audio: audio(&[]),
+            },
+        ] {
+            let encoded = packet.encode();
+            assert_eq!(Packet::decode(&encoded[..encoded.len() - 1]), None);
+        }
+        assert_eq!(Packet::decode(&[]), None);
+        assert_eq!(Packet::decode(&[0x06, 0, 0, 0, 0, 0, 0, 0, 0]), None);
+        assert_eq!(Packet::decode(&[0xEE; 32]), None);
+    }
+}
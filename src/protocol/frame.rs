@@ -0,0 +1,247 @@
+// Length prefixed framing for the TCP channel.
+//
+//   kind u8 | length u32 BE | payload
+//
+// A message frame carries one JSON encoded message. An upload chunk frame
+// carries `upload_id u32 LE` followed by little-endian f32 samples.
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use super::message::ClientMessage;
+
+pub const KIND_MESSAGE: u8 = 0x01;
+const KIND_UPLOAD_CHUNK: u8 = 0x02;
+pub const HEADER_LEN: usize = 1 + 4;
+pub const MAX_FRAME_LEN: usize = 1024 * 1024;
+
+#[derive(Debug)]
+pub enum FrameError {
+    TooLarge(usize),
+    UnknownKind(u8),
+    Malformed(String),
+}
+
+impl std::fmt::Display for FrameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FrameError::TooLarge(len) => write!(f, "frame of {} bytes exceeds the limit", len),
+            FrameError::UnknownKind(kind) => write!(f, "unknown frame kind {:#04x}", kind),
+            FrameError::Malformed(reason) => write!(f, "malformed frame: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+/// Everything the client can put on the wire.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClientFrame {
+    Message(ClientMessage),
+    UploadChunk { upload_id: u32, samples: Vec<f32> },
+}
+
+pub fn encode_message<T: Serialize>(message: &T) -> Vec<u8> {
+    let payload = serde_json::to_vec(message).expect("messages always serialize");
+    encode(KIND_MESSAGE, &payload)
+}
+
+pub fn encode_client_frame(frame: &ClientFrame) -> Vec<u8> {
+    match frame {
+        ClientFrame::Message(message) => encode_message(message),
+        ClientFrame::UploadChunk { upload_id, samples } => {
+            let mut payload = Vec::with_capacity(4 + samples.len() * 4);
+            payload.extend_from_slice(&upload_id.to_le_bytes());
+            for sample in samples {
+                payload.extend_from_slice(&sample.to_le_bytes());
+            }
+            encode(KIND_UPLOAD_CHUNK, &payload)
+        }
+    }
+}
+
+/// Frames a message that is already JSON encoded.
+pub fn encode_message_json(payload: &[u8]) -> Vec<u8> {
+    encode(KIND_MESSAGE, payload)
+}
+
+/// Splits the first complete frame off `data` into its kind, payload and the rest.
+pub fn split_frame(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let header = data.get(..HEADER_LEN)?;
+    let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+    let payload = data.get(HEADER_LEN..HEADER_LEN + len)?;
+    Some((header[0], payload, &data[HEADER_LEN + len..]))
+}
+
+fn encode(kind: u8, payload: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(HEADER_LEN + payload.len());
+    buf.push(kind);
+    buf.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    buf.extend_from_slice(payload);
+    buf
+}
+
+/// Accumulates bytes from a stream and yields complete frames.
+pub struct FrameDecoder {
+    buffer: Vec<u8>,
+    max_len: usize,
+}
+
+impl Default for FrameDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        Self::with_max_len(MAX_FRAME_LEN)
+    }
+
+    /// Rejects frames longer than `max_len`, which is capped at [`MAX_FRAME_LEN`].
+    pub fn with_max_len(max_len: usize) -> Self {
+        Self {
+            buffer: Vec::new(),
+            max_len: max_len.min(MAX_FRAME_LEN),
+        }
+    }
+
+    pub fn push(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    fn next_raw(&mut self) -> Result<Option<(u8, Vec<u8>)>, FrameError> {
+        if self.buffer.len() < HEADER_LEN {
+            return Ok(None);
+        }
+        let kind = self.buffer[0];
+        let mut len_bytes = [0u8; 4];
+        len_bytes.copy_from_slice(&self.buffer[1..HEADER_LEN]);
+        let len = u32::from_be_bytes(len_bytes) as usize;
+        if len > self.max_len {
+            return Err(FrameError::TooLarge(len));
+        }
+        if self.buffer.len() < HEADER_LEN + len {
+            return Ok(None);
+        }
+        let payload = self.buffer[HEADER_LEN..HEADER_LEN + len].to_vec();
+        self.buffer.drain(..HEADER_LEN + len);
+        Ok(Some((kind, payload)))
+    }
+
+    /// Decodes the next JSON message frame, for the direction that only carries messages.
+    pub fn next_message<T: DeserializeOwned>(&mut self) -> Result<Option<T>, FrameError> {
+        match self.next_raw()? {
+            Some((KIND_MESSAGE, payload)) => parse_message(&payload).map(Some),
+            Some((kind, _)) => Err(FrameError::UnknownKind(kind)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn next_client_frame(&mut self) -> Result<Option<ClientFrame>, FrameError> {
+        match self.next_raw()? {
+            Some((KIND_MESSAGE, payload)) => parse_message(&payload).map(|m| Some(ClientFrame::Message(m))),
+            Some((KIND_UPLOAD_CHUNK, payload)) => {
+                if payload.len() < 4 || (payload.len() - 4) % 4 != 0 {
+                    return Err(FrameError::Malformed("upload chunk is not whole samples".into()));
+                }
+                let mut id_bytes = [0u8; 4];
+                id_bytes.copy_from_slice(&payload[..4]);
+                let samples = payload[4..]
+                    .chunks_exact(4)
+                    .map(|chunk| f32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+                    .collect();
+                Ok(Some(ClientFrame::UploadChunk {
+                    upload_id: u32::from_le_bytes(id_bytes),
+                    samples,
+                }))
+            }
+            Some((kind, _)) => Err(FrameError::UnknownKind(kind)),
+            None => Ok(None),
+        }
+    }
+}
+
+fn parse_message<T: DeserializeOwned>(payload: &[u8]) -> Result<T, FrameError> {
+    serde_json::from_slice(payload).map_err(|err| FrameError::Malformed(err.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chat(text: &str) -> ClientFrame {
+        ClientFrame::Message(ClientMessage::Chat { text: text.to_string() })
+    }
+
+    #[test]
+    fn frames_survive_arbitrary_splits() {
+        let frames = [
+            chat("hello"),
+            ClientFrame::UploadChunk {
+                upload_id: 3,
+                samples: vec![0.0, -1.0, 0.5],
+            },
+            chat(""),
+        ];
+        let wire: Vec<u8> = frames.iter().flat_map(encode_client_frame).collect();
+
+        let mut decoder = FrameDecoder::new();
+        let mut decoded = Vec::new();
+        for byte in wire {
+            decoder.push(&[byte]);
+            while let Some(frame) = decoder.next_client_frame().unwrap() {
+                decoded.push(frame);
+            }
+        }
+        assert_eq!(decoded, frames);
+    }
+
+    #[test]
+    fn split_frame_returns_the_rest() {
+        let mut wire = encode_message_json(b"{}");
+        wire.extend_from_slice(&[KIND_MESSAGE, 0, 0]);
+        let (kind, payload, rest) = split_frame(&wire).unwrap();
+        assert_eq!((kind, payload, rest), (KIND_MESSAGE, &b"{}"[..], &[KIND_MESSAGE, 0, 0][..]));
+        assert_eq!(split_frame(rest), None);
+    }
+
+    #[test]
+    fn oversized_frames_are_refused_from_the_header() {
+        let mut decoder = FrameDecoder::with_max_len(8);
+        decoder.push(&[KIND_MESSAGE, 0, 0, 0, 9]);
+        assert!(matches!(decoder.next_client_frame(), Err(FrameError::TooLarge(9))));
+
+        // The limit cannot be raised past the protocol's
+        let mut decoder = FrameDecoder::with_max_len(usize::MAX);
+        decoder.push(&[KIND_MESSAGE]);
+        decoder.push(&(MAX_FRAME_LEN as u32 + 1).to_be_bytes());
+        assert!(matches!(decoder.next_client_frame(), Err(FrameError::TooLarge(_))));
+    }
+
+    #[test]
+    fn bad_frames_are_errors() {
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&encode(0x7F, b""));
+        assert!(matches!(decoder.next_client_frame(), Err(FrameError::UnknownKind(0x7F))));
+
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&encode(KIND_UPLOAD_CHUNK, &[1, 0, 0, 0, 0xAA]));
+        assert!(matches!(decoder.next_client_frame(), Err(FrameError::Malformed(_))));
+
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&encode(KIND_MESSAGE, b"not json"));
+        assert!(matches!(decoder.next_client_frame(), Err(FrameError::Malformed(_))));
+
+        // The server to client direction only carries messages
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&encode_client_frame(&ClientFrame::UploadChunk {
+            upload_id: 1,
+            samples: Vec::new(),
+        }));
+        assert!(matches!(
+            decoder.next_message::<ClientMessage>(),
+            Err(FrameError::UnknownKind(KIND_UPLOAD_CHUNK))
+        ));
+    }
+}
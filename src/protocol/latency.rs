@@ -0,0 +1,61 @@
+use std::collections::VecDeque;
+
+/// How many of the most recent measurements the percentiles are computed over.
+const WINDOW: usize = 256;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Percentiles {
+    pub p50_ms: f64,
+    pub p95_ms: f64,
+    pub p99_ms: f64,
+}
+
+impl std::fmt::Display for Percentiles {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "p50 {:.1}ms, p95 {:.1}ms, p99 {:.1}ms",
+            self.p50_ms, self.p95_ms, self.p99_ms
+        )
+    }
+}
+
+/// A sliding window of latency measurements in microseconds.
+#[derive(Debug, Default)]
+pub struct LatencyWindow {
+    samples: VecDeque<u32>,
+}
+
+impl LatencyWindow {
+    pub fn push(&mut self, microseconds: u32) {
+        if self.samples.len() == WINDOW {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(microseconds);
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    pub fn percentiles(&self) -> Percentiles {
+        let mut sorted: Vec<u32> = self.samples.iter().copied().collect();
+        sorted.sort_unstable();
+        let at = |p: f64| -> f64 {
+            if sorted.is_empty() {
+                return 0.0;
+            }
+            let index = ((sorted.len() - 1) as f64 * p).round() as usize;
+            sorted[index] as f64 / 1000.0
+        };
+        Percentiles {
+            p50_ms: at(0.50),
+            p95_ms: at(0.95),
+            p99_ms: at(0.99),
+        }
+    }
+}
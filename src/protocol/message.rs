@@ -0,0 +1,116 @@
+use serde::{Deserialize, Serialize};
+
+use super::sealed::VoiceKey;
+
+/// Control and chat messages sent by the client over the TCP channel.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    /// Must be the first message when the server requires authentication.
+    Authenticate { token: String },
+    /// Goes to the room the client is in, or to its private conversation
+    /// with the assistant when it is in none.
+    Chat { text: String },
+    /// Creates a room and joins it.
+    CreateRoom { room: String },
+    /// Joins a room, leaving the current one.
+    JoinRoom { room: String },
+    LeaveRoom,
+    ListRooms,
+    ListPrompts,
+    /// Starts a new private conversation with the named system prompt
+    /// template, or with none. Later chat outside rooms goes there.
+    NewConversation { template: Option<String> },
+    /// Switches the template of the current conversation, the room's when
+    /// the client is in one. `None` removes it.
+    SetPrompt { template: Option<String> },
+    /// How loud the client hears a room member in the server's mix, 1.0
+    /// being unchanged. Applies to every member shown with that name.
+    SetSpeakerGain { speaker: String, gain: f32 },
+    /// Announces an audio upload. The samples follow as upload chunk frames,
+    /// mono f32 at [`UPLOAD_SAMPLE_RATE`], and the upload ends with `UploadEnd`.
+    UploadStart { upload_id: u32, file_name: String },
+    UploadEnd { upload_id: u32 },
+}
+
+/// Messages sent by the server over the TCP channel.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    /// First message on every connection, sent once the client is
+    /// authenticated. The client seals its voice datagrams with `voice_key`,
+    /// which also ties the stream to this session. `user` is `None` when the
+    /// server does not require authentication.
+    Welcome {
+        session_id: u64,
+        /// `None` when the client cannot have voice, `voice_unavailable` says why.
+        voice_key: Option<VoiceKey>,
+        voice_unavailable: Option<String>,
+        user: Option<String>,
+        /// What other room members see as the sender of this client's messages.
+        display_name: String,
+    },
+    /// A message from another room member or the assistant. `room` is
+    /// `None` in the client's private conversation.
+    Chat {
+        sender: String,
+        text: String,
+        room: Option<String>,
+    },
+    /// A tool the assistant called while answering, sent before the answer
+    /// itself. `arguments` is the JSON the tool was called with, `output`
+    /// what it returned or why it failed.
+    ToolActivity {
+        tool: String,
+        arguments: String,
+        output: String,
+        ok: bool,
+        duration_ms: u64,
+        room: Option<String>,
+    },
+    /// The templates the server has. `default` is the one new conversations
+    /// start with.
+    PromptList {
+        prompts: Vec<PromptSummary>,
+        default: Option<String>,
+    },
+    /// A new private conversation was started for the client.
+    ConversationStarted { template: Option<String> },
+    /// The conversation's template changed, in `room` when it is `Some`.
+    PromptChanged {
+        template: Option<String>,
+        room: Option<String>,
+        changed_by: String,
+    },
+    /// The client is now in `room`, together with `members`.
+    RoomJoined { room: String, members: Vec<String> },
+    RoomLeft { room: String },
+    RoomList { rooms: Vec<RoomSummary> },
+    MemberJoined { room: String, name: String },
+    MemberLeft { room: String, name: String },
+    /// The room member heard the most right now, `None` once everyone is quiet.
+    ActiveSpeaker { room: String, speaker: Option<String> },
+    UploadComplete { upload_id: u32, duration_ms: u64 },
+    Error { message: String },
+    /// The server is stopping and will close the connection once in-flight
+    /// work is done.
+    ShuttingDown,
+    /// The server closed this connection on purpose.
+    Disconnected { reason: String },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RoomSummary {
+    pub name: String,
+    pub members: usize,
+}
+
+/// A system prompt template, `description` is the first line of its file.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PromptSummary {
+    pub name: String,
+    pub description: String,
+}
+
+/// Uploaded audio uses the same format as decoded live microphone input.
+pub const UPLOAD_SAMPLE_RATE: u32 = 48000;
@@ -0,0 +1,5 @@
+pub mod audio;
+pub mod frame;
+pub mod latency;
+pub mod message;
+pub mod sealed;
@@ -0,0 +1,293 @@
+// Authenticated encryption for the UDP voice channel.
+//
+// A sealed datagram carries any other packet encrypted with the key the
+// server hands out in the TCP welcome message:
+//
+//   Sealed: kind | session_id u64 | counter u64 | ciphertext | tag (16 bytes)
+//
+// The header is authenticated along with the ciphertext. Each direction
+// numbers its datagrams from zero and the nonce is the direction followed by
+// that counter, so client and server never reuse a nonce under the same key.
+// Receivers remember which recent counters they have seen to reject replays.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use chacha20poly1305::aead::{Aead, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use miette::Diagnostic;
+use thiserror::Error;
+
+use super::audio::Packet;
+
+pub const KIND_SEALED: u8 = 0x06;
+pub const SEALED_HEADER_LEN: usize = 1 + 8 + 8;
+pub const TAG_LEN: usize = 16;
+/// Space a sealed datagram needs on top of the packet it carries.
+pub const SEALED_OVERHEAD: usize = SEALED_HEADER_LEN + TAG_LEN;
+/// How many counters behind the newest one a datagram may still arrive.
+const REPLAY_WINDOW: u64 = 64;
+
+pub type VoiceKey = [u8; 32];
+
+pub fn generate_key() -> VoiceKey {
+    ChaCha20Poly1305::generate_key(&mut OsRng).into()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    ClientToServer,
+    ServerToClient,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error, Diagnostic)]
+pub enum OpenError {
+    #[error("not a sealed packet")]
+    #[diagnostic(code(sealed::malformed))]
+    Malformed,
+
+    #[error("packet failed authentication")]
+    #[diagnostic(code(sealed::unauthenticated))]
+    Unauthenticated,
+
+    #[error("packet counter {0} was already seen or is too old")]
+    #[diagnostic(code(sealed::replayed))]
+    Replayed(u64),
+}
+
+/// The session a sealed datagram claims to belong to, read before any key is known.
+pub fn sealed_session_id(data: &[u8]) -> Option<u64> {
+    if data.len() < SEALED_OVERHEAD || data[0] != KIND_SEALED {
+        return None;
+    }
+    Some(read_u64(data, 1))
+}
+
+/// Seals the packets one side of a session sends.
+pub struct Sealer {
+    cipher: ChaCha20Poly1305,
+    session_id: u64,
+    direction: Direction,
+    counter: AtomicU64,
+}
+
+impl Sealer {
+    pub fn new(key: &VoiceKey, session_id: u64, direction: Direction) -> Self {
+        Self {
+            cipher: ChaCha20Poly1305::new(key.into()),
+            session_id,
+            direction,
+            counter: AtomicU64::new(0),
+        }
+    }
+
+    pub fn seal(&self, packet: &Packet) -> Vec<u8> {
+        let counter = self.counter.fetch_add(1, Ordering::Relaxed);
+        let mut sealed = header(self.session_id, counter);
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                &nonce(self.direction, counter),
+                Payload {
+                    msg: &packet.encode(),
+                    aad: &sealed,
+                },
+            )
+            // Only fails for messages of gigabytes
+            .expect("voice packet too large to seal");
+        sealed.extend_from_slice(&ciphertext);
+        sealed
+    }
+}
+
+/// Opens the packets one side of a session receives.
+pub struct Opener {
+    cipher: ChaCha20Poly1305,
+    session_id: u64,
+    direction: Direction,
+    window: ReplayWindow,
+}
+
+impl Opener {
+    pub fn new(key: &VoiceKey, session_id: u64, direction: Direction) -> Self {
+        Self {
+            cipher: ChaCha20Poly1305::new(key.into()),
+            session_id,
+            direction,
+            window: ReplayWindow::default(),
+        }
+    }
+
+    pub fn open(&mut self, data: &[u8]) -> Result<Packet, OpenError> {
+        let session_id = sealed_session_id(data).ok_or(OpenError::Malformed)?;
+        if session_id != self.session_id {
+            return Err(OpenError::Unauthenticated);
+        }
+        let counter = read_u64(data, 9);
+        let plaintext = self
+            .cipher
+            .decrypt(
+                &nonce(self.direction, counter),
+                Payload {
+                    msg: &data[SEALED_HEADER_LEN..],
+                    aad: &data[..SEALED_HEADER_LEN],
+                },
+            )
+            .map_err(|_| OpenError::Unauthenticated)?;
+        // Checked after authentication, so forged counters neither count as
+        // replays nor move the window
+        if !self.window.is_fresh(counter) {
+            return Err(OpenError::Replayed(counter));
+        }
+        self.window.accept(counter);
+        Packet::decode(&plaintext).ok_or(OpenError::Malformed)
+    }
+}
+
+/// Sliding window over recently seen counters, as in IPsec and WireGuard.
+#[derive(Debug, Default)]
+struct ReplayWindow {
+    highest: Option<u64>,
+    // Bit n is set when counter `highest - n` was seen
+    seen: u64,
+}
+
+impl ReplayWindow {
+    fn is_fresh(&self, counter: u64) -> bool {
+        match self.highest {
+            None => true,
+            Some(highest) if counter > highest => true,
+            Some(highest) => {
+                let age = highest - counter;
+                age < REPLAY_WINDOW && self.seen & (1 << age) == 0
+            }
+        }
+    }
+
+    fn accept(&mut self, counter: u64) {
+        match self.highest {
+            Some(highest) if counter <= highest => self.seen |= 1 << (highest - counter),
+            Some(highest) => {
+                let shift = counter - highest;
+                self.seen = if shift >= REPLAY_WINDOW { 0 } else { self.seen << shift };
+                self.seen |= 1;
+                self.highest = Some(counter);
+            }
+            None => {
+                self.seen = 1;
+                self.highest = Some(counter);
+            }
+        }
+    }
+}
+
+fn header(session_id: u64, counter: u64) -> Vec<u8> {
+    let mut header = Vec::with_capacity(SEALED_HEADER_LEN);
+    header.push(KIND_SEALED);
+    header.extend_from_slice(&session_id.to_le_bytes());
+    header.extend_from_slice(&counter.to_le_bytes());
+    header
+}
+
+fn nonce(direction: Direction, counter: u64) -> Nonce {
+    let mut nonce = [0u8; 12];
+    nonce[0] = match direction {
+        Direction::ClientToServer => 0,
+        Direction::ServerToClient => 1,
+    };
+    nonce[4..].copy_from_slice(&counter.to_le_bytes());
+    nonce.into()
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&data[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SESSION: u64 = 42;
+
+    fn opener(key: &VoiceKey) -> Opener {
+        Opener::new(key, SESSION, Direction::ClientToServer)
+    }
+
+    fn sealer(key: &VoiceKey) -> Sealer {
+        Sealer::new(key, SESSION, Direction::ClientToServer)
+    }
+
+    fn bind() -> Packet {
+        Packet::Bind { session_id: SESSION }
+    }
+
+    #[test]
+    fn sealed_packets_open_once() {
+        let key = generate_key();
+        let sealed = sealer(&key).seal(&bind());
+        assert_eq!(sealed.len(), SEALED_OVERHEAD + bind().encode().len());
+        assert_eq!(sealed_session_id(&sealed), Some(SESSION));
+
+        let mut opener = opener(&key);
+        assert_eq!(opener.open(&sealed), Ok(bind()));
+        assert_eq!(opener.open(&sealed), Err(OpenError::Replayed(0)));
+    }
+
+    #[test]
+    fn tampering_and_the_wrong_direction_fail_authentication() {
+        let key = generate_key();
+        let sealed = Sealer::new(&key, SESSION, Direction::ServerToClient).seal(&bind());
+
+        // A datagram reflected back at its sender must not open
+        assert_eq!(opener(&key).open(&sealed), Err(OpenError::Unauthenticated));
+
+        let mut opener = Opener::new(&key, SESSION, Direction::ServerToClient);
+        for byte in [1, SEALED_HEADER_LEN, sealed.len() - 1] {
+            let mut tampered = sealed.clone();
+            tampered[byte] ^= 0x01;
+            assert_eq!(opener.open(&tampered), Err(OpenError::Unauthenticated));
+        }
+        assert_eq!(opener.open(&sealed[..SEALED_OVERHEAD - 1]), Err(OpenError::Malformed));
+        // The failures did not use up the real counter
+        assert_eq!(opener.open(&sealed), Ok(bind()));
+    }
+
+    #[test]
+    fn late_packets_open_within_the_window() {
+        let key = generate_key();
+        let sealer = sealer(&key);
+        let sealed: Vec<Vec<u8>> = (0..=REPLAY_WINDOW).map(|_| sealer.seal(&bind())).collect();
+        let newest = REPLAY_WINDOW as usize;
+
+        let mut reordered = opener(&key);
+        assert!(reordered.open(&sealed[newest - 1]).is_ok());
+        assert!(reordered.open(&sealed[0]).is_ok());
+        assert_eq!(reordered.open(&sealed[0]), Err(OpenError::Replayed(0)));
+
+        // Counter 0 falls out of the window once counter 64 is seen
+        let mut late = opener(&key);
+        assert!(late.open(&sealed[newest]).is_ok());
+        assert_eq!(late.open(&sealed[0]), Err(OpenError::Replayed(0)));
+        assert!(late.open(&sealed[1]).is_ok());
+    }
+
+    #[test]
+    fn window_slides_and_forgets() {
+        let mut window = ReplayWindow::default();
+        assert!(window.is_fresh(10));
+        window.accept(10);
+        assert!(!window.is_fresh(10));
+        assert!(window.is_fresh(9));
+
+        window.accept(10 + REPLAY_WINDOW);
+        // Everything at or before the old highest is now outside the window
+        assert!(!window.is_fresh(10));
+        assert!(window.is_fresh(11));
+        assert!(!window.is_fresh(10 + REPLAY_WINDOW));
+
+        window.accept(u64::MAX);
+        assert!(!window.is_fresh(u64::MAX));
+        assert!(window.is_fresh(u64::MAX - 1));
+    }
+}
@@ -1,24 +1,64 @@
+mod admin;
 mod backend;
+mod config;
 
+use clap::Parser;
+use config::{Cli, Command, Config};
 use miette::IntoDiagnostic;
 use r3bl_terminal_async::port_availability;
+use std::future::Future;
 use std::net::SocketAddr;
-use tokio::task::AbortHandle;
-use tokio_uring::net::TcpListener;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::time::Instant;
 use tokio_util::sync::CancellationToken;
-use backend::{ConnectionHandler, UdpHandler};
+use backend::runtime::{self, BufResult, Tasks, TcpListener, TcpStream, UdpSocket};
+use backend::{
+    generate_self_signed, load_server_config, metrics, run_user_command, AdminServer, AdminState, ApiServer, Assistant,
+    AudioProcessor, ConnectionContext, ConnectionHandler, ConnectionLimiter, ConnectionPermit, ContextBuilder,
+    ConversationStore, MetricsServer, PromptLibrary, RoomRegistry, SessionRegistry, StreamDirectory, StreamSettings,
+    ToolRegistry, Transport, UdpHandler, UserStore, WebSocketServer,
+};
+use protocol::frame::encode_message;
+use protocol::message::ServerMessage;
+use logging::FilterHandle;
 use std::sync::Arc;
-use tokio::sync::Mutex;
-use tracing_subscriber::fmt::format::FmtSpan;
+use tracing::Instrument;
+use talk_to_me::{logging, protocol, tls};
 
-async fn process_socket_connection(stream: tokio_uring::net::TcpStream) -> miette::Result<()> {
-    let mut handler = ConnectionHandler::new(stream);
-    handler.process().await
+// Clients keep datagrams below 1200 bytes, anything up to the Ethernet MTU fits
+pub const MAX_DATAGRAM_SIZE: usize = 1500;
+
+async fn process_socket_connection(
+    transport: Transport,
+    peer: SocketAddr,
+    context: ConnectionContext,
+    // Counts the connection against its address until it ends
+    _permit: ConnectionPermit,
+) {
+    let mut handler = ConnectionHandler::new(transport, peer, context);
+    if let Err(err) = handler.process().await {
+        tracing::error!("Connection from {} failed: {:?}", peer, err);
+    }
 }
 
-async fn start_server(cancellation_token: CancellationToken) -> miette::Result<()> {
+/// Tells a plaintext client over the connection limit why it is turned away.
+/// A TLS client would need a handshake first, the very work being refused.
+async fn refuse_connection(stream: TcpStream, reason: String) {
+    let message = encode_message(&ServerMessage::Disconnected { reason });
+    let (result, _) = stream.write_all(message).await;
+    if let Err(err) = result {
+        tracing::debug!("Could not tell a refused client why: {}", err);
+    }
+}
+
+async fn start_server(
+    config: Config,
+    log_filter: FilterHandle,
+    cancellation_token: CancellationToken,
+) -> miette::Result<()> {
     let tcp_listener = {
-        let tcp_addr: SocketAddr = "0.0.0.0:3000".parse().into_diagnostic()?;
+        let tcp_addr = config.tcp_addr();
 
         match port_availability::check(tcp_addr).await? {
             port_availability::Status::Free => {
@@ -32,85 +72,234 @@ async fn start_server(cancellation_token: CancellationToken) -> miette::Result<(
         TcpListener::bind(tcp_addr).into_diagnostic()?
     };
 
-    let udp_handler = Arc::new(Mutex::new(
-        UdpHandler::new("0.0.0.0:3001").await?
-    ));
-    let udp_socket = udp_handler.lock().await.get_socket();
+    let audio_processor = AudioProcessor::new(config.storage.recordings_dir.clone(), config.audio.chunk_secs)?;
+    let tools = config.tools.enabled.then(|| ToolRegistry::new(&config.tools));
+    let assistant = Assistant::new(
+        config.backend.kind,
+        tools,
+        PromptLibrary::new(&config.prompts),
+        ContextBuilder::new(&config.context),
+    );
+    let tls = if config.tls.enabled {
+        Some(load_server_config(&config.tls.cert_path, &config.tls.key_path)?)
+    } else {
+        None
+    };
+    let users = if config.auth.enabled {
+        Some(UserStore::new(config.auth.users_file.clone()))
+    } else {
+        tracing::warn!("Authentication is disabled, anyone who can reach the server can use it");
+        None
+    };
+    let sessions = SessionRegistry::default();
+    let rooms = RoomRegistry::new(sessions.clone());
+    let context = ConnectionContext {
+        audio_processor: audio_processor.clone(),
+        assistant,
+        sessions: sessions.clone(),
+        rooms: rooms.clone(),
+        conversations: ConversationStore::default(),
+        users,
+        limits: config.session_limits(),
+        allow_plaintext_voice: config.audio.allow_plaintext,
+        shutdown: cancellation_token.clone(),
+    };
+    let connection_limiter = ConnectionLimiter::new(config.limits.connections_per_ip);
+    let streams = StreamDirectory::default();
+    let tasks = Tasks::default();
+    if tls.is_none() {
+        if config.audio.allow_plaintext {
+            tracing::warn!(
+                "VOICE IS NOT PRIVATE: TLS is disabled and audio.allow_plaintext is set, anyone on the \
+                 path can read the voice keys, listen in and inject audio"
+            );
+        } else {
+            tracing::warn!(
+                "TLS is disabled, only clients on this machine get voice. Enable [tls], or set \
+                 audio.allow_plaintext on a network you trust"
+            );
+        }
+    }
+    let mut udp_handler = UdpHandler::new(
+        config.udp_addr(),
+        audio_processor.clone(),
+        sessions.clone(),
+        rooms,
+        Arc::clone(&streams),
+        StreamSettings {
+            report_interval: config.report_interval(),
+            allow_plaintext: config.audio.allow_plaintext,
+            room_mode: config.audio.room_mode,
+            auth_required: config.auth.enabled,
+            limits: config.address_limits(),
+        },
+        tasks.clone(),
+    )
+    .await?;
+    let udp_socket = udp_handler.get_socket();
+    runtime::spawn(udp_handler.voice_rooms().run(cancellation_token.clone()));
+
+    tracing::info!(
+        "TCP Listening on {}{}",
+        config.tcp_addr(),
+        if tls.is_some() { " with TLS" } else { "" }
+    );
+    tracing::info!("UDP Listening on {}", config.udp_addr());
+    tracing::info!("Answering chat with the {:?} backend", config.backend.kind);
 
-    tracing::info!("TCP Listening on 3000");
-    tracing::info!("UDP Listening on 3001");
+    if config.admin.enabled {
+        let admin = AdminServer::bind(
+            config.admin.address,
+            AdminState {
+                sessions: sessions.clone(),
+                streams,
+                audio_processor: audio_processor.clone(),
+                recordings_dir: config.storage.recordings_dir.clone(),
+                log_filter,
+                shutdown: cancellation_token.clone(),
+            },
+        )?;
+        runtime::spawn(admin.run());
+    }
+    if config.metrics.enabled {
+        let metrics = MetricsServer::bind(config.metrics.address)?;
+        runtime::spawn(metrics.run(cancellation_token.clone()));
+    }
+    if config.websocket.enabled {
+        let websocket = WebSocketServer::bind(config.websocket_addr(), tls.clone(), &config.websocket)?;
+        runtime::spawn(websocket.run(context.clone(), connection_limiter.clone(), tasks.clone()));
+    }
+    if config.api.enabled {
+        let api = ApiServer::bind(config.api_addr(), tls.clone())?;
+        runtime::spawn(api.run(context.clone(), connection_limiter.clone(), tasks.clone()));
+    }
 
-    let mut abort_handles: Vec<AbortHandle> = Vec::new();
+    // Both operations stay pending across iterations. Dropping an io_uring
+    // accept or receive that already completed would lose the connection or
+    // datagram. The receive buffer comes back with each datagram and is reused.
+    let mut accept = Box::pin(tcp_listener.accept());
+    let mut recv = start_recv(&udp_socket, vec![0u8; MAX_DATAGRAM_SIZE]);
 
     loop {
-        let mut buf = vec![0u8; 1024];
-
         tokio::select! {
             _ = cancellation_token.cancelled() => {
                 tracing::info!("Cancellation token received, shutting down");
-                abort_handles.iter().for_each(|handle| handle.abort());
                 break;
             }
-            result_tcp_stream = tcp_listener.accept() => {
-                let (tcp_stream, _) = result_tcp_stream.into_diagnostic()?;
-                let join_handle = tokio_uring::spawn(process_socket_connection(tcp_stream));
-                abort_handles.push(join_handle.abort_handle());
+            result_tcp_stream = &mut accept => {
+                accept = Box::pin(tcp_listener.accept());
+                let (tcp_stream, peer) = result_tcp_stream.into_diagnostic()?;
+                let Some(permit) = connection_limiter.try_acquire(peer.ip()) else {
+                    metrics().tcp_connections_rejected.inc();
+                    tracing::warn!(
+                        "Refused connection from {}, its address already has {} open",
+                        peer,
+                        connection_limiter.max_per_ip()
+                    );
+                    if tls.is_none() {
+                        let reason = "Too many connections from your address".to_string();
+                        tasks.spawn(refuse_connection(tcp_stream, reason));
+                    }
+                    continue;
+                };
+                let transport = match Transport::new(tcp_stream, tls.as_ref()) {
+                    Ok(transport) => transport,
+                    Err(err) => {
+                        tracing::error!("Could not set up TLS for {}: {:?}", peer, err);
+                        continue;
+                    }
+                };
+                // The id and user are recorded once the client is authenticated
+                let span = tracing::info_span!(
+                    "session",
+                    id = tracing::field::Empty,
+                    %peer,
+                    user = tracing::field::Empty
+                );
+                let connection = process_socket_connection(transport, peer, context.clone(), permit);
+                tasks.spawn(connection.instrument(span));
             }
-            result = udp_socket.recv_from(buf) => {
-                let (result, received_buf) = result;
+            (result, received_buf) = &mut recv => {
                 match result {
-                    Ok((size, addr)) => {
-                        let data = received_buf[..size].to_vec();
-                        let handler = Arc::clone(&udp_handler);
-                        
-                        let join_handle = tokio_uring::spawn(async move {
-                            let mut handler = handler.lock().await;
-                            let result = handler.process_packet(data, addr).await;
-                            result
-                        });
-                        
-                        abort_handles.push(join_handle.abort_handle());
-                    }
+                    Ok((size, addr)) => udp_handler.route(addr, &received_buf[..size], Instant::now()),
                     Err(e) => {
                         tracing::error!("Error receiving UDP packet: {}", e);
                     }
                 }
+                recv = start_recv(&udp_socket, received_buf);
             }
         }
     }
 
+    // Stop accepting before waiting, so the wait cannot be extended by new
+    // clients. Dropping the router ends every voice stream once its queue is empty.
+    drop(accept);
+    drop(recv);
+    drop(tcp_listener);
+    drop(udp_handler);
+    tasks.close();
+    tracing::info!(
+        "Waiting up to {:?} for {} running tasks",
+        config.shutdown_grace(),
+        tasks.len()
+    );
+    if tokio::time::timeout(config.shutdown_grace(), tasks.wait()).await.is_err() {
+        tracing::warn!(
+            "{} tasks still running after the grace period, stopping them",
+            tasks.len()
+        );
+        // Their recordings are handed to the writer as they are dropped, before the flush
+        tasks.stop().await;
+    }
+
+    let saved = audio_processor.flush_all().await?;
+    tracing::info!("Saved {} buffered recordings, exiting", saved);
+
     Ok(())
 }
 
-fn register_tracing_subscriber() {
-    let subscriber = tracing_subscriber::fmt()
-        .with_max_level(tracing::Level::DEBUG)
-        .with_span_events(FmtSpan::FULL)
-        .with_thread_ids(true)
-        .with_thread_names(true)
-        .with_target(true)
-        .with_line_number(true)
-        .with_file(true)
-        .compact()
-        .without_time();
-
-    tracing::subscriber::set_global_default(subscriber.finish())
-        .expect("Failed to set tracing subscriber");
+type RecvFuture = Pin<Box<dyn Future<Output = BufResult<(usize, SocketAddr), Vec<u8>>>>>;
+
+fn start_recv(socket: &Rc<UdpSocket>, buf: Vec<u8>) -> RecvFuture {
+    let socket = Rc::clone(socket);
+    Box::pin(async move { socket.recv_from(buf).await })
 }
 
 fn main() -> miette::Result<()> {
+    // Variables from .env are picked up by the CLI parser like any other
     dotenv::dotenv().ok();
-    register_tracing_subscriber();
+    let mut cli = Cli::parse();
+    let command = cli.command.take();
+    let config = Config::load(cli)?;
+
+    match command {
+        Some(Command::Admin(command)) => return admin::run(config.admin.address, command),
+        Some(Command::User(command)) => return run_user_command(&config.auth.users_file, command),
+        Some(Command::GenerateCert { names, force }) => {
+            return generate_self_signed(names, &config.tls.cert_path, &config.tls.key_path, force);
+        }
+        None => {}
+    }
+
+    // Held until exit, dropping it stops the log file writer
+    let logging = logging::init(&config.log_settings())?;
 
     let cancellation_token = tokio_util::sync::CancellationToken::new();
     let cancellation_token_clone = cancellation_token.clone();
 
     ctrlc::set_handler(move || {
+        // A second Ctrl-C skips the grace period
+        if cancellation_token_clone.is_cancelled() {
+            std::process::exit(130);
+        }
         cancellation_token_clone.cancel();
     })
     .into_diagnostic()?;
 
-    tokio_uring::start(start_server(cancellation_token.clone()))?;
+    runtime::start(
+        config.server.runtime,
+        start_server(config, logging.filter.clone(), cancellation_token.clone()),
+    )??;
 
     Ok(())
 }
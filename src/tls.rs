@@ -0,0 +1,27 @@
+// Certificate fingerprints. The server prints them, the client pins them.
+
+use sha2::{Digest, Sha256};
+
+/// SHA-256 of a DER certificate as colon separated hex, the notation
+/// `openssl x509 -fingerprint -sha256` prints.
+pub fn fingerprint(der: &[u8]) -> String {
+    Sha256::digest(der)
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Compares ignoring case and separators, so pasted fingerprints match in
+/// either notation.
+pub fn fingerprint_matches(expected: &str, der: &[u8]) -> bool {
+    let normalize = |fingerprint: &str| {
+        fingerprint
+            .chars()
+            .filter(char::is_ascii_hexdigit)
+            .map(|c| c.to_ascii_uppercase())
+            .collect::<String>()
+    };
+    let expected = normalize(expected);
+    !expected.is_empty() && expected == normalize(&fingerprint(der))
+}
@@ -1,29 +1,119 @@
 use tokio::net::UdpSocket;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use crate::protocol::audio::{AudioPacket, LatencyProbe, Packet, ROOM_AUDIO_HEADER_LEN};
+use crate::protocol::sealed::{Direction, Opener, Sealer, VoiceKey, SEALED_OVERHEAD};
 
 const MAX_UDP_PACKET_SIZE: usize = 1200; // Conservative size to avoid fragmentation
+/// The server forgets a voice stream after 30 seconds without a datagram.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Largest Opus frame that still fits in a single sealed datagram together
+/// with its headers, also once the server forwards it to a room.
+pub const MAX_PAYLOAD_SIZE: usize = MAX_UDP_PACKET_SIZE - SEALED_OVERHEAD - ROOM_AUDIO_HEADER_LEN;
 
 #[derive(Clone)]
 pub struct AudioConnection {
     socket: Arc<UdpSocket>,
+    sequence: Arc<AtomicU32>,
+    started: Instant,
+    // Zero until the welcome, session ids start at one
+    session_id: Arc<AtomicU64>,
+    // Both stay empty until the server's welcome brings the session key
+    sealer: Arc<Mutex<Option<Sealer>>>,
+    opener: Arc<Mutex<Option<Opener>>>,
 }
 
 impl AudioConnection {
     pub async fn new() -> std::io::Result<Self> {
         let socket = UdpSocket::bind("0.0.0.0:0").await?;
         socket.connect("127.0.0.1:3001").await?;
-        
-        Ok(AudioConnection { 
-            socket: Arc::new(socket)
+
+        Ok(AudioConnection {
+            socket: Arc::new(socket),
+            sequence: Arc::new(AtomicU32::new(0)),
+            started: Instant::now(),
+            session_id: Arc::new(AtomicU64::new(0)),
+            sealer: Arc::new(Mutex::new(None)),
+            opener: Arc::new(Mutex::new(None)),
         })
     }
 
-    pub async fn send_audio(&self, data: Vec<u8>) -> std::io::Result<()> {
-        // Split data into chunks and send each chunk
-        for chunk in data.chunks(MAX_UDP_PACKET_SIZE) {
-            self.socket.send(chunk).await?;
+    /// Seals everything sent from now on with the key of `session_id`.
+    pub fn set_voice_key(&self, session_id: u64, key: &VoiceKey) {
+        self.session_id.store(session_id, Ordering::Relaxed);
+        *self.sealer.lock().unwrap_or_else(PoisonError::into_inner) =
+            Some(Sealer::new(key, session_id, Direction::ClientToServer));
+        *self.opener.lock().unwrap_or_else(PoisonError::into_inner) =
+            Some(Opener::new(key, session_id, Direction::ServerToClient));
+    }
+
+    /// Microseconds on this connection's clock, which probe and audio timestamps use.
+    pub fn clock_us(&self) -> u64 {
+        self.started.elapsed().as_micros() as u64
+    }
+
+    /// Sends one encoded frame. Called from the audio callback, so it never waits
+    /// for the socket; a frame that cannot be sent right away counts as lost.
+    pub fn send_audio(&self, payload: Vec<u8>) -> std::io::Result<()> {
+        self.send(Packet::Audio(AudioPacket {
+            sequence: self.sequence.fetch_add(1, Ordering::Relaxed),
+            timestamp_ms: self.started.elapsed().as_millis() as u32,
+            payload,
+        }))
+    }
+
+    /// Tells the server which TCP session this voice stream belongs to.
+    pub fn send_bind(&self, session_id: u64) -> std::io::Result<()> {
+        self.send(Packet::Bind { session_id })
+    }
+
+    /// Binds the stream again every so often, so the server keeps sending
+    /// room audio to a client that only listens.
+    pub async fn keep_bound(&self) {
+        let mut interval = tokio::time::interval(KEEPALIVE_INTERVAL);
+        loop {
+            interval.tick().await;
+            let session_id = self.session_id.load(Ordering::Relaxed);
+            if session_id == 0 {
+                continue;
+            }
+            if let Err(err) = self.send_bind(session_id) {
+                tracing::debug!("Failed to refresh the voice stream binding: {}", err);
+            }
         }
+    }
+
+    pub fn send_probe(&self, probe: LatencyProbe) -> std::io::Result<()> {
+        self.send(Packet::LatencyProbe(probe))
+    }
+
+    fn send(&self, packet: Packet) -> std::io::Result<()> {
+        let sealer = self.sealer.lock().unwrap_or_else(PoisonError::into_inner);
+        // The server drops unsealed datagrams, so until the welcome brings the
+        // key there is nothing worth sending. Not an error, capture starts first.
+        let Some(sealer) = sealer.as_ref() else {
+            return Ok(());
+        };
+        self.socket.try_send(&sealer.seal(&packet))?;
         Ok(())
     }
-    
-} 
\ No newline at end of file
+
+    /// Waits for the next packet from the server, skipping anything that does
+    /// not authenticate.
+    pub async fn recv_packet(&self) -> std::io::Result<Packet> {
+        let mut buf = [0u8; MAX_UDP_PACKET_SIZE];
+        loop {
+            let len = self.socket.recv(&mut buf).await?;
+            let mut opener = self.opener.lock().unwrap_or_else(PoisonError::into_inner);
+            let Some(opener) = opener.as_mut() else {
+                continue;
+            };
+            match opener.open(&buf[..len]) {
+                Ok(packet) => return Ok(packet),
+                Err(err) => tracing::debug!("Dropped packet from server: {}", err),
+            }
+        }
+    }
+}
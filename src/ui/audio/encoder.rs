@@ -0,0 +1,222 @@
+use opus::{Application, Bitrate, Channels, Encoder};
+
+use super::file::resample;
+use crate::protocol::audio::ReceiverReport;
+
+// Frame durations Opus accepts that are useful for voice.
+const FRAME_DURATIONS_MS: [u32; 4] = [10, 20, 40, 60];
+const DEFAULT_FRAME_MS: u32 = 20;
+const HIGH_LOSS: f32 = 0.10;
+const MODERATE_LOSS: f32 = 0.02;
+const HIGH_JITTER_MS: u32 = 30;
+// Rates Opus encodes at, anything else is resampled to the last one.
+const OPUS_RATES: [u32; 5] = [8000, 12000, 16000, 24000, 48000];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EncoderSettings {
+    pub bitrate: i32,
+    pub frame_ms: u32,
+    pub fec: bool,
+    pub packet_loss_perc: i32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct AdaptiveBounds {
+    pub min_bitrate: i32,
+    pub max_bitrate: i32,
+    pub min_frame_ms: u32,
+    pub max_frame_ms: u32,
+}
+
+impl Default for AdaptiveBounds {
+    fn default() -> Self {
+        Self {
+            min_bitrate: 12_000,
+            max_bitrate: 64_000,
+            min_frame_ms: 10,
+            max_frame_ms: 60,
+        }
+    }
+}
+
+/// Turns receiver reports from the server into encoder settings, similar to
+/// what an RTCP driven sender does: back off quickly on loss, probe upwards
+/// slowly while the path is clean.
+pub struct BitrateController {
+    bounds: AdaptiveBounds,
+    settings: EncoderSettings,
+}
+
+impl BitrateController {
+    pub fn new(bounds: AdaptiveBounds) -> Self {
+        let settings = EncoderSettings {
+            bitrate: (bounds.min_bitrate + bounds.max_bitrate) / 2,
+            frame_ms: DEFAULT_FRAME_MS.clamp(bounds.min_frame_ms, bounds.max_frame_ms),
+            fec: false,
+            packet_loss_perc: 0,
+        };
+        Self { bounds, settings }
+    }
+
+    pub fn settings(&self) -> EncoderSettings {
+        self.settings
+    }
+
+    pub fn on_report(&mut self, report: &ReceiverReport) -> EncoderSettings {
+        let loss = report.loss_ratio();
+        let mut settings = self.settings;
+
+        let high_jitter = report.jitter_ms >= HIGH_JITTER_MS;
+        if loss > HIGH_LOSS {
+            settings.bitrate = (settings.bitrate as f32 * 0.75) as i32;
+        } else if loss <= MODERATE_LOSS {
+            settings.bitrate = (settings.bitrate as f32 * 1.05) as i32 + 1_000;
+        }
+
+        // Fewer, larger packets spend less on headers and survive bursty loss
+        // and jitter better. One step per report, even when both are high.
+        if loss > HIGH_LOSS || high_jitter {
+            settings.frame_ms = next_frame_duration(settings.frame_ms);
+        } else if loss <= MODERATE_LOSS && settings.frame_ms > DEFAULT_FRAME_MS {
+            settings.frame_ms = previous_frame_duration(settings.frame_ms);
+        }
+
+        settings.fec = loss > 0.0;
+        settings.packet_loss_perc = (loss * 100.0).ceil() as i32;
+        settings.bitrate = settings
+            .bitrate
+            .clamp(self.bounds.min_bitrate, self.bounds.max_bitrate);
+        settings.frame_ms = settings
+            .frame_ms
+            .clamp(self.bounds.min_frame_ms, self.bounds.max_frame_ms);
+
+        if settings != self.settings {
+            tracing::info!(
+                "Adapting encoder to loss {:.1}% / jitter {}ms: {:?}",
+                loss * 100.0,
+                report.jitter_ms,
+                settings
+            );
+        }
+        self.settings = settings;
+        settings
+    }
+}
+
+/// Whether Opus can encode frames of `frame_ms`, as far as voice goes.
+pub fn is_frame_duration(frame_ms: u32) -> bool {
+    FRAME_DURATIONS_MS.contains(&frame_ms)
+}
+
+fn next_frame_duration(frame_ms: u32) -> u32 {
+    FRAME_DURATIONS_MS
+        .iter()
+        .copied()
+        .find(|&duration| duration > frame_ms)
+        .unwrap_or(frame_ms)
+}
+
+fn previous_frame_duration(frame_ms: u32) -> u32 {
+    FRAME_DURATIONS_MS
+        .iter()
+        .rev()
+        .copied()
+        .find(|&duration| duration < frame_ms)
+        .unwrap_or(frame_ms)
+}
+
+/// A long lived Opus encoder that slices the capture callback's buffers into
+/// whole frames of the currently configured duration. Devices running at a
+/// rate Opus does not take, like 44.1 kHz, are resampled to 48 kHz first.
+pub struct StreamEncoder {
+    encoder: Encoder,
+    input_rate: u32,
+    sample_rate: u32,
+    settings: Option<EncoderSettings>,
+    pending: Vec<f32>,
+}
+
+impl StreamEncoder {
+    pub fn new(input_rate: u32) -> Result<Self, opus::Error> {
+        let sample_rate = if OPUS_RATES.contains(&input_rate) {
+            input_rate
+        } else {
+            OPUS_RATES[OPUS_RATES.len() - 1]
+        };
+        Ok(Self {
+            encoder: Encoder::new(sample_rate, Channels::Mono, Application::Voip)?,
+            input_rate,
+            sample_rate,
+            settings: None,
+            pending: Vec::new(),
+        })
+    }
+
+    pub fn apply(&mut self, settings: EncoderSettings) -> Result<(), opus::Error> {
+        if self.settings == Some(settings) {
+            return Ok(());
+        }
+        self.encoder.set_bitrate(Bitrate::Bits(settings.bitrate))?;
+        self.encoder.set_inband_fec(settings.fec)?;
+        self.encoder.set_packet_loss_perc(settings.packet_loss_perc)?;
+        self.settings = Some(settings);
+        Ok(())
+    }
+
+    /// Queues mono samples at the input rate and returns every complete
+    /// frame encoded so far.
+    pub fn push(&mut self, samples: &[f32], max_packet_size: usize) -> Vec<Vec<u8>> {
+        self.pending.extend(resample(samples, self.input_rate, self.sample_rate));
+
+        let frame_ms = self.settings.map_or(DEFAULT_FRAME_MS, |settings| settings.frame_ms);
+        let frame_len = (self.sample_rate * frame_ms / 1000) as usize;
+        let mut packets = Vec::new();
+        let mut output = vec![0u8; max_packet_size];
+
+        while self.pending.len() >= frame_len {
+            match self.encoder.encode_float(&self.pending[..frame_len], &mut output) {
+                Ok(len) => packets.push(output[..len].to_vec()),
+                Err(err) => tracing::error!("Failed to encode Opus frame: {}", err),
+            }
+            self.pending.drain(..frame_len);
+        }
+        packets
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(fraction_lost: u8, jitter_ms: u32) -> ReceiverReport {
+        ReceiverReport {
+            fraction_lost,
+            cumulative_lost: 0,
+            highest_sequence: 0,
+            jitter_ms,
+        }
+    }
+
+    #[test]
+    fn high_loss_and_jitter_take_one_frame_step() {
+        let mut controller = BitrateController::new(AdaptiveBounds::default());
+        assert_eq!(controller.settings().frame_ms, 20);
+
+        // 25% loss and 50ms jitter in the same report
+        let settings = controller.on_report(&report(64, 50));
+        assert_eq!(settings.frame_ms, 40);
+        assert_eq!(settings.bitrate, 28_500);
+        assert!(settings.fec);
+    }
+
+    #[test]
+    fn frames_grow_on_either_and_shrink_on_a_clean_path() {
+        let mut controller = BitrateController::new(AdaptiveBounds::default());
+        assert_eq!(controller.on_report(&report(64, 0)).frame_ms, 40);
+        assert_eq!(controller.on_report(&report(0, 50)).frame_ms, 60);
+        assert_eq!(controller.on_report(&report(64, 50)).frame_ms, 60);
+        assert_eq!(controller.on_report(&report(0, 0)).frame_ms, 40);
+        assert_eq!(controller.on_report(&report(0, 0)).frame_ms, 20);
+        assert_eq!(controller.on_report(&report(0, 0)).frame_ms, 20);
+    }
+}
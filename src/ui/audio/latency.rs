@@ -0,0 +1,122 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::protocol::audio::{LatencyEcho, LatencyProbe};
+use crate::protocol::latency::{LatencyWindow, Percentiles};
+
+const PROBE_INTERVAL: Duration = Duration::from_millis(500);
+// Probes whose echo never arrives are forgotten after this many newer ones.
+const MAX_PENDING: usize = 64;
+
+#[derive(Debug, Clone, Copy)]
+pub struct LatencySummary {
+    pub samples: usize,
+    pub mouth_to_server: Percentiles,
+    pub server_processing: Percentiles,
+    pub round_trip: Percentiles,
+}
+
+#[derive(Default)]
+struct Measurements {
+    pending: HashMap<u32, u32>,
+    last_round_trip_us: u32,
+    last_mouth_to_server_us: u32,
+    mouth_to_server: LatencyWindow,
+    server_processing: LatencyWindow,
+    round_trip: LatencyWindow,
+}
+
+/// Drives the latency diagnostic mode: decides when the capture callback
+/// injects a probe and turns the server's echoes into measurements.
+///
+/// Client and server clocks are not synchronised, so the one-way network
+/// delay is estimated as half of the round trip minus the server's time.
+#[derive(Default)]
+pub struct LatencyMonitor {
+    enabled: AtomicBool,
+    next_probe_id: AtomicU32,
+    last_probe: Mutex<Option<Instant>>,
+    measurements: Mutex<Measurements>,
+}
+
+impl LatencyMonitor {
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::SeqCst);
+        if enabled {
+            if let Ok(mut measurements) = self.measurements.lock() {
+                *measurements = Measurements::default();
+            }
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::SeqCst)
+    }
+
+    /// Returns a probe to send if one is due. `capture_delay` is how long ago
+    /// the microphone captured the audio being sent right now.
+    pub fn maybe_probe(&self, capture_delay: Duration, sent_us: u64) -> Option<LatencyProbe> {
+        if !self.is_enabled() {
+            return None;
+        }
+        {
+            let mut last_probe = self.last_probe.try_lock().ok()?;
+            if last_probe.is_some_and(|last| last.elapsed() < PROBE_INTERVAL) {
+                return None;
+            }
+            *last_probe = Some(Instant::now());
+        }
+
+        let probe_id = self.next_probe_id.fetch_add(1, Ordering::Relaxed);
+        let capture_delay_us = capture_delay.as_micros().min(u32::MAX as u128) as u32;
+        let mut measurements = self.measurements.try_lock().ok()?;
+        if measurements.pending.len() >= MAX_PENDING {
+            let oldest = probe_id.wrapping_sub(MAX_PENDING as u32);
+            measurements.pending.retain(|&id, _| id > oldest);
+        }
+        measurements.pending.insert(probe_id, capture_delay_us);
+
+        // Each measurement is passed on to the server once
+        Some(LatencyProbe {
+            probe_id,
+            sent_us,
+            capture_delay_us,
+            last_round_trip_us: std::mem::take(&mut measurements.last_round_trip_us),
+            last_mouth_to_server_us: std::mem::take(&mut measurements.last_mouth_to_server_us),
+        })
+    }
+
+    pub fn on_echo(&self, echo: &LatencyEcho, now_us: u64) {
+        let Ok(mut measurements) = self.measurements.lock() else {
+            return;
+        };
+        let Some(capture_delay_us) = measurements.pending.remove(&echo.probe_id) else {
+            return;
+        };
+
+        let round_trip_us = now_us.saturating_sub(echo.sent_us).min(u32::MAX as u64) as u32;
+        let one_way_us = round_trip_us.saturating_sub(echo.server_processing_us) / 2;
+        let mouth_to_server_us = capture_delay_us.saturating_add(one_way_us);
+
+        measurements.last_round_trip_us = round_trip_us;
+        measurements.last_mouth_to_server_us = mouth_to_server_us;
+        measurements.round_trip.push(round_trip_us);
+        measurements.server_processing.push(echo.server_processing_us);
+        measurements.mouth_to_server.push(mouth_to_server_us);
+    }
+
+    pub fn summary(&self) -> Option<LatencySummary> {
+        let measurements = self.measurements.lock().ok()?;
+        if measurements.round_trip.is_empty() {
+            return None;
+        }
+        Some(LatencySummary {
+            samples: measurements.round_trip.len(),
+            mouth_to_server: measurements.mouth_to_server.percentiles(),
+            server_processing: measurements.server_processing.percentiles(),
+            round_trip: measurements.round_trip.percentiles(),
+        })
+    }
+}
@@ -1,10 +1,12 @@
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
 use cpal::{FromSample, Sample, SampleFormat, SizedSample};
 use debug::write_input_data;
-use opus::{Application, Channels, Encoder};
+use gtk::gio;
+use gtk::prelude::*;
 use std::f32::consts::E;
 use std::fs::File;
 use std::io::BufWriter;
+use std::path::PathBuf;
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::sync::Mutex;
@@ -14,8 +16,19 @@ use tracing;
 
 mod connection;
 mod debug;
+mod encoder;
+pub mod file;
+mod latency;
+mod playback;
+pub mod waveform;
 use chrono::Local;
-use connection::AudioConnection;
+use connection::{AudioConnection, MAX_PAYLOAD_SIZE};
+use encoder::{AdaptiveBounds, BitrateController, EncoderSettings, StreamEncoder};
+use latency::LatencyMonitor;
+pub use latency::LatencySummary;
+use playback::{Playback, PlaybackQueue};
+use crate::protocol::audio::Packet;
+use crate::protocol::sealed::VoiceKey;
 
 const SILENCE_THRESHOLD: f32 = 0.01; // Adjust this value based on testing
 const MIN_CHUNK_DURATION: Duration = Duration::from_millis(500); // Minimum chunk size
@@ -31,6 +44,19 @@ pub struct AudioCapture {
     buffer: Arc<Mutex<Vec<f32>>>,
     silence_counter: Arc<AtomicUsize>,
     wav_writer: WavWriterHandle,
+    encoder_settings: Arc<Mutex<EncoderSettings>>,
+    latency: Arc<LatencyMonitor>,
+    recording_path: Option<PathBuf>,
+    finished_recording: Option<Recording>,
+    session_id: Option<u64>,
+    // Room audio from other members, kept for as long as the window lives
+    _playback: Option<Playback>,
+}
+
+/// A local recording that has been written out completely.
+pub struct Recording {
+    pub path: PathBuf,
+    pub duration_ms: u64,
 }
 
 type WavWriterHandle = Arc<Mutex<Option<hound::WavWriter<BufWriter<File>>>>>;
@@ -53,6 +79,27 @@ impl AudioCapture {
             }
         });
 
+        let controller = BitrateController::new(adaptive_bounds());
+        let encoder_settings = Arc::new(Mutex::new(controller.settings()));
+        let latency = Arc::new(LatencyMonitor::default());
+        let playback = match Playback::new() {
+            Ok(playback) => Some(playback),
+            Err(err) => {
+                tracing::error!("Room audio will not be played: {}", err);
+                None
+            }
+        };
+        if let Some(audio_connection) = audio_connection.clone() {
+            runtime.spawn(listen_for_feedback(
+                audio_connection.clone(),
+                controller,
+                encoder_settings.clone(),
+                latency.clone(),
+                playback.as_ref().map(Playback::queue),
+            ));
+            runtime.spawn(async move { audio_connection.keep_bound().await });
+        }
+
         AudioCapture {
             is_recording: Arc::new(AtomicBool::new(false)),
             stream: None,
@@ -62,6 +109,12 @@ impl AudioCapture {
             buffer: Arc::new(Mutex::new(Vec::new())),
             silence_counter: Arc::new(AtomicUsize::new(0)),
             wav_writer: Arc::new(Mutex::new(None)),
+            encoder_settings,
+            latency,
+            recording_path: None,
+            finished_recording: None,
+            session_id: None,
+            _playback: playback,
         }
     }
 
@@ -87,19 +140,20 @@ impl AudioCapture {
 
         let current_datetime = Local::now();
         let formatted_datetime: String = current_datetime.format("%Y-%m-%d-%H:%M:%S").to_string();
-        let path = format!(
-            "{}/recordings/record_{}.wav",
-            env!("CARGO_MANIFEST_DIR"),
-            formatted_datetime
-        );
+        let recordings_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("recordings");
+        std::fs::create_dir_all(&recordings_dir).expect("Failed to create recordings directory");
+        let path = recordings_dir.join(format!("record_{}.wav", formatted_datetime));
         let spec = debug::wav_spec_from_config(config.clone());
         let writer = hound::WavWriter::create(&path, spec).unwrap();
         let writer = Arc::new(Mutex::new(Some(writer)));
 
         self.wav_writer = writer;
+        self.recording_path = Some(path);
 
         let is_recording = self.is_recording.clone();
         is_recording.store(true, Ordering::SeqCst);
+        // Datagrams can be lost, so bind again whenever a stream starts
+        self.send_bind();
 
         let stream = match config.sample_format() {
             SampleFormat::F32 => self.build_stream(&device, &config.into()),
@@ -117,6 +171,50 @@ impl AudioCapture {
     fn stop_recording(&mut self) {
         self.is_recording.store(false, Ordering::SeqCst);
         self.stream = None;
+
+        // Finalizing writes the sizes into the WAV header, without it the file cannot be played
+        let writer = self.wav_writer.lock().ok().and_then(|mut guard| guard.take());
+        if let (Some(writer), Some(path)) = (writer, self.recording_path.take()) {
+            let sample_rate = writer.spec().sample_rate as u64;
+            let duration_ms = writer.duration() as u64 * 1000 / sample_rate;
+            // The window computes the waveform once the file is complete
+            match writer.finalize() {
+                Ok(()) => self.finished_recording = Some(Recording { path, duration_ms }),
+                Err(err) => tracing::error!("Failed to finalize recording: {}", err),
+            }
+        }
+    }
+
+    /// Ties the voice stream to the chat connection with this session id and
+    /// seals it with the session's key.
+    pub fn set_session(&mut self, session_id: u64, voice_key: &VoiceKey) {
+        self.session_id = Some(session_id);
+        if let Some(conn) = &self.audio_connection {
+            conn.set_voice_key(session_id, voice_key);
+        }
+        self.send_bind();
+    }
+
+    fn send_bind(&self) {
+        if let (Some(conn), Some(session_id)) = (&self.audio_connection, self.session_id) {
+            if let Err(e) = conn.send_bind(session_id) {
+                tracing::warn!("Failed to bind voice stream to session {}: {}", session_id, e);
+            }
+        }
+    }
+
+    /// Turns the latency diagnostic mode on or off. Probes are only sent while recording.
+    pub fn set_latency_diagnostics(&self, enabled: bool) {
+        self.latency.set_enabled(enabled);
+    }
+
+    pub fn latency_summary(&self) -> Option<LatencySummary> {
+        self.latency.summary()
+    }
+
+    /// Returns the recording completed by the last call that stopped recording.
+    pub fn take_finished_recording(&mut self) -> Option<Recording> {
+        self.finished_recording.take()
     }
 
     fn build_stream(
@@ -127,24 +225,55 @@ impl AudioCapture {
         let is_recording = self.is_recording.clone();
         let writer = self.wav_writer.clone();
         let audio_connection = self.audio_connection.clone();
-        let sample_rate = config.sample_rate.0.clone();
+        let encoder_settings = self.encoder_settings.clone();
+        let latency = self.latency.clone();
+        let channels = config.channels as usize;
+        let mut encoder = match StreamEncoder::new(config.sample_rate.0) {
+            Ok(encoder) => Some(encoder),
+            Err(err) => {
+                tracing::error!("Failed to create Opus encoder: {}", err);
+                None
+            }
+        };
         // let chunk_start = self.chunk_start.clone();
         // let buffer = self.buffer.clone();
         // let silence_counter = self.silence_counter.clone();
 
-        // let runtime = self.runtime.handle().clone();
-
         device.build_input_stream(
             config,
-            move |data: &[f32], _: &cpal::InputCallbackInfo| {
+            move |data: &[f32], info: &cpal::InputCallbackInfo| {
                 if !is_recording.load(Ordering::SeqCst) {
                     return;
                 }
+                let callback_started = Instant::now();
 
                 write_input_data::<f32, f32>(data, &writer);
-                if let Some(audio_connection) = &audio_connection {
-                    let data = encode_opus(data, sample_rate);
-                    audio_connection.send_audio(data);
+                if let (Some(audio_connection), Some(encoder)) = (&audio_connection, encoder.as_mut()) {
+                    if let Ok(settings) = encoder_settings.try_lock() {
+                        if let Err(err) = encoder.apply(*settings) {
+                            tracing::error!("Failed to apply encoder settings: {}", err);
+                        }
+                    }
+
+                    let mono = downmix(data, channels);
+                    for packet in encoder.push(&mono, MAX_PAYLOAD_SIZE) {
+                        if let Err(err) = audio_connection.send_audio(packet) {
+                            tracing::warn!("Dropped audio frame: {}", err);
+                        }
+                    }
+
+                    // The driver's capture timestamp tells how long the samples sat in its buffers
+                    let timestamp = info.timestamp();
+                    let capture_delay = timestamp
+                        .callback
+                        .duration_since(&timestamp.capture)
+                        .unwrap_or_default()
+                        + callback_started.elapsed();
+                    if let Some(probe) = latency.maybe_probe(capture_delay, audio_connection.clock_us()) {
+                        if let Err(err) = audio_connection.send_probe(probe) {
+                            tracing::warn!("Dropped latency probe: {}", err);
+                        }
+                    }
                 }
             },
             move |err| {
@@ -155,15 +284,68 @@ impl AudioCapture {
     }
 }
 
-fn encode_opus(data: &[f32], sample_rate: u32) -> Vec<u8> {
-    tracing::info!(sample_rate);
-
-    let mut encoder = match Encoder::new(sample_rate, Channels::Mono, Application::Audio) {
-        Ok(encoder) => encoder,
-        Err(err) => panic!("Failed to create Opus encoder: {}", err),
-    };
-    
-    let mut output = [0; 256];
-    let len = encoder.encode_float(data, &mut output).unwrap();
-    output[..len].to_vec()
+fn downmix(data: &[f32], channels: usize) -> Vec<f32> {
+    if channels <= 1 {
+        return data.to_vec();
+    }
+    data.chunks(channels)
+        .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+        .collect()
+}
+
+fn adaptive_bounds() -> AdaptiveBounds {
+    let settings = gio::Settings::new(crate::APP_ID);
+    let defaults = AdaptiveBounds::default();
+    let min_bitrate = settings.int("audio-min-bitrate");
+    let max_bitrate = settings.int("audio-max-bitrate");
+    let min_frame_ms = settings.int("audio-min-frame-ms") as u32;
+    let max_frame_ms = settings.int("audio-max-frame-ms") as u32;
+
+    if min_bitrate > max_bitrate || min_frame_ms > max_frame_ms {
+        tracing::warn!("Ignoring inconsistent adaptive audio bounds in settings");
+        return defaults;
+    }
+    // Clamping between two valid durations can only land on one of them
+    if !encoder::is_frame_duration(min_frame_ms) || !encoder::is_frame_duration(max_frame_ms) {
+        tracing::warn!(
+            "Ignoring adaptive audio bounds in settings, frame durations must be 10, 20, 40 or 60 ms"
+        );
+        return defaults;
+    }
+    AdaptiveBounds {
+        min_bitrate,
+        max_bitrate,
+        min_frame_ms,
+        max_frame_ms,
+    }
+}
+
+async fn listen_for_feedback(
+    audio_connection: AudioConnection,
+    mut controller: BitrateController,
+    encoder_settings: Arc<Mutex<EncoderSettings>>,
+    latency: Arc<LatencyMonitor>,
+    mut playback: Option<PlaybackQueue>,
+) {
+    loop {
+        match audio_connection.recv_packet().await {
+            Ok(Packet::ReceiverReport(report)) => {
+                let settings = controller.on_report(&report);
+                if let Ok(mut current) = encoder_settings.lock() {
+                    *current = settings;
+                }
+            }
+            Ok(Packet::LatencyEcho(echo)) => latency.on_echo(&echo, audio_connection.clock_us()),
+            Ok(Packet::RoomAudio { speaker, audio }) => {
+                if let Some(playback) = &mut playback {
+                    playback.push(speaker, &audio);
+                }
+            }
+            Ok(other) => tracing::debug!("Ignoring unexpected packet from server: {:?}", other),
+            Err(err) => {
+                tracing::error!("Failed to receive feedback from server: {}", err);
+                break;
+            }
+        }
+    }
 }
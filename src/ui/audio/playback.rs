@@ -0,0 +1,150 @@
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::SampleFormat;
+use opus::{Channels, Decoder};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use super::file::resample;
+use crate::protocol::audio::AudioPacket;
+
+// Room audio is always Opus at 48kHz, whatever rate the device plays at.
+const DECODE_RATE: u32 = 48000;
+// Longest Opus packet is 120ms.
+const MAX_FRAME_SAMPLES: usize = DECODE_RATE as usize * 120 / 1000;
+/// Audio buffered per speaker before playback starts, to ride out jitter.
+const PRIME_DURATION: Duration = Duration::from_millis(60);
+/// A speaker further behind than this loses its oldest audio.
+const MAX_BUFFERED: Duration = Duration::from_millis(300);
+/// Speakers silent for this long are forgotten along with their decoder.
+const SPEAKER_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct SpeakerBuffer {
+    decoder: Decoder,
+    samples: VecDeque<f32>,
+    primed: bool,
+    heard_at: Instant,
+}
+
+/// Decoded audio of every room member being heard, shared between the
+/// thread receiving it and the output callback mixing it.
+#[derive(Clone)]
+pub struct PlaybackQueue {
+    speakers: Arc<Mutex<HashMap<u64, SpeakerBuffer>>>,
+    output_rate: u32,
+    decoded: Vec<f32>,
+}
+
+impl PlaybackQueue {
+    /// Decodes a packet from `speaker` and queues it for playback.
+    pub fn push(&mut self, speaker: u64, audio: &AudioPacket) {
+        let mut speakers = self.speakers.lock().unwrap_or_else(PoisonError::into_inner);
+        speakers.retain(|_, buffer| buffer.heard_at.elapsed() < SPEAKER_TIMEOUT);
+
+        let buffer = match speakers.entry(speaker) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => match Decoder::new(DECODE_RATE, Channels::Mono) {
+                Ok(decoder) => entry.insert(SpeakerBuffer {
+                    decoder,
+                    samples: VecDeque::new(),
+                    primed: false,
+                    heard_at: Instant::now(),
+                }),
+                Err(err) => {
+                    tracing::error!("Failed to create Opus decoder: {}", err);
+                    return;
+                }
+            },
+        };
+        let len = match buffer.decoder.decode_float(&audio.payload, &mut self.decoded, false) {
+            Ok(len) => len,
+            Err(err) => {
+                tracing::warn!("Dropped undecodable room audio from {}: {}", speaker, err);
+                return;
+            }
+        };
+        buffer.heard_at = Instant::now();
+        buffer
+            .samples
+            .extend(resample(&self.decoded[..len], DECODE_RATE, self.output_rate));
+        let max_len = samples_for(MAX_BUFFERED, self.output_rate);
+        let excess = buffer.samples.len().saturating_sub(max_len);
+        buffer.samples.drain(..excess);
+    }
+
+    /// Mixes the next sample of every speaker that is ready to play.
+    fn next_sample(speakers: &mut HashMap<u64, SpeakerBuffer>, prime_len: usize) -> f32 {
+        let mut mixed = 0.0;
+        for buffer in speakers.values_mut() {
+            if !buffer.primed {
+                if buffer.samples.len() < prime_len {
+                    continue;
+                }
+                buffer.primed = true;
+            }
+            match buffer.samples.pop_front() {
+                Some(sample) => mixed += sample,
+                None => buffer.primed = false,
+            }
+        }
+        mixed.clamp(-1.0, 1.0)
+    }
+}
+
+/// Plays what other room members say on the default output device.
+pub struct Playback {
+    queue: PlaybackQueue,
+    // Playback stops when the stream is dropped
+    _stream: cpal::Stream,
+}
+
+impl Playback {
+    pub fn new() -> Result<Self, String> {
+        let device = cpal::default_host()
+            .default_output_device()
+            .ok_or("no output device")?;
+        let config = device.default_output_config().map_err(|err| err.to_string())?;
+        if config.sample_format() != SampleFormat::F32 {
+            return Err(format!("unsupported output sample format {:?}", config.sample_format()));
+        }
+        let config: cpal::StreamConfig = config.into();
+        let channels = config.channels as usize;
+        let queue = PlaybackQueue {
+            speakers: Arc::default(),
+            output_rate: config.sample_rate.0,
+            decoded: vec![0f32; MAX_FRAME_SAMPLES],
+        };
+
+        let speakers = queue.speakers.clone();
+        let prime_len = samples_for(PRIME_DURATION, queue.output_rate);
+        let stream = device
+            .build_output_stream(
+                &config,
+                move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
+                    // Never wait on the receiving thread, a missed callback is a glitch
+                    let Ok(mut speakers) = speakers.try_lock() else {
+                        data.fill(0.0);
+                        return;
+                    };
+                    for frame in data.chunks_mut(channels) {
+                        frame.fill(PlaybackQueue::next_sample(&mut speakers, prime_len));
+                    }
+                },
+                |err| tracing::error!("Error in playback stream: {}", err),
+                None,
+            )
+            .map_err(|err| err.to_string())?;
+        stream.play().map_err(|err| err.to_string())?;
+
+        Ok(Self { queue, _stream: stream })
+    }
+
+    pub fn queue(&self) -> PlaybackQueue {
+        self.queue.clone()
+    }
+}
+
+fn samples_for(duration: Duration, rate: u32) -> usize {
+    (duration.as_secs_f64() * rate as f64) as usize
+}
@@ -0,0 +1,52 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Number of bars drawn for a voice message, whatever its length.
+const PEAK_BINS: usize = 64;
+
+fn cache_path(audio_path: &Path) -> PathBuf {
+    audio_path.with_extension("peaks.json")
+}
+
+/// Downsamples a finished WAV file to a normalised peak envelope and stores
+/// it next to the file. Decodes the whole recording, so keep it off the main loop.
+pub fn compute_and_cache(audio_path: &Path) -> io::Result<Vec<f32>> {
+    let peaks = compute_peaks(audio_path)?;
+    let json = serde_json::to_vec(&peaks).map_err(io::Error::other)?;
+    std::fs::write(cache_path(audio_path), json)?;
+    Ok(peaks)
+}
+
+fn compute_peaks(audio_path: &Path) -> io::Result<Vec<f32>> {
+    let mut reader = hound::WavReader::open(audio_path).map_err(io::Error::other)?;
+    let spec = reader.spec();
+    let samples: Vec<f32> = match spec.sample_format {
+        hound::SampleFormat::Float => reader.samples::<f32>().filter_map(Result::ok).collect(),
+        hound::SampleFormat::Int => {
+            let scale = (1i64 << (spec.bits_per_sample - 1)) as f32;
+            reader
+                .samples::<i32>()
+                .filter_map(Result::ok)
+                .map(|sample| sample as f32 / scale)
+                .collect()
+        }
+    };
+
+    if samples.is_empty() {
+        return Ok(vec![0.0; PEAK_BINS]);
+    }
+
+    let bin_len = samples.len().div_ceil(PEAK_BINS);
+    let mut peaks: Vec<f32> = samples
+        .chunks(bin_len)
+        .map(|bin| bin.iter().fold(0f32, |peak, sample| peak.max(sample.abs())))
+        .collect();
+    peaks.resize(PEAK_BINS, 0.0);
+
+    // Normalise so quiet recordings still show their shape
+    let loudest = peaks.iter().copied().fold(0f32, f32::max);
+    if loudest > 0.0 {
+        peaks.iter_mut().for_each(|peak| *peak /= loudest);
+    }
+    Ok(peaks)
+}
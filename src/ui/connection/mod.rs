@@ -1,30 +1,107 @@
+mod tls;
+
 use std::io::{Read, Write};
 use std::net::TcpStream;
 use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex, PoisonError};
 use std::thread;
 
+use rustls::pki_types::ServerName;
+use rustls::{ClientConnection, StreamOwned};
+
+use crate::protocol::frame::{encode_client_frame, ClientFrame, FrameDecoder};
+use crate::protocol::message::ServerMessage;
+
+pub use tls::TlsSettings;
+
+const SERVER_HOST: &str = "127.0.0.1";
+const SERVER_PORT: u16 = 3000;
+
+/// The socket, wrapped in TLS when the settings ask for it.
+enum Stream {
+    Plain(TcpStream),
+    Tls(Box<StreamOwned<ClientConnection, TcpStream>>),
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.read(buf),
+            Stream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.write(buf),
+            Stream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Stream::Plain(stream) => stream.flush(),
+            Stream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
 pub struct Connection {
-    stream: TcpStream,
+    // Shared by the listening thread and senders, TLS state cannot be split
+    stream: Arc<Mutex<Stream>>,
 }
 
 impl Connection {
-    pub fn new() -> Result<Self, std::io::Error> {
-        let stream = TcpStream::connect("127.0.0.1:3000")?;
-        stream.set_nonblocking(true)?;
-        Ok(Connection { stream })
+    pub fn new(tls: &TlsSettings) -> Result<Self, std::io::Error> {
+        let mut socket = TcpStream::connect((SERVER_HOST, SERVER_PORT))?;
+
+        let stream = if tls.enabled {
+            let config = tls.client_config().map_err(std::io::Error::other)?;
+            let server_name = ServerName::try_from(SERVER_HOST).map_err(std::io::Error::other)?;
+            let mut connection = ClientConnection::new(config, server_name).map_err(std::io::Error::other)?;
+            // The handshake runs while the socket still blocks
+            while connection.is_handshaking() {
+                connection.complete_io(&mut socket)?;
+            }
+            socket.set_nonblocking(true)?;
+            Stream::Tls(Box::new(StreamOwned::new(connection, socket)))
+        } else {
+            socket.set_nonblocking(true)?;
+            Stream::Plain(socket)
+        };
+
+        Ok(Connection {
+            stream: Arc::new(Mutex::new(stream)),
+        })
     }
 
-    pub fn start_listening(&mut self, response_tx: Sender<String>) {
-        let mut stream = self.stream.try_clone().expect("Failed to clone stream");
-        
+    pub fn start_listening(&mut self, response_tx: Sender<ServerMessage>) {
+        let stream = Arc::clone(&self.stream);
+
         thread::spawn(move || {
-            let mut buffer = [0; 1024];
-            
+            let mut buffer = [0; 4096];
+            let mut decoder = FrameDecoder::new();
+
             loop {
-                match stream.read(&mut buffer) {
+                // The lock is only held for one read so senders are not starved
+                let result = stream.lock().unwrap_or_else(PoisonError::into_inner).read(&mut buffer);
+                match result {
                     Ok(n) if n > 0 => {
-                        let response = String::from_utf8_lossy(&buffer[..n]).to_string();
-                        let _ = response_tx.send(response);
+                        decoder.push(&buffer[..n]);
+                        loop {
+                            match decoder.next_message::<ServerMessage>() {
+                                Ok(Some(message)) => {
+                                    let _ = response_tx.send(message);
+                                }
+                                Ok(None) => break,
+                                Err(err) => {
+                                    tracing::error!("Dropping connection: {}", err);
+                                    return;
+                                }
+                            }
+                        }
                     }
                     Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
                         std::thread::sleep(std::time::Duration::from_millis(100));
@@ -36,8 +113,38 @@ impl Connection {
         });
     }
 
-    pub fn send_message(&mut self, message: String) -> Result<(), std::io::Error> {
-        self.stream.write_all(message.as_bytes())?;
-        Ok(())
+    pub fn send_frame(&mut self, frame: &ClientFrame) -> Result<(), std::io::Error> {
+        let bytes = encode_client_frame(frame);
+        let mut written = 0;
+        // The socket is non-blocking, so large upload chunks may need several
+        // attempts. The lock is taken per attempt, the listening thread needs
+        // it to drain what the server sends meanwhile.
+        while written < bytes.len() {
+            let result = self.lock_stream().write(&bytes[written..]);
+            match result {
+                // Nothing taken without an error, the peer no longer accepts data
+                Ok(0) => return Err(std::io::ErrorKind::WriteZero.into()),
+                Ok(n) => written += n,
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    std::thread::sleep(std::time::Duration::from_millis(5));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        // TLS may still hold encrypted records the socket did not take yet
+        loop {
+            let result = self.lock_stream().flush();
+            match result {
+                Ok(()) => return Ok(()),
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    std::thread::sleep(std::time::Duration::from_millis(5));
+                }
+                Err(e) => return Err(e),
+            }
+        }
     }
-} 
\ No newline at end of file
+
+    fn lock_stream(&self) -> std::sync::MutexGuard<'_, Stream> {
+        self.stream.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
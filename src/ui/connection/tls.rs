@@ -0,0 +1,98 @@
+use std::sync::Arc;
+
+use gtk::gio;
+use gtk::prelude::*;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{CertificateError, ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+
+use crate::tls::fingerprint_matches;
+
+/// TLS settings of the chat connection, read from GSettings.
+#[derive(Debug, Clone, Default)]
+pub struct TlsSettings {
+    pub enabled: bool,
+    /// Accept only this certificate instead of checking the CA chain.
+    pub pinned_fingerprint: String,
+}
+
+impl TlsSettings {
+    pub fn load() -> Self {
+        let settings = gio::Settings::new(crate::APP_ID);
+        Self {
+            enabled: settings.boolean("server-tls"),
+            pinned_fingerprint: settings.string("server-certificate-fingerprint").trim().to_string(),
+        }
+    }
+
+    pub fn client_config(&self) -> Result<Arc<ClientConfig>, rustls::Error> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let builder = ClientConfig::builder_with_provider(Arc::clone(&provider)).with_safe_default_protocol_versions()?;
+
+        let config = if self.pinned_fingerprint.is_empty() {
+            let roots = RootCertStore {
+                roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+            };
+            builder.with_root_certificates(roots).with_no_client_auth()
+        } else {
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(PinnedCertificate {
+                    fingerprint: self.pinned_fingerprint.clone(),
+                    provider,
+                }))
+                .with_no_client_auth()
+        };
+        Ok(Arc::new(config))
+    }
+}
+
+/// Trusts exactly one certificate, whatever signed it and whichever names
+/// it carries. The handshake signature is still checked, so the server has
+/// to hold the matching private key.
+#[derive(Debug)]
+struct PinnedCertificate {
+    fingerprint: String,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if fingerprint_matches(&self.fingerprint, end_entity) {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            tracing::error!("Server certificate does not match the pinned fingerprint");
+            Err(rustls::Error::InvalidCertificate(CertificateError::ApplicationVerificationFailure))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
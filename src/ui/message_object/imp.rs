@@ -1,13 +1,13 @@
 use std::cell::RefCell;
 use std::rc::Rc;
 
-use glib::{ParamSpec, ParamSpecString, Value};
+use glib::{ParamSpec, ParamSpecBoolean, ParamSpecString, ParamSpecUInt64, Value};
 use gtk::glib;
 use gtk::prelude::*;
 use gtk::subclass::prelude::*;
 use once_cell::sync::Lazy;
 
-use super::MessageData;
+use super::{MessageData, MessageKind};
 
 #[derive(Default)]
 pub struct MessageObject {
@@ -25,7 +25,12 @@ impl ObjectImpl for MessageObject {
         static PROPERTIES: Lazy<Vec<ParamSpec>> = Lazy::new(|| {
             vec![
                 ParamSpecString::builder("user").build(),
+                ParamSpecBoolean::builder("outgoing").build(),
                 ParamSpecString::builder("content").build(),
+                ParamSpecString::builder("kind").default_value(Some("text")).build(),
+                ParamSpecUInt64::builder("duration").build(),
+                ParamSpecString::builder("audio-path").build(),
+                ParamSpecString::builder("transcript").build(),
             ]
         });
         PROPERTIES.as_ref()
@@ -39,12 +44,42 @@ impl ObjectImpl for MessageObject {
                     .expect("The value needs to be of type `String`.");
                 self.data.borrow_mut().user = input_value;
             }
+            "outgoing" => {
+                let input_value = value
+                    .get()
+                    .expect("The value needs to be of type `bool`.");
+                self.data.borrow_mut().outgoing = input_value;
+            }
             "content" => {
                 let input_value = value
                     .get()
                     .expect("The value needs to be of type `String`.");
                 self.data.borrow_mut().content = input_value;
             }
+            "kind" => {
+                let input_value: String = value
+                    .get()
+                    .expect("The value needs to be of type `String`.");
+                self.data.borrow_mut().kind = MessageKind::from_name(&input_value);
+            }
+            "duration" => {
+                let input_value = value
+                    .get()
+                    .expect("The value needs to be of type `u64`.");
+                self.data.borrow_mut().duration = input_value;
+            }
+            "audio-path" => {
+                let input_value = value
+                    .get()
+                    .expect("The value needs to be of type `String`.");
+                self.data.borrow_mut().audio_path = input_value;
+            }
+            "transcript" => {
+                let input_value = value
+                    .get()
+                    .expect("The value needs to be of type `String`.");
+                self.data.borrow_mut().transcript = input_value;
+            }
             _ => unimplemented!(),
         }
     }
@@ -52,7 +87,12 @@ impl ObjectImpl for MessageObject {
     fn property(&self, _id: usize, pspec: &ParamSpec) -> Value {
         match pspec.name() {
             "user" => self.data.borrow().user.to_value(),
+            "outgoing" => self.data.borrow().outgoing.to_value(),
             "content" => self.data.borrow().content.to_value(),
+            "kind" => self.data.borrow().kind.as_str().to_value(),
+            "duration" => self.data.borrow().duration.to_value(),
+            "audio-path" => self.data.borrow().audio_path.to_value(),
+            "transcript" => self.data.borrow().transcript.to_value(),
             _ => unimplemented!(),
         }
     }
@@ -2,22 +2,90 @@ mod imp;
 
 use glib::Object;
 use gtk::glib;
+use gtk::subclass::prelude::*;
 
 glib::wrapper! {
     pub struct MessageObject(ObjectSubclass<imp::MessageObject>);
 }
 
 impl MessageObject {
-    pub fn new(user: String, content: String) -> Self {
+    /// `outgoing` marks what this client sent, `user` is who is shown as the sender.
+    pub fn new(user: String, content: String, outgoing: bool) -> Self {
         Object::builder()
             .property("user", user)
+            .property("outgoing", outgoing)
             .property("content", content)
             .build()
     }
+
+    /// A recording this client made, with the peak envelope its waveform is drawn from.
+    pub fn new_audio(user: String, audio_path: String, duration_ms: u64, peaks: Vec<f32>) -> Self {
+        let message: Self = Object::builder()
+            .property("user", user)
+            .property("outgoing", true)
+            .property("kind", MessageKind::Audio.as_str())
+            .property("audio-path", audio_path)
+            .property("duration", duration_ms)
+            .build();
+        message.imp().data.borrow_mut().peaks = peaks;
+        message
+    }
+
+    /// A tool the assistant called, `summary` is shown until the details are expanded.
+    pub fn new_tool(summary: String, details: String) -> Self {
+        Object::builder()
+            .property("user", summary)
+            .property("kind", MessageKind::Tool.as_str())
+            .property("content", details)
+            .build()
+    }
+
+    pub fn kind(&self) -> MessageKind {
+        MessageKind::from_name(&self.property::<String>("kind"))
+    }
+
+    /// Peak envelope of an audio message, empty when it could not be computed.
+    pub fn peaks(&self) -> Vec<f32> {
+        self.imp().data.borrow().peaks.clone()
+    }
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageKind {
+    #[default]
+    Text,
+    Audio,
+    Tool,
+}
+
+impl MessageKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MessageKind::Text => "text",
+            MessageKind::Audio => "audio",
+            MessageKind::Tool => "tool",
+        }
+    }
+
+    pub fn from_name(kind: &str) -> Self {
+        match kind {
+            "audio" => MessageKind::Audio,
+            "tool" => MessageKind::Tool,
+            _ => MessageKind::Text,
+        }
+    }
 }
 
 #[derive(Default)]
 pub struct MessageData {
     pub user: String,
+    pub outgoing: bool,
     pub content: String,
+    pub kind: MessageKind,
+    /// Length of an audio message in milliseconds.
+    pub duration: u64,
+    pub audio_path: String,
+    pub transcript: String,
+    /// Computed once when the recording finishes.
+    pub peaks: Vec<f32>,
 }
\ No newline at end of file
@@ -1,15 +1,41 @@
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 
-use glib::Binding;
+use glib::{Binding, SignalHandlerId};
+use gtk::prelude::*;
 use gtk::subclass::prelude::*;
-use gtk::{glib, CompositeTemplate, Label};
+use gtk::{glib, Box, Button, CompositeTemplate, DrawingArea, Expander, GestureClick, Label, MediaFile};
+
+use crate::ui::message_object::MessageObject;
 
 #[derive(Default, CompositeTemplate)]
 #[template(resource = "/com/geeksesi/talk-to-me/message_row.ui")]
 pub struct MessageRow {
+    #[template_child]
+    pub sender_label: TemplateChild<Label>,
     #[template_child]
     pub content_label: TemplateChild<Label>,
+    #[template_child]
+    pub audio_box: TemplateChild<Box>,
+    #[template_child]
+    pub play_button: TemplateChild<Button>,
+    #[template_child]
+    pub waveform_area: TemplateChild<DrawingArea>,
+    #[template_child]
+    pub elapsed_label: TemplateChild<Label>,
+    #[template_child]
+    pub transcript_label: TemplateChild<Label>,
+    #[template_child]
+    pub tool_expander: TemplateChild<Expander>,
+    #[template_child]
+    pub tool_details_label: TemplateChild<Label>,
     pub bindings: RefCell<Vec<Binding>>,
+    pub message: RefCell<Option<(MessageObject, SignalHandlerId)>>,
+    pub media: RefCell<Option<MediaFile>>,
+    pub peaks: RefCell<Vec<f32>>,
+    /// Playback position as a fraction of the message duration.
+    pub progress: Cell<f64>,
+    /// Recorded length of the message, zero when it is not known.
+    pub duration_ms: Cell<u64>,
 }
 
 #[glib::object_subclass]
@@ -27,7 +53,36 @@ impl ObjectSubclass for MessageRow {
     }
 }
 
-impl ObjectImpl for MessageRow {}
+impl ObjectImpl for MessageRow {
+    fn constructed(&self) {
+        self.parent_constructed();
+
+        let weak_row = self.obj().downgrade();
+        self.play_button.connect_clicked(move |_| {
+            if let Some(row) = weak_row.upgrade() {
+                row.toggle_playback();
+            }
+        });
+
+        let weak_row = self.obj().downgrade();
+        self.waveform_area.set_draw_func(move |area, cr, width, height| {
+            if let Some(row) = weak_row.upgrade() {
+                row.draw_waveform(area, cr, width, height);
+            }
+        });
+        self.waveform_area.set_cursor_from_name(Some("pointer"));
+
+        let click = GestureClick::new();
+        let weak_row = self.obj().downgrade();
+        click.connect_pressed(move |_, _, x, _| {
+            if let Some(row) = weak_row.upgrade() {
+                let width = row.imp().waveform_area.width().max(1) as f64;
+                row.seek_to_fraction(x / width);
+            }
+        });
+        self.waveform_area.add_controller(click);
+    }
+}
 
 impl WidgetImpl for MessageRow {}
 
@@ -5,7 +5,7 @@ use gtk::glib;
 use gtk::prelude::*;
 use gtk::subclass::prelude::*;
 
-use crate::ui::message_object::MessageObject;
+use crate::ui::message_object::{MessageKind, MessageObject};
 
 glib::wrapper! {
     pub struct MessageRow(ObjectSubclass<imp::MessageRow>)
@@ -25,33 +25,242 @@ impl MessageRow {
     }
 
     pub fn bind(&self, message_object: &MessageObject) {
+        let sender_label = self.imp().sender_label.get();
         let content_label = self.imp().content_label.get();
-        let mut bindings = self.imp().bindings.borrow_mut();
-
-        let user: String = message_object
-            .property::<String>("user");
-            
+        let transcript_label = self.imp().transcript_label.get();
 
         let widget = self.upcast_ref::<gtk::Widget>();
         widget.remove_css_class("message-ai");
         widget.remove_css_class("message-user");
-        
-        if user == "You" {
+        widget.remove_css_class("message-tool");
+
+        if message_object.kind() == MessageKind::Tool {
+            self.bind_tool(message_object);
+            return;
+        }
+        sender_label.set_visible(true);
+        self.imp().tool_expander.set_visible(false);
+
+        let mut bindings = self.imp().bindings.borrow_mut();
+        let outgoing = message_object.property::<bool>("outgoing");
+
+        if outgoing {
             widget.add_css_class("message-user");
         } else {
             widget.add_css_class("message-ai");
         }
 
+        let sender_label_binding = message_object
+            .bind_property("user", &sender_label, "label")
+            .flags(BindingFlags::SYNC_CREATE)
+            .build();
+        bindings.push(sender_label_binding);
+
         let content_label_binding = message_object
             .bind_property("content", &content_label, "label")
             .flags(BindingFlags::SYNC_CREATE)
             .build();
         bindings.push(content_label_binding);
+
+        let transcript_label_binding = message_object
+            .bind_property("transcript", &transcript_label, "label")
+            .flags(BindingFlags::SYNC_CREATE)
+            .build();
+        bindings.push(transcript_label_binding);
+
+        // The transcript usually arrives after the recording has been shown
+        let weak_row = self.downgrade();
+        let transcript_handler = message_object.connect_notify_local(Some("transcript"), move |message, _| {
+            if let Some(row) = weak_row.upgrade() {
+                row.update_transcript_visibility(message);
+            }
+        });
+        self.imp()
+            .message
+            .replace(Some((message_object.clone(), transcript_handler)));
+        self.update_transcript_visibility(message_object);
+
+        let is_audio = message_object.kind() == MessageKind::Audio;
+        content_label.set_visible(!is_audio);
+        self.imp().audio_box.set_visible(is_audio);
+        if is_audio {
+            self.setup_media(message_object);
+        }
+    }
+
+    /// Tool rows show only an expander, the summary as its label and the
+    /// arguments and output inside.
+    fn bind_tool(&self, message_object: &MessageObject) {
+        let imp = self.imp();
+        self.add_css_class("message-tool");
+        imp.sender_label.set_visible(false);
+        imp.content_label.set_visible(false);
+        imp.audio_box.set_visible(false);
+        imp.transcript_label.set_visible(false);
+        imp.tool_expander.set_visible(true);
+        imp.tool_expander.set_expanded(false);
+
+        let mut bindings = imp.bindings.borrow_mut();
+        bindings.push(
+            message_object
+                .bind_property("user", &imp.tool_expander.get(), "label")
+                .flags(BindingFlags::SYNC_CREATE)
+                .build(),
+        );
+        bindings.push(
+            message_object
+                .bind_property("content", &imp.tool_details_label.get(), "label")
+                .flags(BindingFlags::SYNC_CREATE)
+                .build(),
+        );
     }
 
     pub fn unbind(&self) {
         for binding in self.imp().bindings.borrow_mut().drain(..) {
             binding.unbind();
         }
+        if let Some((message_object, handler)) = self.imp().message.take() {
+            message_object.disconnect(handler);
+        }
+        // Rows are recycled, so a row scrolled out of view must not keep playing
+        if let Some(media) = self.imp().media.take() {
+            media.set_playing(false);
+        }
+        self.imp().peaks.borrow_mut().clear();
+        self.imp().progress.set(0.0);
+        self.imp().duration_ms.set(0);
+    }
+
+    fn update_transcript_visibility(&self, message_object: &MessageObject) {
+        let transcript = message_object.property::<String>("transcript");
+        self.imp().transcript_label.set_visible(!transcript.is_empty());
+    }
+
+    fn setup_media(&self, message_object: &MessageObject) {
+        let audio_path = message_object.property::<String>("audio-path");
+        self.imp().duration_ms.set(message_object.property::<u64>("duration"));
+        let media = gtk::MediaFile::for_filename(&audio_path);
+        self.imp().peaks.replace(message_object.peaks());
+
+        let weak_row = self.downgrade();
+        media.connect_notify_local(Some("timestamp"), move |media, _| {
+            if let Some(row) = weak_row.upgrade() {
+                row.update_progress(media);
+            }
+        });
+        let weak_row = self.downgrade();
+        media.connect_notify_local(Some("playing"), move |media, _| {
+            if let Some(row) = weak_row.upgrade() {
+                row.update_play_button(media.is_playing());
+            }
+        });
+        let weak_row = self.downgrade();
+        media.connect_notify_local(Some("ended"), move |media, _| {
+            if media.is_ended() {
+                if let Some(row) = weak_row.upgrade() {
+                    row.update_play_button(false);
+                }
+            }
+        });
+
+        self.update_play_button(false);
+        self.update_progress(&media);
+        self.imp().media.replace(Some(media));
     }
+
+    fn toggle_playback(&self) {
+        let Some(media) = self.imp().media.borrow().clone() else {
+            return;
+        };
+        if media.is_playing() {
+            media.pause();
+        } else {
+            if media.is_ended() {
+                media.seek(0);
+            }
+            media.play();
+        }
+    }
+
+    fn update_play_button(&self, playing: bool) {
+        let button = self.imp().play_button.get();
+        if playing {
+            button.set_icon_name("media-playback-pause-symbolic");
+            button.set_tooltip_text(Some("Pause"));
+        } else {
+            button.set_icon_name("media-playback-start-symbolic");
+            button.set_tooltip_text(Some("Play"));
+        }
+    }
+
+    /// Prefers the recorded length, the stream only knows its duration once prepared.
+    fn duration_us(&self, media: &gtk::MediaFile) -> i64 {
+        match self.imp().duration_ms.get() {
+            0 => media.duration(),
+            ms => ms as i64 * 1000,
+        }
+    }
+
+    fn update_progress(&self, media: &gtk::MediaFile) {
+        let duration_us = self.duration_us(media);
+        let elapsed_us = media.timestamp().min(duration_us);
+        let fraction = if duration_us > 0 {
+            elapsed_us as f64 / duration_us as f64
+        } else {
+            0.0
+        };
+
+        self.imp().progress.set(fraction);
+        self.imp().waveform_area.queue_draw();
+        self.imp().elapsed_label.set_label(&format!(
+            "{} / {}",
+            format_time(elapsed_us),
+            format_time(duration_us)
+        ));
+    }
+
+    fn seek_to_fraction(&self, fraction: f64) {
+        let Some(media) = self.imp().media.borrow().clone() else {
+            return;
+        };
+        // Neither the recording nor the stream may know the length yet
+        let duration_us = self.duration_us(&media);
+        if duration_us <= 0 || !media.is_seekable() {
+            return;
+        }
+        media.seek((duration_us as f64 * fraction.clamp(0.0, 1.0)) as i64);
+    }
+
+    fn draw_waveform(&self, area: &gtk::DrawingArea, cr: &gtk::cairo::Context, width: i32, height: i32) {
+        let peaks = self.imp().peaks.borrow();
+        if peaks.is_empty() {
+            return;
+        }
+
+        let color = area.color();
+        let played_until = self.imp().progress.get() * width as f64;
+        let slot = width as f64 / peaks.len() as f64;
+        let bar_width = (slot * 0.6).max(1.0);
+        let height = height as f64;
+
+        for (i, peak) in peaks.iter().enumerate() {
+            let x = i as f64 * slot;
+            // Keep silent parts visible as a thin line
+            let bar_height = (*peak as f64 * height).max(2.0);
+            let alpha = if x < played_until { 1.0 } else { 0.35 };
+            cr.set_source_rgba(
+                color.red() as f64,
+                color.green() as f64,
+                color.blue() as f64,
+                alpha,
+            );
+            cr.rectangle(x, (height - bar_height) / 2.0, bar_width, bar_height);
+            let _ = cr.fill();
+        }
+    }
+}
+
+fn format_time(microseconds: i64) -> String {
+    let seconds = microseconds.max(0) / 1_000_000;
+    format!("{}:{:02}", seconds / 60, seconds % 60)
 }
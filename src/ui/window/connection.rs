@@ -1,23 +1,47 @@
 use std::sync::mpsc::{channel, Sender, Receiver};
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::thread;
-use crate::ui::connection::Connection;
+use gtk::gio;
+use gtk::prelude::*;
+use crate::protocol::frame::ClientFrame;
+use crate::protocol::message::{ClientMessage, ServerMessage};
+use crate::ui::connection::{Connection, TlsSettings};
+
+const UPLOAD_CHUNK_SAMPLES: usize = 4096;
 
 pub struct WindowConnection {
-    sender: Sender<String>,
-    receiver: Receiver<String>,
+    sender: Sender<ClientFrame>,
+    receiver: Receiver<ServerMessage>,
+    next_upload_id: AtomicU32,
 }
 
 impl WindowConnection {
     pub fn new() -> Self {
-        let (tx, rx) = channel();
+        let (tx, rx) = channel::<ClientFrame>();
         let (response_tx, response_rx) = channel();
-        
+        // Read here, GSettings belongs to the main thread
+        let tls = TlsSettings::load();
+        let token = gio::Settings::new(crate::APP_ID).string("server-token").trim().to_string();
+
         thread::spawn(move || {
-            if let Ok(mut connection) = Connection::new() {
-                connection.start_listening(response_tx);
-                
-                for message in rx {
-                    let _ = connection.send_message(message);
+            let mut connection = match Connection::new(&tls) {
+                Ok(connection) => connection,
+                Err(err) => {
+                    tracing::error!("Could not connect to the server: {}", err);
+                    return;
+                }
+            };
+            connection.start_listening(response_tx);
+            if !token.is_empty() {
+                let authenticate = ClientFrame::Message(ClientMessage::Authenticate { token });
+                if let Err(err) = connection.send_frame(&authenticate) {
+                    tracing::error!("Failed to send the token to the server: {}", err);
+                }
+            }
+
+            for frame in rx {
+                if let Err(err) = connection.send_frame(&frame) {
+                    tracing::error!("Failed to send to server: {}", err);
                 }
             }
         });
@@ -25,14 +49,37 @@ impl WindowConnection {
         WindowConnection {
             sender: tx,
             receiver: response_rx,
+            next_upload_id: AtomicU32::new(0),
         }
     }
 
     pub fn send(&self, message: String) {
-        let _ = self.sender.send(message);
+        self.send_message(ClientMessage::Chat { text: message });
     }
 
-    pub fn try_receive(&self) -> Option<String> {
+    pub fn send_message(&self, message: ClientMessage) {
+        let _ = self.sender.send(ClientFrame::Message(message));
+    }
+
+    /// Streams decoded audio to the server as a chunked upload and returns its id.
+    pub fn upload_audio(&self, file_name: String, samples: Vec<f32>) -> u32 {
+        let upload_id = self.next_upload_id.fetch_add(1, Ordering::Relaxed);
+        let _ = self
+            .sender
+            .send(ClientFrame::Message(ClientMessage::UploadStart { upload_id, file_name }));
+        for chunk in samples.chunks(UPLOAD_CHUNK_SAMPLES) {
+            let _ = self.sender.send(ClientFrame::UploadChunk {
+                upload_id,
+                samples: chunk.to_vec(),
+            });
+        }
+        let _ = self
+            .sender
+            .send(ClientFrame::Message(ClientMessage::UploadEnd { upload_id }));
+        upload_id
+    }
+
+    pub fn try_receive(&self) -> Option<ServerMessage> {
         self.receiver.try_recv().ok()
     }
-} 
\ No newline at end of file
+}
@@ -2,7 +2,8 @@
 use glib::subclass::InitializingObject;
 use gtk::prelude::*;
 use gtk::subclass::prelude::*;
-use gtk::{gio, glib, CompositeTemplate, Entry, ListView, Button};
+use gtk::{gio, glib, CompositeTemplate, Entry, Label, ListView, Button, Revealer, ToggleButton};
+use gtk::{DropDown, MenuButton, Popover, StringList};
 use std::cell::RefCell;
 use super::connection::WindowConnection;
 use super::super::audio::AudioCapture;
@@ -14,12 +15,36 @@ pub struct Window {
     #[template_child]
     pub entry: TemplateChild<Entry>,
     #[template_child]
+    pub latency_revealer: TemplateChild<Revealer>,
+    #[template_child]
+    pub latency_label: TemplateChild<Label>,
+    #[template_child]
+    pub latency_button: TemplateChild<ToggleButton>,
+    #[template_child]
+    pub new_conversation_button: TemplateChild<MenuButton>,
+    #[template_child]
+    pub new_conversation_popover: TemplateChild<Popover>,
+    #[template_child]
+    pub prompt_dropdown: TemplateChild<DropDown>,
+    #[template_child]
+    pub prompt_list: TemplateChild<StringList>,
+    #[template_child]
+    pub start_conversation_button: TemplateChild<Button>,
+    #[template_child]
+    pub attach_button: TemplateChild<Button>,
+    #[template_child]
     pub voice_button: TemplateChild<Button>,
     #[template_child]
     pub messages_list: TemplateChild<ListView>,
     pub messages: RefCell<Option<gio::ListStore>>,
     pub connection: RefCell<Option<WindowConnection>>,
     pub audio_capture: RefCell<Option<AudioCapture>>,
+    /// What others in a room see as this client's name, set by the server.
+    pub display_name: RefCell<Option<String>>,
+    /// Title from the template, shown again once the client leaves its room.
+    pub default_title: RefCell<String>,
+    /// Template names in the order of the prompt picker, `None` for no template.
+    pub prompt_names: RefCell<Vec<Option<String>>>,
 }
 
 #[glib::object_subclass]
@@ -12,7 +12,15 @@ use gtk::{prelude::*, ListItem};
 use serde::{Deserialize, Serialize};
 // use serde_json::json;
 use crate::ui::window::connection::WindowConnection;
-use crate::ui::audio::AudioCapture;
+use crate::ui::audio::{waveform, AudioCapture, Recording};
+use crate::ui::audio::file::decode_audio_file;
+use crate::protocol::message::{ClientMessage, PromptSummary, ServerMessage};
+use std::path::PathBuf;
+
+/// Sender shown on what the client itself reports, like errors and uploads.
+const NOTICE_SENDER: &str = "Server";
+/// Sender of the greeting, before the server has named anyone.
+const ASSISTANT_SENDER: &str = "Assistant";
 
 glib::wrapper! {
     pub struct Window(ObjectSubclass<imp::Window>)
@@ -60,16 +68,26 @@ impl Window {
 
     fn setup_callbacks(&self) {
         self.imp().connection.replace(Some(WindowConnection::new()));
-        self.add_message(false, &"Hello! How can I help you today?".to_string());
+        self.imp()
+            .default_title
+            .replace(self.title().map(|title| title.to_string()).unwrap_or_default());
+        self.add_message(ASSISTANT_SENDER, "Hello! How can I help you today?");
 
         // Setup a timeout to check for server responses
         let weak_window = self.downgrade();
         glib::timeout_add_local(std::time::Duration::from_millis(100), move || {
             if let Some(window) = weak_window.upgrade() {
-                if let Some(connection) = window.imp().connection.borrow().as_ref() {
-                    if let Some(response) = connection.try_receive() {
-                        window.add_message(false, &response);
-                    }
+                let message = window
+                    .imp()
+                    .connection
+                    .borrow()
+                    .as_ref()
+                    .and_then(|connection| connection.try_receive());
+                if let Some(message) = message {
+                    window.handle_server_message(message);
+                }
+                if window.imp().latency_revealer.reveals_child() {
+                    window.update_latency_panel();
                 }
             }
             glib::ControlFlow::Continue
@@ -92,6 +110,48 @@ impl Window {
             }
         });
 
+        self.imp().latency_button.connect_toggled({
+            let weak_window = self.downgrade();
+            move |button| {
+                if let Some(window) = weak_window.upgrade() {
+                    let enabled = button.is_active();
+                    if let Some(audio_capture) = window.imp().audio_capture.borrow().as_ref() {
+                        audio_capture.set_latency_diagnostics(enabled);
+                    }
+                    window.imp().latency_revealer.set_reveal_child(enabled);
+                    window.update_latency_panel();
+                }
+            }
+        });
+
+        self.imp().prompt_names.replace(vec![None]);
+        // The templates on the server may have changed since the list was fetched
+        self.imp().new_conversation_popover.connect_show({
+            let weak_window = self.downgrade();
+            move |_| {
+                if let Some(window) = weak_window.upgrade() {
+                    window.send_to_server(ClientMessage::ListPrompts);
+                }
+            }
+        });
+        self.imp().start_conversation_button.connect_clicked({
+            let weak_window = self.downgrade();
+            move |_| {
+                if let Some(window) = weak_window.upgrade() {
+                    window.start_conversation();
+                }
+            }
+        });
+
+        self.imp().attach_button.connect_clicked({
+            let weak_window = self.downgrade();
+            move |_| {
+                if let Some(window) = weak_window.upgrade() {
+                    window.attach_audio_file();
+                }
+            }
+        });
+
         // Add voice button handling
         self.imp().audio_capture.replace(Some(AudioCapture::new()));
         
@@ -99,28 +159,281 @@ impl Window {
             let weak_window = self.downgrade();
             move |button| {
                 if let Some(window) = weak_window.upgrade() {
+                    let mut recording = None;
                     if let Some(audio_capture) = window.imp().audio_capture.borrow_mut().as_mut() {
                         let is_recording = audio_capture.toggle_recording();
                         if is_recording {
                             button.set_icon_name("microphone-sensitivity-high-symbolic");
                         } else {
                             button.set_icon_name("microphone-sensitivity-muted-symbolic");
+                            recording = audio_capture.take_finished_recording();
                         }
                     }
+                    if let Some(recording) = recording {
+                        window.add_voice_message(recording);
+                    }
                 }
             }
         });
     }
 
-    fn add_message(&self, user: bool, msg: &String) {
-        let from_who = match user {
-            true => "You",
-            false => "AI",
-        };
-        let message = MessageObject::new(from_who.parse().unwrap(), msg.to_string());
+    /// Shows a message from someone else, labelled with who sent it.
+    fn add_message(&self, sender: &str, msg: &str) {
+        let message = MessageObject::new(sender.to_string(), msg.to_string(), false);
         self.messages().append(&message);
     }
 
+    fn add_own_message(&self, msg: &str) {
+        let message = MessageObject::new(self.own_name(), msg.to_string(), true);
+        self.messages().append(&message);
+    }
+
+    fn add_tool_activity(&self, tool: &str, arguments: &str, output: &str, ok: bool, duration_ms: u64) {
+        let summary = if ok {
+            format!("Used {} ({} ms)", tool, duration_ms)
+        } else {
+            format!("{} failed ({} ms)", tool, duration_ms)
+        };
+        let details = format!("Arguments: {}\n{}", arguments, output.trim_end());
+        self.messages().append(&MessageObject::new_tool(summary, details));
+    }
+
+    fn add_notice(&self, msg: &str) {
+        self.add_message(NOTICE_SENDER, msg);
+    }
+
+    fn send_to_server(&self, message: ClientMessage) {
+        if let Some(connection) = self.imp().connection.borrow().as_ref() {
+            connection.send_message(message);
+        }
+    }
+
+    /// Fills the prompt picker, with the server's default selected.
+    fn show_prompts(&self, prompts: Vec<PromptSummary>, default: Option<String>) {
+        let mut names = vec![None];
+        let mut labels = vec!["None".to_string()];
+        for prompt in prompts {
+            labels.push(if prompt.description.is_empty() {
+                prompt.name.clone()
+            } else {
+                format!("{} – {}", prompt.name, prompt.description)
+            });
+            names.push(Some(prompt.name));
+        }
+        let selected = names.iter().position(|name| *name == default).unwrap_or(0);
+        let labels: Vec<&str> = labels.iter().map(String::as_str).collect();
+        let list = &self.imp().prompt_list;
+        list.splice(0, list.n_items(), &labels);
+        self.imp().prompt_names.replace(names);
+        self.imp().prompt_dropdown.set_selected(selected as u32);
+    }
+
+    fn start_conversation(&self) {
+        let selected = self.imp().prompt_dropdown.selected() as usize;
+        let template = self.imp().prompt_names.borrow().get(selected).cloned().flatten();
+        self.imp().new_conversation_popover.popdown();
+        self.send_to_server(ClientMessage::NewConversation { template });
+    }
+
+    /// The name room members know this client by, "You" until the server has sent it.
+    fn own_name(&self) -> String {
+        self.imp()
+            .display_name
+            .borrow()
+            .clone()
+            .unwrap_or_else(|| "You".to_string())
+    }
+
+    /// Shows a finished recording once its waveform is computed. The file is
+    /// decoded once, off the main loop, and the peaks stay with the message.
+    fn add_voice_message(&self, recording: Recording) {
+        let weak_window = self.downgrade();
+        glib::spawn_future_local(async move {
+            let path = recording.path.clone();
+            let peaks = match gio::spawn_blocking(move || waveform::compute_and_cache(&path)).await {
+                Ok(Ok(peaks)) => peaks,
+                Ok(Err(err)) => {
+                    tracing::warn!("No waveform for {}: {}", recording.path.display(), err);
+                    Vec::new()
+                }
+                Err(_) => {
+                    tracing::error!("Waveform thread panicked");
+                    Vec::new()
+                }
+            };
+            let Some(window) = weak_window.upgrade() else {
+                return;
+            };
+            let message = MessageObject::new_audio(
+                window.own_name(),
+                recording.path.to_string_lossy().into_owned(),
+                recording.duration_ms,
+                peaks,
+            );
+            window.messages().append(&message);
+        });
+    }
+
+    fn update_latency_panel(&self) {
+        let summary = self
+            .imp()
+            .audio_capture
+            .borrow()
+            .as_ref()
+            .and_then(|audio_capture| audio_capture.latency_summary());
+        let text = match summary {
+            Some(summary) => format!(
+                "Latency over {} probes\nMouth to server:   {}\nServer processing: {}\nRound trip:        {}",
+                summary.samples, summary.mouth_to_server, summary.server_processing, summary.round_trip
+            ),
+            None => "Start recording to measure latency.".to_string(),
+        };
+        self.imp().latency_label.set_label(&text);
+    }
+
+    fn handle_server_message(&self, message: ServerMessage) {
+        match message {
+            ServerMessage::Welcome {
+                session_id,
+                voice_key,
+                voice_unavailable,
+                user,
+                display_name,
+            } => {
+                match user {
+                    Some(user) => tracing::info!("Connected as {} in session {}", user, session_id),
+                    None => tracing::info!("Connected as session {}", session_id),
+                }
+                self.imp().display_name.replace(Some(display_name));
+                self.send_to_server(ClientMessage::ListPrompts);
+                match voice_key {
+                    Some(voice_key) => {
+                        if let Some(audio_capture) = self.imp().audio_capture.borrow_mut().as_mut() {
+                            audio_capture.set_session(session_id, &voice_key);
+                        }
+                    }
+                    None => {
+                        let reason = voice_unavailable.unwrap_or_else(|| "The server offers no voice.".to_string());
+                        tracing::warn!("{}", reason);
+                        self.add_notice(&reason);
+                    }
+                }
+            }
+            ServerMessage::Chat { sender, text, .. } => self.add_message(&sender, &text),
+            ServerMessage::ToolActivity {
+                tool,
+                arguments,
+                output,
+                ok,
+                duration_ms,
+                ..
+            } => self.add_tool_activity(&tool, &arguments, &output, ok, duration_ms),
+            ServerMessage::RoomJoined { room, members } => {
+                self.set_title(Some(&room));
+                self.add_notice(&format!("You are in room {} with {}.", room, members.join(", ")));
+            }
+            ServerMessage::RoomLeft { room } => {
+                self.set_title(Some(&self.imp().default_title.borrow()));
+                self.add_notice(&format!("You left room {}.", room));
+            }
+            ServerMessage::ActiveSpeaker { room, speaker } => {
+                let title = match speaker {
+                    Some(speaker) => format!("{} · {} is speaking", room, speaker),
+                    None => room,
+                };
+                self.set_title(Some(&title));
+            }
+            ServerMessage::RoomList { rooms } => {
+                let text = if rooms.is_empty() {
+                    "There are no rooms yet, create one with /create <name>.".to_string()
+                } else {
+                    let rooms: Vec<String> = rooms
+                        .iter()
+                        .map(|room| format!("{} ({} members)", room.name, room.members))
+                        .collect();
+                    format!("Rooms: {}", rooms.join(", "))
+                };
+                self.add_notice(&text);
+            }
+            ServerMessage::PromptList { prompts, default } => self.show_prompts(prompts, default),
+            ServerMessage::ConversationStarted { template } => {
+                self.messages().remove_all();
+                match template {
+                    Some(template) => self.add_notice(&format!("New conversation with the {} prompt.", template)),
+                    None => self.add_notice("New conversation without a system prompt."),
+                }
+            }
+            ServerMessage::PromptChanged { template, changed_by, .. } => match template {
+                Some(template) => self.add_notice(&format!("{} switched the prompt to {}.", changed_by, template)),
+                None => self.add_notice(&format!("{} removed the prompt.", changed_by)),
+            },
+            ServerMessage::MemberJoined { room, name } => {
+                self.add_notice(&format!("{} joined room {}.", name, room));
+            }
+            ServerMessage::MemberLeft { room, name } => {
+                self.add_notice(&format!("{} left room {}.", name, room));
+            }
+            ServerMessage::UploadComplete { duration_ms, .. } => {
+                self.add_notice(&format!("Received {:.1}s of uploaded audio.", duration_ms as f64 / 1000.0));
+            }
+            ServerMessage::Error { message } => self.add_notice(&format!("Error: {}", message)),
+            ServerMessage::ShuttingDown => self.add_notice("The server is shutting down."),
+            ServerMessage::Disconnected { reason } => {
+                self.add_notice(&format!("Disconnected by the server: {}", reason))
+            }
+        }
+    }
+
+    fn attach_audio_file(&self) {
+        let filter = gtk::FileFilter::new();
+        filter.set_name(Some("Audio files"));
+        for suffix in ["wav", "flac", "ogg", "oga"] {
+            filter.add_suffix(suffix);
+        }
+        let filters = gio::ListStore::new::<gtk::FileFilter>();
+        filters.append(&filter);
+
+        let dialog = gtk::FileDialog::builder()
+            .title("Attach audio file")
+            .modal(true)
+            .filters(&filters)
+            .default_filter(&filter)
+            .build();
+
+        let weak_window = self.downgrade();
+        dialog.open(Some(self), gio::Cancellable::NONE, move |result| {
+            let Some(window) = weak_window.upgrade() else {
+                return;
+            };
+            let Some(path) = result.ok().and_then(|file| file.path()) else {
+                return;
+            };
+            glib::spawn_future_local(async move {
+                window.upload_audio_file(path).await;
+            });
+        });
+    }
+
+    async fn upload_audio_file(&self, path: PathBuf) {
+        let file_name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        // Decoding a long file takes a while, keep it off the main loop
+        let decoded = gio::spawn_blocking(move || decode_audio_file(&path)).await;
+        match decoded {
+            Ok(Ok(audio)) => {
+                self.add_own_message(&format!("Attached {} ({:.1}s)", file_name, audio.duration_secs()));
+                if let Some(connection) = self.imp().connection.borrow().as_ref() {
+                    connection.upload_audio(file_name, audio.samples);
+                }
+            }
+            Ok(Err(err)) => self.add_notice(&format!("Could not read {}: {}", file_name, err)),
+            Err(_) => tracing::error!("Audio decoding thread panicked"),
+        }
+    }
+
     fn send_message(&self) {
         let buffer = self.imp().entry.buffer();
         let content = buffer.text();
@@ -128,7 +441,12 @@ impl Window {
             return;
         }
         buffer.set_text("");
-        self.add_message(true, &content.to_string());
+
+        if content.starts_with('/') {
+            self.run_command(&content);
+            return;
+        }
+        self.add_own_message(&content);
 
         // Send message to server
         if let Some(connection) = self.imp().connection.borrow().as_ref() {
@@ -136,6 +454,49 @@ impl Window {
         }
     }
 
+    /// Commands typed into the entry: /create, /join, /leave, /rooms, /volume,
+    /// /prompts, /new and /prompt.
+    fn run_command(&self, input: &str) {
+        let words: Vec<&str> = input.split_whitespace().collect();
+        let message = match words.as_slice() {
+            ["/create", room] => ClientMessage::CreateRoom { room: room.to_string() },
+            ["/join", room] => ClientMessage::JoinRoom { room: room.to_string() },
+            ["/leave"] => ClientMessage::LeaveRoom,
+            ["/rooms"] => ClientMessage::ListRooms,
+            ["/prompts"] => {
+                // Opening the picker fetches the templates
+                self.imp().new_conversation_button.popup();
+                return;
+            }
+            ["/new"] => ClientMessage::NewConversation { template: None },
+            ["/new", template] => ClientMessage::NewConversation {
+                template: Some(template.to_string()),
+            },
+            ["/prompt", "none"] => ClientMessage::SetPrompt { template: None },
+            ["/prompt", template] => ClientMessage::SetPrompt {
+                template: Some(template.to_string()),
+            },
+            ["/volume", speaker, percent] => match percent.parse::<f32>() {
+                Ok(percent) => ClientMessage::SetSpeakerGain {
+                    speaker: speaker.to_string(),
+                    gain: percent / 100.0,
+                },
+                Err(_) => {
+                    self.add_notice("The volume is a percentage, like 50 or 150.");
+                    return;
+                }
+            },
+            _ => {
+                self.add_notice(
+                    "Commands: /create <room>, /join <room>, /leave, /rooms, /volume <name> <percent>, \
+                    /prompts, /new [template], /prompt <template|none>",
+                );
+                return;
+            }
+        };
+        self.send_to_server(message);
+    }
+
     fn setup_factory(&self) {
         let factory = SignalListItemFactory::new();
 